@@ -0,0 +1,207 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::io;
+
+use bitcoin_hashes::{Hash, sha256};
+
+use crate::bcindex::{self, Spender, UNKNOWN_HEIGHT};
+use crate::bcparse::Block;
+use crate::bcscript::address::address_script;
+use crate::bcutils::reverse_hash;
+use crate::bcutxo::is_unspendable;
+
+pub const SCRIPT_TREE: &str = "script";
+
+// Outputs funding each script, in a tree of the transaction index store:
+//   script  script hash, txid, output index  -> value, height, position in the block
+// An output found again (block indexed twice, or the same transaction in a fork) keeps one entry.
+// Spends are not written here, they come from the spent-by index at query time.
+
+type OutPoint = ([u8; 32], u32);
+
+struct Funding {
+    outpoint: OutPoint,
+    value: i64,
+    height: u32,
+    position: u32,
+}
+
+struct Movement {
+    txid: String,
+    height: u32,
+    position: u32,
+    delta: i64,
+}
+
+// Electrum's script hash: sha256 of the output script, shown byte reversed
+pub fn script_hash(script: &[u8]) -> [u8; 32] {
+    sha256::Hash::hash(script).into_inner()
+}
+
+// Adds every output that can be spent, as the UTXO set does
+pub fn index_block(block: &Block, height: u32) -> io::Result<()> {
+    add_block(&bcindex::open_tree(SCRIPT_TREE)?, block, height)
+}
+
+pub fn add_block(scripts: &sled::Tree, block: &Block, height: u32) -> io::Result<()> {
+    let mut batch = sled::Batch::default();
+    for (position, tx) in block.txns.iter().enumerate() {
+        let txid = bcindex::hash_bytes(&tx.hash);
+        for (vout, output) in tx.outputs.iter().enumerate() {
+            let script = hex::decode(&output.pub_key_script).unwrap_or_default();
+            if is_unspendable(&script) {
+                continue;
+            }
+            batch.insert(
+                [&script_hash(&script)[..], &txid, &(vout as u32).to_le_bytes()].concat(),
+                [&output.value.to_le_bytes()[..], &height.to_le_bytes(), &(position as u32).to_le_bytes()].concat(),
+            );
+        }
+    }
+    scripts.apply_batch(batch)?;
+    Ok(())
+}
+
+// In chain order
+fn fundings(scripts: &sled::Tree, hash: &[u8; 32]) -> io::Result<Vec<Funding>> {
+    let mut fundings = Vec::new();
+    for entry in scripts.scan_prefix(hash) {
+        let (key, value) = entry?;
+        let field = |bytes: &[u8], from: usize| u32::from_le_bytes(bytes[from..from + 4].try_into().unwrap());
+        fundings.push(Funding {
+            outpoint: (key[32..64].try_into().unwrap(), field(&key, 64)),
+            value: i64::from_le_bytes(value[..8].try_into().unwrap()),
+            height: field(&value, 8),
+            position: field(&value, 12),
+        });
+    }
+    fundings.sort_by_key(|funding| (funding.height, funding.position, funding.outpoint.1));
+    Ok(fundings)
+}
+
+// Net amount of each transaction funding or spending the script, in chain order
+fn movements(fundings: &[Funding], spenders: &HashMap<OutPoint, Spender>) -> io::Result<Vec<Movement>> {
+    let spending_txids: HashSet<[u8; 32]> = spenders.values()
+        .map(|spender| bcindex::hash_bytes(&reverse_hash(&spender.txid)))
+        .collect();
+    let locations = bcindex::tx_locations(&spending_txids)?;
+
+    let mut movements: HashMap<String, Movement> = HashMap::new();
+    for funding in fundings {
+        let txid = reverse_hash(&hex::encode(funding.outpoint.0));
+        movements.entry(txid.clone())
+            .or_insert(Movement { txid, height: funding.height, position: funding.position, delta: 0 })
+            .delta += funding.value;
+        if let Some(spender) = spenders.get(&funding.outpoint) {
+            let location = locations.get(&bcindex::hash_bytes(&reverse_hash(&spender.txid)));
+            movements.entry(spender.txid.clone())
+                .or_insert(Movement {
+                    txid: spender.txid.clone(),
+                    height: location.and_then(|location| location.height).unwrap_or(UNKNOWN_HEIGHT),
+                    position: location.map(|location| location.position).unwrap_or_default(),
+                    delta: 0,
+                })
+                .delta -= funding.value;
+        }
+    }
+    let mut movements: Vec<Movement> = movements.into_values().collect();
+    movements.sort_by_key(|movement| (movement.height, movement.position));
+    Ok(movements)
+}
+
+fn show_height(height: u32) -> String {
+    match height {
+        UNKNOWN_HEIGHT => String::from("?"),
+        height => height.to_string(),
+    }
+}
+
+fn target_hash(args: &[String]) -> Result<[u8; 32], String> {
+    match args {
+        [flag, script] if flag == "--script" => hex::decode(script)
+            .map(|script| script_hash(&script))
+            .map_err(|e| format!("{}: {}", script, e)),
+        [flag, hash] if flag == "--scripthash" && hash.len() == 64 && hex::decode(hash).is_ok() => {
+            Ok(bcindex::hash_bytes(&reverse_hash(hash)))
+        }
+        [address] => address_script(address)
+            .map(|script| script_hash(&script))
+            .ok_or_else(|| format!("{}: not a mainnet address", address)),
+        _ => Err(String::from("expected <address>, --script <hex> or --scripthash <hash>")),
+    }
+}
+
+// Usage: address <history | balance | utxos> <address | --script <hex> | --scripthash <electrum hash>>
+pub fn main(args: &[String]) {
+    let (command, hash) = match (args.first().map(String::as_str), target_hash(args.get(1..).unwrap_or_default())) {
+        (Some(command @ ("history" | "balance" | "utxos")), Ok(hash)) => (command, hash),
+        (_, result) => {
+            if let Err(e) = result {
+                eprintln!("{}", e);
+            }
+            eprintln!("Usage: address <history | balance | utxos> <address | --script <hex> | --scripthash <electrum hash>>");
+            std::process::exit(1);
+        }
+    };
+
+    let result = bcindex::open_tree(SCRIPT_TREE).and_then(|scripts| fundings(&scripts, &hash)).and_then(|fundings| {
+        let outpoints = fundings.iter().map(|funding| funding.outpoint).collect();
+        let spenders = bcindex::outpoint_spenders(&outpoints)?;
+        match command {
+            "history" => {
+                let mut balance = 0;
+                for movement in movements(&fundings, &spenders)? {
+                    balance += movement.delta;
+                    println!("{:>7} {} {:+} {}", show_height(movement.height), movement.txid, movement.delta, balance);
+                }
+            }
+            "balance" => {
+                let received: i64 = fundings.iter().map(|funding| funding.value).sum();
+                let spent: i64 = fundings.iter()
+                    .filter(|funding| spenders.contains_key(&funding.outpoint))
+                    .map(|funding| funding.value)
+                    .sum();
+                println!("scripthash {}", reverse_hash(&hex::encode(hash)));
+                println!("received {} in {} outputs", received, fundings.len());
+                println!("spent {} from {} outputs", spent, spenders.len());
+                println!("balance {}", received - spent);
+            }
+            _ => {
+                for funding in fundings.iter().filter(|funding| !spenders.contains_key(&funding.outpoint)) {
+                    let txid = reverse_hash(&hex::encode(funding.outpoint.0));
+                    println!("{}:{} {} height {}", txid, funding.outpoint.1, funding.value, show_height(funding.height));
+                }
+            }
+        }
+        Ok(())
+    });
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bcblocks::GENESIS_BLOCK;
+    use crate::bcparse::parse_block;
+    use crate::bcscript::mainnet::P2WSH_TX;
+
+    #[test]
+    fn indexing_again_keeps_one_funding() {
+        let block = format!("{}02{}{}", &GENESIS_BLOCK[..160], &GENESIS_BLOCK[162..], P2WSH_TX);
+        let block = parse_block(&hex::decode(block).unwrap()).unwrap();
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let scripts = db.open_tree(SCRIPT_TREE).unwrap();
+        add_block(&scripts, &block, UNKNOWN_HEIGHT).unwrap();
+        add_block(&scripts, &block, 1).unwrap();
+        assert_eq!(scripts.len(), 3);
+
+        let output = &block.txns[1].outputs[1];
+        let found = fundings(&scripts, &script_hash(&hex::decode(&output.pub_key_script).unwrap())).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!((found[0].outpoint, found[0].value), ((bcindex::hash_bytes(&block.txns[1].hash), 1), output.value));
+        assert_eq!((found[0].height, found[0].position), (1, 1));
+    }
+}
@@ -6,9 +6,16 @@ use lazy_static::lazy_static;
 
 use crate::bcnet::bcmessage::{VERSION, VERSION_END};
 
+// Raw mainnet genesis block, handy to exercise the parsers without a peer
+pub const GENESIS_BLOCK: &str = "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c0101000000010000000000000000000000000000000000000000000000000000000000000000ffffffff4d04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73ffffffff0100f2052a01000000434104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac00000000";
+
+// Genesis hash in internal byte order, as kept in blocks_id
+pub const GENESIS_HASH: &str = "6fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000";
+
 #[derive(Debug, Clone)]
 pub struct BlockDesc {
     pub idx: usize,
+    #[allow(dead_code)]
     pub previous: String,
 }
 
@@ -57,10 +64,10 @@ pub fn create_getdata_message_payload() -> Vec<u8> {
     let mut search_block = "".to_owned();
 
     let blocks_id = &mut BLOCKS_MUTEX.lock().unwrap().blocks_id;
-    for i in 1..blocks_id.len() {
-        let (bloc, prev, downloaded, downloading) = blocks_id[i].clone();
+    for block_id in blocks_id.iter_mut().skip(1) {
+        let (bloc, prev, downloaded, downloading) = block_id.clone();
         if !(downloaded) && !(downloading) {
-            blocks_id[i] = (bloc.to_string(), prev, downloaded, true);
+            *block_id = (bloc.to_string(), prev, downloaded, true);
             search_block = bloc;
             break;
         }
@@ -96,6 +103,24 @@ pub fn create_block_message_payload() {
     // std::process::exit(1);
 }
 
+// Height, confirmations and next block hash of a known block.
+// Only meaningful while the header list still starts at genesis (headers.lst is pruned on restart).
+pub fn chain_position(block: &str) -> Option<(usize, usize, Option<String>)> {
+    let blocks_mutex_guard = BLOCKS_MUTEX.lock().unwrap();
+    let blocks_id = &blocks_mutex_guard.blocks_id;
+    if blocks_id.get(1).map(|(hash, _, _, _)| hash.as_str()) != Some(GENESIS_HASH) {
+        return None;
+    }
+    let idx = blocks_mutex_guard.known_blocks.get(block)?.idx;
+    if blocks_id.get(idx).map(|(hash, _, _, _)| hash.as_str()) != Some(block) {
+        return None;
+    }
+    let height = idx - 1;
+    let tip = blocks_id.len() - 2;
+    let next = blocks_id.get(idx + 1).map(|(hash, _, _, _)| hash.clone());
+    Some((height, tip - height + 1, next))
+}
+
 pub fn is_new(block: &str, previous: &str) -> Result<usize, ()> {
     let mut blocks_mutex_guard = BLOCKS_MUTEX.lock().unwrap();
 
@@ -108,7 +133,7 @@ pub fn is_new(block: &str, previous: &str) -> Result<usize, ()> {
                 None => {
                     let (val, _, downloaded, _) = blocks_mutex_guard.blocks_id.get(previous_block.idx).unwrap();
                     blocks_mutex_guard.blocks_id[previous_block.idx] = (val.to_string(), true, *downloaded, false);
-                    blocks_mutex_guard.blocks_id.insert((previous_block.idx + 1) as usize, (block.to_string(), false, false, false));
+                    blocks_mutex_guard.blocks_id.insert(previous_block.idx + 1, (block.to_string(), false, false, false));
 
                     let idx = previous_block.idx + 1;
                     blocks_mutex_guard.known_blocks.insert(block.to_string(), BlockDesc { idx, previous: previous.to_string() });
@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use crate::bcparse::Block;
+use crate::bcscript::parse::parse_one_op;
+use crate::bcscript::script::ScriptItem;
+
+const POOLS_FILE: &str = "./pools.json";
+const MIN_TAG_LENGTH: usize = 4;
+// OP_RETURN, push 36, then the BIP141 header aa21a9ed
+const WITNESS_COMMITMENT_HEADER: [u8; 6] = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+
+lazy_static! {
+    pub static ref POOLS: PoolDatabase = PoolDatabase::load(Path::new(POOLS_FILE));
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Pool {
+    pub name: String,
+}
+
+// Same layout as the community maintained pools.json: coinbase tags and payout addresses, each mapped to a pool
+#[derive(Debug, Default, Deserialize)]
+pub struct PoolDatabase {
+    #[serde(default)]
+    pub coinbase_tags: HashMap<String, Pool>,
+    #[serde(default)]
+    pub payout_addresses: HashMap<String, Pool>,
+}
+
+impl PoolDatabase {
+    // A missing or broken file leaves blocks unattributed, the crawl goes on
+    pub fn load(path: &Path) -> PoolDatabase {
+        let database = fs::read(path)
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| serde_json::from_slice(&bytes).map_err(|e| e.to_string()));
+        match database {
+            Ok(database) => database,
+            Err(e) => {
+                eprintln!("No mining pool attribution, {}: {}", path.display(), e);
+                PoolDatabase::default()
+            }
+        }
+    }
+
+    // Payout addresses are checked first, tags are easier to imitate
+    pub fn identify(&self, coinbase: &Coinbase) -> Option<&Pool> {
+        coinbase.payout_addresses.iter()
+            .find_map(|address| self.payout_addresses.get(address))
+            .or_else(|| {
+                let script = String::from_utf8_lossy(&coinbase.script);
+                self.coinbase_tags.iter()
+                    .filter(|(tag, _)| script.contains(tag.as_str()))
+                    // The longest tag wins when several match, the first in alphabetical order among equals
+                    .max_by(|(a, _), (b, _)| a.len().cmp(&b.len()).then_with(|| b.cmp(a)))
+                    .map(|(_, pool)| pool)
+            })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Coinbase {
+    pub script: Vec<u8>,
+    pub height: Option<u32>,
+    pub extranonce: Option<Vec<u8>>,
+    pub tag: String,
+    pub witness_reserved_value: Option<Vec<u8>>,
+    pub witness_commitment: Option<Vec<u8>>,
+    pub payout_addresses: Vec<String>,
+}
+
+// What gets stored with the block
+#[derive(Debug, Serialize)]
+pub struct CoinbaseInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extranonce: Option<String>,
+    pub tag: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub witness_reserved_value: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub witness_commitment: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pool: Option<String>,
+}
+
+pub fn decode_coinbase(block: &Block) -> Option<Coinbase> {
+    let tx = block.txns.first().filter(|tx| tx.is_coinbase())?;
+    let script = hex::decode(&tx.inputs[0].signature_script).ok()?;
+    let height = block.bip34_height();
+
+    // The extranonce is the push after the height, or after nBits before BIP34
+    let mut pc = 0;
+    let extranonce = match (parse_one_op(&script, &mut pc), parse_one_op(&script, &mut pc)) {
+        (Ok(Some(_)), Ok(Some(ScriptItem::ByteArray(extranonce)))) => Some(extranonce),
+        _ => None,
+    };
+
+    let witness_reserved_value = match tx.witnesses.first().map(Vec::as_slice) {
+        Some([item]) => hex::decode(&item.script).ok().filter(|value| value.len() == 32),
+        _ => None,
+    };
+
+    // With several commitments, the last one counts (BIP141)
+    let witness_commitment = tx.outputs.iter().rev()
+        .filter_map(|output| hex::decode(&output.pub_key_script).ok())
+        .find(|script| script.len() >= 38 && script[..6] == WITNESS_COMMITMENT_HEADER)
+        .map(|script| script[6..38].to_vec());
+
+    Some(Coinbase {
+        height,
+        extranonce,
+        tag: ascii_tag(&script),
+        witness_reserved_value,
+        witness_commitment,
+        payout_addresses: tx.outputs.iter().filter_map(|output| output.address.clone()).collect(),
+        script,
+    })
+}
+
+// Runs of printable ASCII long enough not to be random bytes, taken from the pushed data so that
+// length prefixes do not stick to the text. Coinbase scripts need not parse, the end is then taken raw.
+fn ascii_tag(script: &[u8]) -> String {
+    let mut chunks = Vec::new();
+    let mut pc = 0;
+    loop {
+        let start = pc;
+        match parse_one_op(script, &mut pc) {
+            Ok(Some(ScriptItem::ByteArray(data))) => chunks.push(data),
+            Ok(Some(ScriptItem::Opcode(_))) => {}
+            Ok(None) => break,
+            Err(_) => {
+                chunks.push(script[start..].to_vec());
+                break;
+            }
+        }
+    }
+    chunks.iter()
+        .flat_map(|chunk| chunk.split(|b| !(0x20..0x7f).contains(b)))
+        .filter(|run| run.len() >= MIN_TAG_LENGTH)
+        .map(|run| String::from_utf8_lossy(run).trim().to_string())
+        .filter(|run| !run.is_empty())
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+pub fn coinbase_info(block: &Block, pools: &PoolDatabase) -> Option<CoinbaseInfo> {
+    let coinbase = decode_coinbase(block)?;
+    Some(CoinbaseInfo {
+        height: coinbase.height,
+        extranonce: coinbase.extranonce.as_ref().map(hex::encode),
+        tag: coinbase.tag.clone(),
+        witness_reserved_value: coinbase.witness_reserved_value.as_ref().map(hex::encode),
+        witness_commitment: coinbase.witness_commitment.as_ref().map(hex::encode),
+        pool: pools.identify(&coinbase).map(|pool| pool.name.clone()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bcblocks::GENESIS_BLOCK;
+    use crate::bcparse::{parse_block, TxOutput, WitnessItem};
+
+    const COMMITMENT: &str = "6a24aa21a9ede2f61c3f71d1defd3fa999dfa36953755c690689799962b48bebd836974e8cf9";
+
+    fn genesis() -> Block {
+        parse_block(&hex::decode(GENESIS_BLOCK).unwrap()).unwrap()
+    }
+
+    // Genesis turned into a segwit era block: height 702861, an 8 bytes extranonce and a pool tag,
+    // the witness reserved value and the commitment
+    fn segwit_coinbase_block() -> Block {
+        let mut block = genesis();
+        block.version = 0x20000000;
+        let coinbase = &mut block.txns[0];
+        coinbase.inputs[0].signature_script = format!("038db90a08{}{}", "0123456789abcdef", hex::encode(b"\x12/Foundry USA Pool #dropgold/"));
+        coinbase.witnesses = vec![vec![WitnessItem { script: "00".repeat(32) }]];
+        coinbase.outputs.push(TxOutput { pub_key_script: String::from(COMMITMENT), ..Default::default() });
+        block
+    }
+
+    fn pools(tags: &[(&str, &str)], addresses: &[(&str, &str)]) -> PoolDatabase {
+        let entries = |entries: &[(&str, &str)]| entries.iter()
+            .map(|(key, name)| (key.to_string(), Pool { name: name.to_string() }))
+            .collect();
+        PoolDatabase { coinbase_tags: entries(tags), payout_addresses: entries(addresses) }
+    }
+
+    #[test]
+    fn segwit_coinbase() {
+        let coinbase = decode_coinbase(&segwit_coinbase_block()).unwrap();
+        assert_eq!(coinbase.height, Some(702861));
+        assert_eq!(coinbase.extranonce, Some(hex::decode("0123456789abcdef").unwrap()));
+        assert_eq!(coinbase.tag, "/Foundry USA Pool #dropgold/");
+        assert_eq!(coinbase.witness_reserved_value, Some(vec![0; 32]));
+        assert_eq!(coinbase.witness_commitment.map(hex::encode).as_deref(), Some(&COMMITMENT[12..]));
+    }
+
+    #[test]
+    fn genesis_coinbase() {
+        // Version 1: no height, the push after nBits taken as the extranonce
+        let coinbase = decode_coinbase(&genesis()).unwrap();
+        assert_eq!(coinbase.height, None);
+        assert_eq!(coinbase.extranonce, Some(vec![4]));
+        assert_eq!(coinbase.tag, "The Times 03/Jan/2009 Chancellor on brink of second bailout for banks");
+        assert_eq!((coinbase.witness_reserved_value, coinbase.witness_commitment), (None, None));
+    }
+
+    #[test]
+    fn ascii_tags() {
+        // Runs shorter than 4 characters are taken as random bytes, the others are joined with a space
+        assert_eq!(ascii_tag(&[3, b'a', b'b', b'c']), "");
+        assert_eq!(ascii_tag(&[&[7][..], b"ab", &[0xff], b"cdef"].concat()), "cdef");
+        assert_eq!(ascii_tag(&[&[8][..], b"/ViaBTC/", &[10], b"Mined by x"].concat()), "/ViaBTC/ Mined by x");
+        // A push running past the end of the script is taken raw
+        assert_eq!(ascii_tag(&[&[0x20][..], b"/slush/"].concat()), "/slush/");
+    }
+
+    #[test]
+    fn identify_pools() {
+        let mut coinbase = decode_coinbase(&segwit_coinbase_block()).unwrap();
+        let name = |pools: &PoolDatabase, coinbase: &Coinbase| pools.identify(coinbase).map(|pool| pool.name.clone());
+
+        let database = pools(&[("Foundry", "Foundry"), ("/Foundry USA Pool", "Foundry USA"), ("ViaBTC", "ViaBTC")], &[]);
+        assert_eq!(name(&database, &coinbase).as_deref(), Some("Foundry USA"));
+        // Equal lengths: the first tag in alphabetical order, whatever the order of the map
+        for _ in 0..8 {
+            let database = pools(&[("dropgold", "B"), ("USA Pool", "A")], &[]);
+            assert_eq!(name(&database, &coinbase).as_deref(), Some("A"));
+        }
+        // Payout addresses before tags
+        coinbase.payout_addresses = vec![String::from("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa")];
+        let database = pools(&[("Foundry", "Foundry")], &[("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa", "Satoshi")]);
+        assert_eq!(name(&database, &coinbase).as_deref(), Some("Satoshi"));
+        assert_eq!(name(&pools(&[("AntPool", "AntPool")], &[]), &coinbase), None);
+    }
+}
@@ -0,0 +1,291 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::bcfile;
+use crate::bcparse::{Block, Transaction};
+use crate::bcutils::{reverse_hash, to_compact_int};
+use crate::bcutxo::{is_bip30_repeat, is_unspendable, Coin, Key};
+
+const COIN: i64 = 100_000_000;
+const HALVING_INTERVAL: u32 = 210_000;
+const WITNESS_SCALE_FACTOR: i64 = 4;
+// Highest sequence that signals replaceability (BIP125)
+const MAX_BIP125_RBF_SEQUENCE: u32 = 0xfffffffd;
+// Outpoint, height and coinbase flag, counted by Core for each output in utxo_size_inc
+const PER_UTXO_OVERHEAD: i64 = 41;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TxFee {
+    pub input_total: i64,
+    pub fee: i64,
+    pub vsize: usize,
+    // sat/vB
+    pub feerate: f64,
+    pub rbf: bool,
+}
+
+// Coinbase outputs against what the block may claim
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RewardCheck {
+    pub coinbase_out: i64,
+    pub subsidy: i64,
+    pub fees: i64,
+    pub valid: bool,
+}
+
+// Fields of `bitcoin-cli getblockstats`, in the same order, amounts in satoshis and feerates in sat/vB.
+// mediantime needs the 11 previous headers and is left out.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct BlockStats {
+    pub avgfee: i64,
+    pub avgfeerate: i64,
+    pub avgtxsize: i64,
+    pub blockhash: String,
+    pub feerate_percentiles: [i64; 5],
+    pub height: u32,
+    pub ins: i64,
+    pub maxfee: i64,
+    pub maxfeerate: i64,
+    pub maxtxsize: i64,
+    pub medianfee: i64,
+    pub mediantxsize: i64,
+    pub minfee: i64,
+    pub minfeerate: i64,
+    pub mintxsize: i64,
+    pub outs: i64,
+    pub subsidy: i64,
+    pub swtotal_size: i64,
+    pub swtotal_weight: i64,
+    pub swtxs: i64,
+    pub time: u32,
+    pub total_out: i64,
+    pub total_size: i64,
+    pub total_weight: i64,
+    pub totalfee: i64,
+    pub txs: i64,
+    pub utxo_increase: i64,
+    pub utxo_size_inc: i64,
+    pub utxo_increase_actual: i64,
+    pub utxo_size_inc_actual: i64,
+}
+
+pub fn subsidy(height: u32) -> i64 {
+    match height / HALVING_INTERVAL {
+        halvings if halvings >= 64 => 0,
+        halvings => (50 * COIN) >> halvings,
+    }
+}
+
+fn output_size(script: &[u8]) -> i64 {
+    (8 + to_compact_int(script.len() as u64).len() + script.len()) as i64 + PER_UTXO_OVERHEAD
+}
+
+fn truncated_median(mut values: Vec<i64>) -> i64 {
+    values.sort_unstable();
+    match values.len() {
+        0 => 0,
+        n if n % 2 == 0 => (values[n / 2 - 1] + values[n / 2]) / 2,
+        n => values[n / 2],
+    }
+}
+
+// 10th, 25th, 50th, 75th and 90th percentiles of the feerates, weighted by transaction weight
+fn percentiles_by_weight(mut scores: Vec<(i64, i64)>, total_weight: i64) -> [i64; 5] {
+    let mut result = [0; 5];
+    if scores.is_empty() {
+        return result;
+    }
+    scores.sort_unstable();
+    let total = total_weight as f64;
+    let weights = [total / 10.0, total / 4.0, total / 2.0, total * 3.0 / 4.0, total * 9.0 / 10.0];
+    let mut next = 0;
+    let mut cumulative_weight = 0;
+    for (feerate, weight) in &scores {
+        cumulative_weight += weight;
+        while next < weights.len() && cumulative_weight as f64 >= weights[next] {
+            result[next] = *feerate;
+            next += 1;
+        }
+    }
+    for percentile in result.iter_mut().skip(next) {
+        *percentile = scores.last().unwrap().0;
+    }
+    result
+}
+
+// Fills the fee of every non coinbase transaction and the block statistics, from the outputs the
+// block spends in input order (the UTXO undo data)
+pub fn annotate(block: &mut Block, spent: &[(Key, Coin)], height: u32) {
+    let mut stats = BlockStats {
+        blockhash: reverse_hash(&block.hash),
+        height,
+        subsidy: subsidy(height),
+        time: block.timestamp,
+        txs: block.txns.len() as i64,
+        minfee: i64::MAX,
+        minfeerate: i64::MAX,
+        mintxsize: i64::MAX,
+        ..BlockStats::default()
+    };
+    let mut fees = Vec::new();
+    let mut sizes = Vec::new();
+    let mut feerates = Vec::new();
+    let mut coinbase_out = 0;
+    let mut utxos = 0;
+    let mut prevouts = spent.iter();
+    let bip30_repeat = is_bip30_repeat(block, height);
+
+    for tx in block.txns.iter_mut() {
+        let mut tx_total_out = 0;
+        stats.outs += tx.outputs.len() as i64;
+        // The genesis outputs and the coinbases repeated before BIP30 left the set as it was
+        let adds_coins = height > 0 && !(bip30_repeat && tx.is_coinbase());
+        for output in &tx.outputs {
+            let script = hex::decode(&output.pub_key_script).unwrap_or_default();
+            tx_total_out += output.value;
+            let size = output_size(&script);
+            stats.utxo_size_inc += size;
+            if adds_coins && !is_unspendable(&script) {
+                stats.utxo_size_inc_actual += size;
+                utxos += 1;
+            }
+        }
+        if tx.is_coinbase() {
+            coinbase_out = tx_total_out;
+            continue;
+        }
+
+        stats.ins += tx.inputs.len() as i64;
+        stats.total_out += tx_total_out;
+        let stripped_size = Transaction::serialize(tx, false).len() as i64;
+        let size = Transaction::serialize(tx, true).len() as i64;
+        let weight = stripped_size * 3 + size;
+        sizes.push(size);
+        stats.maxtxsize = stats.maxtxsize.max(size);
+        stats.mintxsize = stats.mintxsize.min(size);
+        stats.total_size += size;
+        stats.total_weight += weight;
+        if size != stripped_size {
+            stats.swtxs += 1;
+            stats.swtotal_size += size;
+            stats.swtotal_weight += weight;
+        }
+
+        let mut input_total = 0;
+        for (_, coin) in prevouts.by_ref().take(tx.inputs.len()) {
+            input_total += coin.value;
+            let size = output_size(&coin.script);
+            stats.utxo_size_inc -= size;
+            stats.utxo_size_inc_actual -= size;
+        }
+        let fee = input_total - tx_total_out;
+        let feerate = if weight > 0 { fee * WITNESS_SCALE_FACTOR / weight } else { 0 };
+        fees.push(fee);
+        feerates.push((feerate, weight));
+        stats.maxfee = stats.maxfee.max(fee);
+        stats.minfee = stats.minfee.min(fee);
+        stats.maxfeerate = stats.maxfeerate.max(feerate);
+        stats.minfeerate = stats.minfeerate.min(feerate);
+        stats.totalfee += fee;
+
+        let vsize = (weight as usize).div_ceil(WITNESS_SCALE_FACTOR as usize);
+        tx.fee = Some(TxFee {
+            input_total,
+            fee,
+            vsize,
+            feerate: fee as f64 / vsize as f64,
+            rbf: tx.inputs.iter().any(|input| input.sequence <= MAX_BIP125_RBF_SEQUENCE),
+        });
+    }
+
+    let paying = stats.txs - 1;
+    stats.avgfee = if paying > 0 { stats.totalfee / paying } else { 0 };
+    stats.avgfeerate = if stats.total_weight > 0 { stats.totalfee * WITNESS_SCALE_FACTOR / stats.total_weight } else { 0 };
+    stats.avgtxsize = if paying > 0 { stats.total_size / paying } else { 0 };
+    stats.feerate_percentiles = percentiles_by_weight(feerates, stats.total_weight);
+    stats.medianfee = truncated_median(fees);
+    stats.mediantxsize = truncated_median(sizes);
+    for min in [&mut stats.minfee, &mut stats.minfeerate, &mut stats.mintxsize] {
+        if *min == i64::MAX {
+            *min = 0;
+        }
+    }
+    stats.utxo_increase = stats.outs - stats.ins;
+    stats.utxo_increase_actual = utxos - stats.ins;
+
+    block.reward_check = Some(RewardCheck {
+        coinbase_out,
+        subsidy: stats.subsidy,
+        fees: stats.totalfee,
+        valid: coinbase_out <= stats.subsidy + stats.totalfee,
+    });
+    block.stats = Some(stats);
+}
+
+// Usage: fees <hash | file.json.gz>
+// Prints the getblockstats report stored by `utxo sync`
+pub fn main(args: &[String]) {
+    let path = match args.first() {
+        Some(arg) if Path::new(arg).exists() => arg.clone(),
+        Some(arg) => bcfile::block_file(arg),
+        None => {
+            eprintln!("Usage: fees <hash | file.json.gz>");
+            std::process::exit(1);
+        }
+    };
+    match bcfile::load_block(Path::new(&path)) {
+        Ok(Block { stats: Some(stats), .. }) => println!("{}", serde_json::to_string_pretty(&stats).unwrap()),
+        Ok(_) => {
+            eprintln!("{}: no fee data yet, run `utxo sync` first", path);
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bcblocks::GENESIS_BLOCK;
+    use crate::bcparse::{parse_block, TxOutput};
+
+    fn genesis() -> Block {
+        parse_block(&hex::decode(GENESIS_BLOCK).unwrap()).unwrap()
+    }
+
+    fn stats(mut block: Block, height: u32) -> BlockStats {
+        annotate(&mut block, &[], height);
+        block.stats.unwrap()
+    }
+
+    #[test]
+    fn genesis_adds_no_coins() {
+        let stats = stats(genesis(), 0);
+        assert_eq!((stats.utxo_increase, stats.utxo_increase_actual), (1, 0));
+        assert!(stats.utxo_size_inc > 0);
+        assert_eq!(stats.utxo_size_inc_actual, 0);
+    }
+
+    #[test]
+    fn unspendable_outputs_not_counted() {
+        let mut block = genesis();
+        block.txns[0].outputs.push(TxOutput { pub_key_script: String::from("6a0100"), ..Default::default() });
+        let stats = stats(block, 1);
+        assert_eq!((stats.utxo_increase, stats.utxo_increase_actual), (2, 1));
+        assert_eq!(stats.utxo_size_inc - stats.utxo_size_inc_actual, output_size(&[0x6a, 0x01, 0x00]));
+    }
+
+    #[test]
+    fn repeated_coinbase_adds_no_coins() {
+        let mut block = genesis();
+        block.hash = reverse_hash("00000000000a4d0a398161ffc163c503763b1f4360639393e0e4c8e300e0caec");
+        assert_eq!(stats(block, 91842).utxo_increase_actual, 0);
+        let mut block = genesis();
+        block.hash = reverse_hash("00000000000a4d0a398161ffc163c503763b1f4360639393e0e4c8e300e0caec");
+        assert_eq!(stats(block, 91841).utxo_increase_actual, 1);
+    }
+}
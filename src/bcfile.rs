@@ -1,5 +1,5 @@
 use std::fs::{self, File, OpenOptions, read_to_string};
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, Read};
 use std::io::{self, LineWriter, stdout, Write};
 use std::path::Path;
 use std::sync::mpsc::Receiver;
@@ -8,12 +8,17 @@ use std::sync::Mutex;
 use chrono::{DateTime, Utc};
 use flate2::Compression;
 use flate2::GzBuilder;
+use flate2::read::GzDecoder;
 use lazy_static::lazy_static;
 //use fs_extra::dir::get_dir_content;
 use linecount::count_lines;
 use serde::Deserialize;
 
 use crate::bcblocks;
+use crate::bcaddrindex;
+use crate::bcgetblock;
+use crate::bcindex;
+use crate::bcjson::{self, JsonError, JsonOptions};
 use crate::bcparse::Block;
 use crate::bcutils::reverse_hash;
 
@@ -21,11 +26,15 @@ use crate::bcutils::reverse_hash;
 //use std::time::Duration;
 
 const BLOCKS_DIR: &str = "./blocks";
+const BLOCK_EXTENSION: &str = ".json.gz";
+const GETBLOCK_EXTENSION: &str = ".getblock.json.gz";
+const PREV_HASH_SEARCH_LIMIT: usize = 65536;
 const HEADERS_FILE: &str = "./headers.lst";
 const HEADERS_TEMP_FILE: &str = "./headers.tmp.lst";
 const HEADERS_GENESIS_FILE: &str = "./headers.genesis.lst";
 
 const BLOCKS_MARKS: usize = 10000;
+const STORED_BLOCK_OPTIONS: JsonOptions = JsonOptions { pretty: false, witnesses: true, scripts: false };
 const UPDATED_HEADERS_FROM_GETBLOCK: &str = "./headers_to_update_from_getblocks.lst";
 
 lazy_static! {
@@ -38,6 +47,7 @@ lazy_static! {
     pub static ref HEADERS: Mutex<File> = Mutex::new(File::options().append(true).create(true).open(HEADERS_FILE).unwrap());
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Deserialize)]
 pub struct Header {
     pub elem: String,
@@ -64,7 +74,7 @@ fn create_internal_struct_at_startup(headers: String) {
 
     let mut it = headers.lines().peekable();
     while let Some(header) = it.next() {
-        if idx % BLOCKS_MARKS == 0 {
+        if idx.is_multiple_of(BLOCKS_MARKS) {
             eprint!("*");
             io::stderr().flush().unwrap();
         }
@@ -141,34 +151,106 @@ pub fn store_headers(headers: Vec<String>) {
     out.flush().unwrap();
 }
 
-pub fn store_block(block_channel: Receiver<Block>) {
+// 0000012345 --> <root>/45/3/0000012345<extension>
+pub fn sharded_file(root: &str, rev_hash: &str, extension: &str) -> String {
+    format!("{}/{}/{}/{}{}", root, &rev_hash[rev_hash.len() - 2..], &rev_hash[rev_hash.len() - 3..rev_hash.len() - 2], rev_hash, extension)
+}
+
+// Stored layout (bcjson schema), the one read back, or `bitcoin-cli getblock <hash> 2` layout, written
+// next to it
+#[derive(Debug, Clone, Copy)]
+pub enum BlockFormat {
+    Stored,
+    GetBlock,
+}
+
+pub fn block_file(rev_hash: &str) -> String {
+    sharded_file(BLOCKS_DIR, rev_hash, BLOCK_EXTENSION)
+}
+
+// Display hashes of every stored block, taken from the file names
+pub fn stored_blocks() -> Vec<String> {
+    let mut hashes = Vec::new();
+    for shard in fs::read_dir(BLOCKS_DIR).into_iter().flatten().flatten() {
+        for sub_shard in fs::read_dir(shard.path()).into_iter().flatten().flatten() {
+            for file in fs::read_dir(sub_shard.path()).into_iter().flatten().flatten() {
+                let name = file.file_name();
+                let name = name.to_str().filter(|name| !name.ends_with(GETBLOCK_EXTENSION));
+                if let Some(hash) = name.and_then(|name| name.strip_suffix(BLOCK_EXTENSION)) {
+                    hashes.push(hash.to_string());
+                }
+            }
+        }
+    }
+    hashes
+}
+
+// Previous block hash (display order) of a stored block, found in the first bytes of the file
+// so that the whole block does not have to be decompressed and parsed
+pub fn read_prev_hash(rev_hash: &str) -> Option<String> {
+    let mut gz = GzDecoder::new(File::open(block_file(rev_hash)).ok()?);
+    let mut start = Vec::new();
+    let mut chunk = [0; 4096];
+    while start.len() < PREV_HASH_SEARCH_LIMIT {
+        let read = gz.read(&mut chunk).ok()?;
+        if read == 0 {
+            break;
+        }
+        start.extend_from_slice(&chunk[..read]);
+        let text = String::from_utf8_lossy(&start);
+        if let Some(pos) = text.find("\"prev_hash\":") {
+            let value = text[pos + 12..].trim_start().strip_prefix('"')?;
+            if value.len() > 64 {
+                return Some(value[..64].to_string());
+            }
+        }
+    }
+    None
+}
+
+fn getblock_file(rev_hash: &str) -> String {
+    sharded_file(BLOCKS_DIR, rev_hash, GETBLOCK_EXTENSION)
+}
+
+pub fn write_block_file(block: &Block, format: BlockFormat) -> Result<String, JsonError> {
+    let rev_hash = reverse_hash(&block.hash);
+    let path = match format {
+        BlockFormat::Stored => block_file(&rev_hash),
+        BlockFormat::GetBlock => getblock_file(&rev_hash),
+    };
+    fs::create_dir_all(Path::new(&path).parent().unwrap())?;
+
+    let file = File::create(path)?;
+    let mut gz = GzBuilder::new()
+        .write(file, Compression::default());
+    match format {
+        BlockFormat::Stored => bcjson::write_block(&mut gz, block, &STORED_BLOCK_OPTIONS)?,
+        BlockFormat::GetBlock => bcgetblock::write_block(&mut gz, block, false)?,
+    }
+    gz.finish()?;
+    Ok(rev_hash)
+}
+
+pub fn store_block(block_channel: Receiver<Block>, format: BlockFormat, tx_index: bool, script_index: bool) {
     for block in block_channel.iter() {
 
         // eprintln!("Storing {}",block.hash);
         eprint!(".");
         io::stderr().flush().unwrap();
 
-        let rev_hash = reverse_hash(&block.hash);
-        // 0000012345 --> 45/23/000001.json.gz
-        let dir_path = format!("./{}/{}/{}", BLOCKS_DIR, &rev_hash[rev_hash.len() - 2..], &rev_hash[rev_hash.len() - 3..rev_hash.len() - 2]);
-        fs::create_dir_all(&dir_path).unwrap();
-
-        let file = File::create(format!("{}/{}.json.gz", dir_path, &rev_hash)).unwrap();
-        let mut gz = GzBuilder::new()
-            .write(file, Compression::default());
         // eprintln!("{:?}", &block);
         // std::process::exit(1);
-
-        // gz.write_all(serde_json::to_string_pretty(&block).unwrap().as_bytes()).unwrap();
-        // gz.write_all(format!("{:?}", &block).as_bytes()).unwrap();
-        // gz.write_fmt(format_args!("{}", serde_json::ser::to_string_pretty(&block).unwrap())).unwrap();
-        //gz.write_all(&serde_json::ser::to_vec_pretty(&block).unwrap()).unwrap();
-        //gz.write_fmt(format_args!("{}", &block)).unwrap();
-        //gz.write_all(&block.to_json(0).unwrap()).unwrap();
-        // println!("\n{}", String::from_utf8(block.to_json(0).unwrap()).unwrap());
-        write!(gz, "{}", &block).unwrap();
-        // println!("{}", &block);
-        gz.finish().unwrap();
+        let rev_hash = write_block_file(&block, BlockFormat::Stored).unwrap();
+        if let BlockFormat::GetBlock = format {
+            write_block_file(&block, format).unwrap();
+        }
+        if tx_index {
+            let height = bcindex::block_height(&block, None);
+            bcindex::index_block(&block, height).unwrap();
+            if script_index {
+                bcaddrindex::index_block(&block, height).unwrap();
+            }
+        }
 
         let mut out = HEADERS_FROM_DOWNLOADED_BLOCKS.lock().unwrap();
         out.write_all(rev_hash.as_bytes()).unwrap();
@@ -181,6 +263,17 @@ pub fn store_block(block_channel: Receiver<Block>) {
     }
 }
 
+pub fn read_gz(path: &Path) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    GzDecoder::new(File::open(path)?).read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+// Reads back a block written by store_block, through the same JSON schema
+pub fn load_block(path: &Path) -> Result<Block, JsonError> {
+    bcjson::read_block(&read_gz(path)?[..])
+}
+
 pub fn open_logfile(file_name: &str) {
     let file: File = File::create(file_name).unwrap();
     let mut logger = LOGGER.lock().unwrap();
@@ -0,0 +1,142 @@
+use std::panic;
+
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+use crate::bcblocks::GENESIS_BLOCK;
+use crate::bcgetblock;
+use crate::bcnet::bcmessage;
+use crate::bcparse;
+use crate::bcscript::parse::parse_script;
+use crate::bcscript::script::{find_and_delete, push_data, ScriptItem};
+use crate::bcutils::{get_compact_int, to_compact_int};
+
+const DEFAULT_ITERATIONS: usize = 100000;
+const MAX_RANDOM_LENGTH: usize = 512;
+
+// Seeds the mutations start from, so that inputs get past the first length checks
+const VERSION_MESSAGE: &str = "7f1101000d04000000000000d8928a610000000000000000000000000d0400000000000000000000000000000000ffff7f0000018d200d0400000000000000000000000000000000ffff7f0000018d2014141414141414120c2f626370633a302e302e312fa3da0800";
+const SCRIPT: &str = "483045022100fcb600ea44edb6b3c9408479c9e29d468bf623e5b465be4d594141b0d7611d2a022069d470033446f0b6c8c85ced09b3e3c2b675e21a0f3017a5fac2ad064a4d1d1e012103dd2162aaf74d3f2e0634ad778380eeecea6ac5f2e53411a128a323ad260d0dc776a9148b6305816c87626a9ac4b972c5c376663b2f5dc888ac4c024d4e4d0100ff4e01000000ff";
+
+struct Target {
+    name: &'static str,
+    seed: Vec<u8>,
+    run: fn(&[u8]),
+}
+
+fn targets() -> Vec<Target> {
+    let genesis = hex::decode(GENESIS_BLOCK).unwrap();
+    let headers = [&[0x01][..], &genesis[..80], &[0x00]].concat();
+    let addr = [&[0x02][..], &[0x00; 30], &[0xff; 30]].concat();
+
+    vec![
+        Target { name: "compact_int", seed: to_compact_int(u64::MAX), run: fuzz_compact_int },
+        Target { name: "version", seed: hex::decode(VERSION_MESSAGE).unwrap(), run: fuzz_version },
+        Target { name: "addr", seed: addr, run: fuzz_addr },
+        Target { name: "headers", seed: headers, run: fuzz_headers },
+        Target { name: "block", seed: genesis, run: fuzz_block },
+        Target { name: "script", seed: hex::decode(SCRIPT).unwrap(), run: fuzz_script },
+    ]
+}
+
+fn fuzz_compact_int(data: &[u8]) {
+    if let Some((value, length)) = get_compact_int(data) {
+        assert!(length <= data.len());
+        assert_eq!(get_compact_int(&to_compact_int(value)).map(|(v, _)| v), Some(value));
+    }
+}
+
+fn fuzz_version(data: &[u8]) {
+    let _ = bcmessage::process_version_message(data);
+}
+
+fn fuzz_addr(data: &[u8]) {
+    let _ = bcmessage::process_addr_message(data);
+}
+
+fn fuzz_headers(data: &[u8]) {
+    let _ = bcmessage::parse_headers_message(data);
+}
+
+fn fuzz_block(data: &[u8]) {
+    if let Ok(block) = bcparse::parse_block(data) {
+        let _ = bcgetblock::to_getblock(&block);
+    }
+}
+
+fn fuzz_script(data: &[u8]) {
+    if let Ok(script) = parse_script(data) {
+        if let Some(ScriptItem::ByteArray(bytes)) = script.into_iter().next() {
+            let mut copy = data.to_vec();
+            find_and_delete(&mut copy, &push_data(&bytes));
+        }
+    }
+}
+
+fn mutate(rng: &mut StdRng, seed: &[u8]) -> Vec<u8> {
+    let mut data = seed.to_vec();
+    match rng.gen_range(0..4) {
+        // Fully random payload
+        0 => {
+            data = (0..rng.gen_range(0..MAX_RANDOM_LENGTH)).map(|_| rng.gen()).collect();
+        }
+        // Truncated seed
+        1 => {
+            data.truncate(rng.gen_range(0..=seed.len()));
+        }
+        // Flipped bytes, biased towards length prefixes (0xfd..0xff)
+        2 => {
+            for _ in 0..rng.gen_range(1..8) {
+                if data.is_empty() {
+                    break;
+                }
+                let pos = rng.gen_range(0..data.len());
+                data[pos] = match rng.gen_bool(0.5) {
+                    true => rng.gen_range(0xfd..=0xff),
+                    false => rng.gen(),
+                };
+            }
+        }
+        // Random bytes inserted in the seed
+        _ => {
+            let pos = rng.gen_range(0..=data.len());
+            let extra: Vec<u8> = (0..rng.gen_range(1..16)).map(|_| rng.gen()).collect();
+            data.splice(pos..pos, extra);
+        }
+    }
+    data
+}
+
+// Usage: fuzz [iterations] [seed]
+pub fn main(args: &[String]) {
+    let iterations = args.first().and_then(|a| a.parse().ok()).unwrap_or(DEFAULT_ITERATIONS);
+    let seed = args.get(1).and_then(|a| a.parse().ok()).unwrap_or_else(rand::random);
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    // Panics are counted below, the default hook would flood the output
+    panic::set_hook(Box::new(|_| {}));
+
+    let mut failed = false;
+    eprintln!("Fuzzing decoders: {} iterations per target, seed {}", iterations, seed);
+    for target in targets() {
+        let mut panics = 0;
+        let mut first_failure: Option<Vec<u8>> = None;
+        for _ in 0..iterations {
+            let data = mutate(&mut rng, &target.seed);
+            if panic::catch_unwind(|| (target.run)(&data)).is_err() {
+                panics += 1;
+                first_failure.get_or_insert(data);
+            }
+        }
+        eprintln!("  {:<12} -> {} panics", target.name, panics);
+        if let Some(data) = first_failure {
+            eprintln!("    first failing input: {}", hex::encode(data));
+            failed = true;
+        }
+    }
+
+    let _ = panic::take_hook();
+    if failed {
+        std::process::exit(1);
+    }
+}
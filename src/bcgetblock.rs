@@ -0,0 +1,360 @@
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use bitcoin_hashes::{Hash, sha256d};
+use serde::Serialize;
+use serde_json::value::RawValue;
+
+use crate::bcblocks;
+use crate::bcfile;
+use crate::bcjson::{self, JsonError, JsonOptions};
+use crate::bcparse::{Block, parse_block, Transaction};
+use crate::bcscript::address::address;
+use crate::bcscript::asm::to_asm;
+use crate::bcscript::standard::classify;
+use crate::bcutils::reverse_hash;
+
+const COIN: i64 = 100_000_000;
+
+// Same shape and field order as `bitcoin-cli getblock <hash> 2`.
+// mediantime and chainwork need the previous headers and are left out, as are the
+// descriptors (desc) and the fees, which need the spent outputs.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetBlock {
+    hash: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    confirmations: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    height: Option<usize>,
+    version: i32,
+    version_hex: String,
+    #[serde(rename = "merkleroot")]
+    merkle_root: String,
+    time: u32,
+    nonce: u32,
+    bits: String,
+    difficulty: Box<RawValue>,
+    n_tx: usize,
+    #[serde(rename = "previousblockhash", skip_serializing_if = "Option::is_none")]
+    previous_block_hash: Option<String>,
+    #[serde(rename = "nextblockhash", skip_serializing_if = "Option::is_none")]
+    next_block_hash: Option<String>,
+    #[serde(rename = "strippedsize")]
+    stripped_size: usize,
+    size: usize,
+    weight: usize,
+    tx: Vec<GetBlockTx>,
+}
+
+#[derive(Serialize)]
+pub struct GetBlockTx {
+    txid: String,
+    hash: String,
+    version: i32,
+    size: usize,
+    vsize: usize,
+    weight: usize,
+    locktime: u32,
+    vin: Vec<Vin>,
+    vout: Vec<Vout>,
+    hex: String,
+}
+
+// Coinbase inputs only have coinbase, txinwitness and sequence
+#[derive(Serialize)]
+pub struct Vin {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    coinbase: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    txid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vout: Option<u32>,
+    #[serde(rename = "scriptSig", skip_serializing_if = "Option::is_none")]
+    script_sig: Option<ScriptSig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    txinwitness: Option<Vec<String>>,
+    sequence: u32,
+}
+
+#[derive(Serialize)]
+pub struct ScriptSig {
+    asm: String,
+    hex: String,
+}
+
+#[derive(Serialize)]
+pub struct Vout {
+    value: Box<RawValue>,
+    n: usize,
+    #[serde(rename = "scriptPubKey")]
+    script_pub_key: ScriptPubKey,
+}
+
+#[derive(Serialize)]
+pub struct ScriptPubKey {
+    asm: String,
+    hex: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address: Option<String>,
+    #[serde(rename = "type")]
+    kind: &'static str,
+}
+
+// Amounts are printed as BTC with 8 decimals, like Core's ValueFromAmount
+pub fn btc_value(amount: i64) -> Box<RawValue> {
+    let sign = if amount < 0 { "-" } else { "" };
+    let amount = amount.unsigned_abs();
+    let coin = COIN as u64;
+    RawValue::from_string(format!("{}{}.{:08}", sign, amount / coin, amount % coin)).unwrap()
+}
+
+fn difficulty(bits: u32) -> f64 {
+    let mut shift = (bits >> 24) & 0xff;
+    let mut diff = 0x0000ffff as f64 / (bits & 0x00ffffff) as f64;
+    while shift < 29 {
+        diff *= 256.0;
+        shift += 1;
+    }
+    while shift > 29 {
+        diff /= 256.0;
+        shift -= 1;
+    }
+    diff
+}
+
+// Doubles are printed by Core with 16 significant digits ("%.16g")
+fn g16(value: f64) -> Box<RawValue> {
+    let scientific = format!("{:.15e}", value);
+    let exponent: i32 = scientific.rsplit('e').next().and_then(|e| e.parse().ok()).unwrap_or(0);
+    let text = match value.is_finite() && (-4..16).contains(&exponent) {
+        true => {
+            let fixed = format!("{:.*}", (15 - exponent) as usize, value);
+            match fixed.contains('.') {
+                true => fixed.trim_end_matches('0').trim_end_matches('.').to_string(),
+                false => fixed,
+            }
+        }
+        false => scientific,
+    };
+    RawValue::from_string(text).unwrap_or_else(|_| RawValue::from_string(String::from("null")).unwrap())
+}
+
+fn transaction(tx: &Transaction) -> GetBlockTx {
+    let stripped = tx.serialize(false);
+    let full = tx.serialize(true);
+    let has_witness = tx.is_segwit && tx.witnesses.iter().any(|w| !w.is_empty());
+
+    let vin = tx.inputs.iter().enumerate().map(|(i, input)| {
+        let txinwitness = match tx.witnesses.get(i) {
+            Some(witness) if has_witness && !witness.is_empty() => Some(witness.iter().map(|item| item.script.clone()).collect()),
+            _ => None,
+        };
+        match tx.is_coinbase() {
+            true => Vin {
+                coinbase: Some(input.signature_script.clone()),
+                txid: None,
+                vout: None,
+                script_sig: None,
+                txinwitness,
+                sequence: input.sequence,
+            },
+            false => Vin {
+                coinbase: None,
+                txid: Some(reverse_hash(&input.prev_output.hash)),
+                vout: Some(input.prev_output.idx),
+                script_sig: Some(ScriptSig {
+                    asm: to_asm(&hex::decode(&input.signature_script).unwrap_or_default(), true),
+                    hex: input.signature_script.clone(),
+                }),
+                txinwitness,
+                sequence: input.sequence,
+            },
+        }
+    }).collect();
+
+    let vout = tx.outputs.iter().enumerate().map(|(n, output)| {
+        let script = hex::decode(&output.pub_key_script).unwrap_or_default();
+        let output_type = classify(&script);
+        Vout {
+            value: btc_value(output.value),
+            n,
+            script_pub_key: ScriptPubKey {
+                asm: to_asm(&script, false),
+                hex: output.pub_key_script.clone(),
+                address: address(&output_type),
+                kind: output_type.core_name(),
+            },
+        }
+    }).collect();
+
+    GetBlockTx {
+        txid: reverse_hash(&tx.hash),
+        hash: reverse_hash(&hex::encode(sha256d::Hash::hash(&full))),
+        version: tx.version,
+        size: full.len(),
+        vsize: (stripped.len() * 3 + full.len()).div_ceil(4),
+        weight: stripped.len() * 3 + full.len(),
+        locktime: tx.lock_time,
+        vin,
+        vout,
+        hex: hex::encode(&full),
+    }
+}
+
+pub fn to_getblock(block: &Block) -> GetBlock {
+    at_position(block, bcblocks::chain_position(&block.hash))
+}
+
+// Height, confirmations and next block come from the header index when it knows the block,
+// the height from the coinbase (BIP34) otherwise
+fn at_position(block: &Block, position: Option<(usize, usize, Option<String>)>) -> GetBlock {
+    let height = position.as_ref().map(|(h, _, _)| *h).or(block.bip34_height().map(|h| h as usize));
+    let stripped_size = block.serialize(false).len();
+    let size = block.serialize(true).len();
+
+    GetBlock {
+        hash: reverse_hash(&block.hash),
+        confirmations: position.as_ref().map(|(_, c, _)| *c),
+        height,
+        version: block.version,
+        version_hex: format!("{:08x}", block.version),
+        merkle_root: reverse_hash(&block.merkle_root),
+        time: block.timestamp,
+        nonce: block.nonce,
+        bits: format!("{:08x}", block.bits),
+        difficulty: g16(difficulty(block.bits)),
+        n_tx: block.txns.len(),
+        previous_block_hash: match block.prev_hash.bytes().all(|c| c == b'0') {
+            true => None,
+            false => Some(reverse_hash(&block.prev_hash)),
+        },
+        next_block_hash: position.and_then(|(_, _, next)| next).map(|next| reverse_hash(&next)),
+        stripped_size,
+        size,
+        weight: stripped_size * 3 + size,
+        tx: block.txns.iter().map(transaction).collect(),
+    }
+}
+
+pub fn write_block<W: Write>(writer: W, block: &Block, pretty: bool) -> Result<(), JsonError> {
+    let getblock = to_getblock(block);
+    match pretty {
+        true => serde_json::to_writer_pretty(writer, &getblock)?,
+        false => serde_json::to_writer(writer, &getblock)?,
+    }
+    Ok(())
+}
+
+// A stored block hash, a stored .json.gz file, or a file holding the raw block (hex or binary)
+fn load(arg: &str) -> Result<Block, String> {
+    let path = Path::new(arg);
+    if !path.exists() {
+        let stored = bcfile::block_file(arg);
+        return match arg.len() == 64 && Path::new(&stored).exists() {
+            true => bcfile::load_block(Path::new(&stored)).map_err(|e| e.to_string()),
+            false => Err(format!("{}: no such file or stored block", arg)),
+        };
+    }
+    if arg.ends_with(".json.gz") {
+        return bcfile::load_block(path).map_err(|e| e.to_string());
+    }
+    let bytes = fs::read(path).map_err(|e| e.to_string())?;
+    let raw = match std::str::from_utf8(&bytes).ok().and_then(|text| hex::decode(text.trim()).ok()) {
+        Some(decoded) => decoded,
+        None => bytes,
+    };
+    parse_block(&raw).map_err(|_| format!("{}: invalid block", arg))
+}
+
+// Usage: decode <hash | file.json.gz | raw block file> [--compact] [--stored]
+// --stored prints the stored layout (bcjson) with decoded scripts, keys and signatures instead
+pub fn main(args: &[String]) {
+    let pretty = !args.iter().any(|a| a == "--compact");
+    let stored = args.iter().any(|a| a == "--stored");
+    let target = match args.iter().find(|a| !a.starts_with("--")) {
+        Some(target) => target,
+        None => {
+            eprintln!("Usage: decode <hash | file.json.gz | raw block file> [--compact] [--stored]");
+            std::process::exit(1);
+        }
+    };
+    let block = match load(target) {
+        Ok(block) => block,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    match stored {
+        true => bcjson::write_block(std::io::stdout().lock(), &block, &JsonOptions { pretty, witnesses: true, scripts: true }).unwrap(),
+        false => write_block(std::io::stdout().lock(), &block, pretty).unwrap(),
+    }
+    println!();
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::bcblocks::GENESIS_BLOCK;
+    use crate::bcscript::mainnet::P2WSH_TX;
+
+    fn block(block_hex: &str) -> Block {
+        parse_block(&hex::decode(block_hex).unwrap()).unwrap()
+    }
+
+    fn to_value(getblock: &impl Serialize) -> Value {
+        serde_json::from_str(&serde_json::to_string(getblock).unwrap()).unwrap()
+    }
+
+    // Core's getblock output (test_data/getblock, rebuilt outside the crate as Core prints it), less
+    // the fields to_getblock leaves out
+    fn core_output(file: &str) -> Value {
+        let mut value: Value = serde_json::from_str(&fs::read_to_string(Path::new("test_data/getblock").join(file)).unwrap()).unwrap();
+        for field in ["confirmations", "mediantime", "chainwork"] {
+            value.as_object_mut().unwrap().remove(field);
+        }
+        let txs = match value.get_mut("tx") {
+            Some(txs) => txs.as_array_mut().unwrap(),
+            None => std::slice::from_mut(&mut value),
+        };
+        for tx in txs {
+            tx.as_object_mut().unwrap().remove("fee");
+            for output in tx["vout"].as_array_mut().unwrap() {
+                output["scriptPubKey"].as_object_mut().unwrap().remove("desc");
+            }
+        }
+        value
+    }
+
+    #[test]
+    fn genesis_as_core_prints_it() {
+        let next = reverse_hash("00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048");
+        let mut getblock = to_value(&at_position(&block(GENESIS_BLOCK), Some((0, 1, Some(next)))));
+        getblock.as_object_mut().unwrap().remove("confirmations");
+        assert_eq!(getblock, core_output("genesis.json"));
+    }
+
+    #[test]
+    fn segwit_tx_as_core_prints_it() {
+        // Genesis header and coinbase followed by a mainnet P2WSH spend
+        let block = block(&format!("{}02{}{}", &GENESIS_BLOCK[..160], &GENESIS_BLOCK[162..], P2WSH_TX));
+        let getblock = to_value(&to_getblock(&block));
+        assert_eq!(getblock["tx"][1], core_output("p2wsh_tx.json"));
+        assert_eq!(getblock["weight"], 1140 + 758);
+    }
+
+    #[test]
+    fn height_from_header_index_first() {
+        let mut block = block(GENESIS_BLOCK);
+        // A version 2 block whose coinbase claims height 702861
+        block.version = 2;
+        block.txns[0].inputs[0].signature_script = String::from("038db90a");
+        assert_eq!(at_position(&block, None).height, Some(702861));
+        assert_eq!(at_position(&block, Some((5, 1, None))).height, Some(5));
+        assert_eq!(to_getblock(&self::block(GENESIS_BLOCK)).height, None);
+    }
+}
@@ -0,0 +1,279 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+
+use crate::bcaddrindex;
+use crate::bcblocks;
+use crate::bcfile;
+use crate::bcparse::Block;
+use crate::bcutils::reverse_hash;
+
+const INDEX_DIR: &str = "./index";
+const TX_TREE: &str = "tx";
+const SPENT_TREE: &str = "spent";
+pub const UNKNOWN_HEIGHT: u32 = u32::MAX;
+
+// Both indexes are trees of an embedded key-value store (sled), looked up by key prefix:
+//   tx      txid, block hash                                  -> height, position in the block
+//   spent   spent txid, output index, spending txid           -> input index
+// A block indexed again writes the same keys, so it is only updated (e.g. with a height found since).
+// Hashes are kept in internal byte order, as in the blocks, integers in little endian.
+
+lazy_static! {
+    static ref INDEX: Mutex<Option<sled::Db>> = Mutex::new(None);
+}
+
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub struct TxLocation {
+    pub block: String,
+    pub height: Option<u32>,
+    pub position: u32,
+}
+
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub struct Spender {
+    pub txid: String,
+    pub input: u32,
+}
+
+// Opened on first use and kept until exit: sled locks it, so the queries wait for the crawler to stop
+pub fn open_tree(name: &str) -> io::Result<sled::Tree> {
+    let mut index = INDEX.lock().unwrap();
+    if index.is_none() {
+        *index = Some(sled::open(INDEX_DIR)?);
+    }
+    Ok(index.as_ref().unwrap().open_tree(name)?)
+}
+
+pub fn hash_bytes(hash: &str) -> [u8; 32] {
+    let mut bytes = [0; 32];
+    if let Ok(decoded) = hex::decode(hash) {
+        if decoded.len() == 32 {
+            bytes.copy_from_slice(&decoded);
+        }
+    }
+    bytes
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[..4].try_into().unwrap())
+}
+
+// Without a known height, it is taken from the coinbase (BIP34) or the header index when they know it
+pub fn block_height(block: &Block, known_height: Option<u32>) -> u32 {
+    known_height
+        .or_else(|| block.bip34_height())
+        .or_else(|| bcblocks::chain_position(&block.hash).map(|(height, _, _)| height as u32))
+        .unwrap_or(UNKNOWN_HEIGHT)
+}
+
+// Adds the transactions and the outputs they spend
+pub fn index_block(block: &Block, height: u32) -> io::Result<()> {
+    add_block(&open_tree(TX_TREE)?, &open_tree(SPENT_TREE)?, block, height)
+}
+
+fn add_block(txs: &sled::Tree, spent: &sled::Tree, block: &Block, height: u32) -> io::Result<()> {
+    let block_hash = hash_bytes(&block.hash);
+
+    let mut tx_batch = sled::Batch::default();
+    let mut spent_batch = sled::Batch::default();
+    for (position, tx) in block.txns.iter().enumerate() {
+        let txid = hash_bytes(&tx.hash);
+        tx_batch.insert(
+            [&txid[..], &block_hash].concat(),
+            [height.to_le_bytes(), (position as u32).to_le_bytes()].concat(),
+        );
+
+        if tx.is_coinbase() {
+            continue;
+        }
+        for (input, tx_input) in tx.inputs.iter().enumerate() {
+            let outpoint = hash_bytes(&tx_input.prev_output.hash);
+            spent_batch.insert(
+                [&outpoint[..], &tx_input.prev_output.idx.to_le_bytes(), &txid].concat(),
+                &(input as u32).to_le_bytes(),
+            );
+        }
+    }
+    txs.apply_batch(tx_batch)?;
+    spent.apply_batch(spent_batch)?;
+    Ok(())
+}
+
+fn location(key: &[u8], value: &[u8]) -> TxLocation {
+    TxLocation {
+        block: reverse_hash(&hex::encode(&key[32..64])),
+        height: Some(read_u32(value)).filter(|h| *h != UNKNOWN_HEIGHT),
+        position: read_u32(&value[4..]),
+    }
+}
+
+fn spender(key: &[u8], value: &[u8]) -> Spender {
+    Spender { txid: reverse_hash(&hex::encode(&key[36..68])), input: read_u32(value) }
+}
+
+fn locations(txs: &sled::Tree, txid: &[u8; 32]) -> io::Result<Vec<TxLocation>> {
+    txs.scan_prefix(txid)
+        .map(|entry| entry.map(|(key, value)| location(&key, &value)).map_err(io::Error::from))
+        .collect()
+}
+
+fn spenders(spent: &sled::Tree, txid: &[u8; 32], idx: u32) -> io::Result<Vec<Spender>> {
+    spent.scan_prefix([&txid[..], &idx.to_le_bytes()].concat())
+        .map(|entry| entry.map(|(key, value)| spender(&key, &value)).map_err(io::Error::from))
+        .collect()
+}
+
+// A transaction is found in every stored block containing it, forks included
+pub fn find_tx(txid: &str) -> io::Result<Vec<TxLocation>> {
+    locations(&open_tree(TX_TREE)?, &hash_bytes(&reverse_hash(txid)))
+}
+
+pub fn find_spenders(txid: &str, idx: u32) -> io::Result<Vec<Spender>> {
+    spenders(&open_tree(SPENT_TREE)?, &hash_bytes(&reverse_hash(txid)), idx)
+}
+
+// Batch versions of find_tx and find_spenders, the first location or spender is kept
+pub fn tx_locations(txids: &HashSet<[u8; 32]>) -> io::Result<HashMap<[u8; 32], TxLocation>> {
+    let txs = open_tree(TX_TREE)?;
+    let mut found = HashMap::new();
+    for txid in txids {
+        if let Some(location) = locations(&txs, txid)?.into_iter().next() {
+            found.insert(*txid, location);
+        }
+    }
+    Ok(found)
+}
+
+pub fn outpoint_spenders(outpoints: &HashSet<([u8; 32], u32)>) -> io::Result<HashMap<([u8; 32], u32), Spender>> {
+    let spent = open_tree(SPENT_TREE)?;
+    let mut found = HashMap::new();
+    for (txid, idx) in outpoints {
+        if let Some(spender) = spenders(&spent, txid, *idx)?.into_iter().next() {
+            found.insert((*txid, *idx), spender);
+        }
+    }
+    Ok(found)
+}
+
+// Heights of the stored blocks linked to genesis through their prev_hash, forks included
+fn stored_heights(hashes: &[String]) -> HashMap<String, u32> {
+    let parents: HashMap<&str, String> = hashes.iter()
+        .filter_map(|hash| bcfile::read_prev_hash(hash).map(|prev| (hash.as_str(), prev)))
+        .collect();
+    let mut heights = HashMap::new();
+    heights.insert(reverse_hash(bcblocks::GENESIS_HASH), 0);
+    for hash in hashes {
+        let mut path = vec![hash.as_str()];
+        while let Some(prev) = parents.get(path.last().unwrap()).filter(|prev| !heights.contains_key(prev.as_str())) {
+            path.push(prev);
+        }
+        let last = path.last().unwrap();
+        let mut height = match parents.get(last).and_then(|prev| heights.get(prev)) {
+            Some(height) => *height,
+            None => continue,
+        };
+        for hash in path.into_iter().rev() {
+            height += 1;
+            heights.insert(hash.to_string(), height);
+        }
+    }
+    heights
+}
+
+// Indexes the whole archive again, for blocks stored without --txindex (or --addrindex with scripts)
+fn build(with_scripts: bool) -> io::Result<()> {
+    let txs = open_tree(TX_TREE)?;
+    let spent = open_tree(SPENT_TREE)?;
+    let scripts = open_tree(bcaddrindex::SCRIPT_TREE)?;
+    for tree in [&txs, &spent, &scripts] {
+        tree.clear()?;
+    }
+    let hashes = bcfile::stored_blocks();
+    let heights = stored_heights(&hashes);
+    for (done, hash) in hashes.iter().enumerate() {
+        match bcfile::load_block(Path::new(&bcfile::block_file(hash))) {
+            Ok(block) => {
+                let height = block_height(&block, heights.get(hash).copied());
+                add_block(&txs, &spent, &block, height)?;
+                if with_scripts {
+                    bcaddrindex::add_block(&scripts, &block, height)?;
+                }
+            }
+            Err(e) => eprintln!("Skipping {}: {}", hash, e),
+        }
+        if (done + 1) % 10000 == 0 {
+            eprintln!("  {}/{} blocks indexed", done + 1, hashes.len());
+        }
+    }
+    txs.flush()?;
+    eprintln!("{} blocks indexed", hashes.len());
+    Ok(())
+}
+
+fn is_hash(arg: &str) -> bool {
+    arg.len() == 64 && hex::decode(arg).is_ok()
+}
+
+// Usage: index build [--scripts] | index tx <txid> | index spent <txid>:<vout>
+pub fn main(args: &[String]) {
+    let result = match (args.first().map(String::as_str), args.get(1)) {
+        (Some("build"), scripts) => build(scripts.map(String::as_str) == Some("--scripts")),
+        (Some("tx"), Some(txid)) if is_hash(txid) => find_tx(txid).map(|locations| {
+            if locations.is_empty() {
+                println!("{} not indexed", txid);
+            }
+            for location in locations {
+                let height = location.height.map(|h| h.to_string()).unwrap_or_else(|| String::from("?"));
+                println!("{} block {} height {} position {}", txid, location.block, height, location.position);
+            }
+        }),
+        (Some("spent"), Some(outpoint)) => match outpoint.split_once(':') {
+            Some((txid, vout)) if is_hash(txid) && vout.parse::<u32>().is_ok() => find_spenders(txid, vout.parse().unwrap()).map(|spenders| {
+                if spenders.is_empty() {
+                    println!("{} not spent in the indexed blocks", outpoint);
+                }
+                for spender in spenders {
+                    println!("{} spent by {} input {}", outpoint, spender.txid, spender.input);
+                }
+            }),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "expected <txid>:<vout>")),
+        },
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "usage: index build [--scripts] | index tx <txid> | index spent <txid>:<vout>")),
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bcblocks::GENESIS_BLOCK;
+    use crate::bcparse::parse_block;
+    use crate::bcscript::mainnet::P2WSH_TX;
+
+    #[test]
+    fn indexing_again_updates() {
+        let block = format!("{}02{}{}", &GENESIS_BLOCK[..160], &GENESIS_BLOCK[162..], P2WSH_TX);
+        let block = parse_block(&hex::decode(block).unwrap()).unwrap();
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let (txs, spent) = (db.open_tree(TX_TREE).unwrap(), db.open_tree(SPENT_TREE).unwrap());
+        add_block(&txs, &spent, &block, UNKNOWN_HEIGHT).unwrap();
+        add_block(&txs, &spent, &block, 1).unwrap();
+        assert_eq!((txs.len(), spent.len()), (2, 1));
+
+        let txid = hash_bytes(&block.txns[1].hash);
+        let location = TxLocation { block: reverse_hash(&block.hash), height: Some(1), position: 1 };
+        assert_eq!(locations(&txs, &txid).unwrap(), vec![location]);
+        let outpoint = &block.txns[1].inputs[0].prev_output;
+        let spender = Spender { txid: reverse_hash(&block.txns[1].hash), input: 0 };
+        assert_eq!(spenders(&spent, &hash_bytes(&outpoint.hash), outpoint.idx).unwrap(), vec![spender]);
+        assert!(spenders(&spent, &hash_bytes(&outpoint.hash), outpoint.idx + 1).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,511 @@
+use std::fmt;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::bcblocks::GENESIS_BLOCK;
+use crate::bccoinbase::{coinbase_info, CoinbaseInfo, POOLS};
+use crate::bcfile;
+use crate::bcparse::{Block, classify_output, parse_block, Transaction};
+use crate::bcscript::spend::sighash_name;
+use crate::bcscript::asm::to_asm;
+
+// Bump whenever a field of the stored block is added, renamed or changes meaning
+pub const SCHEMA_VERSION: u32 = 7;
+
+// Documented schema of the stored blocks: every path listed here is required, hashes are
+// hex strings in display (reversed) order and scripts are raw hex.
+// `[]` denotes an array element, `?` a field that is only there with the matching option or when it applies.
+// Output types are Bitcoin Core's names (pubkeyhash, witness_v0_keyhash, nulldata...), addresses are mainnet ones.
+// Input spend types are inferred from the scriptSig and witness only (p2pkh, p2sh-p2wpkh, p2wsh-multisig, p2tr-keypath...).
+// Fees (satoshis, feerates in sat/vB) and block statistics are added by `utxo sync`, once input values are known.
+pub const SCHEMA: &[(&str, &str)] = &[
+    ("schema_version", "number"),
+    ("hash", "string"),
+    ("version", "number"),
+    ("prev_hash", "string"),
+    ("merkle_root", "string"),
+    ("timestamp", "number"),
+    ("bits", "number"),
+    ("nonce", "number"),
+    ("coinbase?", "object"),
+    ("coinbase?.height?", "number"),
+    ("coinbase?.extranonce?", "string"),
+    ("coinbase?.tag", "string"),
+    ("coinbase?.witness_reserved_value?", "string"),
+    ("coinbase?.witness_commitment?", "string"),
+    ("coinbase?.pool?", "string"),
+    ("txns", "array"),
+    ("txns[].hash", "string"),
+    ("txns[].version", "number"),
+    ("txns[].is_segwit", "boolean"),
+    ("txns[].inputs", "array"),
+    ("txns[].inputs[].prev_output", "object"),
+    ("txns[].inputs[].prev_output.hash", "string"),
+    ("txns[].inputs[].prev_output.idx", "number"),
+    ("txns[].inputs[].signature_script", "string"),
+    ("txns[].inputs[].signature_script_asm?", "string"),
+    ("txns[].inputs[].sequence", "number"),
+    ("txns[].inputs[].spend_type", "string"),
+    ("txns[].inputs[].pub_keys?", "array"),
+    ("txns[].inputs[].pub_keys?[]", "string"),
+    ("txns[].inputs[].signatures?", "array"),
+    ("txns[].inputs[].signatures?[].signature", "string"),
+    ("txns[].inputs[].signatures?[].sighash", "string"),
+    ("txns[].outputs", "array"),
+    ("txns[].outputs[].value", "number"),
+    ("txns[].outputs[].pub_key_script", "string"),
+    ("txns[].outputs[].pub_key_script_asm?", "string"),
+    ("txns[].outputs[].type", "string"),
+    ("txns[].outputs[].address?", "string"),
+    ("txns[].witnesses?", "array"),
+    ("txns[].witnesses?[]", "array"),
+    ("txns[].witnesses?[][].script", "string"),
+    ("txns[].lock_time", "number"),
+    ("txns[].fee?", "object"),
+    ("txns[].fee?.input_total", "number"),
+    ("txns[].fee?.fee", "number"),
+    ("txns[].fee?.vsize", "number"),
+    ("txns[].fee?.feerate", "number"),
+    ("txns[].fee?.rbf", "boolean"),
+    ("stats?", "object"),
+    ("stats?.avgfee", "number"),
+    ("stats?.avgfeerate", "number"),
+    ("stats?.avgtxsize", "number"),
+    ("stats?.blockhash", "string"),
+    ("stats?.feerate_percentiles", "array"),
+    ("stats?.feerate_percentiles[]", "number"),
+    ("stats?.height", "number"),
+    ("stats?.ins", "number"),
+    ("stats?.maxfee", "number"),
+    ("stats?.maxfeerate", "number"),
+    ("stats?.maxtxsize", "number"),
+    ("stats?.medianfee", "number"),
+    ("stats?.mediantxsize", "number"),
+    ("stats?.minfee", "number"),
+    ("stats?.minfeerate", "number"),
+    ("stats?.mintxsize", "number"),
+    ("stats?.outs", "number"),
+    ("stats?.subsidy", "number"),
+    ("stats?.swtotal_size", "number"),
+    ("stats?.swtotal_weight", "number"),
+    ("stats?.swtxs", "number"),
+    ("stats?.time", "number"),
+    ("stats?.total_out", "number"),
+    ("stats?.total_size", "number"),
+    ("stats?.total_weight", "number"),
+    ("stats?.totalfee", "number"),
+    ("stats?.txs", "number"),
+    ("stats?.utxo_increase", "number"),
+    ("stats?.utxo_size_inc", "number"),
+    ("stats?.utxo_increase_actual", "number"),
+    ("stats?.utxo_size_inc_actual", "number"),
+    ("reward_check?", "object"),
+    ("reward_check?.coinbase_out", "number"),
+    ("reward_check?.subsidy", "number"),
+    ("reward_check?.fees", "number"),
+    ("reward_check?.valid", "boolean"),
+    ("validation?", "object"),
+    ("validation?.height", "number"),
+    ("validation?.flags", "string"),
+    ("validation?.valid", "boolean"),
+    ("validation?.error?", "string"),
+];
+
+#[derive(Debug, Clone, Copy)]
+pub struct JsonOptions {
+    pub pretty: bool,
+    pub witnesses: bool,
+    pub scripts: bool,
+}
+
+impl Default for JsonOptions {
+    fn default() -> Self {
+        JsonOptions { pretty: false, witnesses: true, scripts: false }
+    }
+}
+
+#[derive(Debug)]
+pub enum JsonError {
+    Io(std::io::Error),
+    Serde(serde_json::Error),
+    UnsupportedVersion(u32),
+    Schema(String),
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JsonError::Io(e) => write!(f, "I/O error: {}", e),
+            JsonError::Serde(e) => write!(f, "JSON error: {}", e),
+            JsonError::UnsupportedVersion(v) => write!(f, "schema version {} is newer than {}", v, SCHEMA_VERSION),
+            JsonError::Schema(e) => write!(f, "schema error: {}", e),
+        }
+    }
+}
+
+impl From<serde_json::Error> for JsonError {
+    fn from(e: serde_json::Error) -> JsonError {
+        JsonError::Serde(e)
+    }
+}
+
+impl From<std::io::Error> for JsonError {
+    fn from(e: std::io::Error) -> JsonError {
+        JsonError::Io(e)
+    }
+}
+
+// The coinbase summary is derived from the block at writing time and not read back
+#[derive(Serialize)]
+struct StoredBlockRef<'a> {
+    schema_version: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    coinbase: Option<CoinbaseInfo>,
+    #[serde(flatten)]
+    block: &'a Block,
+}
+
+// Files written before the schema was versioned have no schema_version and read as version 0
+#[derive(Deserialize)]
+struct StoredBlock {
+    #[serde(default)]
+    schema_version: u32,
+    #[serde(flatten)]
+    block: Block,
+}
+
+pub fn write_block<W: Write>(writer: W, block: &Block, options: &JsonOptions) -> Result<(), JsonError> {
+    let stored = StoredBlockRef { schema_version: SCHEMA_VERSION, coinbase: coinbase_info(block, &POOLS), block };
+
+    // Default output goes straight from the structs, options work on the generic tree
+    if options.witnesses && !options.scripts {
+        return write_value(writer, &stored, options.pretty);
+    }
+
+    let mut value = serde_json::to_value(&stored)?;
+    for (tx, transaction) in value["txns"].as_array_mut().into_iter().flatten().zip(&block.txns) {
+        let tx = tx.as_object_mut().unwrap();
+        if !options.witnesses {
+            tx.remove("witnesses");
+        }
+        if options.scripts {
+            add_asm(tx, "inputs", "signature_script", true);
+            add_asm(tx, "outputs", "pub_key_script", false);
+            add_spends(tx, transaction);
+        }
+    }
+    write_value(writer, &value, options.pretty)
+}
+
+fn write_value<W: Write, T: Serialize>(writer: W, value: &T, pretty: bool) -> Result<(), JsonError> {
+    match pretty {
+        true => serde_json::to_writer_pretty(writer, value)?,
+        false => serde_json::to_writer(writer, value)?,
+    }
+    Ok(())
+}
+
+// Scripts as decoded by Bitcoin Core, signatures with their hash type in scriptSigs
+fn add_asm(tx: &mut Map<String, Value>, list: &str, field: &str, decode_sighash: bool) {
+    for item in tx[list].as_array_mut().into_iter().flatten() {
+        let asm = to_asm(&hex::decode(item[field].as_str().unwrap_or_default()).unwrap_or_default(), decode_sighash);
+        item.as_object_mut().unwrap().insert(format!("{}_asm", field), Value::String(asm));
+    }
+}
+
+// Keys and signatures found in the scriptSig and witness of each input
+fn add_spends(tx: &mut Map<String, Value>, transaction: &Transaction) {
+    for (i, input) in tx["inputs"].as_array_mut().into_iter().flatten().enumerate() {
+        let spend = transaction.spend(i);
+        let input = input.as_object_mut().unwrap();
+        input.insert(String::from("pub_keys"), spend.pub_keys.iter().map(|key| Value::String(hex::encode(key))).collect());
+        input.insert(String::from("signatures"), spend.signatures.iter().map(|sig| json!({
+            "signature": hex::encode(&sig.bytes),
+            "sighash": sighash_name(sig.sighash).unwrap_or_else(|| format!("{:#04x}", sig.sighash)),
+        })).collect());
+    }
+}
+
+pub fn read_block<R: Read>(reader: R) -> Result<Block, JsonError> {
+    let mut stored: StoredBlock = serde_json::from_reader(reader)?;
+    if stored.schema_version > SCHEMA_VERSION {
+        return Err(JsonError::UnsupportedVersion(stored.schema_version));
+    }
+    // Output types and addresses came with version 2
+    if stored.schema_version < 2 {
+        for output in stored.block.txns.iter_mut().flat_map(|tx| tx.outputs.iter_mut()) {
+            (output.script_type, output.address) = classify_output(&hex::decode(&output.pub_key_script).unwrap_or_default());
+        }
+    }
+    // and input spend types with version 3
+    if stored.schema_version < 3 {
+        for tx in stored.block.txns.iter_mut() {
+            tx.set_spend_types();
+        }
+    }
+    Ok(stored.block)
+}
+
+// Checks a JSON document against SCHEMA: required fields, types, and no undocumented field
+pub fn check_schema(value: &Value) -> Result<(), JsonError> {
+    check_node(value, "")
+}
+
+fn check_node(value: &Value, path: &str) -> Result<(), JsonError> {
+    match value {
+        Value::Object(map) => {
+            for (documented, _) in SCHEMA.iter().filter(|(documented, _)| !documented.ends_with(']')) {
+                let (parent, field, optional) = split_path(documented);
+                if parent == path && !optional && !map.contains_key(field) {
+                    return Err(JsonError::Schema(format!("missing field {}", documented)));
+                }
+            }
+            for (key, child) in map {
+                let child_path = join_path(path, key);
+                let (_, expected) = SCHEMA.iter()
+                    .find(|(documented, _)| documented.replace('?', "") == child_path)
+                    .ok_or_else(|| JsonError::Schema(format!("undocumented field {}", child_path)))?;
+                check_type(child, &child_path, expected)?;
+                check_node(child, &child_path)?;
+            }
+        }
+        Value::Array(items) => {
+            let item_path = format!("{}[]", path);
+            for item in items {
+                if let Some((_, expected)) = SCHEMA.iter().find(|(documented, _)| documented.replace('?', "") == item_path) {
+                    check_type(item, &item_path, expected)?;
+                }
+                check_node(item, &item_path)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn check_type(value: &Value, path: &str, expected: &str) -> Result<(), JsonError> {
+    let found = match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    };
+    match found == expected {
+        true => Ok(()),
+        false => Err(JsonError::Schema(format!("{} is {}, expected {}", path, found, expected))),
+    }
+}
+
+// "txns[].witnesses?" -> ("txns[]", "witnesses", true)
+fn split_path(documented: &str) -> (String, &str, bool) {
+    let path = documented.replace('?', "");
+    let optional = documented.ends_with('?');
+    let field = documented.rsplit('.').next().unwrap_or_default().trim_end_matches('?');
+    let parent = match path.rfind('.') {
+        Some(pos) => path[..pos].to_string(),
+        None => String::new(),
+    };
+    (parent, field, optional)
+}
+
+fn join_path(parent: &str, key: &str) -> String {
+    match parent.is_empty() {
+        true => key.to_string(),
+        false => format!("{}.{}", parent, key),
+    }
+}
+
+fn check_document(bytes: &[u8]) -> Result<Block, JsonError> {
+    check_schema(&serde_json::from_slice(bytes)?)?;
+    read_block(bytes)
+}
+
+fn report<T>(name: &str, result: &Result<T, JsonError>) -> bool {
+    match result {
+        Ok(_) => println!("{} -> ok", name),
+        Err(e) => println!("{} -> {}", name, e),
+    }
+    result.is_err()
+}
+
+// Usage: schema [file.json.gz ...]
+// Without file, the genesis block is written under every option, checked and read back
+pub fn main(args: &[String]) {
+    println!("Block JSON schema version {}", SCHEMA_VERSION);
+    for (path, kind) in SCHEMA {
+        println!("  {:<40} {}", path, kind);
+    }
+
+    let mut failed = false;
+    if args.is_empty() {
+        let block = parse_block(&hex::decode(GENESIS_BLOCK).unwrap()).unwrap();
+        let mut reference = Vec::new();
+        write_block(&mut reference, &block, &JsonOptions::default()).unwrap();
+
+        for pretty in [false, true] {
+            for witnesses in [false, true] {
+                for scripts in [false, true] {
+                    let options = JsonOptions { pretty, witnesses, scripts };
+                    let mut output = Vec::new();
+                    write_block(&mut output, &block, &options).unwrap();
+
+                    // Decoded scripts are dropped on reading, the rest must come back unchanged
+                    let result = check_document(&output).and_then(|read| {
+                        let mut rewritten = Vec::new();
+                        write_block(&mut rewritten, &read, &JsonOptions::default())?;
+                        match rewritten == reference {
+                            true => Ok(()),
+                            false => Err(JsonError::Schema(String::from("block differs once read back"))),
+                        }
+                    });
+                    failed |= report(&format!("{:?}", options), &result);
+                }
+            }
+        }
+    }
+
+    for file in args {
+        let result = bcfile::read_gz(Path::new(file))
+            .map_err(JsonError::from)
+            .and_then(|bytes| check_schema(&serde_json::from_slice(&bytes)?))
+            .and_then(|_| bcfile::load_block(Path::new(file)));
+        failed |= report(file, &result);
+    }
+
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::bcscript::mainnet::P2WSH_TX;
+
+    fn genesis() -> Block {
+        parse_block(&hex::decode(GENESIS_BLOCK).unwrap()).unwrap()
+    }
+
+    // Genesis header and coinbase followed by a mainnet P2WSH spend
+    fn segwit_block() -> Block {
+        let block = format!("{}02{}{}", &GENESIS_BLOCK[..160], &GENESIS_BLOCK[162..], P2WSH_TX);
+        parse_block(&hex::decode(block).unwrap()).unwrap()
+    }
+
+    fn write(block: &Block, options: &JsonOptions) -> Vec<u8> {
+        let mut output = Vec::new();
+        write_block(&mut output, block, options).unwrap();
+        output
+    }
+
+    // Stored file of the block rewritten by `edit`, as an older version would have written it
+    fn downgrade(block: &Block, edit: impl Fn(&mut Value)) -> Vec<u8> {
+        let mut value: Value = serde_json::from_slice(&write(block, &JsonOptions::default())).unwrap();
+        edit(&mut value);
+        serde_json::to_vec(&value).unwrap()
+    }
+
+    fn without_output_types(value: &mut Value) {
+        for tx in value["txns"].as_array_mut().unwrap() {
+            for output in tx["outputs"].as_array_mut().unwrap() {
+                output.as_object_mut().unwrap().remove("type");
+                output.as_object_mut().unwrap().remove("address");
+            }
+        }
+    }
+
+    fn without_spend_types(value: &mut Value) {
+        for tx in value["txns"].as_array_mut().unwrap() {
+            for input in tx["inputs"].as_array_mut().unwrap() {
+                input.as_object_mut().unwrap().remove("spend_type");
+            }
+        }
+    }
+
+    // Written under the options, checked against the schema and read back, decoded scripts dropped
+    fn assert_round_trip(block: &Block) {
+        let reference = write(block, &JsonOptions::default());
+        for pretty in [false, true] {
+            for scripts in [false, true] {
+                let options = JsonOptions { pretty, witnesses: true, scripts };
+                let output = write(block, &options);
+                check_schema(&serde_json::from_slice(&output).unwrap()).unwrap();
+                let read = read_block(output.as_slice()).unwrap();
+                assert_eq!(read.serialize(true), block.serialize(true), "{:?}", options);
+                assert_eq!(write(&read, &JsonOptions::default()), reference, "{:?}", options);
+            }
+        }
+    }
+
+    #[test]
+    fn genesis_round_trip() {
+        assert_round_trip(&genesis());
+    }
+
+    #[test]
+    fn segwit_round_trip() {
+        let block = segwit_block();
+        assert!(block.txns[1].is_segwit);
+        assert_round_trip(&block);
+        let read = read_block(write(&block, &JsonOptions::default()).as_slice()).unwrap();
+        assert_eq!(hex::encode(read.txns[1].serialize(true)), P2WSH_TX);
+    }
+
+    #[test]
+    fn witnesses_left_out() {
+        let block = segwit_block();
+        let output = write(&block, &JsonOptions { witnesses: false, ..Default::default() });
+        check_schema(&serde_json::from_slice(&output).unwrap()).unwrap();
+        let read = read_block(output.as_slice()).unwrap();
+        assert!(read.txns[1].witnesses.is_empty());
+        assert_eq!(read.serialize(false), block.serialize(false));
+    }
+
+    #[test]
+    fn output_types_filled_before_version_2() {
+        let block = segwit_block();
+        let reference = write(&block, &JsonOptions::default());
+        let old = downgrade(&block, |value| {
+            value["schema_version"] = json!(1);
+            without_output_types(value);
+            without_spend_types(value);
+        });
+        let read = read_block(old.as_slice()).unwrap();
+        assert_eq!(read.txns[1].outputs[1].script_type, "witness_v0_scripthash");
+        assert!(read.txns[1].outputs[1].address.is_some());
+        assert_eq!(write(&read, &JsonOptions::default()), reference);
+    }
+
+    #[test]
+    fn spend_types_filled_before_version_3() {
+        let block = segwit_block();
+        let reference = write(&block, &JsonOptions::default());
+        let old = downgrade(&block, |value| {
+            value["schema_version"] = json!(2);
+            without_spend_types(value);
+        });
+        let read = read_block(old.as_slice()).unwrap();
+        assert_eq!(read.txns[0].inputs[0].spend_type, "coinbase");
+        assert_eq!(read.txns[1].inputs[0].spend_type, "p2wsh-multisig");
+        assert_eq!(write(&read, &JsonOptions::default()), reference);
+
+        // Current files are taken as they are
+        let current = downgrade(&block, without_spend_types);
+        assert!(read_block(current.as_slice()).unwrap().txns[1].inputs[0].spend_type.is_empty());
+    }
+
+    #[test]
+    fn newer_version_and_undocumented_field_rejected() {
+        let newer = downgrade(&genesis(), |value| value["schema_version"] = json!(SCHEMA_VERSION + 1));
+        assert!(matches!(read_block(newer.as_slice()), Err(JsonError::UnsupportedVersion(_))));
+        let undocumented = downgrade(&genesis(), |value| value["extra"] = json!(0));
+        assert!(matches!(check_schema(&serde_json::from_slice(&undocumented).unwrap()), Err(JsonError::Schema(_))));
+    }
+}
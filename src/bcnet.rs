@@ -29,7 +29,7 @@ const MIN_ADDRESSES_RECEIVED_THRESHOLD: usize = 5;
 const NB_MAX_READ_ON_SOCKET: usize = 20;
 
 // Debugger
-static mut NODES_STATUS: [([u8; 15], u64, u64, u64, u64, u64); crate::THREADS as usize] = [([0; 15], 0, 0, 0, 0, 0); crate::THREADS as usize];
+static mut NODES_STATUS: [([u8; 15], u64, u64, u64, u64, u64); crate::THREADS] = [([0; 15], 0, 0, 0, 0, 0); crate::THREADS];
 lazy_static! {
     pub static ref NB_NOEUDS_CONNECTES:Mutex<HashMap<usize, usize>> = Mutex::new(HashMap::new());
 }
@@ -72,7 +72,7 @@ pub fn handle_one_peer(connection_start_channel: Receiver<String>, address_chann
     }
 }
 
-fn handle_incoming_message<'a>(_num: &usize, connection:& TcpStream, sender: &Sender<String>, block_sender: &SyncSender<Block>, target_address: &String) -> &'a String  {
+fn handle_incoming_message<'a>(_num: &usize, connection:& TcpStream, sender: &Sender<String>, block_sender: &SyncSender<Block>, target_address: &str) -> &'a String  {
     let mut lecture:usize = 0; // Garde pour éviter connection infinie inutile
     loop {
 
@@ -84,8 +84,10 @@ fn handle_incoming_message<'a>(_num: &usize, connection:& TcpStream, sender: &Se
                 // if payload.len() <= 0 { panic!("Payload nul");}
                 match command {
                     cmd if cmd == *MSG_VERSION => {
-                        handle_incoming_cmd_version(target_address, &payload);
-                        return &MSG_VERSION;
+                        return match handle_incoming_cmd_version(target_address, &payload) {
+                            true => &MSG_VERSION,
+                            false => &CONN_CLOSE
+                        };
                     }
                     cmd if cmd == *MSG_VERSION_ACK
                     => return &MSG_VERSION_ACK,
@@ -128,19 +130,19 @@ fn next_status(from: &str) -> &String {
 
 fn trace(num: &usize, target: &str, current: &str) {
     unsafe {
-        let (mut node, run, mut ver, mut addr, mut head, mut data) = NODES_STATUS[*num as usize];
+        let (mut node, run, mut ver, mut addr, mut head, mut data) = NODES_STATUS[*num];
         match current {
-            current if current == "version" => ver += 1,
-            current if current == "getaddr" => addr += 1,
-            current if current == "getheaders" => head += 1,
-            current if current == "getdata" => data += 1,
+            "version" => ver += 1,
+            "getaddr" => addr += 1,
+            "getheaders" => head += 1,
+            "getdata" => data += 1,
             _ => ()
         };
 
         node.copy_from_slice(&target.pad_to_width_with_alignment(20, Alignment::Right).as_bytes()[0..15]);
 
         // eprint!("{} -> ", &num);
-        NODES_STATUS[*num as usize] = (node, run + 1, ver, addr, head, data);
+        NODES_STATUS[*num] = (node, run + 1, ver, addr, head, data);
         for (_a, _b, _c, _d, _e, f) in NODES_STATUS {
             if f > 2 {
                 NB_NOEUDS_CONNECTES.lock().unwrap().insert(*num, 1);
@@ -150,14 +152,14 @@ fn trace(num: &usize, target: &str, current: &str) {
     }
 }
 
-fn activate_peer<'a>(num: &usize, mut connection: &TcpStream, current: &'a String, sender: &Sender<String>, block_sender: &SyncSender<Block>, target: &String) -> Result<&'a String, Error> {
+fn activate_peer<'a>(num: &usize, mut connection: &TcpStream, current: &'a String, sender: &Sender<String>, block_sender: &SyncSender<Block>, target: &str) -> Result<&'a String, Error> {
     // // Trace function
     trace(num, target, current);
 
     connection.write_all(bcmessage::build_request(current).as_slice()).unwrap();
 
     match handle_incoming_message(num, connection, sender, block_sender, target) {
-        res if *res == *CONN_CLOSE => Err(Error::new(ErrorKind::Other, format!("Connexion terminée {} <> {}", current, res))),
+        res if *res == *CONN_CLOSE => Err(Error::other(format!("Connexion terminée {} <> {}", current, res))),
         res if *res == *current => Ok(next_status(current)),
         // res if *res == *MSG_GETADDR && *current == *GET_HEADERS => Ok(current), // Remote node answers many times the same thing
         res => Err(Error::new(ErrorKind::ConnectionReset, format!("Wrong message {} <> {}", current, res)))
@@ -165,13 +167,22 @@ fn activate_peer<'a>(num: &usize, mut connection: &TcpStream, current: &'a Strin
 }
 
 // Incoming messages
-fn handle_incoming_cmd_version(peer: &str, payload: &[u8]) {
-    bcfile::store_version_message(peer, bcmessage::process_version_message(payload));
-    bcpeers::register_peer_connection(peer);
+fn handle_incoming_cmd_version(peer: &str, payload: &[u8]) -> bool {
+    match bcmessage::process_version_message(payload) {
+        Ok(version) => {
+            bcfile::store_version_message(peer, version);
+            bcpeers::register_peer_connection(peer);
+            true
+        }
+        Err(_) => false
+    }
 }
 
 fn handle_incoming_cmd_msg_addr(payload: &[u8], sender: &Sender<String>) -> bool {
-    bcpeers::check_addr_messages(bcmessage::process_addr_message(payload), sender) > MIN_ADDRESSES_RECEIVED_THRESHOLD
+    match bcmessage::process_addr_message(payload) {
+        Ok(addresses) => bcpeers::check_addr_messages(addresses, sender) > MIN_ADDRESSES_RECEIVED_THRESHOLD,
+        Err(_) => false
+    }
 }
 
 fn handle_incoming_cmd_msg_header(payload: &[u8], lecture: &mut usize) -> bool {
@@ -192,6 +203,10 @@ fn handle_incoming_cmd_msg_header(payload: &[u8], lecture: &mut usize) -> bool {
                     eprintln!("Sortie du noeud");
                     false
                 }
+                bcmessage::ProcessHeadersMessageError::Parsing(..) => {
+                    eprintln!("Error processing headers message: Parsing Error");
+                    false
+                }
                 _ => {
                     // eprintln!("Erreur -> {:?}", err);
                     // std::process::exit(1);
@@ -202,7 +217,7 @@ fn handle_incoming_cmd_msg_header(payload: &[u8], lecture: &mut usize) -> bool {
     }
 }
 
-fn handle_incoming_cmd_msg_block(payload: &Vec<u8>, lecture: &mut usize, block_sender: &SyncSender<Block>) -> bool {
+fn handle_incoming_cmd_msg_block(payload: &[u8], lecture: &mut usize, block_sender: &SyncSender<Block>) -> bool {
     match bcmessage::process_block_message(payload) {
         Ok(block) => {
             block_sender.send(block).unwrap();
@@ -1,10 +1,10 @@
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
 use std::io::{Error, ErrorKind, Read};
 use std::net::{IpAddr, TcpStream};
 use std::time::SystemTime;
 
 use bitcoin_hashes::{Hash, sha256d};
-use chrono::{DateTime, NaiveDateTime, Utc};
+use chrono::{DateTime, Utc};
 use hex::FromHex;
 use lazy_static::lazy_static;
 use sha2::{Digest, Sha256};
@@ -58,6 +58,7 @@ const END_DATE: usize = 20;
 
 // HEADER STRUCT
 const HEADER_SIZE: usize = 24;
+const MAX_PAYLOAD_SIZE: u32 = 32 * 1024 * 1024;
 const MAGIC: &[u8; 4] = &[0xF9, 0xBE, 0xB4, 0xD9];
 
 const START_MAGIC: usize = 0;
@@ -108,12 +109,12 @@ fn create_init_message_payload() -> Vec<u8> {
 pub fn read_message(mut connection: &TcpStream) -> Result<(String, Vec<u8>), Error> {
     let mut header_buffer = [0_u8; HEADER_SIZE];
 
-    return match connection.read(&mut header_buffer) {
+    match connection.read_exact(&mut header_buffer) {
         Ok(_) => {
             // println!("Lecture faite {:02X?}", header_buffer);
             if header_buffer[START_MAGIC..END_MAGIC] != MAGIC[..] {
                 //println!("Error in Magic message header: {:?}", &header_buffer[START_MAGIC..END_MAGIC]);
-                return Err(Error::new(ErrorKind::Other, "Magic error"));
+                return Err(Error::other("Magic error"));
             }
 
             let cmd = String::from_utf8_lossy(&header_buffer[START_CMD..END_CMD]);
@@ -123,6 +124,9 @@ pub fn read_message(mut connection: &TcpStream) -> Result<(String, Vec<u8>), Err
             if payload_size == 0 {
                 return Ok((command, vec![0]));
             };
+            if payload_size > MAX_PAYLOAD_SIZE {
+                return Err(Error::new(ErrorKind::InvalidData, "Payload too large"));
+            }
 
             let mut payload_buffer = vec![0u8; payload_size as usize];
             match connection.read_exact(&mut payload_buffer) {
@@ -131,7 +135,7 @@ pub fn read_message(mut connection: &TcpStream) -> Result<(String, Vec<u8>), Err
             }
         }
         Err(e) => Err(e)
-    };
+    }
 }
 
 pub fn build_request(message: &str) -> Vec<u8> {
@@ -166,7 +170,7 @@ fn get_payload_with_current_date() -> Vec<u8> {
 
 fn build_request_message_header(header: &mut Vec<u8>, command_name: &str, payload: &[u8]) {
     header.splice(START_MAGIC..END_MAGIC, MAGIC.iter().cloned());
-    let end_cmd = command_name.as_bytes().len() + START_CMD;
+    let end_cmd = command_name.len() + START_CMD;
     if end_cmd > END_CMD { panic!("wrong command") }
     header.splice(START_CMD..end_cmd, command_name.as_bytes().iter().cloned());
 
@@ -180,26 +184,29 @@ fn build_request_message_header(header: &mut Vec<u8>, command_name: &str, payloa
     header.splice(START_CHECKSUM..END_CHECKSUM, checksum.iter().cloned());
 }
 
-pub fn process_version_message(payload: &[u8]) -> (u32, Vec<u8>, DateTime<Utc>, String) {
-    let version_number = u32::from_le_bytes((&payload[..VERSION_END]).try_into().unwrap());
-    let services = payload[VERSION_END..SERVICES_END].to_vec();
-    let peer_time = get_date_time(payload[SERVICES_END..TIMESTAMP_END].to_vec());
+pub fn process_version_message(payload: &[u8]) -> Result<(u32, Vec<u8>, DateTime<Utc>, String), ParsingError> {
+    let version_number = u32::from_le_bytes(payload.get(..VERSION_END).ok_or(ParsingError)?.try_into().map_err(|_| ParsingError)?);
+    let services = payload.get(VERSION_END..SERVICES_END).ok_or(ParsingError)?.to_vec();
+    let peer_time = get_date_time(payload.get(SERVICES_END..TIMESTAMP_END).ok_or(ParsingError)?.to_vec())?;
 
-    let (tmp, start_byte) = get_compact_int(&payload[USER_AGENT..].to_vec());
-    let useragent_size = tmp as usize;
+    let (tmp, start_byte) = get_compact_int(payload.get(USER_AGENT..).ok_or(ParsingError)?).ok_or(ParsingError)?;
+    let useragent_size = usize::try_from(tmp).map_err(|_| ParsingError)?;
 
     let mut user_agent = String::new();
-    if USER_AGENT + start_byte + useragent_size < payload.len() && useragent_size > 0 {
-        let user_agent_slice = &payload[(USER_AGENT + start_byte)..(USER_AGENT + start_byte + useragent_size)];
-        user_agent.push_str(String::from_utf8(user_agent_slice.to_vec()).unwrap().as_str());
+    if useragent_size > 0 {
+        let user_agent_start = USER_AGENT + start_byte;
+        let user_agent_end = user_agent_start.checked_add(useragent_size).ok_or(ParsingError)?;
+        if let Some(user_agent_slice) = payload.get(user_agent_start..user_agent_end) {
+            user_agent.push_str(&String::from_utf8_lossy(user_agent_slice));
+        }
     }
-    (version_number, services, peer_time, user_agent)
+    Ok((version_number, services, peer_time, user_agent))
 }
 
-pub fn process_addr_message(payload: &[u8]) -> Vec<String> {
-    let (addr_number, start_byte) = get_compact_int(payload);
+pub fn process_addr_message(payload: &[u8]) -> Result<Vec<String>, ParsingError> {
+    let (addr_number, start_byte) = get_compact_int(payload).ok_or(ParsingError)?;
     if addr_number < 2 {
-        return vec![];
+        return Ok(vec![]);
     }
 
     let mut addr = vec![];
@@ -207,9 +214,10 @@ pub fn process_addr_message(payload: &[u8]) -> Vec<String> {
     // let mut new_addr = 0;
     while read_addr < addr_number {
         let addr_begins_at = start_byte + (ADDRESS_LEN * read_addr as usize);
-        let _date_time = get_date_time(payload[addr_begins_at..addr_begins_at + TIME_FIELD_END].to_vec());
-        let _services = payload[addr_begins_at + TIME_FIELD_END..addr_begins_at + SERVICES_END].to_vec();
-        let ip_addr_field = payload[addr_begins_at + SERVICES_END..addr_begins_at + IP_FIELD_END].to_vec();
+        let entry = payload.get(addr_begins_at..addr_begins_at + ADDRESS_LEN).ok_or(ParsingError)?;
+        let _date_time = get_date_time(entry[..TIME_FIELD_END].to_vec())?;
+        let _services = entry[TIME_FIELD_END..SERVICES_END].to_vec();
+        let ip_addr_field = entry[SERVICES_END..IP_FIELD_END].to_vec();
 
         let mut array_v6 = [0; 16];
         array_v6.copy_from_slice(&ip_addr_field[..]);
@@ -219,34 +227,52 @@ pub fn process_addr_message(payload: &[u8]) -> Vec<String> {
         array_v4.copy_from_slice(&ip_addr_field[12..]);
         let ip_v4 = IpAddr::from(array_v4);
 
-        let port = u16::from_be_bytes((&payload[addr_begins_at + IP_FIELD_END..addr_begins_at + PORT_FIELD_END]).try_into().unwrap());
+        let port = u16::from_be_bytes([entry[IP_FIELD_END], entry[PORT_FIELD_END - 1]]);
         let new_peer: String = format!("{}:{:?}", ip_v4, port);
 
         addr.push(new_peer);
         read_addr += 1;
     }
     // eprintln!("--> Ajout {} noeuds", new_addr);
-    addr
+    Ok(addr)
 }
 
 #[derive(Debug)]
 pub enum ProcessHeadersMessageError {
     UnkownBlocks,
     NoNewBlocks,
+    Parsing(ParsingError),
+}
+
+impl From<ParsingError> for ProcessHeadersMessageError {
+    fn from(_: ParsingError) -> ProcessHeadersMessageError {
+        ProcessHeadersMessageError::Parsing(ParsingError)
+    }
+}
+
+// Decodes a headers message into (block hash, previous block hash) pairs, without touching the known blocks
+pub fn parse_headers_message(payload: &[u8]) -> Result<Vec<(String, String)>, ParsingError> {
+    let mut headers = vec![];
+
+    let (nb_headers, mut offset) = get_compact_int(payload).ok_or(ParsingError)?;
+    let header_length = 80;
+    for _i in 0..nb_headers {
+        let header = payload.get(offset..offset + header_length).ok_or(ParsingError)?;
+        let previous_block = &header[4..4 + 32];
+        let current_block = hex::encode(sha256d::Hash::hash(header));
+        headers.push((current_block, hex::encode(previous_block)));
+        offset += header_length + 1
+    }
+    Ok(headers)
 }
 
 pub fn process_headers_message(payload: &[u8]) -> Result<Vec<String>, ProcessHeadersMessageError> {
     let mut new_blocks = vec![];
 
     let mut highest_index = 0;
-    let (nb_headers, mut offset) = get_compact_int(payload);
-    let header_length = 80;
-    for _i in 0..nb_headers {
-        let mut previous_block = [0; 32];
-        previous_block.clone_from_slice(&payload[offset + 4..offset + 4 + 32]);
-        let current_block = hex::encode(sha256d::Hash::hash(&payload[offset..offset + header_length]));
-        // eprintln!("Gen -> {} --> {}", hex::encode(previous_block), current_block.to_string());
-        match bcblocks::is_new(&current_block, &hex::encode(previous_block)) {
+    for (current_block, previous_block) in parse_headers_message(payload)? {
+        // eprintln!("Gen -> {} --> {}", previous_block, current_block);
+        match bcblocks::is_new(&current_block, &previous_block) {
             Ok(idx) if idx > highest_index => {
                 highest_index = idx;
                 new_blocks.push(current_block);
@@ -254,7 +280,6 @@ pub fn process_headers_message(payload: &[u8]) -> Result<Vec<String>, ProcessHea
             Ok(_) => { new_blocks.push(current_block.to_string()); }
             Err(()) => return Err(ProcessHeadersMessageError::UnkownBlocks)
         };
-        offset += header_length + 1
     }
 
     match highest_index {
@@ -295,13 +320,14 @@ pub fn process_block_message(payload: &[u8]) -> Result<Block, ProcessBlockMessag
     }
 }
 
-//// COMMON SERVICES
-fn get_date_time(mut time_vec: Vec<u8>) -> DateTime<Utc> {
+// COMMON SERVICES
+fn get_date_time(mut time_vec: Vec<u8>) -> Result<DateTime<Utc>, ParsingError> {
     if time_vec.len() == 4 {
         /* La taille du champ varie dans le protocole de 4 à 8 octets */
         time_vec.append(&mut vec![0, 0, 0, 0]);
     }
-    DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(i64::from_le_bytes(time_vec.try_into().unwrap()), 0), Utc)
+    let timestamp = i64::from_le_bytes(time_vec.try_into().map_err(|_| ParsingError)?);
+    DateTime::<Utc>::from_timestamp(timestamp, 0).ok_or(ParsingError)
 }
 
 fn compute_checksum(payload: &[u8]) -> Vec<u8> {
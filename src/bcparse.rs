@@ -1,15 +1,21 @@
-use std::error::Error;
+use std::convert::TryFrom;
 use std::fmt;
-use std::fmt::{Display, Formatter};
-use std::io::Write;
+use std::fmt::Formatter;
 
 use bitcoin_hashes::{Hash, sha256d};
 use byteorder::{LittleEndian, ReadBytesExt};
-use indoc::writedoc;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
-use serde::de::Visitor;
-
-use crate::bcutils::{get_compact_int, reverse_hash};
+use serde::de::{Error, Visitor};
+
+use crate::bcfees::{BlockStats, RewardCheck, TxFee};
+use crate::bcscript::address::address;
+use crate::bcscript::opcodes::{OP_1, OP_16};
+use crate::bcscript::parse::parse_one_op;
+use crate::bcscript::script::{as_script_nb, ScriptItem};
+use crate::bcscript::spend::{infer_spend, Spend};
+use crate::bcscript::standard::classify;
+use crate::bcutils::{get_compact_int, reverse_hash, to_compact_int};
+use crate::bcvalidate::Validation;
 
 #[derive(Debug, Default, Deserialize, Serialize)]
 pub struct Block {
@@ -24,67 +30,12 @@ pub struct Block {
     pub bits: u32,
     pub nonce: u32,
     pub txns: Vec<Transaction>,
-}
-
-impl Display for Block {
-    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, r#"{{"hash": "{}", "version": {}, "prev_hash": "{}", "merkle_root": "{}", "timestamp": {}, "bits": {}, "nonce": {}, "txns": ["#,
-               reverse_hash(&self.hash),
-               self.version,
-               reverse_hash(&self.prev_hash),
-               reverse_hash(&self.merkle_root),
-               self.timestamp,
-               self.bits,
-               self.nonce)?;
-
-        for i in 0..self.txns.len() {
-            write!(f, "{}", self.txns[i])?;
-            if i < self.txns.len() - 1 {
-                write!(f, ", ")?;
-            }
-        }
-
-        write!(f, "]}}")
-    }
-}
-
-impl Block {
-    pub(crate) fn to_json(&self, indent_level: usize) -> Result<Vec<u8>, Box<dyn Error>> {
-        let mut result = Vec::new();
-        writedoc!(result, r#"
-            {i}{{
-              {i}"hash": "{}",
-              {i}"version": {},
-              {i}"prev_hash": "{}",
-              {i}"merkle_root": "{}",
-              {i}"timestamp": {},
-              {i}"bits": {},
-              {i}"nonce": {},
-              {i}"txns": [
-            "#,
-            reverse_hash(&self.hash),
-            self.version,
-            reverse_hash(&self.prev_hash),
-            reverse_hash(&self.merkle_root),
-            self.timestamp,
-            self.bits,
-            self.nonce,
-            i = " ".repeat(2 * indent_level)
-        )?;
-
-        for tx in &self.txns {
-            result.append(&mut tx.to_json(indent_level + 2).unwrap())
-        }
-
-        writedoc!(result, r#"
-              {i}]
-            {i}}}
-            "#,
-            i = " ".repeat(2 * indent_level)
-        )?;
-
-        Ok(result)
-    }
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stats: Option<BlockStats>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reward_check: Option<RewardCheck>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub validation: Option<Validation>,
 }
 
 #[derive(Debug, Default, Deserialize, Serialize)]
@@ -95,125 +46,11 @@ pub struct Transaction {
     pub is_segwit: bool,
     pub inputs: Vec<TxInput>,
     pub outputs: Vec<TxOutput>,
+    #[serde(default)]
     pub witnesses: Vec<Vec<WitnessItem>>,
     pub lock_time: u32,
-}
-
-impl Display for Transaction {
-    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, r#"{{"hash": "{}", "version": {}, "is_segwit": {}, "inputs": ["#,
-               reverse_hash(&self.hash),
-               self.version,
-               self.is_segwit)?;
-
-        for i in 0..self.inputs.len() {
-            write!(f, "{}", self.inputs[i])?;
-            if i < self.inputs.len() - 1 {
-                write!(f, ", ")?;
-            }
-        }
-
-        write!(f, r#"], "outputs": ["#)?;
-
-        for i in 0..self.outputs.len() {
-            write!(f, "{}", self.outputs[i])?;
-            if i < self.outputs.len() - 1 {
-                write!(f, ", ")?;
-            }
-        }
-
-        write!(f, r#"], "witnesses": ["#)?;
-
-        for i in 0..self.witnesses.len() {
-            write!(f, "[")?;
-
-            for j in 0..self.witnesses[i].len() {
-                write!(f, r#"{{"script": "{}"}}"#, self.witnesses[i][j].script)?;
-                if j < self.witnesses[i].len() - 1 {
-                    write!(f, ", ")?;
-                }
-            }
-            write!(f, "]")?;
-
-            if i < self.witnesses.len() - 1 {
-                write!(f, ", ")?;
-            }
-        }
-
-        write!(f, r#"], "lock_time": {}}}"#, self.lock_time)
-    }
-}
-
-impl Transaction {
-    fn to_json(&self, indent_level: usize) -> Result<Vec<u8>, Box<dyn Error>> {
-        let mut result = Vec::new();
-
-        writedoc!(result, r#"
-            {i}{{
-              {i}"hash": "{}",
-              {i}"version": {},
-              {i}"is_segwit": {},
-              {i}"inputs": [
-            "#,
-            reverse_hash(&self.hash),
-            self.version,
-            self.is_segwit,
-            i = " ".repeat(2 * indent_level)
-        )?;
-
-        for input in &self.inputs {
-            result.append(&mut input.to_json(indent_level + 2).unwrap())
-        };
-
-        writedoc!(result, r#"
-            {i}],
-            {i}"outputs": [
-            "#,
-            i = " ".repeat(2 * indent_level + 2)
-        )?;
-
-        for output in &self.outputs {
-            result.append(&mut output.to_json(indent_level + 2).unwrap())
-        };
-
-        writedoc!(result, r#"
-            {i}],
-            "#, i = " ".repeat(2 * indent_level + 2))?;
-
-        if !self.witnesses.is_empty() {
-            writedoc!(result, r#"{i}"witnesses": ["#, i = " ".repeat(2 * indent_level + 2))?;
-
-            for witness in &self.witnesses {
-                writedoc!(result, r#"{i}["#, i = " ".repeat(2 * indent_level + 4))?;
-                for item in witness {
-                    writedoc!(result, r#"
-                        {i}{{
-                        {i}"script": {}
-                        {i}}},
-                        "#,
-                        item.script,
-                        i = " ".repeat(2 * indent_level + 6)
-                    )?;
-                }
-                writedoc!(result, r#"
-                    {i}}},
-                    "#, i = " ".repeat(2 * indent_level + 4))?;
-            };
-            writedoc!(result, r#"
-                {i}]
-                "#, i = " ".repeat(2 * indent_level + 2))?;
-        }
-
-        writedoc!(result, r#"
-              {i}"lock_time": {}
-            {i}}},
-            "#,
-            self.lock_time,
-            i = " ".repeat(2 * indent_level)
-        )?;
-
-        Ok(result)
-    }
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fee: Option<TxFee>,
 }
 
 #[derive(Debug, Default, Deserialize, Serialize)]
@@ -221,39 +58,8 @@ pub struct TxInput {
     pub prev_output: OutPoint,
     pub signature_script: String,
     pub sequence: u32,
-}
-
-impl Display for TxInput {
-    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, r#"{{"prev_output": {{"hash": "{}", "idx": {}}}, "signature_script": "{}", "sequence": {}}}"#,
-               reverse_hash(&self.prev_output.hash),
-               self.prev_output.idx,
-               self.signature_script,
-               self.sequence)
-    }
-}
-
-impl TxInput {
-    fn to_json(&self, indent_level: usize) -> Result<Vec<u8>, Box<dyn Error>> {
-        let mut result = Vec::new();
-        writedoc!(result, r#"
-                {i}{{
-                  {i}"prev_output": {{
-                    {i}"hash": "{}",
-                    {i}"idx": {}
-                  {i}}},
-                  {i}"signature_script": "{}",
-                  {i}"sequence": {}
-                {i}}},
-                "#,
-                reverse_hash(&self.prev_output.hash),
-                self.prev_output.idx,
-                self.signature_script,
-                self.sequence,
-                i = " ".repeat(2 * indent_level)
-            )?;
-        Ok(result)
-    }
+    #[serde(default)]
+    pub spend_type: String,
 }
 
 #[derive(Debug, Default, Deserialize, Serialize)]
@@ -267,31 +73,10 @@ pub struct OutPoint {
 pub struct TxOutput {
     pub value: i64,
     pub pub_key_script: String,
-}
-
-impl Display for TxOutput {
-    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, r#"{{"value": {}, "pub_key_script": "{}"}}"#,
-               self.value,
-               self.pub_key_script)
-    }
-}
-
-impl TxOutput {
-    fn to_json(&self, indent_level: usize) -> Result<Vec<u8>, Box<dyn Error>> {
-        let mut result = Vec::new();
-        writedoc!(result, r#"
-                {i}{{
-                  {i}"value": {},
-                  {i}"pub_key_script": "{}"
-                {i}}},
-                "#,
-                self.value,
-                self.pub_key_script,
-                i = " ".repeat(2 * indent_level)
-            )?;
-        Ok(result)
-    }
+    #[serde(rename = "type", default)]
+    pub script_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
 }
 
 #[derive(Debug, Default, Deserialize, Serialize)]
@@ -317,8 +102,10 @@ impl<'de> Visitor<'de> for HashVisitor {
     }
 
     fn visit_str<E>(self, s: &str) -> Result<Self::Value, E> where E: Error {
-        let res = reverse_hash(s);
-        Ok(res)
+        if s.len() != 64 || hex::decode(s).is_err() {
+            return Err(E::custom(format!("invalid hash {}", s)));
+        }
+        Ok(reverse_hash(s))
     }
 }
 
@@ -331,31 +118,33 @@ struct Payload<'a> {
     off: usize,
 }
 
-impl Payload<'_> {
+impl<'a> Payload<'a> {
+    // Moves the cursor forward only if the whole requested slice is available
+    fn take(&mut self, length: usize) -> Result<&'a [u8], ParsingError> {
+        let end = self.off.checked_add(length).ok_or(ParsingError)?;
+        let slice = self.pl.get(self.off..end).ok_or(ParsingError)?;
+        self.off = end;
+        Ok(slice)
+    }
     fn read_u32(&mut self) -> Result<u32, ParsingError> {
-        self.off += 4;
-        Ok(self.pl.get(self.off - 4..self.off).ok_or(ParsingError)?.read_u32::<LittleEndian>().unwrap())
+        self.take(4)?.read_u32::<LittleEndian>().map_err(|_| ParsingError)
     }
     fn read_i32(&mut self) -> Result<i32, ParsingError> {
-        self.off += 4;
-        Ok(self.pl.get(self.off - 4..self.off).ok_or(ParsingError)?.read_i32::<LittleEndian>().unwrap())
+        self.take(4)?.read_i32::<LittleEndian>().map_err(|_| ParsingError)
     }
     fn read_i64(&mut self) -> Result<i64, ParsingError> {
-        self.off += 8;
-        Ok(self.pl.get(self.off - 8..self.off).ok_or(ParsingError)?.read_i64::<LittleEndian>().unwrap())
+        self.take(8)?.read_i64::<LittleEndian>().map_err(|_| ParsingError)
     }
     fn encode_addr(&mut self) -> Result<String, ParsingError> {
-        self.off += 32;
-        Ok(hex::encode(self.pl.get(self.off - 32..self.off).ok_or(ParsingError)?))
+        Ok(hex::encode(self.take(32)?))
     }
     fn encode_string(&mut self, length: usize) -> Result<String, ParsingError> {
-        self.off += length;
-        Ok(hex::encode(self.pl.get(self.off - length..self.off).ok_or(ParsingError)?))
+        Ok(hex::encode(self.take(length)?))
     }
     fn get_compact_int(&mut self) -> Result<usize, ParsingError> {
-        let (txn_count, off) = get_compact_int(self.pl.get(self.off..).ok_or(ParsingError)?);
+        let (txn_count, off) = get_compact_int(self.pl.get(self.off..).ok_or(ParsingError)?).ok_or(ParsingError)?;
         self.off += off;
-        Ok(txn_count as usize)
+        usize::try_from(txn_count).map_err(|_| ParsingError)
     }
 }
 
@@ -382,10 +171,11 @@ fn is_segwit(tx: &Payload) -> Result<bool, ParsingError> {
 fn tx_loop(pl: &mut Payload, tx_count: usize) -> Result<Vec<Transaction>, ParsingError> {
     let mut txs = Vec::new();
     for _ in 0..tx_count {
-        let tx = match is_segwit(pl)? {
+        let mut tx = match is_segwit(pl)? {
             true => parse_segwit_tx(pl)?,
             false => parse_standard_tx(pl)?
         };
+        tx.set_spend_types();
         txs.push(tx);
     }
     Ok(txs)
@@ -445,6 +235,7 @@ fn parse_segwit_tx(payload: &mut Payload) -> Result<Transaction, ParsingError> {
         witnesses: witness_loop(payload, len_in)?,
         lock_time: payload.read_u32()?,
         hash: segwit_hash(payload, start, offset_in_out),
+        fee: None,
     })
 }
 
@@ -465,6 +256,7 @@ fn parse_standard_tx(payload: &mut Payload) -> Result<Transaction, ParsingError>
         witnesses: vec!(),
         lock_time: payload.read_u32()?,
         hash: tx_hash(payload, from),
+        fee: None,
     })
 }
 
@@ -479,19 +271,29 @@ fn parse_tx_input(tx_input: &mut Payload) -> Result<TxInput, ParsingError> {
             tx_input.encode_string(script_length)?
         },
         sequence: tx_input.read_u32()?,
+        spend_type: String::new(),
     })
 }
 
 fn parse_tx_output(tx_output: &mut Payload) -> Result<TxOutput, ParsingError> {
+    let value = tx_output.read_i64()?;
+    let script_length = tx_output.get_compact_int()?;
+    let script = tx_output.take(script_length)?;
+    let (script_type, address) = classify_output(script);
     Ok(TxOutput {
-        value: tx_output.read_i64()?,
-        pub_key_script: {
-            let script_length = tx_output.get_compact_int()?;
-            tx_output.encode_string(script_length)?
-        },
+        value,
+        pub_key_script: hex::encode(script),
+        script_type,
+        address,
     })
 }
 
+// Core type name and address of an output script
+pub fn classify_output(script: &[u8]) -> (String, Option<String>) {
+    let output = classify(script);
+    (output.core_name().to_string(), address(&output))
+}
+
 fn parse_witness_item(tx_witness: &mut Payload) -> Result<WitnessItem, ParsingError> {
     let length = tx_witness.get_compact_int()?;
     Ok(WitnessItem {
@@ -514,5 +316,122 @@ pub fn parse_block(payload: &[u8]) -> Result<Block, ParsingError> {
             let tx_count = block.get_compact_int()?;
             tx_loop(&mut block, tx_count)?
         },
+        stats: None,
+        reward_check: None,
+        validation: None,
     })
 }
+
+pub fn parse_transaction(payload: &[u8]) -> Result<Transaction, ParsingError> {
+    Ok(tx_loop(&mut Payload { pl: payload, off: 0 }, 1)?.remove(0))
+}
+
+// Wire serialization, the reverse of the parsing above
+fn write_hex(out: &mut Vec<u8>, field: &str) {
+    out.extend(hex::decode(field).unwrap_or_default());
+}
+
+fn write_var_hex(out: &mut Vec<u8>, field: &str) {
+    let bytes = hex::decode(field).unwrap_or_default();
+    out.extend(to_compact_int(bytes.len() as u64));
+    out.extend(bytes);
+}
+
+impl Block {
+    pub fn header(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(80);
+        out.extend(self.version.to_le_bytes());
+        write_hex(&mut out, &self.prev_hash);
+        write_hex(&mut out, &self.merkle_root);
+        out.extend(self.timestamp.to_le_bytes());
+        out.extend(self.bits.to_le_bytes());
+        out.extend(self.nonce.to_le_bytes());
+        out
+    }
+
+    pub fn serialize(&self, with_witness: bool) -> Vec<u8> {
+        let mut out = self.header();
+        out.extend(to_compact_int(self.txns.len() as u64));
+        for tx in &self.txns {
+            out.extend(tx.serialize(with_witness));
+        }
+        out
+    }
+
+    // Height pushed first in the coinbase script, mandatory from version 2 blocks (BIP34)
+    pub fn bip34_height(&self) -> Option<u32> {
+        if self.version < 2 {
+            return None;
+        }
+        let script = hex::decode(&self.txns.first()?.inputs.first()?.signature_script).ok()?;
+        match parse_one_op(&script, &mut 0) {
+            Ok(Some(ScriptItem::ByteArray(bytes))) if !bytes.is_empty() && bytes.len() <= 4 => {
+                u32::try_from(as_script_nb(&bytes).ok()?).ok()
+            }
+            Ok(Some(ScriptItem::Opcode(op))) if (OP_1.code..=OP_16.code).contains(&op.code) => {
+                Some((op.code - OP_1.code + 1) as u32)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Transaction {
+    // What the input spends, inferred from its scriptSig and witness only
+    pub fn spend(&self, input: usize) -> Spend {
+        if self.is_coinbase() {
+            return Spend::coinbase();
+        }
+        let script_sig = hex::decode(&self.inputs[input].signature_script).unwrap_or_default();
+        infer_spend(&script_sig, &self.witness(input))
+    }
+
+    // Witness items of an input, none when the transaction has no witness
+    pub fn witness(&self, input: usize) -> Vec<Vec<u8>> {
+        self.witnesses.get(input).into_iter().flatten()
+            .map(|item| hex::decode(&item.script).unwrap_or_default())
+            .collect()
+    }
+
+    pub fn set_spend_types(&mut self) {
+        for i in 0..self.inputs.len() {
+            self.inputs[i].spend_type = self.spend(i).name();
+        }
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.inputs.len() == 1 && self.inputs[0].prev_output.hash.bytes().all(|c| c == b'0') && self.inputs[0].prev_output.idx == u32::MAX
+    }
+
+    // Segwit marker, flag and witnesses are only written when asked for and present
+    pub fn serialize(&self, with_witness: bool) -> Vec<u8> {
+        let with_witness = with_witness && self.is_segwit;
+        let mut out = Vec::new();
+        out.extend(self.version.to_le_bytes());
+        if with_witness {
+            out.extend([0x00, 0x01]);
+        }
+        out.extend(to_compact_int(self.inputs.len() as u64));
+        for input in &self.inputs {
+            write_hex(&mut out, &input.prev_output.hash);
+            out.extend(input.prev_output.idx.to_le_bytes());
+            write_var_hex(&mut out, &input.signature_script);
+            out.extend(input.sequence.to_le_bytes());
+        }
+        out.extend(to_compact_int(self.outputs.len() as u64));
+        for output in &self.outputs {
+            out.extend(output.value.to_le_bytes());
+            write_var_hex(&mut out, &output.pub_key_script);
+        }
+        if with_witness {
+            for witness in &self.witnesses {
+                out.extend(to_compact_int(witness.len() as u64));
+                for item in witness {
+                    write_var_hex(&mut out, &item.script);
+                }
+            }
+        }
+        out.extend(self.lock_time.to_le_bytes());
+        out
+    }
+}
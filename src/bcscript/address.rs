@@ -0,0 +1,267 @@
+use bitcoin_hashes::{Hash, sha256d};
+
+use super::standard::OutputType;
+
+// Mainnet prefixes, the crawler only talks to mainnet peers
+const PUBKEY_ADDRESS: u8 = 0x00;
+const SCRIPT_ADDRESS: u8 = 0x05;
+const BECH32_HRP: &str = "bc";
+
+const BASE58_ALPHABET: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+const BECH32_ALPHABET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const BECH32_CONST: u32 = 1;
+const BECH32M_CONST: u32 = 0x2bc830a3;
+
+// Address of an output as shown by Bitcoin Core: none for pubkey, multisig, nulldata and nonstandard
+pub fn address(output: &OutputType) -> Option<String> {
+    match output {
+        OutputType::PubKeyHash(hash) => Some(base58check(PUBKEY_ADDRESS, hash)),
+        OutputType::ScriptHash(hash) => Some(base58check(SCRIPT_ADDRESS, hash)),
+        OutputType::WitnessV0KeyHash(program) | OutputType::WitnessV0ScriptHash(program) => segwit_address(0, program),
+        OutputType::WitnessV1Taproot(program) => segwit_address(1, program),
+        OutputType::WitnessUnknown { version, program } => segwit_address(*version, program),
+        _ => None,
+    }
+}
+
+// Output script paying to a mainnet address, the reverse of `address`
+pub fn address_script(address: &str) -> Option<Vec<u8>> {
+    if address.to_lowercase().starts_with(BECH32_HRP) {
+        let (version, program) = segwit_decode(address)?;
+        // OP_0, or OP_1..OP_16, then the program push
+        let mut script = vec![if version == 0 { 0 } else { 0x50 + version }, program.len() as u8];
+        script.extend(program);
+        return Some(script);
+    }
+    let data = base58_decode(address)?;
+    if data.len() != 25 || sha256d::Hash::hash(&data[..21])[..4] != data[21..] {
+        return None;
+    }
+    match data[0] {
+        PUBKEY_ADDRESS => Some([&[0x76, 0xa9, 20][..], &data[1..21], &[0x88, 0xac]].concat()),
+        SCRIPT_ADDRESS => Some([&[0xa9, 20][..], &data[1..21], &[0x87]].concat()),
+        _ => None,
+    }
+}
+
+pub fn base58check(version: u8, payload: &[u8]) -> String {
+    let mut data = Vec::with_capacity(payload.len() + 5);
+    data.push(version);
+    data.extend_from_slice(payload);
+    let checksum = sha256d::Hash::hash(&data);
+    data.extend_from_slice(&checksum[..4]);
+    base58(&data)
+}
+
+fn base58(data: &[u8]) -> String {
+    // Big number division by 58, digits are kept least significant first
+    let mut digits: Vec<u8> = Vec::new();
+    for byte in data {
+        let mut carry = *byte as u32;
+        for digit in digits.iter_mut() {
+            carry += (*digit as u32) << 8;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+    // Each leading zero byte is written as a '1'
+    let zeros = data.iter().take_while(|b| **b == 0).count();
+    std::iter::repeat_n(BASE58_ALPHABET[0], zeros)
+        .chain(digits.iter().rev().map(|d| BASE58_ALPHABET[*d as usize]))
+        .map(char::from)
+        .collect()
+}
+
+fn base58_decode(text: &str) -> Option<Vec<u8>> {
+    // Big number multiplication by 58, bytes are kept least significant first
+    let mut bytes: Vec<u8> = Vec::new();
+    for c in text.bytes() {
+        let mut carry = BASE58_ALPHABET.iter().position(|a| *a == c)? as u32;
+        for byte in bytes.iter_mut() {
+            carry += (*byte as u32) * 58;
+            *byte = carry as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.push(carry as u8);
+            carry >>= 8;
+        }
+    }
+    let zeros = text.bytes().take_while(|c| *c == BASE58_ALPHABET[0]).count();
+    Some(std::iter::repeat_n(0, zeros).chain(bytes.into_iter().rev()).collect())
+}
+
+// BIP173 for version 0, BIP350 (bech32m) for later versions
+pub fn segwit_address(version: u8, program: &[u8]) -> Option<String> {
+    if version > 16 || program.len() < 2 || program.len() > 40 {
+        return None;
+    }
+    let mut data = vec![version];
+    data.extend(convert_bits(program));
+    let constant = match version {
+        0 => BECH32_CONST,
+        _ => BECH32M_CONST,
+    };
+    Some(bech32_encode(BECH32_HRP, &data, constant))
+}
+
+// 8 bits groups to 5 bits groups, the last one padded with zeros
+fn convert_bits(data: &[u8]) -> Vec<u8> {
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    let mut out = Vec::with_capacity(data.len() * 8 / 5 + 1);
+    for byte in data {
+        acc = (acc << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(((acc >> bits) & 0x1f) as u8);
+        }
+    }
+    if bits > 0 {
+        out.push(((acc << (5 - bits)) & 0x1f) as u8);
+    }
+    out
+}
+
+// Checksum, constant matching the version and lengths as required by BIP173 and BIP350
+fn segwit_decode(address: &str) -> Option<(u8, Vec<u8>)> {
+    if address.to_lowercase() != address && address.to_uppercase() != address {
+        return None;
+    }
+    let address = address.to_lowercase();
+    let (hrp, data) = address.rsplit_once('1')?;
+    if hrp != BECH32_HRP || data.len() < 7 || address.len() > 90 {
+        return None;
+    }
+    let data = data.bytes()
+        .map(|c| BECH32_ALPHABET.iter().position(|a| *a == c).map(|value| value as u8))
+        .collect::<Option<Vec<u8>>>()?;
+    let mut values: Vec<u8> = hrp.bytes().map(|c| c >> 5).collect();
+    values.push(0);
+    values.extend(hrp.bytes().map(|c| c & 0x1f));
+    values.extend_from_slice(&data);
+    let version = data[0];
+    let constant = match version {
+        0 => BECH32_CONST,
+        _ => BECH32M_CONST,
+    };
+    if polymod(&values) != constant || version > 16 {
+        return None;
+    }
+
+    // 5 bits groups back to bytes, the padding must be short and zero
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    let mut program = Vec::new();
+    for value in &data[1..data.len() - 6] {
+        acc = (acc << 5) | *value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            program.push((acc >> bits) as u8);
+        }
+    }
+    if bits >= 5 || acc & ((1 << bits) - 1) != 0 {
+        return None;
+    }
+    match (version, program.len()) {
+        (0, 20) | (0, 32) => Some((version, program)),
+        (0, _) => None,
+        (_, 2..=40) => Some((version, program)),
+        _ => None,
+    }
+}
+
+fn polymod(values: &[u8]) -> u32 {
+    const GENERATOR: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+    let mut chk: u32 = 1;
+    for value in values {
+        let top = chk >> 25;
+        chk = ((chk & 0x1ffffff) << 5) ^ *value as u32;
+        for (i, generator) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                chk ^= generator;
+            }
+        }
+    }
+    chk
+}
+
+fn bech32_encode(hrp: &str, data: &[u8], constant: u32) -> String {
+    let mut values: Vec<u8> = hrp.bytes().map(|c| c >> 5).collect();
+    values.push(0);
+    values.extend(hrp.bytes().map(|c| c & 0x1f));
+    values.extend_from_slice(data);
+    values.extend([0; 6]);
+    let checksum = polymod(&values) ^ constant;
+
+    let mut address = format!("{}1", hrp);
+    for value in data.iter().copied().chain((0..6).map(|i| ((checksum >> (5 * (5 - i))) & 0x1f) as u8)) {
+        address.push(BECH32_ALPHABET[value as usize] as char);
+    }
+    address
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bcscript::standard::classify;
+
+    // Mainnet valid vectors of BIP173 and BIP350, with the scriptPubKey they pay to
+    const SEGWIT_VECTORS: [(&str, &str); 6] = [
+        ("BC1QW508D6QEJXTDG4Y5R3ZARVARY0C5XW7KV8F3T4", "0014751e76e8199196d454941c45d1b3a323f1433bd6"),
+        ("bc1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3qccfmv3", "00201863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262"),
+        ("bc1pw508d6qejxtdg4y5r3zarvary0c5xw7kw508d6qejxtdg4y5r3zarvary0c5xw7kt5nd6y", "5128751e76e8199196d454941c45d1b3a323f1433bd6751e76e8199196d454941c45d1b3a323f1433bd6"),
+        ("BC1SW50QGDZ25J", "6002751e"),
+        ("bc1zw508d6qejxtdg4y5r3zarvaryvaxxpcs", "5210751e76e8199196d454941c45d1b3a323"),
+        ("bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqzk5jj0", "512079be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"),
+    ];
+
+    #[test]
+    fn segwit_vectors() {
+        for (address_text, script) in SEGWIT_VECTORS {
+            let script = hex::decode(script).unwrap();
+            assert_eq!(address_script(address_text), Some(script.clone()), "{}", address_text);
+            // Encoded back in lower case, through the output type for the standard ones
+            assert_eq!(segwit_address(script[0].saturating_sub(0x50), &script[2..]).as_deref(), Some(address_text.to_lowercase().as_str()));
+            assert_eq!(address(&classify(&script)).as_deref(), Some(address_text.to_lowercase().as_str()));
+        }
+    }
+
+    #[test]
+    fn segwit_invalid() {
+        let program = hex::decode("751e76e8199196d454941c45d1b3a323f1433bd6").unwrap();
+        let mut data = vec![0];
+        data.extend(convert_bits(&program));
+        // Version 0 with the bech32m checksum, version 1 with the bech32 one
+        assert_eq!(address_script(&bech32_encode(BECH32_HRP, &data, BECH32M_CONST)), None);
+        data[0] = 1;
+        assert_eq!(address_script(&bech32_encode(BECH32_HRP, &data, BECH32_CONST)), None);
+        // Mixed case, a wrong checksum, a version 0 program of 16 bytes, another network
+        assert_eq!(address_script("bc1qW508D6QEJXTDG4Y5R3ZARVARY0C5XW7KV8F3T4"), None);
+        assert_eq!(address_script("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t5"), None);
+        assert_eq!(segwit_decode(&segwit_address(0, &program[..16]).unwrap()), None);
+        assert_eq!(address_script("tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx"), None);
+    }
+
+    #[test]
+    fn base58_round_trips() {
+        // The key hash of the genesis coinbase, and a P2SH script hash
+        let p2pkh = hex::decode("76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac").unwrap();
+        assert_eq!(address(&classify(&p2pkh)).as_deref(), Some("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa"));
+        assert_eq!(address_script("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa"), Some(p2pkh));
+        let p2sh = hex::decode("a914b472a266d0bd89c13706a4132ccfb16f7c3b9fcb87").unwrap();
+        assert_eq!(address(&classify(&p2sh)).as_deref(), Some("3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy"));
+        assert_eq!(address_script("3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy"), Some(p2sh));
+
+        // Leading zero bytes are kept as 1s, a wrong checksum is refused
+        assert_eq!(base58check(PUBKEY_ADDRESS, &[0; 20]), "1111111111111111111114oLvT2");
+        assert_eq!(address_script("1111111111111111111114oLvT2"), Some([&[0x76, 0xa9, 20][..], &[0; 20], &[0x88, 0xac]].concat()));
+        assert_eq!(address_script("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNb"), None);
+    }
+}
@@ -0,0 +1,133 @@
+use crate::bcutxo::is_unspendable;
+
+use super::flags::VerifyFlags;
+use super::signature::check_signature_encoding;
+use super::opcodes::*;
+use super::parse::parse_one_op;
+use super::script::{as_script_nb, ScriptItem};
+use super::spend::sighash_name;
+
+// Opcode names as printed by Bitcoin Core (GetOpName)
+pub fn op_name(op: Opcode) -> String {
+    match op {
+        OP_0 => String::from("0"),
+        OP_1NEGATE => String::from("-1"),
+        Opcode { code: c } if (OP_1.code..=OP_16.code).contains(&c) => format!("{}", c - OP_1.code + 1),
+        OP_PUSH_DATA_1 => String::from("OP_PUSHDATA1"),
+        OP_PUSH_DATA_2 => String::from("OP_PUSHDATA2"),
+        OP_PUSH_DATA_4 => String::from("OP_PUSHDATA4"),
+        OP_UNASSIGNED_255 => String::from("OP_INVALIDOPCODE"),
+        Opcode { code: c } if c > OP_CHECKSIGADD.code => String::from("OP_UNKNOWN"),
+        _ => format!("{}", op),
+    }
+}
+
+// Script disassembly in Bitcoin Core format (ScriptToAsmStr): pushes of at most 4 bytes are shown
+// as numbers, longer ones as hex, and "[error]" ends a script whose last push is truncated.
+// With decode_sighash, as for scriptSigs, strictly encoded signatures of a spendable script end
+// with their hash type ([ALL], [NONE|ANYONECANPAY]...) in place of the last byte.
+pub fn to_asm(script: &[u8], decode_sighash: bool) -> String {
+    let decode_sighash = decode_sighash && !is_unspendable(script);
+    let mut items = Vec::new();
+    let mut pc = 0;
+    loop {
+        match parse_one_op(script, &mut pc) {
+            Ok(Some(ScriptItem::ByteArray(data))) => items.push(push_to_asm(&data, decode_sighash)),
+            Ok(Some(ScriptItem::Opcode(op))) => items.push(op_name(op)),
+            Ok(None) => break,
+            Err(_) => {
+                items.push(String::from("[error]"));
+                break;
+            }
+        }
+    }
+    items.join(" ")
+}
+
+fn push_to_asm(data: &[u8], decode_sighash: bool) -> String {
+    if let Ok(n) = as_script_nb(data) {
+        return format!("{}", n);
+    }
+    if decode_sighash && check_signature_encoding(data, VerifyFlags::STRICTENC).is_ok() {
+        if let Some((hash_type, der)) = data.split_last() {
+            if let Some(name) = sighash_name(*hash_type) {
+                return format!("{}[{}]", hex::encode(der), name);
+            }
+        }
+    }
+    hex::encode(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bcparse::parse_transaction;
+
+    use super::*;
+    use super::super::assemble::{assemble, Encoding};
+    use super::super::mainnet::*;
+    use super::super::script::push_data;
+
+    // First push of the scriptSig of the mainnet P2PKH spend: a DER signature followed by SIGHASH_ALL
+    fn signature() -> Vec<u8> {
+        let script = hex::decode(&parse_transaction(&hex::decode(P2PKH_TX).unwrap()).unwrap().inputs[0].signature_script).unwrap();
+        script[1..1 + script[0] as usize].to_vec()
+    }
+
+    #[test]
+    fn truncated_push() {
+        assert_eq!(to_asm(&[0x4c], false), "[error]");
+        assert_eq!(to_asm(&[0x02, 0x01], false), "[error]");
+        assert_eq!(to_asm(&[0x76, 0x4d, 0x01], false), "OP_DUP [error]");
+        assert_eq!(to_asm(&[0x51, 0x4e, 0x02, 0x00, 0x00, 0x00, 0xaa], false), "1 [error]");
+    }
+
+    #[test]
+    fn sighash_types() {
+        let mut signature = signature();
+        let der = hex::encode(&signature[..signature.len() - 1]);
+        assert_eq!(signature.last(), Some(&0x01));
+        assert_eq!(to_asm(&push_data(&signature), true), format!("{}[ALL]", der));
+        assert_eq!(to_asm(&push_data(&signature), false), hex::encode(&signature));
+
+        *signature.last_mut().unwrap() = 0x83;
+        assert_eq!(to_asm(&push_data(&signature), true), format!("{}[SINGLE|ANYONECANPAY]", der));
+        // Undefined hash types are not decoded
+        *signature.last_mut().unwrap() = 0x04;
+        assert_eq!(to_asm(&push_data(&signature), true), hex::encode(&signature));
+
+        // Nor anything in an OP_RETURN script
+        *signature.last_mut().unwrap() = 0x01;
+        let script = [&[0x6a][..], &push_data(&signature)].concat();
+        assert_eq!(to_asm(&script, true), format!("OP_RETURN {}", hex::encode(&signature)));
+    }
+
+    #[test]
+    fn numbers_and_names() {
+        assert_eq!(to_asm(&[0x00, 0x4f, 0x51, 0x60, 0x01, 0x11, 0x02, 0xe8, 0x03], false), "0 -1 1 16 17 1000");
+        // Negative zero and non minimal numbers are at most 4 bytes: still numbers
+        assert_eq!(to_asm(&[0x01, 0x80, 0x02, 0x01, 0x00], false), "0 1");
+        assert_eq!(to_asm(&push_data(&[1, 2, 3, 4, 5]), false), "0102030405");
+        assert_eq!(
+            to_asm(&[0x4c, 0x00, 0xb1, 0xb2, 0xba, 0xbb, 0xff], false),
+            "0 OP_CHECKLOCKTIMEVERIFY OP_CHECKSEQUENCEVERIFY OP_CHECKSIGADD OP_UNKNOWN OP_INVALIDOPCODE",
+        );
+    }
+
+    #[test]
+    fn round_trips() {
+        let tx = parse_transaction(&hex::decode(MULTISIG_TX).unwrap()).unwrap();
+        let p2wsh = parse_transaction(&hex::decode(P2WSH_TX).unwrap()).unwrap();
+        let scripts = [
+            hex::decode(P2PKH_SCRIPT).unwrap(),
+            hex::decode(MULTISIG_SCRIPT).unwrap(),
+            hex::decode(P2WSH_SCRIPT).unwrap(),
+            hex::decode(&tx.inputs[0].signature_script).unwrap(),
+            p2wsh.witness(0).pop().unwrap(),
+            [&[0x6a][..], &push_data(&[0x42; 80])].concat(),
+        ];
+        for script in &scripts {
+            let asm = to_asm(script, false);
+            assert_eq!(&assemble(&asm, Encoding::Exact).unwrap(), script, "{}", asm);
+        }
+    }
+}
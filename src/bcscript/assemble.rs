@@ -0,0 +1,209 @@
+use std::fmt;
+
+use super::opcodes::*;
+use super::script::{push_data, to_script_nb};
+
+// How data is pushed: Minimal as MINIMALDATA requires (OP_0, OP_1 to OP_16 and OP_1NEGATE for the
+// values they push, then the shortest push), Exact as it is written, explicit OP_PUSHDATAs kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Minimal,
+    Exact,
+}
+
+#[derive(Debug)]
+pub enum AsmError {
+    UnknownWord(String),
+    BadHex(String),
+    NumberOutOfRange(String),
+    // OP_PUSHDATA1/2/4 without data after it, or with more than it can push
+    BadPushData(String),
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AsmError::UnknownWord(word) => write!(f, "unknown opcode or bad data {}", word),
+            AsmError::BadHex(word) => write!(f, "bad hex {}", word),
+            AsmError::NumberOutOfRange(word) => write!(f, "number out of range {}", word),
+            AsmError::BadPushData(word) => write!(f, "bad data for {}", word),
+        }
+    }
+}
+
+// Opcode from its name, with or without OP_: the names printed by Opcode's Display and the ones of
+// Core (OP_FALSE, OP_TRUE, OP_PUSHDATA1, OP_NOP2, OP_NOP3, OP_INVALIDOPCODE)
+pub fn opcode_by_name(name: &str) -> Option<Opcode> {
+    let name = name.strip_prefix("OP_").unwrap_or(name);
+    let alias = match name {
+        "FALSE" => Some(OP_0),
+        "TRUE" => Some(OP_1),
+        "PUSHDATA1" => Some(OP_PUSH_DATA_1),
+        "PUSHDATA2" => Some(OP_PUSH_DATA_2),
+        "PUSHDATA4" => Some(OP_PUSH_DATA_4),
+        "NOP2" => Some(OP_CHECKLOCKTIMEVERIFY),
+        "NOP3" => Some(OP_CHECKSEQUENCEVERIFY),
+        "INVALIDOPCODE" => Some(OP_UNASSIGNED_255),
+        _ => None,
+    };
+    alias.or_else(|| (0..=255).map(Opcode::from).find(|opcode| opcode.to_string()[3..] == *name))
+}
+
+fn minimal_push(data: &[u8]) -> Vec<u8> {
+    match data {
+        [] => vec![OP_0.code],
+        [n @ 1..=16] => vec![OP_1.code + n - 1],
+        [0x81] => vec![OP_1NEGATE.code],
+        _ => push_data(data),
+    }
+}
+
+fn push(data: &[u8], encoding: Encoding) -> Vec<u8> {
+    match encoding {
+        Encoding::Minimal => minimal_push(data),
+        Encoding::Exact => push_data(data),
+    }
+}
+
+// Decimal numbers as Core's ParseScript reads them, in [-0xffffffff, 0xffffffff]. decodescript
+// prints pushes of more than 4 bytes in hex, which can be all digits: words with leading zeros or
+// out of range ones of even length are hex data.
+fn parse_number(word: &str) -> Option<Result<i64, AsmError>> {
+    let digits = word.strip_prefix('-').unwrap_or(word);
+    if digits.is_empty() || !digits.bytes().all(|c| c.is_ascii_digit()) || (digits.len() > 1 && digits.starts_with('0')) {
+        return None;
+    }
+    match word.parse::<i64>() {
+        Ok(n) if (-0xffffffff..=0xffffffff).contains(&n) => Some(Ok(n)),
+        _ if word.len().is_multiple_of(2) && digits == word => None,
+        _ => Some(Err(AsmError::NumberOutOfRange(word.to_string()))),
+    }
+}
+
+// Data of a push: hex as printed by decodescript, or 'text'
+fn parse_data(word: &str) -> Option<Vec<u8>> {
+    if word.len() >= 2 && word.starts_with('\'') && word.ends_with('\'') {
+        return Some(word.as_bytes()[1..word.len() - 1].to_vec());
+    }
+    hex::decode(word).ok()
+}
+
+// Script from its ASM, in the notation of decodescript (OP_DUP OP_HASH160 <hex> OP_EQUALVERIFY) or
+// of Core's test vectors (DUP HASH160 0x14 0x<hex> EQUALVERIFY):
+//   12, -1        number, pushed as by Core's `CScript() << n`
+//   0x<hex>       bytes copied as they are, to write any encoding
+//   <hex>, 'text' data push
+//   OP_PUSHDATA1 <hex or 'text'>, and 2 and 4: push of the data with this opcode
+//   opcode names, with or without OP_
+pub fn assemble(asm: &str, encoding: Encoding) -> Result<Vec<u8>, AsmError> {
+    let mut script = Vec::new();
+    let mut words = asm.split_whitespace();
+    while let Some(word) = words.next() {
+        if let Some(n) = parse_number(word) {
+            match n? {
+                -1 => script.push(OP_1NEGATE.code),
+                0 => script.push(OP_0.code),
+                n @ 1..=16 => script.push(OP_1.code + n as u8 - 1),
+                n => script.extend(push_data(&to_script_nb(n))),
+            }
+        } else if let Some(bytes) = word.strip_prefix("0x") {
+            match hex::decode(bytes) {
+                Ok(bytes) if !bytes.is_empty() => script.extend(bytes),
+                _ => return Err(AsmError::BadHex(word.to_string())),
+            }
+        } else if let Some(opcode) = opcode_by_name(word) {
+            let size_len = match opcode {
+                OP_PUSH_DATA_1 => 1,
+                OP_PUSH_DATA_2 => 2,
+                OP_PUSH_DATA_4 => 4,
+                _ => {
+                    script.push(opcode.code);
+                    continue;
+                }
+            };
+            let data = words.next().and_then(parse_data)
+                .filter(|data| size_len == 4 || data.len() < 1 << (8 * size_len))
+                .ok_or_else(|| AsmError::BadPushData(word.to_string()))?;
+            match encoding {
+                Encoding::Minimal => script.extend(minimal_push(&data)),
+                Encoding::Exact => {
+                    script.push(opcode.code);
+                    script.extend(&(data.len() as u32).to_le_bytes()[..size_len]);
+                    script.extend(data);
+                }
+            }
+        } else if let Some(data) = parse_data(word) {
+            script.extend(push(&data, encoding));
+        } else {
+            return Err(AsmError::UnknownWord(word.to_string()));
+        }
+    }
+    Ok(script)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exact(asm: &str) -> String {
+        hex::encode(assemble(asm, Encoding::Exact).unwrap())
+    }
+
+    fn minimal(asm: &str) -> String {
+        hex::encode(assemble(asm, Encoding::Minimal).unwrap())
+    }
+
+    #[test]
+    fn push_data_opcodes() {
+        assert_eq!(exact("OP_PUSHDATA1 0102"), "4c020102");
+        assert_eq!(exact("OP_PUSHDATA2 0102"), "4d02000102");
+        assert_eq!(exact("OP_PUSHDATA4 0102"), "4e020000000102");
+        assert_eq!(exact("PUSHDATA1 'ab'"), "4c026162");
+        assert_eq!(exact("OP_PUSHDATA1 ''"), "4c00");
+        assert_eq!(minimal("OP_PUSHDATA1 0102"), "020102");
+        assert_eq!(minimal("OP_PUSHDATA2 05"), "55");
+        assert_eq!(minimal("OP_PUSHDATA4 ''"), "00");
+
+        // Pushes longer than 75 bytes, written or not with their opcode
+        let data = hex::encode([7; 300]);
+        assert_eq!(exact(&hex::encode([7; 76])), format!("4c4c{}", hex::encode([7; 76])));
+        assert_eq!(exact(&data), format!("4d2c01{}", data));
+        assert_eq!(exact(&format!("OP_PUSHDATA4 {}", data)), format!("4e2c010000{}", data));
+        assert!(matches!(assemble(&format!("OP_PUSHDATA1 {}", data), Encoding::Exact), Err(AsmError::BadPushData(_))));
+        assert!(matches!(assemble("OP_PUSHDATA1", Encoding::Exact), Err(AsmError::BadPushData(_))));
+        assert!(matches!(assemble("OP_PUSHDATA2 OP_DUP", Encoding::Minimal), Err(AsmError::BadPushData(_))));
+    }
+
+    #[test]
+    fn numbers_and_hex() {
+        assert_eq!(exact("0 -1 1 16"), "004f5160");
+        assert_eq!(exact("17 -2 1000"), "0111018202e803");
+        assert_eq!(exact("4294967295 -4294967295"), "05ffffffff0005ffffffff80");
+        // Leading zeros or too large for a number, of even length: data
+        assert_eq!(exact("01"), "0101");
+        assert_eq!(minimal("01"), "51");
+        assert_eq!(exact("0010"), "020010");
+        assert_eq!(exact("4294967296"), "054294967296");
+        assert!(matches!(assemble("42949672960", Encoding::Exact), Err(AsmError::NumberOutOfRange(_))));
+        assert!(matches!(assemble("-4294967296", Encoding::Exact), Err(AsmError::NumberOutOfRange(_))));
+        assert_eq!(exact("81"), "0151");
+        // Single bytes 1 to 16 and 0x81 are minimally pushed by their opcode, not 0
+        assert_eq!(minimal("OP_PUSHDATA1 81 OP_PUSHDATA1 10 OP_PUSHDATA1 00 OP_PUSHDATA1 11"), "4f6001000111");
+    }
+
+    #[test]
+    fn core_notation() {
+        let hash = hex::encode([0x11; 20]);
+        assert_eq!(
+            exact(&format!("DUP HASH160 0x14 0x{} EQUALVERIFY CHECKSIG", hash)),
+            format!("76a914{}88ac", hash),
+        );
+        assert_eq!(exact("OP_DUP OP_HASH160 OP_EQUALVERIFY"), exact("DUP HASH160 EQUALVERIFY"));
+        assert_eq!(exact("FALSE TRUE NOP2 NOP3 INVALIDOPCODE"), "0051b1b2ff");
+        assert_eq!(exact("0x4c 0x01 0x07"), "4c0107");
+        assert!(matches!(assemble("0x", Encoding::Exact), Err(AsmError::BadHex(_))));
+        assert!(matches!(assemble("0x0g", Encoding::Exact), Err(AsmError::BadHex(_))));
+        assert!(matches!(assemble("OP_FOO", Encoding::Exact), Err(AsmError::UnknownWord(_))));
+        assert!(matches!(assemble("abc", Encoding::Exact), Err(AsmError::UnknownWord(_))));
+    }
+}
@@ -0,0 +1,361 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use bitcoin_hashes::{Hash, sha256d};
+use serde_json::Value;
+
+use crate::bcparse::{parse_transaction, OutPoint, Transaction, TxInput, TxOutput, WitnessItem};
+use crate::bcutils::reverse_hash;
+use crate::bcvalidate::check_transaction;
+
+use super::assemble::{assemble, Encoding};
+use super::flags::VerifyFlags;
+use super::interpret::verify;
+use super::opcodes::OP_0;
+use super::script::{ScriptError, SEQUENCE_FINAL};
+use super::sighash::TxContext;
+
+// Bitcoin Core's script and transaction test vectors (src/test/data), in their own format:
+//   script_tests.json  [[witness items..., amount]?, scriptSig, scriptPubKey, flags, expected error, comment?]
+//   tx_valid.json      [[[prevout txid, vout, scriptPubKey, amount?], ...], tx, flags not applied]
+//   tx_invalid.json    [[[prevout txid, vout, scriptPubKey, amount?], ...], tx, flags, or BADTX]
+// Entries made of a single string are comments. Vectors are named <file>:<index in the file>, the
+// ones listed in known_failures.txt are expected to fail until the interpreter is fixed.
+// Core's files go unmodified under test_data/core (upstream commit in its UPSTREAM file), the
+// vectors written for bc-crawl in the same format under test_data/bc-crawl.
+pub const FIXTURES_DIR: &str = "./test_data/core";
+const OWN_FIXTURES_DIR: &str = "./test_data/bc-crawl";
+const FILES: [&str; 3] = ["script_tests.json", "tx_valid.json", "tx_invalid.json"];
+const KNOWN_FAILURES: &str = "known_failures.txt";
+
+enum Outcome {
+    Pass,
+    Fail(String),
+}
+
+// Core's ParseScript notation, a subset of the assembler's
+fn parse_asm(asm: &str) -> Result<Vec<u8>, String> {
+    assemble(asm, Encoding::Exact).map_err(|e| e.to_string())
+}
+
+// Names used by Core's vectors, from FormatScriptError. Script number errors are exceptions there.
+fn error_name(error: &ScriptError) -> &'static str {
+    match error {
+        ScriptError::InvalidStackOperation => "INVALID_STACK_OPERATION",
+        ScriptError::InvalidAltStackOperation => "INVALID_ALTSTACK_OPERATION",
+        ScriptError::ScriptNumberOverflow | ScriptError::ScriptNumberNotMinimal => "UNKNOWN_ERROR",
+        ScriptError::StackOverflow => "STACK_SIZE",
+        ScriptError::ScriptSize => "SCRIPT_SIZE",
+        ScriptError::PushSize => "PUSH_SIZE",
+        ScriptError::EqualVerify => "EQUALVERIFY",
+        ScriptError::NumEqualVerify => "NUMEQUALVERIFY",
+        ScriptError::UnbalancedConditional => "UNBALANCED_CONDITIONAL",
+        ScriptError::DisabledOpcode => "DISABLED_OPCODE",
+        ScriptError::BadOpcode => "BAD_OPCODE",
+        ScriptError::Verify => "VERIFY",
+        ScriptError::OpReturn => "OP_RETURN",
+        ScriptError::OpCount => "OP_COUNT",
+        ScriptError::CheckSigVerify => "CHECKSIGVERIFY",
+        ScriptError::CheckMultiSigVerify => "CHECKMULTISIGVERIFY",
+        ScriptError::PubKeyCount => "PUBKEY_COUNT",
+        ScriptError::SigCount => "SIG_COUNT",
+        ScriptError::SigNullDummy => "SIG_NULLDUMMY",
+        ScriptError::SigDer => "SIG_DER",
+        ScriptError::SigHighS => "SIG_HIGH_S",
+        ScriptError::SigHashType => "SIG_HASHTYPE",
+        ScriptError::SigNullFail => "NULLFAIL",
+        ScriptError::SigFindAndDelete => "SIG_FINDANDDELETE",
+        ScriptError::MinimalData => "MINIMALDATA",
+        ScriptError::MinimalIf => "MINIMALIF",
+        ScriptError::NegativeLockTime => "NEGATIVE_LOCKTIME",
+        ScriptError::UnsatisfiedLockTime => "UNSATISFIED_LOCKTIME",
+        ScriptError::OpCodeSeparator => "OP_CODESEPARATOR",
+        ScriptError::DiscourageUpgradableNops => "DISCOURAGE_UPGRADABLE_NOPS",
+        ScriptError::DiscourageUpgradableWitnessProgram => "DISCOURAGE_UPGRADABLE_WITNESS_PROGRAM",
+        ScriptError::DiscourageUpgradableTaprootVersion => "DISCOURAGE_UPGRADABLE_TAPROOT_VERSION",
+        ScriptError::DiscourageOpSuccess => "DISCOURAGE_OP_SUCCESS",
+        ScriptError::DiscourageUpgradablePubKeyType => "DISCOURAGE_UPGRADABLE_PUBKEYTYPE",
+        ScriptError::EvalFalse => "EVAL_FALSE",
+        ScriptError::CleanStack => "CLEANSTACK",
+        ScriptError::WitnessProgramWrongLength => "WITNESS_PROGRAM_WRONG_LENGTH",
+        ScriptError::WitnessProgramWitnessEmpty => "WITNESS_PROGRAM_WITNESS_EMPTY",
+        ScriptError::WitnessProgramMismatch => "WITNESS_PROGRAM_MISMATCH",
+        ScriptError::WitnessMalleated => "WITNESS_MALLEATED",
+        ScriptError::WitnessMalleatedP2SH => "WITNESS_MALLEATED_P2SH",
+        ScriptError::WitnessUnexpected => "WITNESS_UNEXPECTED",
+        ScriptError::SigPushOnly => "SIG_PUSHONLY",
+        ScriptError::PubKeyType => "PUBKEYTYPE",
+        ScriptError::WitnessPubKeyType => "WITNESS_PUBKEYTYPE",
+        ScriptError::SchnorrSig => "SCHNORR_SIG",
+        ScriptError::SchnorrSigSize => "SCHNORR_SIG_SIZE",
+        ScriptError::SchnorrSigHashType => "SCHNORR_SIG_HASHTYPE",
+        ScriptError::TaprootWrongControlSize => "TAPROOT_WRONG_CONTROL_SIZE",
+        ScriptError::TapscriptValidationWeight => "TAPSCRIPT_VALIDATION_WEIGHT",
+        ScriptError::TapscriptCheckMultiSig => "TAPSCRIPT_CHECKMULTISIG",
+        ScriptError::TapscriptMinimalIf => "TAPSCRIPT_MINIMALIF",
+    }
+}
+
+fn to_satoshis(btc: &Value) -> Option<i64> {
+    Some((btc.as_f64()? * 100_000_000.0).round() as i64)
+}
+
+fn txid(tx: &Transaction) -> String {
+    hex::encode(sha256d::Hash::hash(&tx.serialize(false)))
+}
+
+fn input(prev_hash: String, prev_idx: u32, script_sig: &[u8]) -> TxInput {
+    TxInput {
+        prev_output: OutPoint { hash: prev_hash, idx: prev_idx },
+        signature_script: hex::encode(script_sig),
+        sequence: SEQUENCE_FINAL,
+        spend_type: String::new(),
+    }
+}
+
+fn output(value: i64, script: &[u8]) -> TxOutput {
+    TxOutput { value, pub_key_script: hex::encode(script), ..Default::default() }
+}
+
+// As in Core, the scriptPubKey is funded by a coinbase like transaction and spent by a one input,
+// one output transaction of version 1 that the signatures commit to
+pub fn spending_tx(script_sig: &[u8], script_pub_key: &[u8], witness: &[Vec<u8>], amount: i64) -> Transaction {
+    let credit = Transaction {
+        hash: String::new(),
+        version: 1,
+        is_segwit: false,
+        inputs: vec![input("00".repeat(32), u32::MAX, &[OP_0.code, OP_0.code])],
+        outputs: vec![output(amount, script_pub_key)],
+        witnesses: Vec::new(),
+        lock_time: 0,
+        fee: None,
+    };
+    Transaction {
+        hash: String::new(),
+        version: 1,
+        is_segwit: !witness.is_empty(),
+        inputs: vec![input(txid(&credit), 0, script_sig)],
+        outputs: vec![output(amount, &[])],
+        witnesses: vec![witness.iter().map(|item| WitnessItem { script: hex::encode(item) }).collect()],
+        lock_time: 0,
+        fee: None,
+    }
+}
+
+fn script_test(vector: &[Value]) -> Result<Outcome, String> {
+    let (witness, amount, fields) = match vector {
+        [Value::Array(witness), fields @ ..] => {
+            let (amount, items) = witness.split_last().ok_or("empty witness")?;
+            let items = items.iter()
+                .map(|item| item.as_str().and_then(|item| hex::decode(item).ok()).ok_or("bad witness item"))
+                .collect::<Result<Vec<Vec<u8>>, &str>>()?;
+            (items, to_satoshis(amount).ok_or("bad amount")?, fields)
+        }
+        fields => (Vec::new(), 0, fields),
+    };
+    let field = |i: usize| fields.get(i).and_then(Value::as_str).ok_or(format!("missing field {}", i));
+    let script_sig = parse_asm(field(0)?)?;
+    let script_pub_key = parse_asm(field(1)?)?;
+    let flags = VerifyFlags::from_names(field(2)?).ok_or(format!("unknown flag in {}", field(2)?))?;
+    let expected = field(3)?;
+
+    let spend = spending_tx(&script_sig, &script_pub_key, &witness, amount);
+    let spent_outputs = [output(amount, &script_pub_key)];
+    let ctx = TxContext { tx: &spend, input_idx: 0, amount, spent_outputs: &spent_outputs };
+    let result = match verify(&script_sig, &script_pub_key, &witness, &ctx, flags) {
+        Ok(()) => "OK",
+        Err(e) => error_name(&e.error),
+    };
+    Ok(match result == expected {
+        true => Outcome::Pass,
+        false => Outcome::Fail(format!("got {}, expected {}", result, expected)),
+    })
+}
+
+// Runs every input against the scriptPubKey and amount of the output it spends
+fn check_tx_scripts(tx: &Transaction, prevouts: &HashMap<(String, u32), TxOutput>, flags: VerifyFlags) -> Result<(), String> {
+    let spent_outputs = tx.inputs.iter()
+        .map(|input| {
+            prevouts.get(&(input.prev_output.hash.clone(), input.prev_output.idx))
+                .map(|prevout| output(prevout.value, &hex::decode(&prevout.pub_key_script).unwrap_or_default()))
+                .ok_or(format!("missing prevout {}:{}", reverse_hash(&input.prev_output.hash), input.prev_output.idx))
+        })
+        .collect::<Result<Vec<TxOutput>, String>>()?;
+    for (input_idx, input) in tx.inputs.iter().enumerate() {
+        let script_sig = hex::decode(&input.signature_script).unwrap_or_default();
+        let script_pub_key = hex::decode(&spent_outputs[input_idx].pub_key_script).unwrap_or_default();
+        let witness: Vec<Vec<u8>> = tx.witnesses.get(input_idx).into_iter().flatten()
+            .map(|item| hex::decode(&item.script).unwrap_or_default())
+            .collect();
+        let ctx = TxContext { tx, input_idx, amount: spent_outputs[input_idx].value, spent_outputs: &spent_outputs };
+        verify(&script_sig, &script_pub_key, &witness, &ctx, flags)
+            .map_err(|e| format!("input {}: {} in {:?}", input_idx, error_name(&e.error), e.phase))?;
+    }
+    Ok(())
+}
+
+// Valid vectors list the flags to leave out, invalid ones the flags to apply
+fn tx_test(vector: &[Value], expect_valid: bool) -> Result<Outcome, String> {
+    let (prevouts, tx, flags) = match vector {
+        [Value::Array(prevouts), Value::String(tx), Value::String(flags)] => (prevouts, tx, flags),
+        _ => return Err(String::from("bad vector")),
+    };
+    let mut outputs = HashMap::new();
+    for prevout in prevouts {
+        let (hash, idx, script, amount) = match prevout.as_array().map(Vec::as_slice) {
+            Some([hash, idx, script]) => (hash, idx, script, 0),
+            Some([hash, idx, script, amount]) => (hash, idx, script, amount.as_i64().ok_or("bad amount")?),
+            _ => return Err(String::from("bad prevout")),
+        };
+        // -1 stands for the null outpoint index
+        let idx = idx.as_i64().ok_or("bad prevout index")? as u32;
+        let script = parse_asm(script.as_str().ok_or("bad prevout script")?)?;
+        outputs.insert((reverse_hash(hash.as_str().ok_or("bad prevout hash")?), idx), output(amount, &script));
+    }
+    let tx = hex::decode(tx).ok().and_then(|tx| parse_transaction(&tx).ok()).ok_or("bad transaction")?;
+
+    let checked = check_transaction(&tx);
+    let outcome = match (expect_valid, flags.as_str()) {
+        (true, _) => {
+            let excluded = VerifyFlags::from_names(flags).ok_or(format!("unknown flag in {}", flags))?;
+            let mut flags = VerifyFlags::all().remove(excluded);
+            // Same as Core: CLEANSTACK needs WITNESS, which needs P2SH
+            if !flags.contains(VerifyFlags::P2SH) {
+                flags = flags.remove(VerifyFlags::WITNESS);
+            }
+            if !flags.contains(VerifyFlags::WITNESS) {
+                flags = flags.remove(VerifyFlags::CLEANSTACK);
+            }
+            checked.map_err(String::from).and_then(|_| check_tx_scripts(&tx, &outputs, flags))
+                .map_or_else(Outcome::Fail, |_| Outcome::Pass)
+        }
+        (false, "BADTX") => match checked {
+            Err(_) => Outcome::Pass,
+            Ok(()) => Outcome::Fail(String::from("passes CheckTransaction")),
+        },
+        (false, flags) => {
+            let flags = VerifyFlags::from_names(flags).ok_or(format!("unknown flag in {}", flags))?;
+            match checked.map_err(String::from).and_then(|_| check_tx_scripts(&tx, &outputs, flags)) {
+                Err(_) => Outcome::Pass,
+                Ok(()) => Outcome::Fail(String::from("valid")),
+            }
+        }
+    };
+    Ok(outcome)
+}
+
+fn load_known_failures(dir: &str) -> HashSet<String> {
+    fs::read_to_string(format!("{}/{}", dir, KNOWN_FAILURES))
+        .unwrap_or_default()
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+// Runs the vectors of a directory, printing the failures and a summary per file. Returns the number
+// of unexpected outcomes: failures not listed as known, and known failures that pass.
+fn run(dir: &str, verbose: bool) -> Result<usize, String> {
+    let known_failures = load_known_failures(dir);
+
+    let mut unexpected = 0;
+    for file in FILES {
+        let path = format!("{}/{}", dir, file);
+        let vectors: Vec<Value> = fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|json| serde_json::from_str(&json).map_err(|e| e.to_string()))
+            .map_err(|e| format!("{}: {}", path, e))?;
+
+        let (mut passed, mut failed, mut known) = (0, 0, 0);
+        for (idx, vector) in vectors.iter().enumerate() {
+            let vector = match vector.as_array().map(Vec::as_slice) {
+                Some([Value::String(_)]) | None => continue,
+                Some(vector) => vector,
+            };
+            let name = format!("{}:{}", file, idx);
+            let outcome = match file {
+                "script_tests.json" => script_test(vector),
+                _ => tx_test(vector, file == "tx_valid.json"),
+            };
+            let outcome = outcome.unwrap_or_else(|e| Outcome::Fail(format!("bad vector: {}", e)));
+            let is_known = known_failures.contains(&name);
+            match outcome {
+                Outcome::Pass if is_known => {
+                    passed += 1;
+                    unexpected += 1;
+                    println!("FIXED {} (remove it from {}/{})", name, dir, KNOWN_FAILURES);
+                }
+                Outcome::Pass => {
+                    passed += 1;
+                    if verbose {
+                        println!("PASS  {}", name);
+                    }
+                }
+                Outcome::Fail(reason) if is_known => {
+                    failed += 1;
+                    known += 1;
+                    if verbose {
+                        println!("KNOWN {} {} {}", name, reason, Value::from(vector));
+                    }
+                }
+                Outcome::Fail(reason) => {
+                    failed += 1;
+                    unexpected += 1;
+                    println!("FAIL  {} {} {}", name, reason, Value::from(vector));
+                }
+            }
+        }
+        println!("{}/{}: {} passed, {} failed ({} known)", dir, file, passed, failed, known);
+    }
+    Ok(unexpected)
+}
+
+fn has_vectors(dir: &str) -> bool {
+    FILES.iter().all(|file| Path::new(dir).join(file).exists())
+}
+
+// Usage: conformance [--verbose] [fixtures directory]
+// Without directory, Core's vectors then bc-crawl's own ones
+pub fn main(args: &[String]) {
+    let verbose = args.iter().any(|arg| arg == "--verbose");
+    let dirs = match args.iter().find(|arg| !arg.starts_with("--")) {
+        Some(dir) => vec![dir.as_str()],
+        None if has_vectors(FIXTURES_DIR) => vec![FIXTURES_DIR, OWN_FIXTURES_DIR],
+        None => {
+            eprintln!("{}: Core's vectors are not vendored, see its UPSTREAM file", FIXTURES_DIR);
+            vec![OWN_FIXTURES_DIR]
+        }
+    };
+    let mut unexpected = 0;
+    for dir in dirs {
+        match run(dir, verbose) {
+            Ok(count) => unexpected += count,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if unexpected > 0 {
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn own_vectors() {
+        assert_eq!(run(OWN_FIXTURES_DIR, false), Ok(0));
+    }
+
+    // The attribute goes once Core's files are vendored, with the known failures listed next to them
+    #[test]
+    #[ignore = "Core's vectors are not vendored yet, see test_data/core/UPSTREAM"]
+    fn core_vectors() {
+        assert!(has_vectors(FIXTURES_DIR), "{}: Core's vectors are not vendored, see its UPSTREAM file", FIXTURES_DIR);
+        assert_eq!(run(FIXTURES_DIR, false), Ok(0));
+    }
+}
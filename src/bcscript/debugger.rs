@@ -0,0 +1,631 @@
+use std::io::{self, BufRead, Write};
+use std::mem;
+use std::path::Path;
+
+use colored::Colorize;
+use serde_json::{json, Value};
+
+use crate::bcfile;
+use crate::bcindex::find_tx;
+use crate::bcparse::{Transaction, TxOutput};
+use crate::bcutils::reverse_hash;
+
+use super::asm::to_asm;
+use super::assemble::{assemble, opcode_by_name, Encoding};
+use super::conformance::spending_tx;
+use super::flags::{consensus_flags, standard_flags, VerifyFlags};
+use super::interpret::{interpret, verify, Execution, Output, Phase, PhaseScript, Phases, Progress, Stack, VerifyError};
+use super::opcodes::Opcode;
+use super::parse::parse_one_op;
+use super::script::{to_script_nb, ScriptError};
+use super::sighash::{ExecData, SigVersion, TxContext};
+
+const USAGE: &str = "Usage: debug [--print | --trace] [--flags FLAG,...] [--amount <satoshis>] [--minimal] <scriptSig asm> <scriptPubKey asm> [witness item hex...]
+       debug [--print | --trace] [--flags FLAG,...] <txid>:<input>";
+
+const HELP: &str = "  s, step [n]              run the next opcode (or n of them), then the next script
+  c, continue              run up to a breakpoint, a failure or the end
+  b, break [pc | OPCODE]   stop before the opcode at pc or before each OPCODE, list them without argument
+  d, delete [n]            delete breakpoint n, all of them without argument
+  r, back [n]              undo the last step or edit (or n of them)
+  p, print                 show the script around pc, the stacks and the conditions
+  l, list                  the same with the whole script
+  set <main|alt> <i> <v>   replace item i (0 is the top) by v: a number, 0x<hex> or '' for empty
+  push <main|alt> <v>      push v on the stack
+  pop <main|alt> [i]       remove item i, the top without argument
+  q, quit";
+
+// Opcodes shown on each side of pc by print
+const PRINT_CONTEXT: usize = 8;
+
+// The input being debugged, with the outputs spent by its transaction
+struct Spend {
+    tx: Transaction,
+    input_idx: usize,
+    spent_outputs: Vec<TxOutput>,
+    script_sig: Vec<u8>,
+    script_pub_key: Vec<u8>,
+    witness: Vec<Vec<u8>>,
+    flags: VerifyFlags,
+}
+
+impl Spend {
+    fn ctx(&self) -> TxContext<'_> {
+        TxContext { tx: &self.tx, input_idx: self.input_idx, amount: self.spent_outputs[self.input_idx].value, spent_outputs: &self.spent_outputs }
+    }
+
+    // The scripts are the ones of the spend as given, edits only change the stacks
+    fn phases<'a>(&'a self, ctx: &'a TxContext<'a>) -> Phases<'a> {
+        Phases { script_sig: &self.script_sig, script_pub_key: &self.script_pub_key, witness: &self.witness, ctx, flags: self.flags }
+    }
+}
+
+// A script of the spend and everything a step changes, saved before each step to go back to it
+#[derive(Clone)]
+struct State {
+    phase: Phase,
+    script: Vec<u8>,
+    sig_version: SigVersion,
+    execution: Execution,
+    stack: Stack,
+    exec: ExecData,
+    // Where verify stands, to get the script that follows this one
+    progress: Progress,
+    // Start of the opcode run last, the one that failed
+    last_pc: usize,
+    // Set once the script is over or the spend failed, in the script or in the checks after it
+    end: Option<Result<(), VerifyError>>,
+}
+
+impl State {
+    fn new(script: PhaseScript, progress: Progress) -> State {
+        let PhaseScript { phase, script, sig_version, stack, exec } = script;
+        let (execution, end) = match Execution::start(&script, sig_version) {
+            Ok(execution) => (execution, None),
+            Err(error) => (Execution::default(), Some(Err(VerifyError { phase, error }))),
+        };
+        State { phase, script, sig_version, execution, stack: Stack { main: stack, alt: Vec::new() }, exec, progress, last_pc: 0, end }
+    }
+
+    fn fail(&mut self, error: ScriptError) {
+        self.end = Some(Err(VerifyError { phase: self.phase, error }));
+    }
+}
+
+enum Breakpoint {
+    Pc(usize),
+    Opcode(Opcode),
+}
+
+struct Debugger {
+    spend: Spend,
+    state: State,
+    history: Vec<State>,
+    breakpoints: Vec<Breakpoint>,
+}
+
+impl Debugger {
+    fn new(spend: Spend) -> Debugger {
+        let ctx = spend.ctx();
+        let state = match spend.phases(&ctx).start() {
+            Ok(script) => State::new(script, Progress::default()),
+            // The scriptSig is still shown, failed before its first opcode
+            Err(e) => {
+                let script = PhaseScript { phase: e.phase, script: spend.script_sig.clone(), sig_version: SigVersion::Base, stack: Vec::new(), exec: ExecData::default() };
+                State { end: Some(Err(e)), ..State::new(script, Progress::default()) }
+            }
+        };
+        let mut debugger = Debugger { spend, state, history: Vec::new(), breakpoints: Vec::new() };
+        debugger.settle();
+        debugger
+    }
+
+    // The script verify runs after the current one, from the stack it left, edits included. None
+    // when the spend is valid.
+    fn next_script(&self) -> Result<Option<State>, VerifyError> {
+        let ctx = self.spend.ctx();
+        let mut progress = self.state.progress.clone();
+        let next = self.spend.phases(&ctx).next(&mut progress, self.state.stack.main.clone())?;
+        Ok(next.map(|script| State::new(script, progress)))
+    }
+
+    // After its last opcode, a script must have closed its conditionals and pass the checks verify
+    // makes before the next one, its failure shown at once
+    fn settle(&mut self) {
+        let state = &mut self.state;
+        if state.end.is_none() && state.execution.pc >= state.script.len() {
+            if let Err(error) = state.execution.finish() {
+                return state.fail(error);
+            }
+            self.state.end = Some(self.next_script().map(|_| ()));
+        }
+    }
+
+    // Runs the next opcode, or moves to the next script once the current one is over. False when
+    // there is nothing left to run.
+    fn step(&mut self) -> bool {
+        match self.state.end {
+            Some(Err(_)) => return false,
+            Some(Ok(())) => {
+                let next = match self.next_script() {
+                    Ok(Some(next)) => next,
+                    Ok(None) => return false,
+                    // Edits made once the script was over
+                    Err(e) => State { end: Some(Err(e)), ..self.state.clone() },
+                };
+                self.history.push(mem::replace(&mut self.state, next));
+                self.settle();
+                return true;
+            }
+            None => {}
+        }
+        self.history.push(self.state.clone());
+        let ctx = self.spend.ctx();
+        let state = &mut self.state;
+        state.last_pc = state.execution.pc;
+        match state.execution.step(&mut state.stack, &state.script, &ctx, self.spend.flags, state.sig_version, &mut state.exec) {
+            Ok(_) => self.settle(),
+            Err(e) => state.fail(e),
+        }
+        true
+    }
+
+    fn is_over(&self) -> bool {
+        match self.state.end {
+            Some(Err(_)) => true,
+            Some(Ok(())) => matches!(self.next_script(), Ok(None)),
+            None => false,
+        }
+    }
+
+    fn at_breakpoint(&self) -> bool {
+        let state = &self.state;
+        state.end.is_none() && self.breakpoints.iter().any(|breakpoint| match breakpoint {
+            Breakpoint::Pc(pc) => *pc == state.execution.pc,
+            Breakpoint::Opcode(opcode) => state.script.get(state.execution.pc) == Some(&opcode.code),
+        })
+    }
+
+    fn run(&mut self, max_steps: usize) {
+        let mut steps = 0;
+        while steps < max_steps && self.step() {
+            steps += 1;
+            if self.at_breakpoint() || matches!(self.state.end, Some(Err(_))) {
+                break;
+            }
+        }
+        if steps > 0 {
+            self.print_state(false);
+        }
+        if self.is_over() {
+            self.print_end();
+        }
+    }
+
+    fn back(&mut self, steps: usize) {
+        for _ in 0..steps {
+            match self.history.pop() {
+                Some(state) => self.state = state,
+                None => break,
+            }
+        }
+        self.print_state(false);
+    }
+
+    // Edits are saved as steps, back undoes them
+    fn edit(&mut self, words: &[&str]) -> Result<(), String> {
+        let (command, name, args) = match words {
+            [command, name, args @ ..] => (*command, *name, args),
+            _ => return Err(String::from("missing stack name")),
+        };
+        let mut state = self.state.clone();
+        let stack = match name {
+            "main" => &mut state.stack.main,
+            "alt" => &mut state.stack.alt,
+            _ => return Err(format!("unknown stack {}", name)),
+        };
+        let index = |stack: &Vec<Vec<u8>>, arg: &str| match arg.parse::<usize>() {
+            Ok(i) if i < stack.len() => Ok(stack.len() - 1 - i),
+            _ => Err(format!("no item {} on the {} stack", arg, name)),
+        };
+        match (command, args) {
+            ("set", [i, value]) => {
+                let i = index(stack, i)?;
+                stack[i] = parse_value(value)?;
+            }
+            ("push", [value]) => stack.push(parse_value(value)?),
+            ("pop", []) => {
+                let i = index(stack, "0")?;
+                stack.remove(i);
+            }
+            ("pop", [i]) => {
+                let i = index(stack, i)?;
+                stack.remove(i);
+            }
+            _ => return Err(format!("usage: {} <main|alt> {}", command, match command {
+                "set" => "<i> <value>",
+                "push" => "<value>",
+                _ => "[i]",
+            })),
+        }
+        self.history.push(mem::replace(&mut self.state, state));
+        self.print_state(false);
+        Ok(())
+    }
+
+    fn print_state(&self, whole_script: bool) {
+        let state = &self.state;
+        println!("\n{} ({:?}), step {}", format!("{:?}", state.phase).bold(), state.sig_version, self.history.len());
+
+        let mut ops = Vec::new();
+        let mut pc = 0;
+        loop {
+            let start = pc;
+            match parse_one_op(&state.script, &mut pc) {
+                Ok(Some(_)) => ops.push((start, to_asm(&state.script[start..pc], false))),
+                Ok(None) => break,
+                Err(_) => {
+                    ops.push((start, String::from("[error]")));
+                    break;
+                }
+            }
+        }
+        let failed = matches!(state.end, Some(Err(_)));
+        let current_pc = if failed { state.last_pc } else { state.execution.pc };
+        let current = ops.iter().position(|(pc, _)| *pc == current_pc).unwrap_or(ops.len());
+        let shown = match whole_script {
+            true => 0..ops.len(),
+            false => current.saturating_sub(PRINT_CONTEXT)..ops.len().min(current + PRINT_CONTEXT + 1),
+        };
+        if shown.start > 0 {
+            println!("         ...");
+        }
+        for (i, (pc, asm)) in ops.iter().enumerate().take(shown.end).skip(shown.start) {
+            let breakpoint = match self.breakpoints.iter().any(|b| matches!(b, Breakpoint::Pc(at) if at == pc)) {
+                true => "*",
+                false => " ",
+            };
+            let line = format!("{} {}{:>5}  {}", if i == current { "=>" } else { "  " }, breakpoint, pc, asm);
+            match i == current {
+                true if failed => println!("{}", line.bold().red()),
+                true => println!("{}", line.bold().green()),
+                false => println!("{}", line),
+            }
+        }
+        if shown.end < ops.len() {
+            println!("         ...");
+        }
+        match &state.end {
+            Some(Ok(())) => println!("{}", "Script over".green()),
+            Some(Err(e)) => println!("{}", format!("Failed: {:?} in {:?}", e.error, e.phase).red()),
+            None => {}
+        }
+
+        print_items("Main stack", &state.stack.main);
+        if !state.stack.alt.is_empty() {
+            print_items("Alt stack", &state.stack.alt);
+        }
+        if !state.execution.condition_stack.is_empty() {
+            let conditions: Vec<String> = state.execution.condition_stack.iter().map(|c| c.to_string()).collect();
+            println!("{} {}", "Conditions (innermost last):".bold(), conditions.join(" "));
+        }
+    }
+
+    // The verdict is the one of verify on the spend as given, edits left out
+    fn verify(&self) -> Result<(), VerifyError> {
+        let spend = &self.spend;
+        verify(&spend.script_sig, &spend.script_pub_key, &spend.witness, &spend.ctx(), spend.flags)
+    }
+
+    fn print_end(&self) {
+        if let Some(Err(e)) = &self.state.end {
+            return println!("{}", format!("Spend invalid: {:?} in {:?}", e.error, e.phase).red());
+        }
+        match self.verify() {
+            Ok(()) => println!("{}", "Spend valid".green()),
+            Err(e) => println!("{}", format!("Spend invalid: {:?} in {:?}", e.error, e.phase).red()),
+        }
+    }
+
+    // Runs each script in one go, with the tables of interpret after each opcode or its trace,
+    // returned with the script
+    fn run_scripts(&mut self, print: bool) -> Vec<Value> {
+        let mut scripts = Vec::new();
+        loop {
+            let ctx = self.spend.ctx();
+            let state = &mut self.state;
+            let mut steps = Vec::new();
+            if state.end.is_none() {
+                let output = match print {
+                    true => Output::Print,
+                    false => Output::Trace(&mut steps),
+                };
+                let result = interpret(&mut state.stack, &state.script, &ctx, self.spend.flags, state.sig_version, &mut state.exec, output);
+                state.execution.pc = state.script.len();
+                match result {
+                    Ok(()) => self.state.end = Some(self.next_script().map(|_| ())),
+                    Err(e) => state.fail(e),
+                }
+            }
+            let state = &self.state;
+            scripts.push(json!({
+                "phase": format!("{:?}", state.phase),
+                "sig_version": format!("{:?}", state.sig_version),
+                "script": to_asm(&state.script, false),
+                "steps": steps,
+                "error": match &state.end {
+                    Some(Err(e)) => Some(format!("{:?}", e.error)),
+                    _ => None,
+                },
+            }));
+            match (&state.end, self.next_script()) {
+                (Some(Ok(())), Ok(Some(next))) => self.state = next,
+                _ => return scripts,
+            }
+        }
+    }
+
+    fn add_breakpoint(&mut self, arg: &str) -> Result<(), String> {
+        let breakpoint = match arg.parse::<usize>() {
+            Ok(pc) => Breakpoint::Pc(pc),
+            Err(_) => Breakpoint::Opcode(opcode_by_name(arg).ok_or(format!("unknown opcode {}", arg))?),
+        };
+        self.breakpoints.push(breakpoint);
+        Ok(())
+    }
+
+    fn list_breakpoints(&self) {
+        if self.breakpoints.is_empty() {
+            println!("No breakpoints");
+        }
+        for (i, breakpoint) in self.breakpoints.iter().enumerate() {
+            match breakpoint {
+                Breakpoint::Pc(pc) => println!("  {}  pc {}", i, pc),
+                Breakpoint::Opcode(opcode) => println!("  {}  {}", i, opcode),
+            }
+        }
+    }
+
+    // False to quit
+    fn command(&mut self, words: &[&str]) -> Result<bool, String> {
+        let count = |args: &[&str]| match args {
+            [] => Ok(1),
+            [n] => n.parse::<usize>().map_err(|_| format!("bad count {}", n)),
+            _ => Err(String::from("too many arguments")),
+        };
+        match words {
+            [] => {}
+            ["s" | "step", args @ ..] => self.run(count(args)?),
+            ["c" | "continue"] => self.run(usize::MAX),
+            ["b" | "break"] => self.list_breakpoints(),
+            ["b" | "break", arg] => self.add_breakpoint(arg)?,
+            ["d" | "delete"] => self.breakpoints.clear(),
+            ["d" | "delete", n] => match n.parse::<usize>() {
+                Ok(i) if i < self.breakpoints.len() => {
+                    self.breakpoints.remove(i);
+                }
+                _ => return Err(format!("no breakpoint {}", n)),
+            },
+            ["r" | "back", args @ ..] => self.back(count(args)?),
+            ["p" | "print"] => self.print_state(false),
+            ["l" | "list"] => self.print_state(true),
+            ["set" | "push" | "pop", ..] => self.edit(words)?,
+            ["h" | "help"] => println!("{}", HELP),
+            ["q" | "quit"] => return Ok(false),
+            _ => return Err(format!("unknown command {}, `help` lists them", words.join(" "))),
+        }
+        Ok(true)
+    }
+}
+
+fn print_items(title: &str, items: &[Vec<u8>]) {
+    println!("{}", title.bold());
+    if items.is_empty() {
+        println!("         (empty)");
+    }
+    for (i, item) in items.iter().rev().enumerate() {
+        println!("  {:>5}  0x{}", i, hex::encode(item));
+    }
+}
+
+// A number, pushed as a script number, 0x<hex> or '' for an empty item
+fn parse_value(value: &str) -> Result<Vec<u8>, String> {
+    if value == "''" {
+        return Ok(Vec::new());
+    }
+    if let Some(bytes) = value.strip_prefix("0x") {
+        return hex::decode(bytes).map_err(|_| format!("bad hex {}", value));
+    }
+    value.parse::<i64>().map(to_script_nb).map_err(|_| format!("bad value {}, expected a number, 0x<hex> or ''", value))
+}
+
+// Transaction from the stored blocks, found through the txid index
+fn stored_tx(txid: &str) -> Result<(Transaction, Option<u32>), String> {
+    let location = find_tx(txid).map_err(|e| e.to_string())?.into_iter().next()
+        .ok_or(format!("{} not in the txid index (see `index build`)", txid))?;
+    let path = bcfile::block_file(&location.block);
+    let mut block = bcfile::load_block(Path::new(&path)).map_err(|e| format!("{}: {}", path, e))?;
+    let position = location.position as usize;
+    if position >= block.txns.len() {
+        return Err(format!("{}: no transaction at position {}", path, position));
+    }
+    Ok((block.txns.swap_remove(position), location.height))
+}
+
+// Input of a stored transaction, run with the consensus rules of its block unless flags are given
+fn stored_spend(txid: &str, input_idx: usize, flags: Option<VerifyFlags>) -> Result<Spend, String> {
+    let (tx, height) = stored_tx(txid)?;
+    if tx.is_coinbase() {
+        return Err(format!("{} is a coinbase, it has no script to run", txid));
+    }
+    let input = tx.inputs.get(input_idx).ok_or(format!("{} has {} inputs", txid, tx.inputs.len()))?;
+    let script_sig = hex::decode(&input.signature_script).map_err(|_| String::from("bad scriptSig hex"))?;
+    let witness = tx.witnesses.get(input_idx).into_iter().flatten()
+        .map(|item| hex::decode(&item.script).map_err(|_| String::from("bad witness hex")))
+        .collect::<Result<Vec<Vec<u8>>, String>>()?;
+
+    // Taproot signatures commit to all the spent outputs
+    let mut spent_outputs = Vec::new();
+    for input in &tx.inputs {
+        let prev_txid = reverse_hash(&input.prev_output.hash);
+        let (mut prev_tx, _) = stored_tx(&prev_txid)?;
+        let idx = input.prev_output.idx as usize;
+        if idx >= prev_tx.outputs.len() {
+            return Err(format!("{} has no output {}", prev_txid, idx));
+        }
+        spent_outputs.push(prev_tx.outputs.swap_remove(idx));
+    }
+    let script_pub_key = hex::decode(&spent_outputs[input_idx].pub_key_script).map_err(|_| String::from("bad scriptPubKey hex"))?;
+    let flags = flags.unwrap_or_else(|| consensus_flags(height.unwrap_or(u32::MAX)));
+    Ok(Spend { tx, input_idx, spent_outputs, script_sig, script_pub_key, witness, flags })
+}
+
+// Scripts given in ASM, spent as in Core's script tests
+fn script_spend(script_sig: &str, script_pub_key: &str, witness: &[&str], amount: i64, flags: Option<VerifyFlags>, encoding: Encoding) -> Result<Spend, String> {
+    let script_sig = assemble(script_sig, encoding).map_err(|e| format!("scriptSig: {}", e))?;
+    let script_pub_key = assemble(script_pub_key, encoding).map_err(|e| format!("scriptPubKey: {}", e))?;
+    let witness = witness.iter()
+        .map(|item| hex::decode(item).map_err(|_| format!("bad witness item {}", item)))
+        .collect::<Result<Vec<Vec<u8>>, String>>()?;
+    let tx = spending_tx(&script_sig, &script_pub_key, &witness, amount);
+    let spent_outputs = vec![TxOutput { value: amount, pub_key_script: hex::encode(&script_pub_key), ..Default::default() }];
+    Ok(Spend { tx, input_idx: 0, spent_outputs, script_sig, script_pub_key, witness, flags: flags.unwrap_or_else(standard_flags) })
+}
+
+enum Mode {
+    Interactive,
+    Print,
+    Trace,
+}
+
+fn load(args: &[String]) -> Result<(Spend, Mode), String> {
+    let mut mode = Mode::Interactive;
+    let mut flags = None;
+    let mut amount = 0;
+    let mut encoding = Encoding::Exact;
+    let mut positional = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--print" => mode = Mode::Print,
+            "--trace" => mode = Mode::Trace,
+            "--flags" => flags = Some(args.next().and_then(|names| VerifyFlags::from_names(names)).ok_or("bad --flags")?),
+            "--amount" => amount = args.next().and_then(|value| value.parse().ok()).ok_or("bad --amount")?,
+            "--minimal" => encoding = Encoding::Minimal,
+            _ => positional.push(arg.as_str()),
+        }
+    }
+    let spend = match positional.as_slice() {
+        [outpoint] if outpoint.contains(':') => match outpoint.split_once(':') {
+            Some((txid, input)) if txid.len() == 64 && hex::decode(txid).is_ok() => {
+                stored_spend(txid, input.parse().map_err(|_| format!("bad input index {}", input))?, flags)
+            }
+            _ => Err(format!("expected <txid>:<input>, got {}", outpoint)),
+        },
+        [script_sig, script_pub_key, witness @ ..] => script_spend(script_sig, script_pub_key, witness, amount, flags, encoding),
+        _ => Err(String::from("missing scripts")),
+    }?;
+    Ok((spend, mode))
+}
+
+// Usage: debug [--print | --trace] [--flags FLAG,...] [--amount <satoshis>] [--minimal] <scriptSig asm> <scriptPubKey asm> [witness item hex...]
+//        debug [--print | --trace] [--flags FLAG,...] <txid>:<input>
+// Steps through the scripts of a spend in the order verify runs them, commands are read from stdin.
+// --print runs them all showing each step, --trace prints the trace of each script as JSON.
+// --minimal pushes the data of the ASM as MINIMALDATA requires instead of as written.
+pub fn main(args: &[String]) {
+    let (mut debugger, mode) = match load(args) {
+        Ok((spend, mode)) => (Debugger::new(spend), mode),
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            std::process::exit(1);
+        }
+    };
+    match mode {
+        Mode::Interactive => {}
+        Mode::Print => {
+            debugger.run_scripts(true);
+            return debugger.print_end();
+        }
+        Mode::Trace => {
+            let scripts = debugger.run_scripts(false);
+            let result = debugger.verify();
+            let trace = json!({
+                "flags": debugger.spend.flags.to_string(),
+                "scripts": scripts,
+                "valid": result.is_ok(),
+                "error": result.as_ref().err().map(|e| format!("{:?}", e.error)),
+                "phase": result.as_ref().err().map(|e| format!("{:?}", e.phase)),
+            });
+            return println!("{}", serde_json::to_string_pretty(&trace).unwrap());
+        }
+    }
+    println!("Flags {}\n`help` lists the commands", debugger.spend.flags);
+    debugger.print_state(false);
+    let stdin = io::stdin();
+    loop {
+        print!("(debug) ");
+        io::stdout().flush().ok();
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match debugger.command(&words) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => println!("{}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    // Verdict the debugger reaches on the spend, by steps or script by script
+    fn verdict(debugger: &Debugger) -> String {
+        match &debugger.state.end {
+            Some(Err(e)) => format!("{:?} in {:?}", e.error, e.phase),
+            _ if debugger.is_over() => String::from("valid"),
+            _ => String::from("not over"),
+        }
+    }
+
+    // Every script test is a spend the debugger must judge as verify does
+    #[test]
+    fn same_verdict_as_verify() {
+        let json = fs::read_to_string("test_data/bc-crawl/script_tests.json").unwrap();
+        let vectors: Vec<Value> = serde_json::from_str(&json).unwrap();
+        let mut checked = 0;
+        for vector in vectors.iter().filter_map(Value::as_array).filter(|vector| vector.len() >= 4) {
+            let (witness, amount, fields) = match vector.as_slice() {
+                [Value::Array(witness), fields @ ..] => {
+                    let (amount, items) = witness.split_last().unwrap();
+                    let items: Vec<&str> = items.iter().filter_map(Value::as_str).collect();
+                    (items, (amount.as_f64().unwrap() * 100_000_000.0).round() as i64, fields)
+                }
+                fields => (Vec::new(), 0, fields),
+            };
+            let field = |i: usize| fields[i].as_str().unwrap();
+            let flags = VerifyFlags::from_names(field(2)).unwrap();
+            let load = || script_spend(field(0), field(1), &witness, amount, Some(flags), Encoding::Exact).unwrap();
+            let expected = match Debugger::new(load()).verify() {
+                Ok(()) => String::from("valid"),
+                Err(e) => format!("{:?} in {:?}", e.error, e.phase),
+            };
+
+            let mut debugger = Debugger::new(load());
+            while debugger.step() {}
+            assert_eq!(verdict(&debugger), expected, "stepping {:?}", vector);
+            let mut debugger = Debugger::new(load());
+            debugger.run_scripts(false);
+            assert_eq!(verdict(&debugger), expected, "running {:?}", vector);
+            checked += 1;
+        }
+        assert!(checked > 300);
+    }
+}
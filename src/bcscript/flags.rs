@@ -0,0 +1,165 @@
+use std::fmt;
+use std::ops::BitOr;
+
+// Script verification flags, with the bits of Core's SCRIPT_VERIFY_* so that they can be compared
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct VerifyFlags(u32);
+
+impl VerifyFlags {
+    pub const NONE: VerifyFlags = VerifyFlags(0);
+    // Pay to script hash outputs (BIP16)
+    pub const P2SH: VerifyFlags = VerifyFlags(1 << 0);
+    // Signatures must be strict DER with a defined hash type, public keys compressed or uncompressed
+    pub const STRICTENC: VerifyFlags = VerifyFlags(1 << 1);
+    // Signatures must be strict DER (BIP66)
+    pub const DERSIG: VerifyFlags = VerifyFlags(1 << 2);
+    // The S value of signatures must be at most half the curve order
+    pub const LOW_S: VerifyFlags = VerifyFlags(1 << 3);
+    // The dummy element consumed by CHECKMULTISIG must be empty (BIP147)
+    pub const NULLDUMMY: VerifyFlags = VerifyFlags(1 << 4);
+    // The scriptSig can only push data
+    pub const SIGPUSHONLY: VerifyFlags = VerifyFlags(1 << 5);
+    // Data is pushed with the smallest possible opcode and numbers are minimally encoded
+    pub const MINIMALDATA: VerifyFlags = VerifyFlags(1 << 6);
+    // OP_NOP1 and OP_NOP4 to OP_NOP10, reserved for soft forks, fail
+    pub const DISCOURAGE_UPGRADABLE_NOPS: VerifyFlags = VerifyFlags(1 << 7);
+    // Legacy and P2SH spends must leave a single element on the stack
+    pub const CLEANSTACK: VerifyFlags = VerifyFlags(1 << 8);
+    // OP_CHECKLOCKTIMEVERIFY (BIP65)
+    pub const CHECKLOCKTIMEVERIFY: VerifyFlags = VerifyFlags(1 << 9);
+    // OP_CHECKSEQUENCEVERIFY (BIP112)
+    pub const CHECKSEQUENCEVERIFY: VerifyFlags = VerifyFlags(1 << 10);
+    // Witness programs, bare or nested in P2SH (BIP141, BIP143)
+    pub const WITNESS: VerifyFlags = VerifyFlags(1 << 11);
+    // Witness versions and program sizes without meaning yet fail
+    pub const DISCOURAGE_UPGRADABLE_WITNESS_PROGRAM: VerifyFlags = VerifyFlags(1 << 12);
+    // The argument of OP_IF and OP_NOTIF in witness v0 scripts must be empty or 0x01
+    pub const MINIMALIF: VerifyFlags = VerifyFlags(1 << 13);
+    // A signature check that fails must be given an empty signature (BIP146)
+    pub const NULLFAIL: VerifyFlags = VerifyFlags(1 << 14);
+    // Witness v0 scripts only accept compressed public keys
+    pub const WITNESS_PUBKEYTYPE: VerifyFlags = VerifyFlags(1 << 15);
+    // No OP_CODESEPARATOR in legacy scripts, and no signature removed from the signed script
+    pub const CONST_SCRIPTCODE: VerifyFlags = VerifyFlags(1 << 16);
+    // Witness v1 outputs: taproot key and script path spends, tapscript (BIP341, BIP342)
+    pub const TAPROOT: VerifyFlags = VerifyFlags(1 << 17);
+    // Unknown tapleaf versions fail
+    pub const DISCOURAGE_UPGRADABLE_TAPROOT_VERSION: VerifyFlags = VerifyFlags(1 << 18);
+    // OP_SUCCESSx opcodes fail instead of making the tapscript valid
+    pub const DISCOURAGE_OP_SUCCESS: VerifyFlags = VerifyFlags(1 << 19);
+    // Tapscript keys of unknown type (not 32 bytes) fail
+    pub const DISCOURAGE_UPGRADABLE_PUBKEYTYPE: VerifyFlags = VerifyFlags(1 << 20);
+
+    // Names of Core's SCRIPT_VERIFY_* flags, without prefix
+    const NAMES: [(&'static str, VerifyFlags); 21] = [
+        ("P2SH", VerifyFlags::P2SH),
+        ("STRICTENC", VerifyFlags::STRICTENC),
+        ("DERSIG", VerifyFlags::DERSIG),
+        ("LOW_S", VerifyFlags::LOW_S),
+        ("NULLDUMMY", VerifyFlags::NULLDUMMY),
+        ("SIGPUSHONLY", VerifyFlags::SIGPUSHONLY),
+        ("MINIMALDATA", VerifyFlags::MINIMALDATA),
+        ("DISCOURAGE_UPGRADABLE_NOPS", VerifyFlags::DISCOURAGE_UPGRADABLE_NOPS),
+        ("CLEANSTACK", VerifyFlags::CLEANSTACK),
+        ("CHECKLOCKTIMEVERIFY", VerifyFlags::CHECKLOCKTIMEVERIFY),
+        ("CHECKSEQUENCEVERIFY", VerifyFlags::CHECKSEQUENCEVERIFY),
+        ("WITNESS", VerifyFlags::WITNESS),
+        ("DISCOURAGE_UPGRADABLE_WITNESS_PROGRAM", VerifyFlags::DISCOURAGE_UPGRADABLE_WITNESS_PROGRAM),
+        ("MINIMALIF", VerifyFlags::MINIMALIF),
+        ("NULLFAIL", VerifyFlags::NULLFAIL),
+        ("WITNESS_PUBKEYTYPE", VerifyFlags::WITNESS_PUBKEYTYPE),
+        ("CONST_SCRIPTCODE", VerifyFlags::CONST_SCRIPTCODE),
+        ("TAPROOT", VerifyFlags::TAPROOT),
+        ("DISCOURAGE_UPGRADABLE_TAPROOT_VERSION", VerifyFlags::DISCOURAGE_UPGRADABLE_TAPROOT_VERSION),
+        ("DISCOURAGE_OP_SUCCESS", VerifyFlags::DISCOURAGE_OP_SUCCESS),
+        ("DISCOURAGE_UPGRADABLE_PUBKEYTYPE", VerifyFlags::DISCOURAGE_UPGRADABLE_PUBKEYTYPE),
+    ];
+
+    pub fn all() -> VerifyFlags {
+        VerifyFlags::NAMES.iter().fold(VerifyFlags::NONE, |flags, (_, flag)| flags | *flag)
+    }
+
+    // Comma separated names, as in Core's test vectors: "P2SH,STRICTENC", "" or "NONE" for none
+    pub fn from_names(names: &str) -> Option<VerifyFlags> {
+        names.split(',')
+            .filter(|name| !name.is_empty() && *name != "NONE")
+            .map(|name| VerifyFlags::NAMES.iter().find(|(known, _)| *known == name).map(|(_, flag)| *flag))
+            .try_fold(VerifyFlags::NONE, |flags, flag| Some(flags | flag?))
+    }
+
+    pub fn remove(self, flags: VerifyFlags) -> VerifyFlags {
+        VerifyFlags(self.0 & !flags.0)
+    }
+
+    pub fn contains(self, flags: VerifyFlags) -> bool {
+        self.0 & flags.0 == flags.0
+    }
+
+    pub fn intersects(self, flags: VerifyFlags) -> bool {
+        self.0 & flags.0 != 0
+    }
+}
+
+impl BitOr for VerifyFlags {
+    type Output = VerifyFlags;
+
+    fn bitor(self, other: VerifyFlags) -> VerifyFlags {
+        VerifyFlags(self.0 | other.0)
+    }
+}
+
+// The names read by from_names
+impl fmt::Display for VerifyFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = VerifyFlags::NAMES.iter().filter(|(_, flag)| self.contains(*flag)).map(|(name, _)| *name).collect();
+        match names.is_empty() {
+            true => write!(f, "NONE"),
+            false => write!(f, "{}", names.join(",")),
+        }
+    }
+}
+
+// Mainnet activation heights of the soft forks changing script rules
+const BIP16_HEIGHT: u32 = 173805;
+const BIP66_HEIGHT: u32 = 363725;
+const BIP65_HEIGHT: u32 = 388381;
+const CSV_HEIGHT: u32 = 419328;
+const SEGWIT_HEIGHT: u32 = 481824;
+const TAPROOT_HEIGHT: u32 = 709632;
+
+// Consensus rules in force for the scripts of a block at this height. P2SH, segwit and taproot are
+// enforced from their activation only, so that the two blocks breaking them before (BIP16 and
+// taproot exceptions in Core) are accepted without special case.
+pub fn consensus_flags(height: u32) -> VerifyFlags {
+    let rules = [
+        (BIP16_HEIGHT, VerifyFlags::P2SH),
+        (BIP66_HEIGHT, VerifyFlags::DERSIG),
+        (BIP65_HEIGHT, VerifyFlags::CHECKLOCKTIMEVERIFY),
+        (CSV_HEIGHT, VerifyFlags::CHECKSEQUENCEVERIFY),
+        (SEGWIT_HEIGHT, VerifyFlags::WITNESS | VerifyFlags::NULLDUMMY),
+        (TAPROOT_HEIGHT, VerifyFlags::TAPROOT),
+    ];
+    rules.iter()
+        .filter(|(activation, _)| height >= *activation)
+        .fold(VerifyFlags::NONE, |flags, (_, rule)| flags | *rule)
+}
+
+// Current consensus rules and the ones Core adds for the transactions it relays (STANDARD_SCRIPT_VERIFY_FLAGS)
+pub fn standard_flags() -> VerifyFlags {
+    let policy = [
+        VerifyFlags::STRICTENC,
+        VerifyFlags::LOW_S,
+        VerifyFlags::MINIMALDATA,
+        VerifyFlags::DISCOURAGE_UPGRADABLE_NOPS,
+        VerifyFlags::CLEANSTACK,
+        VerifyFlags::DISCOURAGE_UPGRADABLE_WITNESS_PROGRAM,
+        VerifyFlags::MINIMALIF,
+        VerifyFlags::NULLFAIL,
+        VerifyFlags::WITNESS_PUBKEYTYPE,
+        VerifyFlags::CONST_SCRIPTCODE,
+        VerifyFlags::DISCOURAGE_UPGRADABLE_TAPROOT_VERSION,
+        VerifyFlags::DISCOURAGE_OP_SUCCESS,
+        VerifyFlags::DISCOURAGE_UPGRADABLE_PUBKEYTYPE,
+    ];
+    policy.iter().fold(consensus_flags(u32::MAX), |flags, rule| flags | *rule)
+}
@@ -3,29 +3,49 @@ use std::cmp::{max, min};
 use bitcoin_hashes::{hash160, ripemd160, sha1, sha256, sha256d};
 use bitcoin_hashes::Hash;
 use colored::Colorize;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use secp256k1::{Message, Secp256k1, VerifyOnly};
 use tabled::{Alignment, MaxWidth, MinWidth, Modify, Style};
 use tabled::builder::Builder;
 use tabled::object::Rows;
 
+use crate::bcutils::to_compact_int;
+
+use super::asm::to_asm;
 use super::opcodes::*;
 use super::parse::{parse_one_op, parse_script};
-use super::public_key::PublicKey;
-use super::script::{as_bool, as_script_nb};
+use super::script::as_bool;
 use super::script::*;
+use super::flags::VerifyFlags;
+use super::public_key::{check_pub_key_encoding, PublicKey};
+use super::signature::{check_signature_encoding, EcdsaSignature, SchnorrSignature, SigError};
+use super::sighash::{legacy_sighash, segwit_v0_sighash, taproot_sighash, ExecData, SigVersion, TxContext};
+use super::taproot::*;
+use super::standard::{classify, witness_program, OutputType};
+
+lazy_static! {
+    pub static ref SECP: Secp256k1<VerifyOnly> = Secp256k1::verification_only();
+}
 
+#[derive(Clone)]
 pub struct Stack {
     pub main: Vec<Vec<u8>>,
     pub alt: Vec<Vec<u8>>,
 }
 
 impl Stack {
+    pub fn new() -> Stack {
+        Stack { main: Vec::with_capacity(20), alt: Vec::with_capacity(20) }
+    }
+
     fn push(&mut self, bytes: Vec<u8>) -> Result<(), ScriptError> {
         if bytes.len() > MAX_SCRIPT_ELEMENT_SIZE {
             return Err(ScriptError::PushSize);
         }
         self.main.push(bytes);
 
-        if self.main.len() + self.alt.len() >= MAX_STACK_SIZE {
+        if self.main.len() + self.alt.len() > MAX_STACK_SIZE {
             return Err(ScriptError::StackOverflow);
         }
         Ok(())
@@ -37,7 +57,7 @@ impl Stack {
         }
         self.alt.push(bytes);
 
-        if self.main.len() + self.alt.len() >= MAX_STACK_SIZE {
+        if self.main.len() + self.alt.len() > MAX_STACK_SIZE {
             return Err(ScriptError::StackOverflow);
         }
         Ok(())
@@ -181,46 +201,389 @@ fn print_state(stack: &Stack, script: &Script, step_nb: usize) {
     }
 }
 
-fn check_sig(mut sig: Vec<u8>, pub_key_bytes: Vec<u8>, _script_code: Vec<u8>) -> bool {
-    // we check if pub key is valid
-    let pub_key = PublicKey::from(pub_key_bytes);
-    if !pub_key.is_valid() {
-        return false;
+// ECDSA signature followed by its hash type, checked against the sighash of the input. Encoding
+// rules are enforced by flags; without them, as in Core, the DER encoding is parsed leniently and
+// high S values are accepted.
+fn check_sig(sig: &[u8], pub_key: &[u8], script_code: &[u8], ctx: &TxContext, flags: VerifyFlags, sig_version: SigVersion) -> Result<bool, ScriptError> {
+    check_signature_encoding(sig, flags)?;
+    check_pub_key_encoding(pub_key, flags, sig_version)?;
+    let EcdsaSignature { mut signature, hash_type } = match EcdsaSignature::parse_lax(sig) {
+        Ok(signature) => signature,
+        Err(_) => return Ok(false),
+    };
+    let pub_key = match PublicKey::parse(pub_key) {
+        Ok(PublicKey::Ecdsa(_, pub_key)) => pub_key,
+        _ => return Ok(false),
+    };
+    signature.normalize_s();
+
+    let sighash = match sig_version {
+        SigVersion::Base => legacy_sighash(ctx.tx, ctx.input_idx, script_code, hash_type as u32),
+        SigVersion::WitnessV0 => segwit_v0_sighash(ctx.tx, ctx.input_idx, script_code, ctx.amount, hash_type as u32),
+        // Taproot signatures are Schnorr ones
+        SigVersion::Taproot | SigVersion::Tapscript => return Ok(false),
+    };
+    let message = Message::from_slice(&sighash).unwrap();
+    Ok(SECP.verify_ecdsa(&message, &signature, &pub_key).is_ok())
+}
+
+// BIP340 signature, followed by its hash type unless it is SIGHASH_DEFAULT, against a 32 bytes key.
+// Unlike ECDSA ones, an invalid signature is an error.
+fn check_schnorr_sig(sig: &[u8], pub_key: &[u8], ctx: &TxContext, sig_version: SigVersion, exec: &ExecData) -> Result<(), ScriptError> {
+    let SchnorrSignature { signature, hash_type } = SchnorrSignature::parse(sig).map_err(|e| match e {
+        SigError::SchnorrHashType => ScriptError::SchnorrSigHashType,
+        _ => ScriptError::SchnorrSigSize,
+    })?;
+    let sighash = taproot_sighash(ctx, hash_type, sig_version, exec).ok_or(ScriptError::SchnorrSigHashType)?;
+    let message = Message::from_slice(&sighash).unwrap();
+    match PublicKey::parse_x_only(pub_key) {
+        Ok(PublicKey::XOnly(pub_key)) if SECP.verify_schnorr(&signature, &message, &pub_key).is_ok() => Ok(()),
+        _ => Err(ScriptError::SchnorrSig),
     }
+}
 
-    // we get hash type of signature
-    if sig.is_empty() {
-        return false;
+// Tapscript CHECKSIG family: an empty signature fails without error, any other one is charged to the
+// validation weight budget. Keys of another size than 32 bytes are left for future soft forks.
+fn check_sig_tapscript(sig: &[u8], pub_key: &[u8], ctx: &TxContext, flags: VerifyFlags, exec: &mut ExecData) -> Result<bool, ScriptError> {
+    if !sig.is_empty() {
+        exec.validation_weight_left -= VALIDATION_WEIGHT_PER_SIGOP_PASSED;
+        if exec.validation_weight_left < 0 {
+            return Err(ScriptError::TapscriptValidationWeight);
+        }
+    }
+    match pub_key.len() {
+        0 => Err(ScriptError::PubKeyType),
+        32 if !sig.is_empty() => check_schnorr_sig(sig, pub_key, ctx, SigVersion::Tapscript, exec).map(|_| true),
+        32 => Ok(false),
+        _ if flags.contains(VerifyFlags::DISCOURAGE_UPGRADABLE_PUBKEYTYPE) => Err(ScriptError::DiscourageUpgradablePubKeyType),
+        _ => Ok(!sig.is_empty()),
     }
-    let _hash_type = sig.last().copied().unwrap();
-    sig.pop();
-    true
 }
 
-pub fn interpret(script: &[u8], verbose: bool) -> Result<(), ScriptError> {
-    const SCRIPT_FALSE: [u8; 0] = [];
-    const SCRIPT_TRUE: [u8; 1] = [0x01];
+// Script being run when a spend failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    ScriptSig,
+    ScriptPubKey,
+    RedeemScript,
+    Witness,
+}
 
-    let mut stack = Stack { main: Vec::with_capacity(20), alt: Vec::with_capacity(20) };
-    let mut condition_stack: Vec<bool> = Vec::with_capacity(10);
-    let mut execute: bool;
-    let mut op_count: usize = 0;
-    let mut pc: usize = 0;
-    let mut code_hash_start: usize = 0;
+#[derive(Debug, Clone)]
+pub struct VerifyError {
+    pub phase: Phase,
+    pub error: ScriptError,
+}
+
+fn in_phase(phase: Phase) -> impl Fn(ScriptError) -> VerifyError {
+    move |error| VerifyError { phase, error }
+}
 
-    if script.len() > MAX_SCRIPT_SIZE {
-        return Err(ScriptError::ScriptSize);
+fn check_top(stack: &[Vec<u8>], phase: Phase) -> Result<(), VerifyError> {
+    match stack.last() {
+        Some(top) if as_bool(top) => Ok(()),
+        _ => Err(VerifyError { phase, error: ScriptError::EvalFalse }),
     }
+}
 
-    let mut display_script: Script = Script::new();
-    let mut step_nb: usize = 0;
-    if verbose {
-        display_script = parse_script(script)?;
-        print_state(&stack, &display_script, step_nb);
+// A script of a spend as verify runs it, with the main stack and execution data it starts from
+#[derive(Debug, Clone)]
+pub struct PhaseScript {
+    pub phase: Phase,
+    pub script: Vec<u8>,
+    pub sig_version: SigVersion,
+    pub stack: Vec<Vec<u8>>,
+    pub exec: ExecData,
+}
+
+impl PhaseScript {
+    fn new(phase: Phase, script: Vec<u8>, sig_version: SigVersion, stack: Vec<Vec<u8>>) -> PhaseScript {
+        PhaseScript { phase, script, sig_version, stack, exec: ExecData::default() }
+    }
+}
+
+// Where a verification stands between two scripts, from the scriptSig by default
+#[derive(Debug, Clone)]
+pub struct Progress {
+    phase: Phase,
+    // Stack left by the scriptSig, the one a P2SH redeem script starts from
+    sig_stack: Vec<Vec<u8>>,
+}
+
+impl Default for Progress {
+    fn default() -> Progress {
+        Progress { phase: Phase::ScriptSig, sig_stack: Vec::new() }
+    }
+}
+
+// VerifyScript in Core, as the sequence of the scripts it runs: the scriptSig pushes are the initial
+// stack of the scriptPubKey. With BIP16, a P2SH scriptPubKey only checks the hash of the redeem
+// script, which is then deserialized from the last push and run against the ones before it. A
+// witness program, bare or as the redeem script, is then run against the witness.
+// Each script is run by the caller, which hands back the stack it left to get the next one; the
+// checks made between scripts and once they are all run are done here.
+pub struct Phases<'a> {
+    pub script_sig: &'a [u8],
+    pub script_pub_key: &'a [u8],
+    pub witness: &'a [Vec<u8>],
+    pub ctx: &'a TxContext<'a>,
+    pub flags: VerifyFlags,
+}
+
+impl Phases<'_> {
+    // The scriptSig, run from an empty stack
+    pub fn start(&self) -> Result<PhaseScript, VerifyError> {
+        if self.flags.contains(VerifyFlags::SIGPUSHONLY) && !is_push_only(self.script_sig) {
+            return Err(VerifyError { phase: Phase::ScriptSig, error: ScriptError::SigPushOnly });
+        }
+        Ok(PhaseScript::new(Phase::ScriptSig, self.script_sig.to_vec(), SigVersion::Base, Vec::new()))
+    }
+
+    // The script to run after the one of progress left this stack, None once the spend is valid
+    pub fn next(&self, progress: &mut Progress, stack: Vec<Vec<u8>>) -> Result<Option<PhaseScript>, VerifyError> {
+        let flags = self.flags;
+        match progress.phase {
+            Phase::ScriptSig => {
+                if flags.contains(VerifyFlags::P2SH) {
+                    progress.sig_stack = stack.clone();
+                }
+                progress.phase = Phase::ScriptPubKey;
+                Ok(Some(PhaseScript::new(Phase::ScriptPubKey, self.script_pub_key.to_vec(), SigVersion::Base, stack)))
+            }
+            Phase::ScriptPubKey => {
+                check_top(&stack, Phase::ScriptPubKey)?;
+                if flags.contains(VerifyFlags::WITNESS) {
+                    if let Some((version, program)) = witness_program(self.script_pub_key) {
+                        // The scriptSig could be changed without invalidating the witness signatures
+                        if !self.script_sig.is_empty() {
+                            return Err(VerifyError { phase: Phase::Witness, error: ScriptError::WitnessMalleated });
+                        }
+                        progress.phase = Phase::Witness;
+                        return self.witness_script(version, program, false).map_err(in_phase(Phase::Witness));
+                    }
+                }
+                if !self.is_p2sh() {
+                    return self.end(&stack);
+                }
+                // Anything else could change the redeem script run without touching the hash checked
+                if !is_push_only(self.script_sig) {
+                    return Err(VerifyError { phase: Phase::ScriptSig, error: ScriptError::SigPushOnly });
+                }
+                // Not empty when the scriptPubKey succeeded on a hash of the last push
+                let (redeem_script, stack) = progress.sig_stack.split_last()
+                    .ok_or(VerifyError { phase: Phase::RedeemScript, error: ScriptError::InvalidStackOperation })?;
+                progress.phase = Phase::RedeemScript;
+                Ok(Some(PhaseScript::new(Phase::RedeemScript, redeem_script.clone(), SigVersion::Base, stack.to_vec())))
+            }
+            Phase::RedeemScript => {
+                check_top(&stack, Phase::RedeemScript)?;
+                // P2SH-P2WPKH and P2SH-P2WSH: the scriptSig is a single push of the witness program
+                let redeem_script = progress.sig_stack.last().map(Vec::as_slice).unwrap_or_default();
+                if flags.contains(VerifyFlags::WITNESS) {
+                    if let Some((version, program)) = witness_program(redeem_script) {
+                        if self.script_sig != push_data(redeem_script) {
+                            return Err(VerifyError { phase: Phase::Witness, error: ScriptError::WitnessMalleatedP2SH });
+                        }
+                        progress.phase = Phase::Witness;
+                        return self.witness_script(version, program, true).map_err(in_phase(Phase::Witness));
+                    }
+                }
+                self.end(&stack)
+            }
+            // Unlike legacy scripts, a witness script must leave exactly one true element
+            Phase::Witness => match stack.as_slice() {
+                [top] if as_bool(top) => Ok(None),
+                [_] => Err(VerifyError { phase: Phase::Witness, error: ScriptError::EvalFalse }),
+                _ => Err(VerifyError { phase: Phase::Witness, error: ScriptError::CleanStack }),
+            },
+        }
+    }
+
+    fn is_p2sh(&self) -> bool {
+        self.flags.contains(VerifyFlags::P2SH) && matches!(classify(self.script_pub_key), OutputType::ScriptHash(_))
+    }
+
+    // Checks of a spend without witness program once its last script is run. Witness spends are
+    // checked for a clean stack by their witness script, whatever the scriptSig left.
+    fn end(&self, stack: &[Vec<u8>]) -> Result<Option<PhaseScript>, VerifyError> {
+        if self.flags.contains(VerifyFlags::CLEANSTACK) && stack.len() != 1 {
+            let phase = match self.is_p2sh() {
+                true => Phase::RedeemScript,
+                false => Phase::ScriptPubKey,
+            };
+            return Err(VerifyError { phase, error: ScriptError::CleanStack });
+        }
+        if self.flags.contains(VerifyFlags::WITNESS) && !self.witness.is_empty() {
+            return Err(VerifyError { phase: Phase::Witness, error: ScriptError::WitnessUnexpected });
+        }
+        Ok(None)
+    }
+
+    // BIP141: a 20 bytes v0 program is the hash of the key of an implicit P2PKH script, a 32 bytes one
+    // the sha256 of the script given as last witness item. A 32 bytes v1 program outside of P2SH is a
+    // taproot output key (BIP341). Other versions are left for future soft forks. None when the
+    // program is satisfied without running a script.
+    fn witness_script(&self, version: u8, program: &[u8], is_p2sh: bool) -> Result<Option<PhaseScript>, ScriptError> {
+        let (witness, flags) = (self.witness, self.flags);
+        let mut exec = ExecData::default();
+        let (script, stack, sig_version) = match (version, program.len()) {
+            (0, 32) => {
+                let (witness_script, stack) = witness.split_last().ok_or(ScriptError::WitnessProgramWitnessEmpty)?;
+                if sha256::Hash::hash(witness_script).into_inner() != program {
+                    return Err(ScriptError::WitnessProgramMismatch);
+                }
+                (witness_script.clone(), stack, SigVersion::WitnessV0)
+            }
+            (0, 20) => {
+                if witness.len() != 2 {
+                    return Err(ScriptError::WitnessProgramMismatch);
+                }
+                (key_hash_script(program), witness, SigVersion::WitnessV0)
+            }
+            (0, _) => return Err(ScriptError::WitnessProgramWrongLength),
+            (1, 32) if !is_p2sh && !flags.contains(VerifyFlags::TAPROOT) => return Ok(None),
+            (1, 32) if !is_p2sh => {
+                // With at least two items, a last one starting with 0x50 is an annex, signed but not used
+                let stack = match witness {
+                    [stack @ .., annex] if !stack.is_empty() && annex.first() == Some(&ANNEX_TAG) => {
+                        exec.annex = Some(annex.clone());
+                        stack
+                    }
+                    _ => witness,
+                };
+                match stack {
+                    [] => return Err(ScriptError::WitnessProgramWitnessEmpty),
+                    // Key path: a signature for the output key
+                    [sig] => return check_schnorr_sig(sig, program, self.ctx, SigVersion::Taproot, &exec).map(|_| None),
+                    // Script path: the script and a control block proving that the output key commits to it
+                    [stack @ .., script, control] => {
+                        if !is_valid_control_size(control.len()) {
+                            return Err(ScriptError::TaprootWrongControlSize);
+                        }
+                        exec.tapleaf_hash = tapleaf_hash(control[0] & TAPROOT_LEAF_MASK, script);
+                        if !verify_commitment(control, program, &exec.tapleaf_hash) {
+                            return Err(ScriptError::WitnessProgramMismatch);
+                        }
+                        if control[0] & TAPROOT_LEAF_MASK != TAPROOT_LEAF_TAPSCRIPT {
+                            return match flags.contains(VerifyFlags::DISCOURAGE_UPGRADABLE_TAPROOT_VERSION) {
+                                true => Err(ScriptError::DiscourageUpgradableTaprootVersion),
+                                false => Ok(None),
+                            };
+                        }
+                        exec.validation_weight_left = witness_size(witness) as i64 + VALIDATION_WEIGHT_OFFSET;
+                        // An OP_SUCCESSx anywhere makes the script valid, provided the script parses up to it
+                        let mut pc = 0;
+                        while let Some(item) = parse_one_op(script, &mut pc)? {
+                            if matches!(item, ScriptItem::Opcode(op) if is_op_success(op.code)) {
+                                return match flags.contains(VerifyFlags::DISCOURAGE_OP_SUCCESS) {
+                                    true => Err(ScriptError::DiscourageOpSuccess),
+                                    false => Ok(None),
+                                };
+                            }
+                        }
+                        if stack.len() > MAX_STACK_SIZE {
+                            return Err(ScriptError::StackOverflow);
+                        }
+                        (script.clone(), stack, SigVersion::Tapscript)
+                    }
+                }
+            }
+            _ if flags.contains(VerifyFlags::DISCOURAGE_UPGRADABLE_WITNESS_PROGRAM) => return Err(ScriptError::DiscourageUpgradableWitnessProgram),
+            _ => return Ok(None),
+        };
+        if stack.iter().any(|item| item.len() > MAX_SCRIPT_ELEMENT_SIZE) {
+            return Err(ScriptError::PushSize);
+        }
+        Ok(Some(PhaseScript { phase: Phase::Witness, script, sig_version, stack: stack.to_vec(), exec }))
+    }
+}
+
+// Runs the scripts of a spend one after the other, each with its own alt stack
+pub fn verify(script_sig: &[u8], script_pub_key: &[u8], witness: &[Vec<u8>], ctx: &TxContext, flags: VerifyFlags) -> Result<(), VerifyError> {
+    let phases = Phases { script_sig, script_pub_key, witness, ctx, flags };
+    let mut script = phases.start()?;
+    let mut progress = Progress::default();
+    loop {
+        let mut stack = Stack::new();
+        stack.main.append(&mut script.stack);
+        interpret(&mut stack, &script.script, ctx, flags, script.sig_version, &mut script.exec, Output::Silent).map_err(in_phase(script.phase))?;
+        match phases.next(&mut progress, stack.main)? {
+            Some(next) => script = next,
+            None => return Ok(()),
+        }
+    }
+}
+
+// Script run for a 20 bytes v0 program: P2PKH of the key hash
+pub fn key_hash_script(program: &[u8]) -> Vec<u8> {
+    let mut script = vec![OP_DUP.code, OP_HASH160.code];
+    script.extend(push_data(program));
+    script.extend([OP_EQUALVERIFY.code, OP_CHECKSIG.code]);
+    script
+}
+
+// Serialized size of a witness, as in the transaction
+pub fn witness_size(witness: &[Vec<u8>]) -> usize {
+    let items: usize = witness.iter().map(|item| to_compact_int(item.len() as u64).len() + item.len()).sum();
+    to_compact_int(witness.len() as u64).len() + items
+}
+
+// BIP65: the transaction lock time must be of the same kind (height or time) and at least the one
+// required, and not be disabled by a final input sequence
+fn check_lock_time(lock_time: i64, ctx: &TxContext) -> bool {
+    let tx_lock_time = ctx.tx.lock_time as i64;
+    (tx_lock_time < LOCKTIME_THRESHOLD) == (lock_time < LOCKTIME_THRESHOLD)
+        && lock_time <= tx_lock_time
+        && ctx.tx.inputs[ctx.input_idx].sequence != SEQUENCE_FINAL
+}
+
+// BIP112: the same for the relative lock time of the input (BIP68), which needs a version 2 transaction
+fn check_sequence(sequence: i64, ctx: &TxContext) -> bool {
+    let tx_sequence = ctx.tx.inputs[ctx.input_idx].sequence as i64;
+    if (ctx.tx.version as u32) < 2 || tx_sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+        return false;
+    }
+    let mask = SEQUENCE_LOCKTIME_TYPE_FLAG | SEQUENCE_LOCKTIME_MASK;
+    let (sequence, tx_sequence) = (sequence & mask, tx_sequence & mask);
+    (sequence < SEQUENCE_LOCKTIME_TYPE_FLAG) == (tx_sequence < SEQUENCE_LOCKTIME_TYPE_FLAG) && sequence <= tx_sequence
+}
+
+// Where a script is being run and what its executed opcodes left besides the stacks, so that it
+// can be run one opcode at a time. The script size and opcode count limits do not apply to
+// tapscripts, bounded by their signature budget.
+#[derive(Debug, Clone, Default)]
+pub struct Execution {
+    pub pc: usize,
+    // One entry per open OP_IF, false while in a branch that is not executed
+    pub condition_stack: Vec<bool>,
+    op_count: usize,
+    code_hash_start: usize,
+    opcode_pos: u32,
+}
+
+impl Execution {
+    pub fn start(script: &[u8], sig_version: SigVersion) -> Result<Execution, ScriptError> {
+        if sig_version != SigVersion::Tapscript && script.len() > MAX_SCRIPT_SIZE {
+            return Err(ScriptError::ScriptSize);
+        }
+        Ok(Execution::default())
     }
 
-    while let Some(item) = parse_one_op(script, &mut pc)? {
-        execute = !condition_stack.contains(&false);
+    // Runs the opcode at pc, false at the end of the script
+    pub fn step(&mut self, stack: &mut Stack, script: &[u8], ctx: &TxContext, flags: VerifyFlags, sig_version: SigVersion, exec: &mut ExecData) -> Result<bool, ScriptError> {
+        const SCRIPT_FALSE: [u8; 0] = [];
+        const SCRIPT_TRUE: [u8; 1] = [0x01];
+
+        let is_tapscript = sig_version == SigVersion::Tapscript;
+        let require_minimal = flags.contains(VerifyFlags::MINIMALDATA);
+        let script_nb = |bytes: &[u8]| as_script_nb_sized(bytes, MAX_NUM_SIZE, require_minimal);
+
+        let op_start = self.pc;
+        let item = match parse_one_op(script, &mut self.pc)? {
+            Some(item) => item,
+            None => return Ok(false),
+        };
+        let execute = !self.condition_stack.contains(&false);
 
         match item {
             ScriptItem::ByteArray(b) => {
@@ -228,6 +591,9 @@ pub fn interpret(script: &[u8], verbose: bool) -> Result<(), ScriptError> {
                     return Err(ScriptError::PushSize);
                 }
                 if execute {
+                    if require_minimal && !is_minimal_push(&b, script[op_start]) {
+                        return Err(ScriptError::MinimalData);
+                    }
                     stack.push(b)?
                 }
             }
@@ -235,12 +601,16 @@ pub fn interpret(script: &[u8], verbose: bool) -> Result<(), ScriptError> {
                 if DISABLED_OPCODES.contains(&op) {
                     return Err(ScriptError::DisabledOpcode);
                 }
-
-                if op.code > OP_16.code {
-                    op_count += 1;
+                // Executed or not
+                if op == OP_CODESEPARATOR && sig_version == SigVersion::Base && flags.contains(VerifyFlags::CONST_SCRIPTCODE) {
+                    return Err(ScriptError::OpCodeSeparator);
                 }
-                if op_count > MAX_OPS_PER_SCRIPT {
-                    return Err(ScriptError::OpCount);
+
+                if !is_tapscript && op.code > OP_16.code {
+                    self.op_count += 1;
+                    if self.op_count > MAX_OPS_PER_SCRIPT {
+                        return Err(ScriptError::OpCount);
+                    }
                 }
 
                 if execute || (OP_IF.code <= op.code && op.code <= OP_ENDIF.code) {
@@ -250,38 +620,71 @@ pub fn interpret(script: &[u8], verbose: bool) -> Result<(), ScriptError> {
                         //
                         OP_0 => stack.push(to_script_nb(0))?,
                         OP_1NEGATE => stack.push(to_script_nb(-1))?,
-                        Opcode { code: c } if c >= OP_1.code && c <= OP_16.code => stack.push(to_script_nb((c - OP_1.code + 1) as i64))?,
+                        Opcode { code: c } if (OP_1.code..=OP_16.code).contains(&c) => stack.push(to_script_nb((c - OP_1.code + 1) as i64))?,
 
                         //
                         // Flow Control
                         //
                         OP_NOP => {}
-                        OP_CHECKSEQUENCEVERIFY => {}
-                        OP_CHECKLOCKTIMEVERIFY => {}
-                        OP_NOP1 | OP_NOP4 | OP_NOP5 | OP_NOP6 |
-                        OP_NOP7 | OP_NOP8 | OP_NOP9 | OP_NOP10 => {}
+                        // The lock time is left on the stack, these were NOPs before
+                        OP_CHECKLOCKTIMEVERIFY if flags.contains(VerifyFlags::CHECKLOCKTIMEVERIFY) => {
+                            // Up to 5 bytes, timestamps go past 2^31
+                            let lock_time = as_script_nb_sized(&stack.top(0)?, 5, require_minimal)?;
+                            if lock_time < 0 {
+                                return Err(ScriptError::NegativeLockTime);
+                            }
+                            if !check_lock_time(lock_time, ctx) {
+                                return Err(ScriptError::UnsatisfiedLockTime);
+                            }
+                        }
+                        OP_CHECKSEQUENCEVERIFY if flags.contains(VerifyFlags::CHECKSEQUENCEVERIFY) => {
+                            let sequence = as_script_nb_sized(&stack.top(0)?, 5, require_minimal)?;
+                            if sequence < 0 {
+                                return Err(ScriptError::NegativeLockTime);
+                            }
+                            // With the disable flag, the opcode stays a NOP
+                            if sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG == 0 && !check_sequence(sequence, ctx) {
+                                return Err(ScriptError::UnsatisfiedLockTime);
+                            }
+                        }
+                        OP_NOP1 | OP_CHECKLOCKTIMEVERIFY | OP_CHECKSEQUENCEVERIFY | OP_NOP4 | OP_NOP5 |
+                        OP_NOP6 | OP_NOP7 | OP_NOP8 | OP_NOP9 | OP_NOP10 => {
+                            if flags.contains(VerifyFlags::DISCOURAGE_UPGRADABLE_NOPS) {
+                                return Err(ScriptError::DiscourageUpgradableNops);
+                            }
+                        }
                         OP_IF | OP_NOTIF => {
                             let mut condition = false;
                             if execute {
-                                condition = as_bool(&stack.pop()?);
+                                let value = stack.pop().map_err(|_| ScriptError::UnbalancedConditional)?;
+                                // Tapscript only accepts empty or 0x01 as condition
+                                // and so does segwit v0 with MINIMALIF
+                                let is_minimal = value.is_empty() || value == [1];
+                                if is_tapscript && !is_minimal {
+                                    return Err(ScriptError::TapscriptMinimalIf);
+                                }
+                                if sig_version == SigVersion::WitnessV0 && flags.contains(VerifyFlags::MINIMALIF) && !is_minimal {
+                                    return Err(ScriptError::MinimalIf);
+                                }
+                                condition = as_bool(&value);
                                 if op == OP_NOTIF {
                                     condition = !condition;
                                 }
                             }
-                            condition_stack.push(condition);
+                            self.condition_stack.push(condition);
                         }
                         OP_ELSE => {
-                            if condition_stack.is_empty() {
+                            if self.condition_stack.is_empty() {
                                 return Err(ScriptError::UnbalancedConditional);
                             }
-                            let last = condition_stack.last_mut().unwrap();
+                            let last = self.condition_stack.last_mut().unwrap();
                             *last = !*last;
                         }
                         OP_ENDIF => {
-                            if condition_stack.is_empty() {
+                            if self.condition_stack.is_empty() {
                                 return Err(ScriptError::UnbalancedConditional);
                             }
-                            condition_stack.pop();
+                            self.condition_stack.pop();
                         }
                         OP_VERIFY => {
                             let v = as_bool(&stack.pop()?);
@@ -357,12 +760,18 @@ pub fn interpret(script: &[u8], verbose: bool) -> Result<(), ScriptError> {
                             stack.push(v)?
                         }
                         OP_PICK => {
-                            let n = as_script_nb(&stack.pop()?)?;
+                            let n = script_nb(&stack.pop()?)?;
+                            if n < 0 {
+                                return Err(ScriptError::InvalidStackOperation);
+                            }
                             let v = stack.top(-n)?;
                             stack.push(v)?
                         }
                         OP_ROLL => {
-                            let n = as_script_nb(&stack.pop()?)?;
+                            let n = script_nb(&stack.pop()?)?;
+                            if n < 0 {
+                                return Err(ScriptError::InvalidStackOperation);
+                            }
                             let v = stack.rm_top(-n)?;
                             stack.push(v)?
                         }
@@ -413,7 +822,7 @@ pub fn interpret(script: &[u8], verbose: bool) -> Result<(), ScriptError> {
                         // Arithmetic
                         //
                         OP_1ADD | OP_1SUB | OP_NEGATE | OP_ABS | OP_NOT | OP_0NOTEQUAL => {
-                            let mut v = as_script_nb(&stack.pop()?)?;
+                            let mut v = script_nb(&stack.pop()?)?;
                             match op {
                                 OP_1ADD => v += 1,
                                 OP_1SUB => v -= 1,
@@ -428,8 +837,8 @@ pub fn interpret(script: &[u8], verbose: bool) -> Result<(), ScriptError> {
                         OP_ADD | OP_SUB | OP_BOOLAND | OP_BOOLOR | OP_NUMEQUAL | OP_NUMEQUALVERIFY |
                         OP_NUMNOTEQUAL | OP_LESSTHAN | OP_GREATERTHAN | OP_LESSTHANOREQUAL |
                         OP_GREATERTHANOREQUAL | OP_MIN | OP_MAX => {
-                            let v2 = as_script_nb(&stack.pop()?)?;
-                            let v1 = as_script_nb(&stack.pop()?)?;
+                            let v2 = script_nb(&stack.pop()?)?;
+                            let v1 = script_nb(&stack.pop()?)?;
                             let res = match op {
                                 OP_ADD => v1 + v2,
                                 OP_SUB => v1 - v2,
@@ -456,9 +865,9 @@ pub fn interpret(script: &[u8], verbose: bool) -> Result<(), ScriptError> {
                             }
                         }
                         OP_WITHIN => {
-                            let max = stack.pop()?;
-                            let min = stack.pop()?;
-                            let x = stack.pop()?;
+                            let max = script_nb(&stack.pop()?)?;
+                            let min = script_nb(&stack.pop()?)?;
+                            let x = script_nb(&stack.pop()?)?;
                             let res = (min <= x && x < max) as i64;
                             stack.push(to_script_nb(res))?
                         }
@@ -478,22 +887,132 @@ pub fn interpret(script: &[u8], verbose: bool) -> Result<(), ScriptError> {
                             };
                             stack.push(res)?
                         }
-                        OP_CODESEPARATOR => code_hash_start = pc,
+                        OP_CODESEPARATOR => {
+                            self.code_hash_start = self.pc;
+                            exec.codesep_pos = self.opcode_pos;
+                        }
                         OP_CHECKSIG | OP_CHECKSIGVERIFY => {
                             // Critical part of script verification
                             let pub_key_bytes = stack.pop()?;
                             let signature = stack.pop()?;
 
-                            // part of the script that will be included in the serialized transaction
-                            let mut script_code = script[code_hash_start..].to_vec();
+                            let success = match is_tapscript {
+                                true => check_sig_tapscript(&signature, &pub_key_bytes, ctx, flags, exec)?,
+                                false => {
+                                    // part of the script that will be included in the serialized transaction
+                                    let mut script_code = script[self.code_hash_start..].to_vec();
+
+                                    // we remove the sig from the script_code if present, witness scripts are signed as they are
+                                    if sig_version == SigVersion::Base && find_and_delete(&mut script_code, &push_data(&signature)) > 0
+                                        && flags.contains(VerifyFlags::CONST_SCRIPTCODE) {
+                                        return Err(ScriptError::SigFindAndDelete);
+                                    }
+                                    let success = check_sig(&signature, &pub_key_bytes, &script_code, ctx, flags, sig_version)?;
+                                    if !success && !signature.is_empty() && flags.contains(VerifyFlags::NULLFAIL) {
+                                        return Err(ScriptError::SigNullFail);
+                                    }
+                                    success
+                                }
+                            };
+                            if op == OP_CHECKSIGVERIFY {
+                                if !success {
+                                    return Err(ScriptError::CheckSigVerify);
+                                }
+                            } else {
+                                stack.push(Vec::from(if success { &SCRIPT_TRUE[..] } else { &SCRIPT_FALSE[..] }))?
+                            }
+                        }
+                        // Replaced by OP_CHECKSIGADD in tapscripts
+                        OP_CHECKMULTISIG | OP_CHECKMULTISIGVERIFY if is_tapscript => return Err(ScriptError::TapscriptCheckMultiSig),
+                        OP_CHECKMULTISIG | OP_CHECKMULTISIGVERIFY => {
+                            // Stack: dummy, signatures, signature count, keys, key count
+                            let mut i: i64 = 1;
+                            let mut keys_nb = script_nb(&stack.top(0)?)?;
+                            if !(0..=MAX_PUBKEYS_PER_MULTISIG).contains(&keys_nb) {
+                                return Err(ScriptError::PubKeyCount);
+                            }
+                            // Each key counts as an operation, executed or not
+                            self.op_count += keys_nb as usize;
+                            if self.op_count > MAX_OPS_PER_SCRIPT {
+                                return Err(ScriptError::OpCount);
+                            }
+                            i += 1;
+                            let mut key_idx = i;
+                            i += keys_nb;
+
+                            let mut sigs_nb = script_nb(&stack.top(1 - i)?)?;
+                            if sigs_nb < 0 || sigs_nb > keys_nb {
+                                return Err(ScriptError::SigCount);
+                            }
+                            i += 1;
+                            let mut sig_idx = i;
+                            let (first_sig_idx, sigs_total) = (sig_idx, sigs_nb);
+                            i += sigs_nb;
+                            if (stack.main.len() as i64) < i {
+                                return Err(ScriptError::InvalidStackOperation);
+                            }
 
-                            // we remove the sig from the script_code if present
-                            let to_delete = ScriptItem::ByteArray(signature.clone());
-                            find_and_delete(&mut script_code, &to_delete)?;
+                            // None of the signatures can sign itself
+                            let mut script_code = script[self.code_hash_start..].to_vec();
+                            if sig_version == SigVersion::Base {
+                                for k in 0..sigs_nb {
+                                    if find_and_delete(&mut script_code, &push_data(&stack.top(1 - sig_idx - k)?)) > 0
+                                        && flags.contains(VerifyFlags::CONST_SCRIPTCODE) {
+                                        return Err(ScriptError::SigFindAndDelete);
+                                    }
+                                }
+                            }
 
-                            check_sig(signature, pub_key_bytes, script_code);
+                            // Signatures must match keys in the same order, a key can only be used once
+                            let mut success = true;
+                            while success && sigs_nb > 0 {
+                                let signature = stack.top(1 - sig_idx)?;
+                                let pub_key_bytes = stack.top(1 - key_idx)?;
+                                if check_sig(&signature, &pub_key_bytes, &script_code, ctx, flags, sig_version)? {
+                                    sig_idx += 1;
+                                    sigs_nb -= 1;
+                                }
+                                key_idx += 1;
+                                keys_nb -= 1;
+                                if sigs_nb > keys_nb {
+                                    success = false;
+                                }
+                            }
+
+                            // With NULLFAIL, all the signatures must be empty when the check fails
+                            if !success && flags.contains(VerifyFlags::NULLFAIL) {
+                                for k in 0..sigs_total {
+                                    if !stack.top(1 - first_sig_idx - k)?.is_empty() {
+                                        return Err(ScriptError::SigNullFail);
+                                    }
+                                }
+                            }
+                            for _ in 1..i {
+                                stack.pop()?;
+                            }
+                            // An extra element is consumed because of an off-by-one in the original implementation
+                            let dummy = stack.pop()?;
+                            if flags.contains(VerifyFlags::NULLDUMMY) && !dummy.is_empty() {
+                                return Err(ScriptError::SigNullDummy);
+                            }
+
+                            if op == OP_CHECKMULTISIGVERIFY {
+                                if !success {
+                                    return Err(ScriptError::CheckMultiSigVerify);
+                                }
+                            } else {
+                                stack.push(Vec::from(if success { &SCRIPT_TRUE[..] } else { &SCRIPT_FALSE[..] }))?
+                            }
+                        }
+
+                        // Stack: signature, number, key. Adds 1 to the number when the signature is valid
+                        OP_CHECKSIGADD if is_tapscript => {
+                            let pub_key_bytes = stack.pop()?;
+                            let n = script_nb(&stack.pop()?)?;
+                            let signature = stack.pop()?;
+                            let success = check_sig_tapscript(&signature, &pub_key_bytes, ctx, flags, exec)?;
+                            stack.push(to_script_nb(n + success as i64))?
                         }
-                        OP_CHECKMULTISIG | OP_CHECKMULTISIGVERIFY => {}
 
                         _ => return Err(ScriptError::BadOpcode)
                     }
@@ -501,16 +1020,91 @@ pub fn interpret(script: &[u8], verbose: bool) -> Result<(), ScriptError> {
             }
         }
 
-        if verbose {
-            display_script.remove(0);
-            step_nb += 1;
-            print_state(&stack, &display_script, step_nb);
+        self.opcode_pos += 1;
+        Ok(true)
+    }
+
+    pub fn finish(&self) -> Result<(), ScriptError> {
+        if !self.condition_stack.is_empty() {
+            return Err(ScriptError::UnbalancedConditional);
         }
+        Ok(())
     }
+}
 
-    if !condition_stack.is_empty() {
-        return Err(ScriptError::UnbalancedConditional);
+// Record of an opcode run, stack items in hex from the bottom: the main stack before and after it,
+// the alt and condition stacks after it, and the error when it failed
+#[derive(Debug, Serialize)]
+pub struct TraceStep {
+    pub pc: usize,
+    pub opcode: String,
+    pub stack_before: Vec<String>,
+    pub stack_after: Vec<String>,
+    pub alt_stack: Vec<String>,
+    pub condition_stack: Vec<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+fn hex_items(items: &[Vec<u8>]) -> Vec<String> {
+    items.iter().map(hex::encode).collect()
+}
+
+// Opcode at pc in the notation of to_asm
+fn op_asm(script: &[u8], pc: usize) -> String {
+    let mut end = pc;
+    match parse_one_op(script, &mut end) {
+        Ok(Some(_)) => to_asm(&script[pc..end], false),
+        _ => String::from("[error]"),
     }
+}
 
-    Ok(())
-}
\ No newline at end of file
+// What interpret shows of the execution: nothing, the script and stacks printed after each opcode,
+// or a TraceStep for each of them
+pub enum Output<'a> {
+    Silent,
+    Print,
+    Trace(&'a mut Vec<TraceStep>),
+}
+
+pub fn interpret(stack: &mut Stack, script: &[u8], ctx: &TxContext, flags: VerifyFlags, sig_version: SigVersion, exec: &mut ExecData, mut output: Output) -> Result<(), ScriptError> {
+    let mut execution = Execution::start(script, sig_version)?;
+
+    let mut display_script: Script = Script::new();
+    let mut step_nb: usize = 0;
+    if let Output::Print = output {
+        display_script = parse_script(script)?;
+        print_state(stack, &display_script, step_nb);
+    }
+
+    loop {
+        let pc = execution.pc;
+        let stack_before = match output {
+            Output::Trace(_) => hex_items(&stack.main),
+            _ => Vec::new(),
+        };
+        let result = execution.step(stack, script, ctx, flags, sig_version, exec);
+        if let Output::Trace(trace) = &mut output {
+            if !matches!(result, Ok(false)) {
+                trace.push(TraceStep {
+                    pc,
+                    opcode: op_asm(script, pc),
+                    stack_before,
+                    stack_after: hex_items(&stack.main),
+                    alt_stack: hex_items(&stack.alt),
+                    condition_stack: execution.condition_stack.clone(),
+                    error: result.as_ref().err().map(|e| format!("{:?}", e)),
+                });
+            }
+        }
+        if !result? {
+            break;
+        }
+        if let Output::Print = output {
+            display_script.remove(0);
+            step_nb += 1;
+            print_state(stack, &display_script, step_nb);
+        }
+    }
+    execution.finish()
+}
@@ -3,16 +3,116 @@ pub mod parse;
 pub mod script;
 pub mod interpret;
 pub mod public_key;
+pub mod signature;
+pub mod address;
+pub mod asm;
+pub mod assemble;
+pub mod spend;
+pub mod standard;
+pub mod sighash;
+pub mod flags;
+pub mod sigops;
+pub mod policy;
+pub mod taproot;
+pub mod conformance;
+pub mod debugger;
 
 
-pub(crate) fn main() {
-    let sig_script = "483045022100fcb600ea44edb6b3c9408479c9e29d468bf623e5b465be4d594141b0d7611d2a022069d470033446f0b6c8c85ced09b3e3c2b675e21a0f3017a5fac2ad064a4d1d1e012103dd2162aaf74d3f2e0634ad778380eeecea6ac5f2e53411a128a323ad260d0dc7";
-    let pk_script = "76a9148b6305816c87626a9ac4b972c5c376663b2f5dc888ac";
-    let hex_script = sig_script.to_owned() + pk_script;
-    //let hex_script = "515560606b6c05abcdefabcd";
-    let bin_script = hex::decode(hex_script).unwrap();
-    let _script = parse::parse_script(&bin_script).unwrap();
-    //println!("{:?}", &script);
+// Real mainnet spends (block 702861) and the scripts they spend, shared by the tests
+#[cfg(test)]
+pub(crate) mod mainnet {
+    pub(crate) const P2PKH_TX: &str = "01000000012868a653b61133640d670778681b8b9cbd946997dddd4153eb45e5002a39af24000000006a47304402201bd45608373cdc853edba3ed892d81c7632b029d900cd3a6aa02e78c4559de0e02202dc3f625e9144c6a0dfa4dba0ec45275178aca2924088b3263f9ce15d120c2a501210214d4d14e3b53a1ac11ae61a1cf8a86c8d9495e19009641923d5125be1aee54a3ffffffff0175dc510100000000160014bc152adf1cc44fc0ad2c5b1694a9b0442f80006100000000";
+    pub(crate) const P2PKH_SCRIPT: &str = "76a91430a71576a709538d456781c13461b3ac9dc79fb088ac";
+    pub(crate) const MULTISIG_SCRIPT: &str = "a914c2e135b3011fac78e79d29dfa1d32c79a4d3ca9787";
+    pub(crate) const MULTISIG_TX: &str = "0100000001948e170b0b7b6410d271f377c4d337d773ffb4835f3ed785e06573f1cb7c360c01000000da004830450221009f0819b2717051b792064e86940fde3999220e117598e5af53d1a7bcb027eb6a02205aaa8d94ff99836b96f6cdc2131336264229cce45616c23074cbb98da26fd32901473044022007757fc2514672f51b2d6379a4924bbd5ad159abeafeca4d20d6e7e95e2ecaf402204d37ecd1b9a5daaf799c9a47a72fff45ef9fac26353f76a6ec0b79c3a5dec718014752210235e0cd7369fc01213cd6bc08994e97781a4165f5d938425332a73dad6cc258202102d25ee0d8c899d2f91e0973c6cadedbd4a1223a64bab956d03e4ceddcb87f353f52aeffffffff0127d10b00000000001976a9148f5924c0d8f316c6eaac85ef8ee8789b887347cb88ac00000000";
 
-    let _ = interpret::interpret(&bin_script, false).unwrap();
+    // Mainnet 2-of-3 multisig P2WSH spend, of an 18393430 satoshis output
+    pub(crate) const P2WSH_TX: &str = "010000000001011f97548fbbe7a0db7588a66e18d803d0089315aa7d4cc28360b6ec50ef36718a0100000000ffffffff02df1776000000000017a9146c002a686959067f4866b8fb493ad7970290ab728757d29f0000000000220020701a8d401c84fb13e6baf169d59684e17abd9fa216c8cc5b9fc63d622ff8c58d04004730440220565d170eed95ff95027a69b313758450ba84a01224e1f7f130dda46e94d13f8602207bdd20e307f062594022f12ed5017bbf4a055a06aea91c10110a0e3bb23117fc014730440220647d2dc5b15f60bc37dc42618a370b2a1490293f9e5c8464f53ec4fe1dfe067302203598773895b4b16d37485cbe21b337f4e4b650739880098c592553add7dd4355016952210375e00eb72e29da82b89367947f29ef34afb75e8654f6ea368e0acdfd92976b7c2103a1b26313f430c4b15bb1fdce663207659d8cac749a0e53d70eff01874496feff2103c96d495bfdd5ba4145e3e046fee45e84a8a48ad05bd8dbb395c011a32cf9f88053ae00000000";
+    pub(crate) const P2WSH_SCRIPT: &str = "0020701a8d401c84fb13e6baf169d59684e17abd9fa216c8cc5b9fc63d622ff8c58d";
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bcparse::{parse_transaction, Transaction};
+
+    use super::flags::{standard_flags, VerifyFlags};
+    use super::interpret::{verify, Phase, VerifyError};
+    use super::parse::parse_script;
+    use super::script::{push_data, ScriptError, ScriptItem};
+    use super::sighash::TxContext;
+    use super::mainnet::*;
+
+    // Verifies the first input of a transaction against the script it spends, given in hex
+    fn spend(tx: &Transaction, pk_script: &str, amount: i64, flags: VerifyFlags) -> Result<(), VerifyError> {
+        let sig_script = hex::decode(&tx.inputs[0].signature_script).unwrap();
+        let pk_script = hex::decode(pk_script).unwrap();
+        let witness: Vec<Vec<u8>> = tx.witnesses.first().into_iter().flatten()
+            .map(|item| hex::decode(&item.script).unwrap())
+            .collect();
+        let ctx = TxContext { tx, input_idx: 0, amount, spent_outputs: &[] };
+        verify(&sig_script, &pk_script, &witness, &ctx, flags)
+    }
+
+    fn transaction(tx_hex: &str) -> Transaction {
+        parse_transaction(&hex::decode(tx_hex).unwrap()).unwrap()
+    }
+
+    // Pushes of the scriptSig of the first input
+    fn pushes(tx: &Transaction) -> Vec<Vec<u8>> {
+        parse_script(&hex::decode(&tx.inputs[0].signature_script).unwrap()).unwrap().into_iter()
+            .map(|item| match item {
+                ScriptItem::ByteArray(bytes) => bytes,
+                ScriptItem::Opcode(_) => Vec::new(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn mainnet_spends() {
+        // Legacy spends do not sign the amount
+        spend(&transaction(P2PKH_TX), P2PKH_SCRIPT, 0, standard_flags()).unwrap();
+        // 2-of-2 multisig behind P2SH
+        spend(&transaction(MULTISIG_TX), MULTISIG_SCRIPT, 0, standard_flags()).unwrap();
+        spend(&transaction(P2WSH_TX), P2WSH_SCRIPT, 18393430, standard_flags()).unwrap();
+    }
+
+    #[test]
+    fn non_null_dummy() {
+        let mut tx = transaction(MULTISIG_TX);
+        assert!(tx.inputs[0].signature_script.starts_with("00"));
+        tx.inputs[0].signature_script.replace_range(..2, "51");
+        let e = spend(&tx, MULTISIG_SCRIPT, 0, standard_flags()).unwrap_err();
+        assert_eq!(e.phase, Phase::RedeemScript);
+        assert!(matches!(e.error, ScriptError::SigNullDummy));
+        // Any dummy before BIP147
+        spend(&tx, MULTISIG_SCRIPT, 0, standard_flags().remove(VerifyFlags::NULLDUMMY)).unwrap();
+    }
+
+    #[test]
+    fn multisig_signatures_out_of_key_order() {
+        let mut tx = transaction(MULTISIG_TX);
+        let pushes = pushes(&tx);
+        let (dummy, first, second, redeem_script) = (&pushes[0], &pushes[1], &pushes[2], &pushes[3]);
+        let swapped = [push_data(dummy), push_data(second), push_data(first), push_data(redeem_script)].concat();
+        tx.inputs[0].signature_script = hex::encode(swapped);
+        let e = spend(&tx, MULTISIG_SCRIPT, 0, standard_flags().remove(VerifyFlags::NULLFAIL)).unwrap_err();
+        assert_eq!(e.phase, Phase::RedeemScript);
+        assert!(matches!(e.error, ScriptError::EvalFalse));
+        let e = spend(&tx, MULTISIG_SCRIPT, 0, standard_flags()).unwrap_err();
+        assert!(matches!(e.error, ScriptError::SigNullFail));
+    }
+
+    #[test]
+    fn failing_signature_not_empty() {
+        let mut tx = transaction(P2PKH_TX);
+        let mut pushes = pushes(&tx);
+        // A byte of R, the signature still strict DER with a low S
+        pushes[0][10] ^= 1;
+        tx.inputs[0].signature_script = hex::encode(pushes.iter().map(|push| push_data(push)).collect::<Vec<_>>().concat());
+        let e = spend(&tx, P2PKH_SCRIPT, 0, standard_flags()).unwrap_err();
+        assert_eq!(e.phase, Phase::ScriptPubKey);
+        assert!(matches!(e.error, ScriptError::SigNullFail));
+        let e = spend(&tx, P2PKH_SCRIPT, 0, standard_flags().remove(VerifyFlags::NULLFAIL)).unwrap_err();
+        assert!(matches!(e.error, ScriptError::EvalFalse));
+    }
 }
@@ -213,8 +213,10 @@ pub const OP_NOP8: Opcode = Opcode { code: 0xb7 };
 pub const OP_NOP9: Opcode = Opcode { code: 0xb8 };
 pub const OP_NOP10: Opcode = Opcode { code: 0xb9 };
 
+// Tapscript only (BIP342)
+pub const OP_CHECKSIGADD: Opcode = Opcode { code: 0xba };
+
 // Unassigned
-pub const OP_UNASSIGNED_186: Opcode = Opcode { code: 0xba };
 pub const OP_UNASSIGNED_187: Opcode = Opcode { code: 0xbb };
 pub const OP_UNASSIGNED_188: Opcode = Opcode { code: 0xbc };
 pub const OP_UNASSIGNED_189: Opcode = Opcode { code: 0xbd };
@@ -386,6 +388,7 @@ impl fmt::Display for Opcode {
             OP_CHECKLOCKTIMEVERIFY => write!(f, "CHECKLOCKTIMEVERIFY"),
             OP_CHECKSEQUENCEVERIFY => write!(f, "CHECKSEQUENCEVERIFY"),
             Opcode { code: c } if (0xb3..=0xb9).contains(&c) => write!(f, "NOP{}", c - 0xb3 + 4),
+            OP_CHECKSIGADD => write!(f, "CHECKSIGADD"),
             Opcode { code: c } => write!(f, "UNASSIGNED_{}", c),
         }
     }
@@ -30,17 +30,18 @@ pub fn parse_one_op(bytes: &[u8], pc: &mut usize) -> Result<Option<ScriptItem>,
             }
             OP_PUSH_DATA_2 => {
                 *pc += 2;
-                usize::from_le_bytes(bytes.get(*pc - 2..*pc).ok_or(ScriptError::BadOpcode)?.try_into().unwrap())
+                u16::from_le_bytes(bytes.get(*pc - 2..*pc).ok_or(ScriptError::BadOpcode)?.try_into().map_err(|_| ScriptError::BadOpcode)?) as usize
             }
             OP_PUSH_DATA_4 => {
                 *pc += 4;
-                usize::from_le_bytes(bytes.get(*pc - 4..*pc).ok_or(ScriptError::BadOpcode)?.try_into().unwrap())
+                u32::from_le_bytes(bytes.get(*pc - 4..*pc).ok_or(ScriptError::BadOpcode)?.try_into().map_err(|_| ScriptError::BadOpcode)?) as usize
             }
             _ => 0
         };
 
-        let data = bytes.get(*pc..*pc + byte_nb).ok_or(ScriptError::BadOpcode)?;
-        *pc += byte_nb;
+        let end = pc.checked_add(byte_nb).ok_or(ScriptError::BadOpcode)?;
+        let data = bytes.get(*pc..end).ok_or(ScriptError::BadOpcode)?;
+        *pc = end;
         Ok(Some(ByteArray(Vec::from(data))))
     }
     // OP_PUSH_BYTES_X opcode
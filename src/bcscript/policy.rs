@@ -0,0 +1,285 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+use crate::bcfile;
+use crate::bcparse::{Transaction, TxOutput};
+use crate::bcutils::{reverse_hash, to_compact_int};
+use crate::bcutxo::is_unspendable;
+
+use super::script::is_push_only;
+use super::sigops::WITNESS_SCALE_FACTOR;
+use super::standard::{classify, witness_program, OutputType};
+
+// Default relay policy of Bitcoin Core (policy.h). OP_RETURN outputs are limited in size and number
+// as before v30, under which most mined transactions were relayed.
+const TX_MAX_STANDARD_VERSION: i32 = 3;
+const MAX_STANDARD_TX_WEIGHT: usize = 400_000;
+const MAX_STANDARD_SCRIPTSIG_SIZE: usize = 1650;
+const MAX_STANDARD_MULTISIG_KEYS: usize = 3;
+const MAX_OP_RETURN_RELAY: usize = 83;
+// sat/kvB
+const DUST_RELAY_TX_FEE: i64 = 3000;
+// Outpoint, scriptSig length and sequence of the input spending an output, and the scriptSig of a
+// P2PKH spend, or its discounted witness for a segwit one
+const SPENDING_INPUT_SIZE: usize = 32 + 4 + 1 + 4;
+const P2PKH_SCRIPT_SIG_SIZE: usize = 107;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    Version(i32),
+    Weight(usize),
+    ScriptSigSize { input: usize, size: usize },
+    ScriptSigNotPushOnly(usize),
+    NonStandardOutput(usize),
+    MultisigKeys { output: usize, keys: usize },
+    DataCarrierSize { output: usize, size: usize },
+    // Second OP_RETURN output
+    MultipleOpReturn(usize),
+    Dust { output: usize, value: i64, threshold: i64 },
+}
+
+impl Violation {
+    // Reject reason of Core, shared by all the non standard output scripts
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Violation::Version(_) => "version",
+            Violation::Weight(_) => "tx-size",
+            Violation::ScriptSigSize { .. } => "scriptsig-size",
+            Violation::ScriptSigNotPushOnly(_) => "scriptsig-not-pushonly",
+            Violation::NonStandardOutput(_) | Violation::MultisigKeys { .. } | Violation::DataCarrierSize { .. } => "scriptpubkey",
+            Violation::MultipleOpReturn(_) => "multi-op-return",
+            Violation::Dust { .. } => "dust",
+        }
+    }
+
+    // One name per kind, to count them
+    pub fn name(&self) -> &'static str {
+        match self {
+            Violation::NonStandardOutput(_) => "nonstandard-output",
+            Violation::MultisigKeys { .. } => "bare-multisig-keys",
+            Violation::DataCarrierSize { .. } => "datacarrier-size",
+            other => other.reason(),
+        }
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (", self.reason())?;
+        match self {
+            Violation::Version(version) => write!(f, "version {}", version)?,
+            Violation::Weight(weight) => write!(f, "weight {} over {}", weight, MAX_STANDARD_TX_WEIGHT)?,
+            Violation::ScriptSigSize { input, size } => write!(f, "input {} scriptSig of {} bytes", input, size)?,
+            Violation::ScriptSigNotPushOnly(input) => write!(f, "input {}", input)?,
+            Violation::NonStandardOutput(output) => write!(f, "output {} nonstandard", output)?,
+            Violation::MultisigKeys { output, keys } => write!(f, "output {} bare multisig of {} keys", output, keys)?,
+            Violation::DataCarrierSize { output, size } => write!(f, "output {} OP_RETURN of {} bytes", output, size)?,
+            Violation::MultipleOpReturn(output) => write!(f, "output {}", output)?,
+            Violation::Dust { output, value, threshold } => write!(f, "output {} of {} sat under {}", output, value, threshold)?,
+        }
+        write!(f, ")")
+    }
+}
+
+// GetDustThreshold: the value under which spending the output would cost more than it is worth at
+// the dust relay feerate. OP_RETURN and oversized scripts are never spent and have none.
+pub fn dust_threshold(output: &TxOutput) -> i64 {
+    let script = hex::decode(&output.pub_key_script).unwrap_or_default();
+    if is_unspendable(&script) {
+        return 0;
+    }
+    let output_size = 8 + to_compact_int(script.len() as u64).len() + script.len();
+    let input_size = match witness_program(&script) {
+        Some(_) => SPENDING_INPUT_SIZE + P2PKH_SCRIPT_SIG_SIZE / WITNESS_SCALE_FACTOR,
+        None => SPENDING_INPUT_SIZE + P2PKH_SCRIPT_SIG_SIZE,
+    };
+    DUST_RELAY_TX_FEE * (output_size + input_size) as i64 / 1000
+}
+
+// IsStandardTx of Core, every reason listed instead of the first one: an empty list means the
+// transaction would be relayed as far as it alone can tell. The checks of the inputs against the
+// outputs they spend (AreInputsStandard, IsWitnessStandard) are left out.
+pub fn check_standard(tx: &Transaction) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    if !(1..=TX_MAX_STANDARD_VERSION).contains(&tx.version) {
+        violations.push(Violation::Version(tx.version));
+    }
+    let weight = tx.serialize(false).len() * (WITNESS_SCALE_FACTOR - 1) + tx.serialize(true).len();
+    if weight > MAX_STANDARD_TX_WEIGHT {
+        violations.push(Violation::Weight(weight));
+    }
+    for (input, tx_input) in tx.inputs.iter().enumerate() {
+        let script_sig = hex::decode(&tx_input.signature_script).unwrap_or_default();
+        if script_sig.len() > MAX_STANDARD_SCRIPTSIG_SIZE {
+            violations.push(Violation::ScriptSigSize { input, size: script_sig.len() });
+        }
+        if !is_push_only(&script_sig) {
+            violations.push(Violation::ScriptSigNotPushOnly(input));
+        }
+    }
+    let mut data_outputs = 0;
+    for (output, tx_output) in tx.outputs.iter().enumerate() {
+        let script = hex::decode(&tx_output.pub_key_script).unwrap_or_default();
+        match classify(&script) {
+            OutputType::NonStandard => violations.push(Violation::NonStandardOutput(output)),
+            OutputType::Multisig { keys, .. } if keys.len() > MAX_STANDARD_MULTISIG_KEYS => {
+                violations.push(Violation::MultisigKeys { output, keys: keys.len() });
+            }
+            OutputType::NullData if script.len() > MAX_OP_RETURN_RELAY => {
+                violations.push(Violation::DataCarrierSize { output, size: script.len() });
+            }
+            OutputType::NullData => {
+                data_outputs += 1;
+                if data_outputs == 2 {
+                    violations.push(Violation::MultipleOpReturn(output));
+                }
+            }
+            _ => {
+                let threshold = dust_threshold(tx_output);
+                if tx_output.value < threshold {
+                    violations.push(Violation::Dust { output, value: tx_output.value, threshold });
+                }
+            }
+        }
+    }
+    violations
+}
+
+// Usage: policy [hash | file.json.gz ...]
+// Lists the mined transactions that Core would not relay, in the given blocks or in all the stored
+// ones, then counts them by kind of violation. Coinbases are never relayed and are skipped.
+pub fn main(args: &[String]) {
+    let paths: Vec<String> = match args.is_empty() {
+        true => bcfile::stored_blocks().iter().map(|hash| bcfile::block_file(hash)).collect(),
+        false => args.iter()
+            .map(|arg| match Path::new(arg).exists() {
+                true => arg.clone(),
+                false => bcfile::block_file(arg),
+            })
+            .collect(),
+    };
+    let mut transactions = 0;
+    let mut non_standard = 0;
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for path in &paths {
+        let block = match bcfile::load_block(Path::new(path)) {
+            Ok(block) => block,
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                continue;
+            }
+        };
+        for tx in block.txns.iter().skip(1) {
+            transactions += 1;
+            let violations = check_standard(tx);
+            if violations.is_empty() {
+                continue;
+            }
+            non_standard += 1;
+            for violation in &violations {
+                *counts.entry(violation.name()).or_default() += 1;
+            }
+            let reasons: Vec<String> = violations.iter().map(Violation::to_string).collect();
+            println!("{} {} {}", reverse_hash(&block.hash), reverse_hash(&tx.hash), reasons.join(", "));
+        }
+    }
+    println!("{} blocks, {} transactions, {} non standard", paths.len(), transactions, non_standard);
+    for (name, count) in counts {
+        println!("  {:<24} {}", name, count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bcparse::{parse_transaction, WitnessItem};
+
+    use super::*;
+    use super::super::mainnet::*;
+    use super::super::script::push_data;
+
+    // Standard mainnet P2PKH spend
+    fn tx() -> Transaction {
+        parse_transaction(&hex::decode(P2PKH_TX).unwrap()).unwrap()
+    }
+
+    fn output(script: Vec<u8>, value: i64) -> TxOutput {
+        TxOutput { value, pub_key_script: hex::encode(script), ..Default::default() }
+    }
+
+    fn op_return(size: usize) -> Vec<u8> {
+        [&[0x6a][..], &push_data(&vec![0; size])].concat()
+    }
+
+    #[test]
+    fn standard() {
+        assert_eq!(check_standard(&tx()), vec![]);
+        let mut tx = tx();
+        tx.version = 3;
+        tx.outputs.push(output(op_return(80), 0));
+        assert_eq!(check_standard(&tx), vec![]);
+    }
+
+    #[test]
+    fn each_violation() {
+        let mut version = tx();
+        version.version = 4;
+        assert_eq!(check_standard(&version), vec![Violation::Version(4)]);
+
+        let mut weight = tx();
+        weight.is_segwit = true;
+        weight.witnesses = vec![vec![WitnessItem { script: hex::encode(vec![0; MAX_STANDARD_TX_WEIGHT]) }]];
+        assert!(matches!(check_standard(&weight)[..], [Violation::Weight(w)] if w > MAX_STANDARD_TX_WEIGHT));
+
+        let mut script_sig_size = tx();
+        script_sig_size.inputs[0].signature_script = hex::encode(push_data(&[1; MAX_STANDARD_SCRIPTSIG_SIZE]));
+        let size = MAX_STANDARD_SCRIPTSIG_SIZE + 3;
+        assert_eq!(check_standard(&script_sig_size), vec![Violation::ScriptSigSize { input: 0, size }]);
+
+        let mut not_push_only = tx();
+        not_push_only.inputs[0].signature_script.push_str("61");
+        assert_eq!(check_standard(&not_push_only), vec![Violation::ScriptSigNotPushOnly(0)]);
+
+        let mut non_standard = tx();
+        non_standard.outputs[0] = output(vec![0x51], 100_000);
+        assert_eq!(check_standard(&non_standard), vec![Violation::NonStandardOutput(0)]);
+
+        let mut multisig = tx();
+        let key = push_data(&[2; 33]);
+        multisig.outputs.push(output([&[0x51][..], &key, &key, &key, &key, &[0x54, 0xae]].concat(), 100_000));
+        assert_eq!(check_standard(&multisig), vec![Violation::MultisigKeys { output: 1, keys: 4 }]);
+
+        let mut data_carrier = tx();
+        data_carrier.outputs.push(output(op_return(81), 0));
+        assert_eq!(check_standard(&data_carrier), vec![Violation::DataCarrierSize { output: 1, size: 84 }]);
+
+        let mut op_returns = tx();
+        op_returns.outputs.push(output(op_return(4), 0));
+        op_returns.outputs.push(output(op_return(4), 0));
+        assert_eq!(check_standard(&op_returns), vec![Violation::MultipleOpReturn(2)]);
+
+        let mut dust = tx();
+        dust.outputs[0] = output(hex::decode(P2PKH_SCRIPT).unwrap(), 545);
+        assert_eq!(check_standard(&dust), vec![Violation::Dust { output: 0, value: 545, threshold: 546 }]);
+    }
+
+    #[test]
+    fn dust_thresholds() {
+        assert_eq!(dust_threshold(&output(hex::decode(P2PKH_SCRIPT).unwrap(), 0)), 546);
+        assert_eq!(dust_threshold(&output(hex::decode(MULTISIG_SCRIPT).unwrap(), 0)), 540);
+        assert_eq!(dust_threshold(&output([&[0, 20][..], &[0; 20]].concat(), 0)), 294);
+        assert_eq!(dust_threshold(&output(hex::decode(P2WSH_SCRIPT).unwrap(), 0)), 330);
+        assert_eq!(dust_threshold(&output(op_return(4), 0)), 0);
+    }
+
+    #[test]
+    fn names() {
+        let dust = Violation::Dust { output: 1, value: 10, threshold: 546 };
+        assert_eq!((dust.reason(), dust.name()), ("dust", "dust"));
+        assert_eq!(dust.to_string(), "dust (output 1 of 10 sat under 546)");
+        let multisig = Violation::MultisigKeys { output: 0, keys: 4 };
+        assert_eq!((multisig.reason(), multisig.name()), ("scriptpubkey", "bare-multisig-keys"));
+        assert_eq!(Violation::NonStandardOutput(0).name(), "nonstandard-output");
+        assert_eq!(Violation::DataCarrierSize { output: 0, size: 84 }.name(), "datacarrier-size");
+    }
+}
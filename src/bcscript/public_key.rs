@@ -1,39 +1,205 @@
-pub struct PublicKey {
-    bytes: Vec<u8>,
+use std::fmt;
+
+use secp256k1::{Parity, XOnlyPublicKey};
+
+use super::flags::VerifyFlags;
+use super::script::ScriptError;
+use super::sighash::SigVersion;
+
+// Encodings of a key: 02/03 and X (compressed), 04, X and Y (uncompressed), 06/07 for an even/odd
+// Y then X and Y (hybrid, accepted by OpenSSL and so by consensus without STRICTENC), and the X alone
+// of taproot keys (BIP340)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyFormat {
+    Compressed,
+    Uncompressed,
+    Hybrid,
+    XOnly,
 }
 
-impl From<Vec<u8>> for PublicKey {
-    fn from(bytes: Vec<u8>) -> Self {
-        let len = if bytes.is_empty() {
-            0
-        } else {
-            get_len(bytes[0])
-        };
+impl KeyFormat {
+    pub fn name(&self) -> &'static str {
+        match self {
+            KeyFormat::Compressed => "compressed",
+            KeyFormat::Uncompressed => "uncompressed",
+            KeyFormat::Hybrid => "hybrid",
+            KeyFormat::XOnly => "x-only",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyError {
+    // Size that does not go with the first byte
+    Size(usize),
+    Prefix(u8),
+    // Prefix of a hybrid key telling the wrong parity of Y
+    HybridParity,
+    NotOnCurve,
+}
 
-        if len != 0 && len == bytes.len() {
-            PublicKey { bytes }
-        } else {
-            PublicKey { bytes: Vec::from([0xff]) }
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyError::Size(size) => write!(f, "bad key size {}", size),
+            KeyError::Prefix(prefix) => write!(f, "bad key prefix {:02x}", prefix),
+            KeyError::HybridParity => write!(f, "hybrid key prefix does not match Y"),
+            KeyError::NotOnCurve => write!(f, "not a point of secp256k1"),
         }
     }
 }
 
+// Key parsed as a point of the curve, in the format it was written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PublicKey {
+    Ecdsa(KeyFormat, secp256k1::PublicKey),
+    XOnly(XOnlyPublicKey),
+}
+
+// Format told by the first byte and the size, whether the point is on the curve or not
+pub fn key_format(bytes: &[u8]) -> Option<KeyFormat> {
+    match (bytes.first(), bytes.len()) {
+        (Some(0x02 | 0x03), 33) => Some(KeyFormat::Compressed),
+        (Some(0x04), 65) => Some(KeyFormat::Uncompressed),
+        (Some(0x06 | 0x07), 65) => Some(KeyFormat::Hybrid),
+        _ => None,
+    }
+}
+
+// CPubKey::ValidSize in Core, all that output templates check of their keys
+pub fn valid_size(bytes: &[u8]) -> bool {
+    key_format(bytes).is_some()
+}
+
 impl PublicKey {
-    pub fn len(&self) -> usize {
-        get_len(self.bytes[0])
+    // Keys of ECDSA signatures, as Core's CPubKey and libsecp256k1 accept them
+    pub fn parse(bytes: &[u8]) -> Result<PublicKey, KeyError> {
+        let format = match (key_format(bytes), bytes.first()) {
+            (Some(format), _) => format,
+            (None, Some(0x02..=0x04 | 0x06 | 0x07)) => return Err(KeyError::Size(bytes.len())),
+            (None, Some(prefix)) => return Err(KeyError::Prefix(*prefix)),
+            (None, None) => return Err(KeyError::Size(0)),
+        };
+        if format == KeyFormat::Hybrid && (bytes[0] == 0x07) != (bytes[64] & 1 == 1) {
+            return Err(KeyError::HybridParity);
+        }
+        secp256k1::PublicKey::from_slice(bytes)
+            .map(|key| PublicKey::Ecdsa(format, key))
+            .map_err(|_| KeyError::NotOnCurve)
+    }
+
+    // 32 bytes keys of taproot outputs and tapscript signatures
+    pub fn parse_x_only(bytes: &[u8]) -> Result<PublicKey, KeyError> {
+        if bytes.len() != 32 {
+            return Err(KeyError::Size(bytes.len()));
+        }
+        XOnlyPublicKey::from_slice(bytes).map(PublicKey::XOnly).map_err(|_| KeyError::NotOnCurve)
+    }
+
+    pub fn format(&self) -> KeyFormat {
+        match self {
+            PublicKey::Ecdsa(format, _) => *format,
+            PublicKey::XOnly(_) => KeyFormat::XOnly,
+        }
+    }
+
+    // Same point in compressed form, even Y for x-only keys: the encodings of a key share it
+    pub fn compressed(&self) -> [u8; 33] {
+        match self {
+            PublicKey::Ecdsa(_, key) => key.serialize(),
+            PublicKey::XOnly(key) => key.public_key(Parity::Even).serialize(),
+        }
     }
+}
 
-    pub fn is_valid(&self) -> bool {
-        self.len() != 0
+// STRICTENC rejects hybrid and malformed keys, WITNESS_PUBKEYTYPE uncompressed ones in segwit v0
+// scripts. Encodings only: a well encoded key off the curve makes the check fail, not the script.
+pub fn check_pub_key_encoding(pub_key: &[u8], flags: VerifyFlags, sig_version: SigVersion) -> Result<(), ScriptError> {
+    let format = key_format(pub_key);
+    if flags.contains(VerifyFlags::STRICTENC) && !matches!(format, Some(KeyFormat::Compressed | KeyFormat::Uncompressed)) {
+        return Err(ScriptError::PubKeyType);
+    }
+    if flags.contains(VerifyFlags::WITNESS_PUBKEYTYPE) && sig_version == SigVersion::WitnessV0 && format != Some(KeyFormat::Compressed) {
+        return Err(ScriptError::WitnessPubKeyType);
     }
+    Ok(())
 }
 
-fn get_len(first_byte: u8) -> usize {
-    if first_byte == 2 || first_byte == 3 {
-        33
-    } else if first_byte == 4 || first_byte == 6 || first_byte == 7 {
-        65
-    } else {
-        0
+// Usage: pubkey <hex> ...
+// Format of each key and whether it is on the curve, 32 bytes keys read as x-only ones
+pub fn main(args: &[String]) {
+    for arg in args {
+        let bytes = hex::decode(arg).unwrap_or_default();
+        let key = match bytes.len() {
+            32 => PublicKey::parse_x_only(&bytes),
+            _ => PublicKey::parse(&bytes),
+        };
+        match key {
+            Ok(key) => println!("{}: {} key, point {}", arg, key.format().name(), hex::encode(key.compressed())),
+            Err(e) => println!("{}: invalid, {}", arg, e),
+        }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Generator of secp256k1, its Y is even
+    const G_X: &str = "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+    const G_Y: &str = "483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8";
+
+    fn key(hex: &str) -> Vec<u8> {
+        hex::decode(hex).unwrap()
+    }
+
+    #[test]
+    fn formats() {
+        let compressed = PublicKey::parse(&key(&format!("02{}", G_X))).unwrap();
+        assert_eq!(compressed.format(), KeyFormat::Compressed);
+        let uncompressed = PublicKey::parse(&key(&format!("04{}{}", G_X, G_Y))).unwrap();
+        assert_eq!(uncompressed.format(), KeyFormat::Uncompressed);
+        let hybrid = PublicKey::parse(&key(&format!("06{}{}", G_X, G_Y))).unwrap();
+        assert_eq!(hybrid.format(), KeyFormat::Hybrid);
+        let x_only = PublicKey::parse_x_only(&key(G_X)).unwrap();
+        assert_eq!(x_only.format(), KeyFormat::XOnly);
+        // All the same point
+        for key in [uncompressed, hybrid, x_only] {
+            assert_eq!(key.compressed(), compressed.compressed());
+        }
+        assert_eq!(hex::encode(compressed.compressed()), format!("02{}", G_X));
+    }
+
+    #[test]
+    fn invalid_keys() {
+        assert_eq!(PublicKey::parse(&key(&format!("07{}{}", G_X, G_Y))), Err(KeyError::HybridParity));
+        assert_eq!(PublicKey::parse(&key(&format!("05{}", G_X))), Err(KeyError::Prefix(5)));
+        assert_eq!(PublicKey::parse(&key(G_X)), Err(KeyError::Prefix(0x79)));
+        assert_eq!(PublicKey::parse(&key(&format!("04{}", G_X))), Err(KeyError::Size(33)));
+        assert_eq!(PublicKey::parse(&[]), Err(KeyError::Size(0)));
+        assert_eq!(PublicKey::parse_x_only(&key(&format!("02{}", G_X))), Err(KeyError::Size(33)));
+
+        // Well encoded, off the curve: Y + 1, and an X over the field size
+        let off_curve = format!("{}{}", G_X, &G_Y[..62]) + "b9";
+        assert_eq!(PublicKey::parse(&key(&format!("04{}", off_curve))), Err(KeyError::NotOnCurve));
+        assert_eq!(PublicKey::parse(&key(&format!("07{}", off_curve))), Err(KeyError::NotOnCurve));
+        assert_eq!(PublicKey::parse_x_only(&[0xff; 32]), Err(KeyError::NotOnCurve));
+        assert!(valid_size(&key(&format!("04{}", off_curve))));
+    }
+
+    #[test]
+    fn encoding_flags() {
+        let compressed = key(&format!("03{}", G_X));
+        let uncompressed = key(&format!("04{}{}", G_X, G_Y));
+        let hybrid = key(&format!("06{}{}", G_X, G_Y));
+        let check = |key: &[u8], flags, sig_version| check_pub_key_encoding(key, flags, sig_version);
+
+        assert!(matches!(check(&hybrid, VerifyFlags::NONE, SigVersion::Base), Ok(())));
+        assert!(matches!(check(&hybrid, VerifyFlags::STRICTENC, SigVersion::Base), Err(ScriptError::PubKeyType)));
+        assert!(matches!(check(&[0x02; 5], VerifyFlags::STRICTENC, SigVersion::Base), Err(ScriptError::PubKeyType)));
+        assert!(matches!(check(&uncompressed, VerifyFlags::STRICTENC, SigVersion::Base), Ok(())));
+        assert!(matches!(check(&uncompressed, VerifyFlags::WITNESS_PUBKEYTYPE, SigVersion::Base), Ok(())));
+        assert!(matches!(check(&uncompressed, VerifyFlags::WITNESS_PUBKEYTYPE, SigVersion::WitnessV0), Err(ScriptError::WitnessPubKeyType)));
+        assert!(matches!(check(&compressed, VerifyFlags::STRICTENC | VerifyFlags::WITNESS_PUBKEYTYPE, SigVersion::WitnessV0), Ok(())));
+    }
+}
@@ -1,6 +1,7 @@
 use std::fmt::{Debug, Formatter};
 
 use super::opcodes::Opcode as op;
+use super::opcodes::{OP_0, OP_16, OP_PUSH_DATA_1, OP_PUSH_DATA_2};
 use super::parse::parse_one_op;
 
 pub const MAX_NUM_SIZE: usize = 4;
@@ -8,12 +9,23 @@ pub const MAX_SCRIPT_SIZE: usize = 10000;
 pub const MAX_STACK_SIZE: usize = 1000;
 pub const MAX_OPS_PER_SCRIPT: usize = 201;
 pub const MAX_SCRIPT_ELEMENT_SIZE: usize = 520;
-
-#[derive(Debug)]
+pub const MAX_PUBKEYS_PER_MULTISIG: i64 = 20;
+
+// Lock times below are block heights, above timestamps
+pub const LOCKTIME_THRESHOLD: i64 = 500_000_000;
+pub const SEQUENCE_FINAL: u32 = 0xffffffff;
+// Relative lock times (BIP68): disabled by the top bit, in units of 512 seconds instead of blocks
+// with the type flag, over the 16 low bits
+pub const SEQUENCE_LOCKTIME_DISABLE_FLAG: i64 = 1 << 31;
+pub const SEQUENCE_LOCKTIME_TYPE_FLAG: i64 = 1 << 22;
+pub const SEQUENCE_LOCKTIME_MASK: i64 = 0xffff;
+
+#[derive(Debug, Clone)]
 pub enum ScriptError {
     InvalidStackOperation,
     InvalidAltStackOperation,
     ScriptNumberOverflow,
+    ScriptNumberNotMinimal,
     StackOverflow,
     ScriptSize,
     PushSize,
@@ -25,6 +37,44 @@ pub enum ScriptError {
     Verify,
     OpReturn,
     OpCount,
+    CheckSigVerify,
+    CheckMultiSigVerify,
+    PubKeyCount,
+    SigCount,
+    SigNullDummy,
+    SigDer,
+    SigHighS,
+    SigHashType,
+    SigNullFail,
+    SigFindAndDelete,
+    MinimalData,
+    MinimalIf,
+    NegativeLockTime,
+    UnsatisfiedLockTime,
+    OpCodeSeparator,
+    DiscourageUpgradableNops,
+    DiscourageUpgradableWitnessProgram,
+    DiscourageUpgradableTaprootVersion,
+    DiscourageOpSuccess,
+    DiscourageUpgradablePubKeyType,
+    EvalFalse,
+    CleanStack,
+    WitnessProgramWrongLength,
+    WitnessProgramWitnessEmpty,
+    WitnessProgramMismatch,
+    WitnessMalleated,
+    WitnessMalleatedP2SH,
+    WitnessUnexpected,
+    SigPushOnly,
+    PubKeyType,
+    WitnessPubKeyType,
+    SchnorrSig,
+    SchnorrSigSize,
+    SchnorrSigHashType,
+    TaprootWrongControlSize,
+    TapscriptValidationWeight,
+    TapscriptCheckMultiSig,
+    TapscriptMinimalIf,
 }
 
 pub type Script = Vec<ScriptItem>;
@@ -45,19 +95,70 @@ impl Debug for ScriptItem {
     }
 }
 
-pub fn find_and_delete(script: &mut Vec<u8>, to_delete: &ScriptItem) -> Result<usize, ScriptError> {
+// Serialized push of `bytes`, as Core's `CScript() << bytes`
+pub fn push_data(bytes: &[u8]) -> Vec<u8> {
+    let mut push = match bytes.len() {
+        len if len < 0x4c => vec![len as u8],
+        len if len <= 0xff => vec![0x4c, len as u8],
+        len if len <= 0xffff => [&[0x4d][..], &(len as u16).to_le_bytes()].concat(),
+        len => [&[0x4e][..], &(len as u32).to_le_bytes()].concat(),
+    };
+    push.extend_from_slice(bytes);
+    push
+}
+
+// Whether data is pushed by the shortest opcode: OP_0, OP_1 to OP_16 and OP_1NEGATE for the values
+// they push, then a direct push up to 75 bytes and the smallest OP_PUSHDATA
+pub fn is_minimal_push(data: &[u8], opcode: u8) -> bool {
+    match data {
+        [] => opcode == OP_0.code,
+        [1..=16] | [0x81] => false,
+        _ if data.len() <= 75 => opcode as usize == data.len(),
+        _ if data.len() <= 0xff => opcode == OP_PUSH_DATA_1.code,
+        _ if data.len() <= 0xffff => opcode == OP_PUSH_DATA_2.code,
+        _ => true,
+    }
+}
+
+// Only pushes, small integers and OP_RESERVED (all the opcodes up to OP_16), as Core's IsPushOnly
+pub fn is_push_only(script: &[u8]) -> bool {
     let mut pc = 0;
-    let mut old_pc = pc;
-    let mut occurences_nb = 0;
+    loop {
+        match parse_one_op(script, &mut pc) {
+            Ok(Some(ScriptItem::Opcode(opcode))) if opcode.code > OP_16.code => return false,
+            Ok(Some(_)) => {}
+            Ok(None) => return true,
+            Err(_) => return false,
+        }
+    }
+}
 
-    while let Some(item) = parse_one_op(script, &mut pc)? {
-        if item == *to_delete {
-            script.drain(old_pc..pc);
+// Removes the serialized `pattern` wherever it starts at an opcode boundary, repeated occurrences
+// included, and stops at the first push that cannot be parsed (FindAndDelete in Core)
+pub fn find_and_delete(script: &mut Vec<u8>, pattern: &[u8]) -> usize {
+    if pattern.is_empty() {
+        return 0;
+    }
+    let mut result = Vec::with_capacity(script.len());
+    let mut occurences_nb = 0;
+    let mut pc = 0;
+    let mut kept = 0;
+    loop {
+        result.extend_from_slice(&script[kept..pc]);
+        while script[pc..].starts_with(pattern) {
+            pc += pattern.len();
             occurences_nb += 1;
         }
-        old_pc = pc;
+        kept = pc;
+        if !matches!(parse_one_op(script, &mut pc), Ok(Some(_))) {
+            break;
+        }
+    }
+    if occurences_nb > 0 {
+        result.extend_from_slice(&script[kept..]);
+        *script = result;
     }
-    Ok(occurences_nb)
+    occurences_nb
 }
 
 // Convert an int to the Script Number format used on the stack
@@ -89,17 +190,32 @@ pub fn to_script_nb(value: i64) -> Vec<u8> {
 // Convert a Script Number to an int
 // Only numbers of at most 4 bytes are accepted
 pub fn as_script_nb(bytes: &[u8]) -> Result<i64, ScriptError> {
-    if bytes.len() > MAX_NUM_SIZE {
+    as_script_nb_sized(bytes, MAX_NUM_SIZE, false)
+}
+
+// Same with a size limit of its own (5 bytes for lock times), and optionally rejecting numbers
+// that could be shorter: padded with zeros, or a negative zero
+pub fn as_script_nb_sized(bytes: &[u8], max_size: usize, require_minimal: bool) -> Result<i64, ScriptError> {
+    if bytes.len() > max_size {
         return Err(ScriptError::ScriptNumberOverflow);
     }
+    // The last byte only holds the sign, which is not needed unless the byte before uses its high bit
+    let padded = match bytes {
+        [] => false,
+        [last] => last & 0x7f == 0,
+        [.., before, last] => last & 0x7f == 0 && before & 0x80 == 0,
+    };
+    if require_minimal && padded {
+        return Err(ScriptError::ScriptNumberNotMinimal);
+    }
 
     if bytes.is_empty() {
         return Ok(0);
     }
 
     let mut result = 0;
-    for i in 0..bytes.len() {
-        result |= (bytes[i] as i64) << (8 * i);
+    for (i, byte) in bytes.iter().enumerate() {
+        result |= (*byte as i64) << (8 * i);
     }
 
     if bytes.last().unwrap() & 0x80 != 0 {
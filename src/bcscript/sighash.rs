@@ -0,0 +1,260 @@
+use bitcoin_hashes::{Hash, sha256, sha256d};
+
+use crate::bcparse::{Transaction, TxInput, TxOutput};
+use crate::bcutils::to_compact_int;
+
+use super::opcodes::OP_CODESEPARATOR;
+use super::parse::parse_one_op;
+use super::script::ScriptItem;
+use super::taproot::tagged_hash;
+
+pub const SIGHASH_NONE: u32 = 0x02;
+pub const SIGHASH_SINGLE: u32 = 0x03;
+pub const SIGHASH_ANYONECANPAY: u32 = 0x80;
+const SIGHASH_BASE_MASK: u32 = 0x1f;
+
+// 1 as a little endian uint256: what gets signed when SIGHASH_SINGLE has no matching output
+const SIGHASH_ONE: [u8; 32] = {
+    let mut one = [0; 32];
+    one[0] = 1;
+    one
+};
+
+// Transaction and input the signatures of a script are checked against, with the amount of the
+// spent output (only signed by segwit inputs). Taproot signatures commit to all the outputs spent
+// by the transaction, in input order; without them, they cannot be checked.
+pub struct TxContext<'a> {
+    pub tx: &'a Transaction,
+    pub input_idx: usize,
+    pub amount: i64,
+    pub spent_outputs: &'a [TxOutput],
+}
+
+// Digest algorithm of the script being run: original one, BIP143 for witness v0 scripts, BIP341 for
+// taproot key path spends and its BIP342 extension for tapscripts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SigVersion {
+    Base,
+    WitnessV0,
+    Taproot,
+    Tapscript,
+}
+
+// What a taproot signature commits to besides the transaction, and the signature budget left to
+// the tapscript being run
+#[derive(Debug, Clone)]
+pub struct ExecData {
+    pub annex: Option<Vec<u8>>,
+    pub tapleaf_hash: [u8; 32],
+    // Position (in opcodes) of the last executed OP_CODESEPARATOR
+    pub codesep_pos: u32,
+    pub validation_weight_left: i64,
+}
+
+impl Default for ExecData {
+    fn default() -> ExecData {
+        ExecData { annex: None, tapleaf_hash: [0; 32], codesep_pos: u32::MAX, validation_weight_left: 0 }
+    }
+}
+
+fn write_var_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend(to_compact_int(bytes.len() as u64));
+    out.extend_from_slice(bytes);
+}
+
+fn write_outpoint(out: &mut Vec<u8>, input: &TxInput) {
+    out.extend(hex::decode(&input.prev_output.hash).unwrap_or_default());
+    out.extend(input.prev_output.idx.to_le_bytes());
+}
+
+fn write_output(out: &mut Vec<u8>, output: &TxOutput) {
+    out.extend(output.value.to_le_bytes());
+    write_var_bytes(out, &hex::decode(&output.pub_key_script).unwrap_or_default());
+}
+
+fn hash_of(out: Vec<u8>) -> [u8; 32] {
+    sha256d::Hash::hash(&out).into_inner()
+}
+
+// The script code is signed without its OP_CODESEPARATORs; as in Core, what follows a push that
+// cannot be parsed is kept as it is
+fn write_script_code(out: &mut Vec<u8>, script_code: &[u8]) {
+    let mut stripped = Vec::with_capacity(script_code.len());
+    let mut pc = 0;
+    let mut kept = 0;
+    loop {
+        let start = pc;
+        match parse_one_op(script_code, &mut pc) {
+            Ok(Some(ScriptItem::Opcode(OP_CODESEPARATOR))) => {
+                stripped.extend_from_slice(&script_code[kept..start]);
+                kept = pc;
+            }
+            Ok(Some(_)) => {}
+            _ => break,
+        }
+    }
+    stripped.extend_from_slice(&script_code[kept..]);
+    write_var_bytes(out, &stripped);
+}
+
+// Original transaction digest, SignatureHash in Core for scripts that are not witness ones.
+// Includes the SIGHASH_SINGLE bug: an input without an output at the same index signs 1.
+pub fn legacy_sighash(tx: &Transaction, input_idx: usize, script_code: &[u8], hash_type: u32) -> [u8; 32] {
+    let base_type = hash_type & SIGHASH_BASE_MASK;
+    let anyone_can_pay = hash_type & SIGHASH_ANYONECANPAY != 0;
+    if input_idx >= tx.inputs.len() || (base_type == SIGHASH_SINGLE && input_idx >= tx.outputs.len()) {
+        return SIGHASH_ONE;
+    }
+
+    let mut out = Vec::new();
+    out.extend(tx.version.to_le_bytes());
+
+    // With ANYONECANPAY, only the input being signed
+    let inputs: Vec<usize> = match anyone_can_pay {
+        true => vec![input_idx],
+        false => (0..tx.inputs.len()).collect(),
+    };
+    out.extend(to_compact_int(inputs.len() as u64));
+    for i in inputs {
+        let input = &tx.inputs[i];
+        write_outpoint(&mut out, input);
+        match i == input_idx {
+            true => write_script_code(&mut out, script_code),
+            false => write_var_bytes(&mut out, &[]),
+        }
+        // Other inputs can be updated at will with NONE and SINGLE
+        let sequence = match i != input_idx && (base_type == SIGHASH_NONE || base_type == SIGHASH_SINGLE) {
+            true => 0,
+            false => input.sequence,
+        };
+        out.extend(sequence.to_le_bytes());
+    }
+
+    // No output with NONE, with SINGLE the one at the input index, the previous ones being blanked
+    let outputs = match base_type {
+        SIGHASH_NONE => 0,
+        SIGHASH_SINGLE => input_idx + 1,
+        _ => tx.outputs.len(),
+    };
+    out.extend(to_compact_int(outputs as u64));
+    for (i, output) in tx.outputs.iter().take(outputs).enumerate() {
+        if base_type == SIGHASH_SINGLE && i != input_idx {
+            out.extend((-1i64).to_le_bytes());
+            write_var_bytes(&mut out, &[]);
+        } else {
+            write_output(&mut out, output);
+        }
+    }
+    out.extend(tx.lock_time.to_le_bytes());
+    out.extend(hash_type.to_le_bytes());
+    hash_of(out)
+}
+
+// BIP143 digest of witness v0 inputs: commits to the spent amount, the script code is signed as it
+// is (no OP_CODESEPARATOR removal) and SIGHASH_SINGLE without matching output signs no output
+pub fn segwit_v0_sighash(tx: &Transaction, input_idx: usize, script_code: &[u8], amount: i64, hash_type: u32) -> [u8; 32] {
+    let base_type = hash_type & SIGHASH_BASE_MASK;
+    let anyone_can_pay = hash_type & SIGHASH_ANYONECANPAY != 0;
+    let input = &tx.inputs[input_idx];
+
+    let mut hash_prevouts = [0; 32];
+    let mut hash_sequence = [0; 32];
+    let mut hash_outputs = [0; 32];
+    if !anyone_can_pay {
+        let mut prevouts = Vec::with_capacity(36 * tx.inputs.len());
+        tx.inputs.iter().for_each(|input| write_outpoint(&mut prevouts, input));
+        hash_prevouts = hash_of(prevouts);
+        if base_type != SIGHASH_NONE && base_type != SIGHASH_SINGLE {
+            hash_sequence = hash_of(tx.inputs.iter().flat_map(|input| input.sequence.to_le_bytes()).collect());
+        }
+    }
+    if base_type != SIGHASH_NONE && base_type != SIGHASH_SINGLE {
+        let mut outputs = Vec::new();
+        tx.outputs.iter().for_each(|output| write_output(&mut outputs, output));
+        hash_outputs = hash_of(outputs);
+    } else if base_type == SIGHASH_SINGLE && input_idx < tx.outputs.len() {
+        let mut output = Vec::new();
+        write_output(&mut output, &tx.outputs[input_idx]);
+        hash_outputs = hash_of(output);
+    }
+
+    let mut out = Vec::new();
+    out.extend(tx.version.to_le_bytes());
+    out.extend(hash_prevouts);
+    out.extend(hash_sequence);
+    write_outpoint(&mut out, input);
+    write_var_bytes(&mut out, script_code);
+    out.extend(amount.to_le_bytes());
+    out.extend(input.sequence.to_le_bytes());
+    out.extend(hash_outputs);
+    out.extend(tx.lock_time.to_le_bytes());
+    out.extend(hash_type.to_le_bytes());
+    hash_of(out)
+}
+
+fn single_hash(out: Vec<u8>) -> [u8; 32] {
+    sha256::Hash::hash(&out).into_inner()
+}
+
+// BIP341 digest, with the tapscript extension (BIP342). None for an undefined hash type, SIGHASH_SINGLE
+// without matching output, or when the spent outputs are not known.
+pub fn taproot_sighash(ctx: &TxContext, hash_type: u8, sig_version: SigVersion, exec: &ExecData) -> Option<[u8; 32]> {
+    let tx = ctx.tx;
+    let base_type = (hash_type & 0x03) as u32;
+    let anyone_can_pay = hash_type as u32 & SIGHASH_ANYONECANPAY != 0;
+    if !matches!(hash_type, 0x00..=0x03 | 0x81..=0x83) || ctx.spent_outputs.len() != tx.inputs.len() {
+        return None;
+    }
+
+    // Epoch 0
+    let mut out = vec![0, hash_type];
+    out.extend(tx.version.to_le_bytes());
+    out.extend(tx.lock_time.to_le_bytes());
+    if !anyone_can_pay {
+        let mut prevouts = Vec::with_capacity(36 * tx.inputs.len());
+        tx.inputs.iter().for_each(|input| write_outpoint(&mut prevouts, input));
+        out.extend(single_hash(prevouts));
+        out.extend(single_hash(ctx.spent_outputs.iter().flat_map(|output| output.value.to_le_bytes()).collect()));
+        let mut scripts = Vec::new();
+        ctx.spent_outputs.iter()
+            .for_each(|output| write_var_bytes(&mut scripts, &hex::decode(&output.pub_key_script).unwrap_or_default()));
+        out.extend(single_hash(scripts));
+        out.extend(single_hash(tx.inputs.iter().flat_map(|input| input.sequence.to_le_bytes()).collect()));
+    }
+    if base_type != SIGHASH_NONE && base_type != SIGHASH_SINGLE {
+        let mut outputs = Vec::new();
+        tx.outputs.iter().for_each(|output| write_output(&mut outputs, output));
+        out.extend(single_hash(outputs));
+    }
+
+    let ext_flag = match sig_version {
+        SigVersion::Tapscript => 1,
+        _ => 0,
+    };
+    out.push(ext_flag << 1 | exec.annex.is_some() as u8);
+    if anyone_can_pay {
+        let input = &tx.inputs[ctx.input_idx];
+        write_outpoint(&mut out, input);
+        write_output(&mut out, &ctx.spent_outputs[ctx.input_idx]);
+        out.extend(input.sequence.to_le_bytes());
+    } else {
+        out.extend((ctx.input_idx as u32).to_le_bytes());
+    }
+    if let Some(annex) = &exec.annex {
+        let mut serialized = Vec::new();
+        write_var_bytes(&mut serialized, annex);
+        out.extend(single_hash(serialized));
+    }
+    if base_type == SIGHASH_SINGLE {
+        let mut output = Vec::new();
+        write_output(&mut output, tx.outputs.get(ctx.input_idx)?);
+        out.extend(single_hash(output));
+    }
+    if sig_version == SigVersion::Tapscript {
+        out.extend(exec.tapleaf_hash);
+        // Key version
+        out.push(0);
+        out.extend(exec.codesep_pos.to_le_bytes());
+    }
+    Some(tagged_hash("TapSighash", &out))
+}
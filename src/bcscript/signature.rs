@@ -0,0 +1,249 @@
+use std::fmt;
+
+use secp256k1::{ecdsa, schnorr};
+
+use super::flags::VerifyFlags;
+use super::script::ScriptError;
+use super::sighash::SIGHASH_ANYONECANPAY;
+use super::spend::sighash_name;
+
+const SIGHASH_DEFAULT: u8 = 0x00;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SigError {
+    Empty,
+    // Not BIP66 DER
+    StrictDer,
+    // Not even what OpenSSL used to parse
+    LaxDer,
+    SchnorrSize(usize),
+    // 65 bytes Schnorr signature with an explicit SIGHASH_DEFAULT
+    SchnorrHashType,
+}
+
+impl fmt::Display for SigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SigError::Empty => write!(f, "empty signature"),
+            SigError::StrictDer => write!(f, "not strict DER"),
+            SigError::LaxDer => write!(f, "not DER even leniently parsed"),
+            SigError::SchnorrSize(size) => write!(f, "bad Schnorr signature size {}", size),
+            SigError::SchnorrHashType => write!(f, "explicit SIGHASH_DEFAULT"),
+        }
+    }
+}
+
+// ECDSA signature of a script, DER encoded R and S followed by the hash type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EcdsaSignature {
+    pub signature: ecdsa::Signature,
+    pub hash_type: u8,
+}
+
+// BIP340 signature, with the hash type that follows it or SIGHASH_DEFAULT when there is none
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SchnorrSignature {
+    pub signature: schnorr::Signature,
+    pub hash_type: u8,
+}
+
+// BIP66 strict DER encoding, hash type byte included: 0x30 <length> 0x02 <length> R 0x02 <length> S,
+// with R and S positive and without padding
+pub fn is_strict_der(sig: &[u8]) -> bool {
+    if !(9..=73).contains(&sig.len()) || sig[0] != 0x30 || sig[1] as usize != sig.len() - 3 {
+        return false;
+    }
+    let r_len = sig[3] as usize;
+    if 5 + r_len >= sig.len() {
+        return false;
+    }
+    let s_len = sig[5 + r_len] as usize;
+    if r_len + s_len + 7 != sig.len() {
+        return false;
+    }
+    let is_valid_integer = |int: &[u8]| match int {
+        [] => false,
+        [first, ..] if first & 0x80 != 0 => false,
+        [0x00, second, ..] => second & 0x80 != 0,
+        _ => true,
+    };
+    sig[2] == 0x02 && is_valid_integer(&sig[4..4 + r_len]) && sig[4 + r_len] == 0x02 && is_valid_integer(&sig[6 + r_len..6 + r_len + s_len])
+}
+
+impl EcdsaSignature {
+    // What consensus accepted before BIP66: any length encodings, padded or negative integers and
+    // garbage after them, as Core parses signatures whatever the flags (ecdsa_signature_parse_der_lax)
+    pub fn parse_lax(sig: &[u8]) -> Result<EcdsaSignature, SigError> {
+        let (hash_type, der) = sig.split_last().ok_or(SigError::Empty)?;
+        let signature = ecdsa::Signature::from_der_lax(der).map_err(|_| SigError::LaxDer)?;
+        Ok(EcdsaSignature { signature, hash_type: *hash_type })
+    }
+
+    // Signatures allowed by DERSIG, the values read as Core does once the encoding is checked
+    pub fn parse_strict(sig: &[u8]) -> Result<EcdsaSignature, SigError> {
+        if sig.is_empty() {
+            return Err(SigError::Empty);
+        }
+        if !is_strict_der(sig) {
+            return Err(SigError::StrictDer);
+        }
+        EcdsaSignature::parse_lax(sig)
+    }
+
+    // Signatures with a S value above half the curve order have a twin with the opposite value
+    pub fn is_low_s(&self) -> bool {
+        let mut normalized = self.signature;
+        normalized.normalize_s();
+        normalized == self.signature
+    }
+}
+
+impl SchnorrSignature {
+    // 64 bytes for SIGHASH_DEFAULT, 65 with another hash type (BIP341)
+    pub fn parse(sig: &[u8]) -> Result<SchnorrSignature, SigError> {
+        let (bytes, hash_type) = match sig.len() {
+            64 => (sig, SIGHASH_DEFAULT),
+            65 if sig[64] != SIGHASH_DEFAULT => (&sig[..64], sig[64]),
+            65 => return Err(SigError::SchnorrHashType),
+            size => return Err(SigError::SchnorrSize(size)),
+        };
+        let signature = schnorr::Signature::from_slice(bytes).map_err(|_| SigError::SchnorrSize(sig.len()))?;
+        Ok(SchnorrSignature { signature, hash_type })
+    }
+}
+
+// An empty signature is always well encoded, it only makes the check fail
+pub fn check_signature_encoding(sig: &[u8], flags: VerifyFlags) -> Result<(), ScriptError> {
+    if sig.is_empty() {
+        return Ok(());
+    }
+    if flags.intersects(VerifyFlags::DERSIG | VerifyFlags::LOW_S | VerifyFlags::STRICTENC) && !is_strict_der(sig) {
+        return Err(ScriptError::SigDer);
+    }
+    if flags.contains(VerifyFlags::LOW_S) && !EcdsaSignature::parse_lax(sig).is_ok_and(|sig| sig.is_low_s()) {
+        return Err(ScriptError::SigHighS);
+    }
+    let hash_type = sig[sig.len() - 1] as u32;
+    if flags.contains(VerifyFlags::STRICTENC) && !(1..=3).contains(&(hash_type & !SIGHASH_ANYONECANPAY)) {
+        return Err(ScriptError::SigHashType);
+    }
+    Ok(())
+}
+
+fn hash_type_name(hash_type: u8) -> String {
+    sighash_name(hash_type).unwrap_or_else(|| format!("unknown hash type {:02x}", hash_type))
+}
+
+// Usage: signature <hex> ...
+// How each signature, hash type included, parses: as ECDSA under DERSIG or only leniently, else as
+// Schnorr
+pub fn main(args: &[String]) {
+    for arg in args {
+        let sig = hex::decode(arg).unwrap_or_default();
+        let description = match (EcdsaSignature::parse_strict(&sig), EcdsaSignature::parse_lax(&sig), SchnorrSignature::parse(&sig)) {
+            (strict, Ok(ecdsa), _) => format!("ECDSA, {}, {} S, {}",
+                strict.map_or_else(|e| e.to_string(), |_| String::from("strict DER")),
+                if ecdsa.is_low_s() { "low" } else { "high" },
+                hash_type_name(ecdsa.hash_type)),
+            (_, Err(_), Ok(schnorr)) => format!("Schnorr, {}", hash_type_name(schnorr.hash_type)),
+            (_, Err(ecdsa), Err(schnorr)) => format!("invalid, {}; {}", ecdsa, schnorr),
+        };
+        println!("{}: {}", arg, description);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bcparse::parse_transaction;
+
+    use super::*;
+    use super::super::mainnet::P2PKH_TX;
+
+    // Order of the curve, S and n - S verify the same
+    const ORDER: [u8; 32] = [
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe,
+        0xba, 0xae, 0xdc, 0xe6, 0xaf, 0x48, 0xa0, 0x3b, 0xbf, 0xd2, 0x5e, 0x8c, 0xd0, 0x36, 0x41, 0x41,
+    ];
+
+    // R and S of the signature of the mainnet P2PKH spend, and its hash type
+    fn r_s() -> (Vec<u8>, Vec<u8>, u8) {
+        let script = hex::decode(&parse_transaction(&hex::decode(P2PKH_TX).unwrap()).unwrap().inputs[0].signature_script).unwrap();
+        let sig = &script[1..1 + script[0] as usize];
+        let r_len = sig[3] as usize;
+        (sig[4..4 + r_len].to_vec(), sig[6 + r_len..sig.len() - 1].to_vec(), sig[sig.len() - 1])
+    }
+
+    fn der(r: &[u8], s: &[u8], hash_type: u8) -> Vec<u8> {
+        let mut sig = vec![0x30, (4 + r.len() + s.len()) as u8, 0x02, r.len() as u8];
+        sig.extend(r);
+        sig.extend([0x02, s.len() as u8]);
+        sig.extend(s);
+        sig.push(hash_type);
+        sig
+    }
+
+    // n - S, with the 0x00 that keeps it positive
+    fn high_s(s: &[u8]) -> Vec<u8> {
+        let mut low = [0; 32];
+        low[32 - s.len()..].copy_from_slice(s);
+        let mut high = vec![0; 33];
+        let mut borrow = 0;
+        for i in (0..32).rev() {
+            let diff = ORDER[i] as i16 - low[i] as i16 - borrow;
+            borrow = (diff < 0) as i16;
+            high[i + 1] = diff.rem_euclid(256) as u8;
+        }
+        high
+    }
+
+    #[test]
+    fn strict_and_lax_der() {
+        let (r, s, hash_type) = r_s();
+        let sig = der(&r, &s, hash_type);
+        let strict = EcdsaSignature::parse_strict(&sig).unwrap();
+        assert!(strict.is_low_s());
+        assert_eq!(strict.hash_type, 0x01);
+
+        // Padded R and a length byte that covers the hash type: only leniently parsed, same values
+        let padded = der(&[&[0][..], &r].concat(), &s, hash_type);
+        assert!(!is_strict_der(&padded));
+        assert_eq!(EcdsaSignature::parse_strict(&padded), Err(SigError::StrictDer));
+        assert_eq!(EcdsaSignature::parse_lax(&padded).unwrap().signature, strict.signature);
+        let mut long_length = sig.clone();
+        long_length[1] += 1;
+        assert!(!is_strict_der(&long_length));
+
+        assert_eq!(EcdsaSignature::parse_strict(&[]), Err(SigError::Empty));
+        assert_eq!(EcdsaSignature::parse_lax(&[]), Err(SigError::Empty));
+        assert_eq!(EcdsaSignature::parse_lax(&[0x31, 0x02, 0x01]), Err(SigError::LaxDer));
+    }
+
+    #[test]
+    fn encoding_flags() {
+        let (r, s, hash_type) = r_s();
+        let sig = der(&r, &s, hash_type);
+        let high = der(&r, &high_s(&s), hash_type);
+        assert!(!EcdsaSignature::parse_strict(&high).unwrap().is_low_s());
+        let padded = der(&[&[0][..], &r].concat(), &s, hash_type);
+        let check = |sig: &[u8], flags| check_signature_encoding(sig, flags);
+
+        assert!(matches!(check(&padded, VerifyFlags::NONE), Ok(())));
+        assert!(matches!(check(&padded, VerifyFlags::DERSIG), Err(ScriptError::SigDer)));
+        assert!(matches!(check(&high, VerifyFlags::DERSIG), Ok(())));
+        assert!(matches!(check(&high, VerifyFlags::LOW_S), Err(ScriptError::SigHighS)));
+        assert!(matches!(check(&der(&r, &s, 0x83), VerifyFlags::STRICTENC), Ok(())));
+        assert!(matches!(check(&der(&r, &s, 0x04), VerifyFlags::STRICTENC), Err(ScriptError::SigHashType)));
+        assert!(matches!(check(&der(&r, &s, 0x04), VerifyFlags::DERSIG | VerifyFlags::LOW_S), Ok(())));
+        assert!(matches!(check(&[], VerifyFlags::STRICTENC | VerifyFlags::LOW_S), Ok(())));
+        assert!(matches!(check(&sig, VerifyFlags::STRICTENC | VerifyFlags::LOW_S), Ok(())));
+    }
+
+    #[test]
+    fn schnorr_sizes() {
+        assert_eq!(SchnorrSignature::parse(&[1; 64]).unwrap().hash_type, SIGHASH_DEFAULT);
+        assert_eq!(SchnorrSignature::parse(&[[1; 64].as_ref(), &[0x81]].concat()).unwrap().hash_type, 0x81);
+        assert_eq!(SchnorrSignature::parse(&[[1; 64].as_ref(), &[0]].concat()), Err(SigError::SchnorrHashType));
+        assert_eq!(SchnorrSignature::parse(&[1; 63]), Err(SigError::SchnorrSize(63)));
+        assert_eq!(SchnorrSignature::parse(&[1; 66]), Err(SigError::SchnorrSize(66)));
+    }
+}
@@ -0,0 +1,196 @@
+use crate::bcparse::{Block, Transaction, TxOutput};
+
+use super::flags::VerifyFlags;
+use super::opcodes::*;
+use super::parse::parse_one_op;
+use super::script::{is_push_only, ScriptItem, MAX_PUBKEYS_PER_MULTISIG};
+use super::standard::{classify, witness_program, OutputType};
+
+// Signature operations a block can hold, legacy ones weighing 4 (the witness scale factor)
+pub const MAX_BLOCK_SIGOPS_COST: usize = 80_000;
+pub const WITNESS_SCALE_FACTOR: usize = 4;
+
+// GetSigOpCount in Core: CHECKSIG counts 1, CHECKMULTISIG 20, or with accurate the number of keys
+// when it is pushed by OP_1 to OP_16 just before. Counting stops where the script fails to parse.
+pub fn sigop_count(script: &[u8], accurate: bool) -> usize {
+    let mut count = 0;
+    let mut pc = 0;
+    let mut last_opcode = None;
+    while let Ok(Some(item)) = parse_one_op(script, &mut pc) {
+        if let ScriptItem::Opcode(op) = item {
+            match op {
+                OP_CHECKSIG | OP_CHECKSIGVERIFY => count += 1,
+                OP_CHECKMULTISIG | OP_CHECKMULTISIGVERIFY => count += match last_opcode {
+                    Some(Opcode { code }) if accurate && (OP_1.code..=OP_16.code).contains(&code) => (code - OP_1.code + 1) as usize,
+                    _ => MAX_PUBKEYS_PER_MULTISIG as usize,
+                },
+                _ => {}
+            }
+            last_opcode = Some(op);
+        } else {
+            last_opcode = None;
+        }
+    }
+    count
+}
+
+// Last data pushed by a scriptSig (empty for OP_0 to OP_16), None if it does anything but push
+fn last_push(script_sig: &[u8]) -> Option<Vec<u8>> {
+    let mut pc = 0;
+    let mut data = Vec::new();
+    loop {
+        match parse_one_op(script_sig, &mut pc) {
+            Ok(Some(ScriptItem::ByteArray(bytes))) => data = bytes,
+            Ok(Some(ScriptItem::Opcode(op))) if op.code <= OP_16.code => data.clear(),
+            Ok(None) => return Some(data),
+            _ => return None,
+        }
+    }
+}
+
+fn is_p2sh(script: &[u8]) -> bool {
+    matches!(classify(script), OutputType::ScriptHash(_))
+}
+
+// GetLegacySigOpCount: scriptSigs and output scripts, multisig counted as 20
+pub fn legacy_sigops(tx: &Transaction) -> usize {
+    let scripts = tx.inputs.iter().map(|input| &input.signature_script)
+        .chain(tx.outputs.iter().map(|output| &output.pub_key_script));
+    scripts.map(|script| sigop_count(&hex::decode(script).unwrap_or_default(), false)).sum()
+}
+
+// GetP2SHSigOpCount: redeem scripts of the inputs spending P2SH outputs, accurately counted
+pub fn p2sh_sigops(tx: &Transaction, spent_outputs: &[TxOutput]) -> usize {
+    if tx.is_coinbase() {
+        return 0;
+    }
+    tx.inputs.iter().zip(spent_outputs)
+        .filter(|(_, output)| is_p2sh(&hex::decode(&output.pub_key_script).unwrap_or_default()))
+        .filter_map(|(input, _)| last_push(&hex::decode(&input.signature_script).unwrap_or_default()))
+        .map(|redeem_script| sigop_count(&redeem_script, true))
+        .sum()
+}
+
+// P2WPKH counts 1, P2WSH the sigops of its witness script. Other versions have none: taproot limits
+// signature checks per input with the witness size instead (BIP342).
+fn witness_program_sigops(version: u8, program: &[u8], witness: &[Vec<u8>]) -> usize {
+    match (version, program.len(), witness.last()) {
+        (0, 20, _) => 1,
+        (0, 32, Some(witness_script)) => sigop_count(witness_script, true),
+        _ => 0,
+    }
+}
+
+// CountWitnessSigOps: sigops of a native witness program, or of one nested in P2SH
+pub fn witness_sigops(script_sig: &[u8], script_pub_key: &[u8], witness: &[Vec<u8>], flags: VerifyFlags) -> usize {
+    if !flags.contains(VerifyFlags::WITNESS) {
+        return 0;
+    }
+    if let Some((version, program)) = witness_program(script_pub_key) {
+        return witness_program_sigops(version, program, witness);
+    }
+    if is_p2sh(script_pub_key) && is_push_only(script_sig) {
+        if let Some(redeem_script) = last_push(script_sig) {
+            if let Some((version, program)) = witness_program(&redeem_script) {
+                return witness_program_sigops(version, program, witness);
+            }
+        }
+    }
+    0
+}
+
+impl Transaction {
+    // GetTransactionSigOpCost: legacy and P2SH sigops weigh WITNESS_SCALE_FACTOR, witness ones 1.
+    // spent_outputs are the outputs spent by the inputs, in order (none for the coinbase).
+    pub fn sigop_cost(&self, spent_outputs: &[TxOutput], flags: VerifyFlags) -> usize {
+        let mut cost = legacy_sigops(self) * WITNESS_SCALE_FACTOR;
+        if self.is_coinbase() {
+            return cost;
+        }
+        if flags.contains(VerifyFlags::P2SH) {
+            cost += p2sh_sigops(self, spent_outputs) * WITNESS_SCALE_FACTOR;
+        }
+        let witness_cost: usize = self.inputs.iter().zip(spent_outputs).enumerate()
+            .map(|(idx, (input, output))| {
+                let script_sig = hex::decode(&input.signature_script).unwrap_or_default();
+                let script_pub_key = hex::decode(&output.pub_key_script).unwrap_or_default();
+                witness_sigops(&script_sig, &script_pub_key, &self.witness(idx), flags)
+            })
+            .sum();
+        cost + witness_cost
+    }
+}
+
+impl Block {
+    // spent_outputs holds the outputs spent by each transaction, in block order
+    pub fn sigop_cost(&self, spent_outputs: &[Vec<TxOutput>], flags: VerifyFlags) -> usize {
+        self.txns.iter().zip(spent_outputs).map(|(tx, outputs)| tx.sigop_cost(outputs, flags)).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bcparse::parse_transaction;
+
+    use super::*;
+    use super::super::mainnet::*;
+    use super::super::script::push_data;
+
+    fn spent(script: &str) -> Vec<TxOutput> {
+        vec![TxOutput { pub_key_script: script.to_string(), ..Default::default() }]
+    }
+
+    #[test]
+    fn multisig_counts() {
+        let key = push_data(&[2; 33]);
+        let two_of_three = [&[OP_2.code][..], &key, &key, &key, &[OP_3.code, OP_CHECKMULTISIG.code]].concat();
+        assert_eq!(sigop_count(&two_of_three, true), 3);
+        assert_eq!(sigop_count(&two_of_three, false), 20);
+        // Key count not pushed by OP_1 to OP_16 just before
+        let pushed_count = [&key[..], &push_data(&[3]), &[OP_CHECKMULTISIGVERIFY.code]].concat();
+        assert_eq!(sigop_count(&pushed_count, true), 20);
+        assert_eq!(sigop_count(&[OP_16.code, OP_CHECKMULTISIG.code, OP_0.code, OP_CHECKMULTISIG.code], true), 36);
+        assert_eq!(sigop_count(&[OP_CHECKSIG.code, OP_CHECKSIGVERIFY.code, OP_CHECKSIGADD.code], true), 2);
+        // Up to a truncated push
+        assert_eq!(sigop_count(&[OP_CHECKSIG.code, OP_PUSH_DATA_1.code, 0x02, OP_CHECKSIG.code], false), 1);
+    }
+
+    #[test]
+    fn p2sh_redeem_script() {
+        let tx = parse_transaction(&hex::decode(MULTISIG_TX).unwrap()).unwrap();
+        let redeem_script = last_push(&hex::decode(&tx.inputs[0].signature_script).unwrap()).unwrap();
+        assert_eq!(redeem_script.last(), Some(&OP_CHECKMULTISIG.code));
+        let keys = sigop_count(&redeem_script, true);
+        assert_eq!(keys, 2);
+        assert_eq!(p2sh_sigops(&tx, &spent(MULTISIG_SCRIPT)), keys);
+        // Not counted for other outputs, nor without P2SH
+        assert_eq!(p2sh_sigops(&tx, &spent(P2PKH_SCRIPT)), 0);
+        let legacy = legacy_sigops(&tx) * WITNESS_SCALE_FACTOR;
+        assert_eq!(tx.sigop_cost(&spent(MULTISIG_SCRIPT), VerifyFlags::P2SH), legacy + keys * WITNESS_SCALE_FACTOR);
+        assert_eq!(tx.sigop_cost(&spent(MULTISIG_SCRIPT), VerifyFlags::NONE), legacy);
+    }
+
+    #[test]
+    fn witness_scripts() {
+        let tx = parse_transaction(&hex::decode(P2WSH_TX).unwrap()).unwrap();
+        let witness = tx.witness(0);
+        let keys = sigop_count(witness.last().unwrap(), true);
+        assert_eq!(keys, 3);
+        let p2wsh = hex::decode(P2WSH_SCRIPT).unwrap();
+        assert_eq!(witness_sigops(&[], &p2wsh, &witness, VerifyFlags::WITNESS), keys);
+        assert_eq!(witness_sigops(&[], &p2wsh, &witness, VerifyFlags::P2SH), 0);
+        // Witness sigops weigh 1
+        let legacy = legacy_sigops(&tx) * WITNESS_SCALE_FACTOR;
+        assert_eq!(tx.sigop_cost(&spent(P2WSH_SCRIPT), VerifyFlags::P2SH | VerifyFlags::WITNESS), legacy + keys);
+
+        // P2WPKH, native or nested in P2SH, and taproot
+        let p2wpkh = [&[OP_0.code, 20][..], &[7; 20]].concat();
+        let p2sh = [&[OP_HASH160.code, 20][..], &[8; 20], &[OP_EQUAL.code]].concat();
+        let taproot = [&[OP_1.code, 32][..], &[9; 32]].concat();
+        let signature = vec![vec![1; 71], vec![2; 33]];
+        assert_eq!(witness_sigops(&[], &p2wpkh, &signature, VerifyFlags::WITNESS), 1);
+        assert_eq!(witness_sigops(&push_data(&p2wpkh), &p2sh, &signature, VerifyFlags::WITNESS), 1);
+        assert_eq!(witness_sigops(&[&[OP_NOP.code][..], &push_data(&p2wpkh)].concat(), &p2sh, &signature, VerifyFlags::WITNESS), 0);
+        assert_eq!(witness_sigops(&[], &taproot, &witness, VerifyFlags::WITNESS), 0);
+    }
+}
@@ -0,0 +1,345 @@
+use super::opcodes::*;
+use super::parse::parse_script;
+use super::public_key::valid_size;
+use super::script::to_script_nb;
+use super::script::ScriptItem::{ByteArray, Opcode as Op};
+use super::standard::{classify, OutputType};
+
+const ANNEX_TAG: u8 = 0x50;
+const TAPROOT_LEAF_MASK: u8 = 0xfe;
+const TAPROOT_LEAF_TAPSCRIPT: u8 = 0xc0;
+const SIGHASH_DEFAULT: u8 = 0x00;
+const SIGHASH_ANYONECANPAY: u8 = 0x80;
+
+// Type of the output an input spends, as far as the scriptSig and the witness tell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpendType {
+    Coinbase,
+    PubKey,
+    PubKeyHash,
+    Multisig,
+    ScriptHash,
+    ScriptHashWitnessKeyHash,
+    ScriptHashWitnessScriptHash,
+    WitnessKeyHash,
+    WitnessScriptHash,
+    TaprootKeyPath,
+    TaprootScriptPath,
+    Unknown,
+}
+
+impl SpendType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SpendType::Coinbase => "coinbase",
+            SpendType::PubKey => "p2pk",
+            SpendType::PubKeyHash => "p2pkh",
+            SpendType::Multisig => "multisig",
+            SpendType::ScriptHash => "p2sh",
+            SpendType::ScriptHashWitnessKeyHash => "p2sh-p2wpkh",
+            SpendType::ScriptHashWitnessScriptHash => "p2sh-p2wsh",
+            SpendType::WitnessKeyHash => "p2wpkh",
+            SpendType::WitnessScriptHash => "p2wsh",
+            SpendType::TaprootKeyPath => "p2tr-keypath",
+            SpendType::TaprootScriptPath => "p2tr-scriptpath",
+            SpendType::Unknown => "unknown",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Signature {
+    pub bytes: Vec<u8>,
+    pub sighash: u8,
+}
+
+#[derive(Debug)]
+pub struct Spend {
+    pub kind: SpendType,
+    // Redeem script (P2SH), witness script (P2WSH) or leaf script (Taproot script path)
+    pub script: Option<Vec<u8>>,
+    pub pub_keys: Vec<Vec<u8>>,
+    pub signatures: Vec<Signature>,
+}
+
+impl Spend {
+    fn new(kind: SpendType) -> Spend {
+        Spend { kind, script: None, pub_keys: Vec::new(), signatures: Vec::new() }
+    }
+
+    // "p2sh-multisig", "p2wsh-pubkeyhash"... when the revealed script is a standard one
+    pub fn name(&self) -> String {
+        match self.script.as_deref().map(classify) {
+            Some(inner) if inner != OutputType::NonStandard && self.kind != SpendType::TaprootScriptPath => {
+                format!("{}-{}", self.kind.name(), inner.core_name())
+            }
+            _ => self.kind.name().to_string(),
+        }
+    }
+
+    pub fn coinbase() -> Spend {
+        Spend::new(SpendType::Coinbase)
+    }
+}
+
+// Core's names for the sighash flags ("ALL", "SINGLE|ANYONECANPAY"...)
+pub fn sighash_name(sighash: u8) -> Option<String> {
+    let base = match sighash & !SIGHASH_ANYONECANPAY {
+        0x00 if sighash == SIGHASH_DEFAULT => return Some(String::from("DEFAULT")),
+        0x01 => "ALL",
+        0x02 => "NONE",
+        0x03 => "SINGLE",
+        _ => return None,
+    };
+    match sighash & SIGHASH_ANYONECANPAY {
+        0 => Some(base.to_string()),
+        _ => Some(format!("{}|ANYONECANPAY", base)),
+    }
+}
+
+// DER encoded ECDSA signature followed by its sighash byte, checked on the outer lengths only
+fn is_ecdsa_signature(bytes: &[u8]) -> bool {
+    (9..=73).contains(&bytes.len()) && bytes[0] == 0x30 && bytes[1] as usize == bytes.len() - 3
+}
+
+fn ecdsa_signature(bytes: &[u8]) -> Signature {
+    Signature { bytes: bytes.to_vec(), sighash: bytes[bytes.len() - 1] }
+}
+
+// 64 bytes for SIGHASH_DEFAULT, 65 with an explicit sighash byte (BIP341)
+fn schnorr_signature(bytes: &[u8]) -> Option<Signature> {
+    match bytes.len() {
+        64 => Some(Signature { bytes: bytes.to_vec(), sighash: SIGHASH_DEFAULT }),
+        65 => Some(Signature { bytes: bytes.to_vec(), sighash: bytes[64] }),
+        _ => None,
+    }
+}
+
+fn is_pub_key(bytes: &[u8]) -> bool {
+    valid_size(bytes)
+}
+
+fn is_compressed_pub_key(bytes: &[u8]) -> bool {
+    bytes.len() == 33 && is_pub_key(bytes)
+}
+
+// Keys pushed by a revealed script, x-only ones for tapscript
+fn script_pub_keys(script: &[u8], x_only: bool) -> Vec<Vec<u8>> {
+    parse_script(script).unwrap_or_default().into_iter()
+        .filter_map(|item| match item {
+            ByteArray(key) if x_only && key.len() == 32 => Some(key),
+            ByteArray(key) if !x_only && is_pub_key(&key) => Some(key),
+            _ => None,
+        })
+        .collect()
+}
+
+// Stack items of a scriptSig, None when it is not push only
+fn push_items(script_sig: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let items = parse_script(script_sig).ok()?;
+    items.into_iter()
+        .map(|item| match item {
+            ByteArray(bytes) => Some(bytes),
+            Op(OP_0) => Some(Vec::new()),
+            // What OP_1NEGATE and OP_1..OP_16 push, OP_RESERVED fails the script
+            Op(OP_1NEGATE) => Some(to_script_nb(-1)),
+            Op(op) if (OP_1.code..=OP_16.code).contains(&op.code) => Some(to_script_nb((op.code - OP_1.code + 1) as i64)),
+            Op(_) => None,
+        })
+        .collect()
+}
+
+pub fn infer_spend(script_sig: &[u8], witness: &[Vec<u8>]) -> Spend {
+    let items = match push_items(script_sig) {
+        Some(items) => items,
+        None => return Spend::new(SpendType::Unknown),
+    };
+
+    if items.is_empty() {
+        return match witness.is_empty() {
+            true => Spend::new(SpendType::Unknown),
+            false => infer_witness_spend(witness),
+        };
+    }
+
+    // Nested segwit: the scriptSig only pushes the witness program
+    if !witness.is_empty() && items.len() == 1 {
+        let program = &items[0];
+        if program.len() == 22 && program[..2] == [0x00, 20] {
+            let mut spend = infer_witness_spend(witness);
+            if spend.kind == SpendType::WitnessKeyHash {
+                spend.kind = SpendType::ScriptHashWitnessKeyHash;
+            }
+            return spend;
+        }
+        if program.len() == 34 && program[..2] == [0x00, 32] {
+            let mut spend = witness_script_spend(witness);
+            spend.kind = SpendType::ScriptHashWitnessScriptHash;
+            return spend;
+        }
+    }
+
+    match items.as_slice() {
+        [sig] if is_ecdsa_signature(sig) => Spend {
+            signatures: vec![ecdsa_signature(sig)],
+            ..Spend::new(SpendType::PubKey)
+        },
+        [sig, key] if is_ecdsa_signature(sig) && is_pub_key(key) => Spend {
+            pub_keys: vec![key.clone()],
+            signatures: vec![ecdsa_signature(sig)],
+            ..Spend::new(SpendType::PubKeyHash)
+        },
+        // Bare multisig: the dummy element consumed by CHECKMULTISIG, then the signatures
+        [dummy, sigs @ ..] if dummy.is_empty() && sigs.iter().all(|sig| is_ecdsa_signature(sig)) => Spend {
+            signatures: sigs.iter().map(|sig| ecdsa_signature(sig)).collect(),
+            ..Spend::new(SpendType::Multisig)
+        },
+        // Anything else ending with a script is taken as P2SH
+        [stack @ .., redeem] if parse_script(redeem).is_ok() => Spend {
+            pub_keys: script_pub_keys(redeem, false),
+            signatures: stack.iter().filter(|item| is_ecdsa_signature(item)).map(|sig| ecdsa_signature(sig)).collect(),
+            script: Some(redeem.clone()),
+            ..Spend::new(SpendType::ScriptHash)
+        },
+        _ => Spend::new(SpendType::Unknown),
+    }
+}
+
+fn infer_witness_spend(witness: &[Vec<u8>]) -> Spend {
+    if let [sig, key] = witness {
+        if is_ecdsa_signature(sig) && is_compressed_pub_key(key) {
+            return Spend {
+                pub_keys: vec![key.clone()],
+                signatures: vec![ecdsa_signature(sig)],
+                ..Spend::new(SpendType::WitnessKeyHash)
+            };
+        }
+    }
+
+    // An annex is only possible for taproot spends, which have at least 2 elements then
+    let stack = match witness {
+        [stack @ .., annex] if !stack.is_empty() && annex.first() == Some(&ANNEX_TAG) => stack,
+        _ => witness,
+    };
+
+    match stack {
+        [sig] => match schnorr_signature(sig) {
+            Some(sig) => Spend { signatures: vec![sig], ..Spend::new(SpendType::TaprootKeyPath) },
+            None => witness_script_spend(witness),
+        },
+        [stack @ .., leaf, control] if is_control_block(control) => {
+            let mut pub_keys = vec![control[1..33].to_vec()];
+            pub_keys.extend(script_pub_keys(leaf, true));
+            Spend {
+                script: Some(leaf.clone()),
+                pub_keys,
+                signatures: stack.iter().filter_map(|item| schnorr_signature(item)).collect(),
+                ..Spend::new(SpendType::TaprootScriptPath)
+            }
+        }
+        _ => witness_script_spend(witness),
+    }
+}
+
+// Leaf version, internal key, then the merkle path (BIP341)
+fn is_control_block(control: &[u8]) -> bool {
+    control.len() >= 33 && (control.len() - 33).is_multiple_of(32) && control.len() <= 33 + 128 * 32
+        && control[0] & TAPROOT_LEAF_MASK == TAPROOT_LEAF_TAPSCRIPT
+}
+
+// P2WSH: the last witness element is the script, the others its stack
+fn witness_script_spend(witness: &[Vec<u8>]) -> Spend {
+    match witness {
+        [stack @ .., script] => Spend {
+            pub_keys: script_pub_keys(script, false),
+            signatures: stack.iter().filter(|item| is_ecdsa_signature(item)).map(|sig| ecdsa_signature(sig)).collect(),
+            script: Some(script.clone()),
+            ..Spend::new(SpendType::WitnessScriptHash)
+        },
+        [] => Spend::new(SpendType::Unknown),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bcparse::{parse_transaction, Transaction};
+    use crate::bcscript::mainnet::*;
+
+    // P2SH-P2WPKH and taproot spends of the script tests (test_data/bc-crawl/script_tests.json)
+    const P2SH_P2WPKH_SCRIPT_SIG: &str = "16001479b000887626b294a914501a4cd226b58b235983";
+    const P2SH_P2WPKH_WITNESS: [&str; 2] = [
+        "304402201fe47f6686784693494bfb2ecb87b51e06fe18e8e34bfdc81c027410fd53c98602206ea7cb0cc1d5286c4ae80e8b4de466e48e6d6fa65d719445450b7f517bbc81d101",
+        "031b84c5567b126440995d3ed5aaba0565d71e1834604819ff9c17f5e9d5dd078f",
+    ];
+    const KEY_PATH_WITNESS: [&str; 1] = [
+        "bf6dce96baaa9287fe13dfd3e1b73f3c84c66ff481e5434a361b3070dc3ffed0374adf9f069786799de418d57dc059c13c6434baa06635a2abd107dcb588d28c01",
+    ];
+    const SCRIPT_PATH_WITNESS: [&str; 3] = [
+        "accd7327aaec68398e5d283f4f33f92c90d6483955cb00700de3760545e60c131bf097a4d11a50d17888a8ecba356b85acb3559007201847cabda03e6392c41b",
+        "201b84c5567b126440995d3ed5aaba0565d71e1834604819ff9c17f5e9d5dd078fac",
+        "c11b84c5567b126440995d3ed5aaba0565d71e1834604819ff9c17f5e9d5dd078f",
+    ];
+
+    fn input_spend(tx_hex: &str) -> Spend {
+        let tx: Transaction = parse_transaction(&hex::decode(tx_hex).unwrap()).unwrap();
+        infer_spend(&hex::decode(&tx.inputs[0].signature_script).unwrap(), &tx.witness(0))
+    }
+
+    fn witness(items: &[&str]) -> Vec<Vec<u8>> {
+        items.iter().map(|item| hex::decode(item).unwrap()).collect()
+    }
+
+    fn sighashes(spend: &Spend) -> Vec<u8> {
+        spend.signatures.iter().map(|sig| sig.sighash).collect()
+    }
+
+    #[test]
+    fn mainnet_spends() {
+        let spend = input_spend(P2PKH_TX);
+        assert_eq!(spend.name(), "p2pkh");
+        assert_eq!(hex::encode(&spend.pub_keys[0]), "0214d4d14e3b53a1ac11ae61a1cf8a86c8d9495e19009641923d5125be1aee54a3");
+        assert_eq!(sighashes(&spend), [0x01]);
+
+        // 2-of-2 multisig behind P2SH
+        let spend = input_spend(MULTISIG_TX);
+        assert_eq!(spend.kind, SpendType::ScriptHash);
+        assert_eq!(spend.name(), "p2sh-multisig");
+        assert_eq!((spend.pub_keys.len(), sighashes(&spend)), (2, vec![0x01, 0x01]));
+
+        // 2-of-3 multisig behind P2WSH, the empty dummy first in the witness
+        let spend = input_spend(P2WSH_TX);
+        assert_eq!(spend.name(), "p2wsh-multisig");
+        assert_eq!((spend.pub_keys.len(), sighashes(&spend)), (3, vec![0x01, 0x01]));
+        assert_eq!(spend.script.as_ref().map(|script| script.len()), Some(105));
+    }
+
+    #[test]
+    fn nested_and_taproot_spends() {
+        let spend = infer_spend(&hex::decode(P2SH_P2WPKH_SCRIPT_SIG).unwrap(), &witness(&P2SH_P2WPKH_WITNESS));
+        assert_eq!(spend.name(), "p2sh-p2wpkh");
+        assert_eq!(spend.pub_keys, witness(&P2SH_P2WPKH_WITNESS[1..]));
+
+        let spend = infer_spend(&[], &witness(&KEY_PATH_WITNESS));
+        assert_eq!(spend.name(), "p2tr-keypath");
+        assert_eq!(sighashes(&spend), [0x01]);
+        // An annex is left out
+        let with_annex = [KEY_PATH_WITNESS[0], "50"];
+        assert_eq!(infer_spend(&[], &witness(&with_annex)).kind, SpendType::TaprootKeyPath);
+
+        // Internal key of the control block, then the key of the leaf, the leaf is not named
+        let spend = infer_spend(&[], &witness(&SCRIPT_PATH_WITNESS));
+        assert_eq!(spend.name(), "p2tr-scriptpath");
+        assert_eq!(spend.pub_keys.len(), 2);
+        assert_eq!(spend.pub_keys[0], spend.pub_keys[1]);
+        assert_eq!((sighashes(&spend), spend.script), (vec![SIGHASH_DEFAULT], Some(witness(&SCRIPT_PATH_WITNESS[1..2]).remove(0))));
+    }
+
+    #[test]
+    fn small_number_pushes() {
+        // OP_1NEGATE, OP_1 and OP_16 push numbers, OP_RESERVED is not a push
+        assert_eq!(push_items(&[0x4f, 0x51, 0x60]), Some(vec![vec![0x81], vec![1], vec![16]]));
+        assert_eq!(push_items(&[0x00, 0x50]), None);
+        assert_eq!(infer_spend(&[0x50], &[]).kind, SpendType::Unknown);
+    }
+}
@@ -0,0 +1,120 @@
+use super::opcodes::*;
+use super::parse::parse_script;
+use super::public_key::valid_size;
+use super::script::ScriptItem::{ByteArray, Opcode as Op};
+
+// Standard output templates, recognised the same way as Bitcoin Core's Solver
+#[derive(Debug, PartialEq, Eq)]
+pub enum OutputType {
+    PubKey(Vec<u8>),
+    PubKeyHash(Vec<u8>),
+    ScriptHash(Vec<u8>),
+    Multisig { required: usize, keys: Vec<Vec<u8>> },
+    NullData,
+    WitnessV0KeyHash(Vec<u8>),
+    WitnessV0ScriptHash(Vec<u8>),
+    WitnessV1Taproot(Vec<u8>),
+    WitnessUnknown { version: u8, program: Vec<u8> },
+    NonStandard,
+}
+
+impl OutputType {
+    // Name used in the "type" field of Bitcoin Core RPCs
+    pub fn core_name(&self) -> &'static str {
+        match self {
+            OutputType::PubKey(..) => "pubkey",
+            OutputType::PubKeyHash(..) => "pubkeyhash",
+            OutputType::ScriptHash(..) => "scripthash",
+            OutputType::Multisig { .. } => "multisig",
+            OutputType::NullData => "nulldata",
+            OutputType::WitnessV0KeyHash(..) => "witness_v0_keyhash",
+            OutputType::WitnessV0ScriptHash(..) => "witness_v0_scripthash",
+            OutputType::WitnessV1Taproot(..) => "witness_v1_taproot",
+            OutputType::WitnessUnknown { .. } => "witness_unknown",
+            OutputType::NonStandard => "nonstandard",
+        }
+    }
+}
+
+// Returns the witness version and program of a segwit output script (BIP141)
+pub fn witness_program(script: &[u8]) -> Option<(u8, &[u8])> {
+    if script.len() < 4 || script.len() > 42 || script[1] as usize != script.len() - 2 {
+        return None;
+    }
+    match script[0] {
+        0 => Some((0, &script[2..])),
+        c if (OP_1.code..=OP_16.code).contains(&c) => Some((c - OP_1.code + 1, &script[2..])),
+        _ => None,
+    }
+}
+
+fn small_int(op: Opcode) -> Option<usize> {
+    match op.code {
+        c if (OP_1.code..=OP_16.code).contains(&c) => Some((c - OP_1.code + 1) as usize),
+        _ => None,
+    }
+}
+
+fn is_pub_key(bytes: &[u8]) -> bool {
+    valid_size(bytes)
+}
+
+pub fn classify(script: &[u8]) -> OutputType {
+    // Fixed size templates are matched on the raw bytes, as Core does
+    if script.len() == 25 && script[..3] == [OP_DUP.code, OP_HASH160.code, 20] && script[23..] == [OP_EQUALVERIFY.code, OP_CHECKSIG.code] {
+        return OutputType::PubKeyHash(script[3..23].to_vec());
+    }
+    if script.len() == 23 && script[..2] == [OP_HASH160.code, 20] && script[22] == OP_EQUAL.code {
+        return OutputType::ScriptHash(script[2..22].to_vec());
+    }
+    for key_len in [33, 65] {
+        if script.len() == key_len + 2 && script[0] as usize == key_len && script[key_len + 1] == OP_CHECKSIG.code && is_pub_key(&script[1..key_len + 1]) {
+            return OutputType::PubKey(script[1..key_len + 1].to_vec());
+        }
+    }
+    if let Some((version, program)) = witness_program(script) {
+        return match (version, program.len()) {
+            (0, 20) => OutputType::WitnessV0KeyHash(program.to_vec()),
+            (0, 32) => OutputType::WitnessV0ScriptHash(program.to_vec()),
+            (0, _) => OutputType::NonStandard,
+            (1, 32) => OutputType::WitnessV1Taproot(program.to_vec()),
+            _ => OutputType::WitnessUnknown { version, program: program.to_vec() },
+        };
+    }
+
+    let items = match parse_script(script) {
+        Ok(items) => items,
+        Err(_) => return OutputType::NonStandard,
+    };
+
+    // OP_RETURN followed by pushes only (opcodes up to OP_16 count as pushes)
+    if items.first() == Some(&Op(OP_RETURN)) {
+        let push_only = items[1..].iter().all(|item| match item {
+            ByteArray(..) => true,
+            Op(op) => op.code <= OP_16.code,
+        });
+        return match push_only {
+            true => OutputType::NullData,
+            false => OutputType::NonStandard,
+        };
+    }
+
+    match items.as_slice() {
+        [Op(m), keys @ .., Op(n), Op(OP_CHECKMULTISIG)] => {
+            match (small_int(*m), small_int(*n)) {
+                (Some(required), Some(total)) if required <= total && total == keys.len() => {
+                    let mut pub_keys = Vec::with_capacity(total);
+                    for key in keys {
+                        match key {
+                            ByteArray(key) if is_pub_key(key) => pub_keys.push(key.clone()),
+                            _ => return OutputType::NonStandard,
+                        }
+                    }
+                    OutputType::Multisig { required, keys: pub_keys }
+                }
+                _ => OutputType::NonStandard,
+            }
+        }
+        _ => OutputType::NonStandard,
+    }
+}
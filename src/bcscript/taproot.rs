@@ -0,0 +1,73 @@
+use bitcoin_hashes::{Hash, HashEngine, sha256};
+use secp256k1::{Parity, Scalar, XOnlyPublicKey};
+
+use crate::bcutils::to_compact_int;
+
+use super::interpret::SECP;
+
+// Last witness item of a taproot spend with at least two items, when it starts with 0x50
+pub const ANNEX_TAG: u8 = 0x50;
+// First byte of the control block: leaf version, and parity of the output key in the low bit
+pub const TAPROOT_LEAF_MASK: u8 = 0xfe;
+pub const TAPROOT_LEAF_TAPSCRIPT: u8 = 0xc0;
+// Internal key, then up to 128 nodes of the merkle path
+const CONTROL_BASE_SIZE: usize = 33;
+const CONTROL_NODE_SIZE: usize = 32;
+const CONTROL_MAX_NODE_COUNT: usize = 128;
+// Tapscript signature budget (BIP342): the witness size plus 50, each checked signature costs 50
+pub const VALIDATION_WEIGHT_OFFSET: i64 = 50;
+pub const VALIDATION_WEIGHT_PER_SIGOP_PASSED: i64 = 50;
+
+// BIP340 tagged hash: sha256(sha256(tag) || sha256(tag) || data)
+pub fn tagged_hash(tag: &str, data: &[u8]) -> [u8; 32] {
+    let tag_hash = sha256::Hash::hash(tag.as_bytes());
+    let mut engine = sha256::Hash::engine();
+    engine.input(&tag_hash);
+    engine.input(&tag_hash);
+    engine.input(data);
+    sha256::Hash::from_engine(engine).into_inner()
+}
+
+pub fn tapleaf_hash(leaf_version: u8, script: &[u8]) -> [u8; 32] {
+    let mut leaf = vec![leaf_version];
+    leaf.extend(to_compact_int(script.len() as u64));
+    leaf.extend_from_slice(script);
+    tagged_hash("TapLeaf", &leaf)
+}
+
+pub fn is_valid_control_size(size: usize) -> bool {
+    (CONTROL_BASE_SIZE..=CONTROL_BASE_SIZE + CONTROL_NODE_SIZE * CONTROL_MAX_NODE_COUNT).contains(&size)
+        && (size - CONTROL_BASE_SIZE).is_multiple_of(CONTROL_NODE_SIZE)
+}
+
+// The merkle root is rebuilt from the leaf and the path of the control block (each branch hashes
+// its two children in lexicographic order), then the output key must be the internal key tweaked
+// with the hash of the two
+pub fn verify_commitment(control: &[u8], program: &[u8], tapleaf_hash: &[u8; 32]) -> bool {
+    let mut node = *tapleaf_hash;
+    for sibling in control[CONTROL_BASE_SIZE..].chunks(CONTROL_NODE_SIZE) {
+        let branch = match &node[..] < sibling {
+            true => [&node[..], sibling].concat(),
+            false => [sibling, &node[..]].concat(),
+        };
+        node = tagged_hash("TapBranch", &branch);
+    }
+
+    let internal_key = &control[1..CONTROL_BASE_SIZE];
+    let tweak = tagged_hash("TapTweak", &[internal_key, &node[..]].concat());
+    match (XOnlyPublicKey::from_slice(internal_key), XOnlyPublicKey::from_slice(program), Scalar::from_be_bytes(tweak)) {
+        (Ok(internal_key), Ok(output_key), Ok(tweak)) => {
+            let parity = match control[0] & 1 {
+                0 => Parity::Even,
+                _ => Parity::Odd,
+            };
+            internal_key.tweak_add_check(&SECP, &output_key, parity, tweak)
+        }
+        _ => false,
+    }
+}
+
+// Opcodes that make a tapscript valid as soon as they appear in it, reserved for soft forks
+pub fn is_op_success(code: u8) -> bool {
+    matches!(code, 80 | 98 | 126..=129 | 131..=134 | 137..=138 | 141..=142 | 149..=153 | 187..=254)
+}
@@ -4,19 +4,20 @@ const UNIT_16: u8 = 0xFD;
 const UNIT_32: u8 = 0xFE;
 const UNIT_64: u8 = 0xFF;
 
-pub fn get_compact_int(payload: &[u8]) -> (u64, usize) {
-    let storage_length: u8 = payload[0];
+// Returns the decoded value and the number of bytes it was stored on, None if the payload is too short
+pub fn get_compact_int(payload: &[u8]) -> Option<(u64, usize)> {
+    let storage_length: u8 = *payload.first()?;
 
     if storage_length == UNIT_16 {
-        return (u16::from_le_bytes((&payload[1..3]).try_into().unwrap()) as u64, 3);
+        return Some((u16::from_le_bytes(payload.get(1..3)?.try_into().ok()?) as u64, 3));
     }
     if storage_length == UNIT_32 {
-        return (u32::from_le_bytes((&payload[1..5]).try_into().unwrap()) as u64, 5);
+        return Some((u32::from_le_bytes(payload.get(1..5)?.try_into().ok()?) as u64, 5));
     }
     if storage_length == UNIT_64 {
-        return (u64::from_le_bytes((&payload[1..9]).try_into().unwrap()) as u64, 9);
+        return Some((u64::from_le_bytes(payload.get(1..9)?.try_into().ok()?), 9));
     }
-    (storage_length as u64, 1)
+    Some((storage_length as u64, 1))
 }
 
 pub fn to_compact_int(n: u64) -> Vec<u8> {
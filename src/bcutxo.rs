@@ -0,0 +1,649 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use bitcoin_hashes::{Hash, HashEngine, sha256d};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::Serialize;
+use serde_json::value::RawValue;
+
+use crate::bcblocks::GENESIS_BLOCK;
+use crate::bcfees;
+use crate::bcfile::{self, BlockFormat};
+use crate::bcgetblock::btc_value;
+use crate::bcjson::JsonError;
+use crate::bcparse::{Block, parse_block};
+use crate::bcscript::script::MAX_SCRIPT_SIZE;
+use crate::bcutils::{get_compact_int, reverse_hash, to_compact_int};
+use crate::bcvalidate;
+
+const UTXO_DIR: &str = "./utxo";
+const UNDO_DIR: &str = "./utxo/undo";
+const CHAINSTATE_FILE: &str = "./utxo/chainstate.gz";
+const CHAINSTATE_TEMP_FILE: &str = "./utxo/chainstate.tmp.gz";
+const UNDO_EXTENSION: &str = ".undo.gz";
+const CHECKPOINT_BLOCKS: usize = 10000;
+const OP_RETURN: u8 = 0x6a;
+
+// Blocks whose coinbase has the txid of an earlier unspent one, which it overwrote
+const BIP30_REPEATS: [(u32, &str); 2] = [
+    (91842, "00000000000a4d0a398161ffc163c503763b1f4360639393e0e4c8e300e0caec"),
+    (91880, "00000000000743f190a18c5577a3c2d2a1f610ae9601ac046a38084ccb7cd721"),
+];
+
+// Upper bound on what the snapshot header may make us preallocate
+const PREALLOCATED_COINS: u64 = 1 << 24;
+
+// Outpoint: txid in internal byte order and output index
+pub type Key = ([u8; 32], u32);
+
+#[derive(Debug, Clone)]
+pub struct Coin {
+    pub value: i64,
+    pub script: Vec<u8>,
+    pub height: u32,
+    pub coinbase: bool,
+}
+
+#[derive(Debug)]
+pub enum UtxoError {
+    Io(io::Error),
+    Json(JsonError),
+    // The previous block, or the undo data of a block to disconnect, is not stored
+    Missing(String),
+    // An input spends an output that is not in the set
+    MissingInput(String, String),
+    Corrupted(&'static str),
+    // Block that failed validation and why
+    Invalid(String, String),
+}
+
+impl fmt::Display for UtxoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UtxoError::Io(e) => write!(f, "I/O error: {}", e),
+            UtxoError::Json(e) => write!(f, "{}", e),
+            UtxoError::Missing(what) => write!(f, "missing {}", what),
+            UtxoError::MissingInput(block, outpoint) => write!(f, "block {} spends unknown output {}", block, outpoint),
+            UtxoError::Corrupted(file) => write!(f, "corrupted {}", file),
+            UtxoError::Invalid(block, reason) => write!(f, "block {} is invalid: {}", block, reason),
+        }
+    }
+}
+
+impl From<io::Error> for UtxoError {
+    fn from(e: io::Error) -> UtxoError {
+        UtxoError::Io(e)
+    }
+}
+
+impl From<JsonError> for UtxoError {
+    fn from(e: JsonError) -> UtxoError {
+        UtxoError::Json(e)
+    }
+}
+
+// OP_RETURN outputs and oversized scripts can never be spent and are not kept, as in Core
+pub fn is_unspendable(script: &[u8]) -> bool {
+    script.first() == Some(&OP_RETURN) || script.len() > MAX_SCRIPT_SIZE
+}
+
+// IsBIP30Repeat
+pub fn is_bip30_repeat(block: &Block, height: u32) -> bool {
+    BIP30_REPEATS.contains(&(height, reverse_hash(&block.hash).as_str()))
+}
+
+pub fn outpoint_key(hash: &str, idx: u32) -> Key {
+    let mut txid = [0; 32];
+    if let Ok(bytes) = hex::decode(hash) {
+        if bytes.len() == 32 {
+            txid.copy_from_slice(&bytes);
+        }
+    }
+    (txid, idx)
+}
+
+fn display_outpoint((txid, idx): &Key) -> String {
+    format!("{}:{}", reverse_hash(&hex::encode(txid)), idx)
+}
+
+// Core's TxOutSer: outpoint, height * 2 + coinbase, amount, script
+fn write_coin<W: Write>(out: &mut W, (txid, idx): &Key, coin: &Coin) -> io::Result<()> {
+    out.write_all(txid)?;
+    out.write_u32::<LittleEndian>(*idx)?;
+    out.write_u32::<LittleEndian>(coin.height << 1 | coin.coinbase as u32)?;
+    out.write_i64::<LittleEndian>(coin.value)?;
+    out.write_all(&to_compact_int(coin.script.len() as u64))?;
+    out.write_all(&coin.script)
+}
+
+fn read_compact_int<R: Read>(input: &mut R) -> io::Result<u64> {
+    let mut bytes = vec![input.read_u8()?];
+    let extra = match bytes[0] {
+        0xfd => 2,
+        0xfe => 4,
+        0xff => 8,
+        _ => 0,
+    };
+    bytes.resize(1 + extra, 0);
+    input.read_exact(&mut bytes[1..])?;
+    get_compact_int(&bytes).map(|(n, _)| n).ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))
+}
+
+fn read_coin<R: Read>(input: &mut R) -> io::Result<(Key, Coin)> {
+    let mut txid = [0; 32];
+    input.read_exact(&mut txid)?;
+    let idx = input.read_u32::<LittleEndian>()?;
+    let code = input.read_u32::<LittleEndian>()?;
+    let value = input.read_i64::<LittleEndian>()?;
+    let length = read_compact_int(input)?;
+    if length > MAX_SCRIPT_SIZE as u64 {
+        return Err(io::Error::from(io::ErrorKind::InvalidData));
+    }
+    let mut script = vec![0; length as usize];
+    input.read_exact(&mut script)?;
+    Ok(((txid, idx), Coin { value, script, height: code >> 1, coinbase: code & 1 == 1 }))
+}
+
+// Written next to the block files: 0000012345 --> utxo/undo/45/3/0000012345.undo.gz
+fn undo_file(rev_hash: &str) -> String {
+    bcfile::sharded_file(UNDO_DIR, rev_hash, UNDO_EXTENSION)
+}
+
+// Outputs spent by a block, in the order of its inputs (coinbase excluded), like Core's rev files
+pub fn load_undo(rev_hash: &str) -> Result<Vec<(Key, Coin)>, UtxoError> {
+    let file = File::open(undo_file(rev_hash)).map_err(|_| UtxoError::Missing(format!("undo data of {}", rev_hash)))?;
+    let mut input = BufReader::new(GzDecoder::new(file));
+    let count = input.read_u64::<LittleEndian>()?;
+    (0..count).map(|_| read_coin(&mut input).map_err(UtxoError::from)).collect()
+}
+
+fn store_undo(rev_hash: &str, spent: &[(Key, Coin)]) -> Result<(), UtxoError> {
+    let path = undo_file(rev_hash);
+    fs::create_dir_all(Path::new(&path).parent().unwrap())?;
+    let mut out = BufWriter::new(GzEncoder::new(File::create(path)?, Compression::default()));
+    out.write_u64::<LittleEndian>(spent.len() as u64)?;
+    for (key, coin) in spent {
+        write_coin(&mut out, key, coin)?;
+    }
+    out.into_inner().map_err(|e| e.into_error())?.finish()?;
+    Ok(())
+}
+
+fn load_block(rev_hash: &str) -> Result<Block, UtxoError> {
+    if rev_hash == genesis_hash() {
+        return Ok(parse_block(&hex::decode(GENESIS_BLOCK).unwrap()).unwrap());
+    }
+    let path = bcfile::block_file(rev_hash);
+    if !Path::new(&path).exists() {
+        return Err(UtxoError::Missing(format!("block {}", rev_hash)));
+    }
+    Ok(bcfile::load_block(Path::new(&path))?)
+}
+
+fn genesis_hash() -> String {
+    reverse_hash(&parse_block(&hex::decode(GENESIS_BLOCK).unwrap()).unwrap().hash)
+}
+
+pub struct UtxoSet {
+    pub coins: HashMap<Key, Coin>,
+    // Display hash of the last connected block and its height
+    pub tip: String,
+    pub height: u32,
+}
+
+// Same fields as `bitcoin-cli gettxoutsetinfo`; hash_serialized is Core's coin serialization hashed
+// in outpoint order, but computed here over the set as replayed from the stored blocks
+#[derive(Serialize)]
+pub struct UtxoSetInfo {
+    pub height: u32,
+    pub bestblock: String,
+    pub txouts: usize,
+    pub transactions: usize,
+    pub bogosize: usize,
+    pub hash_serialized: String,
+    pub total_amount: Box<RawValue>,
+}
+
+impl UtxoSet {
+    // The genesis outputs are not spendable, the set starts empty on top of it
+    pub fn new() -> UtxoSet {
+        UtxoSet { coins: HashMap::new(), tip: genesis_hash(), height: 0 }
+    }
+
+    pub fn load() -> Result<UtxoSet, UtxoError> {
+        if !Path::new(CHAINSTATE_FILE).exists() {
+            return Ok(UtxoSet::new());
+        }
+        let mut input = BufReader::new(GzDecoder::new(File::open(CHAINSTATE_FILE)?));
+        let mut tip = [0; 32];
+        input.read_exact(&mut tip)?;
+        let height = input.read_u32::<LittleEndian>()?;
+        let count = input.read_u64::<LittleEndian>()?;
+        let mut coins = HashMap::with_capacity(count.min(PREALLOCATED_COINS) as usize);
+        for _ in 0..count {
+            let (key, coin) = read_coin(&mut input).map_err(|_| UtxoError::Corrupted(CHAINSTATE_FILE))?;
+            coins.insert(key, coin);
+        }
+        Ok(UtxoSet { coins, tip: hex::encode(tip), height })
+    }
+
+    // Written aside then renamed, an interrupted save leaves the previous state
+    pub fn save(&self) -> Result<(), UtxoError> {
+        fs::create_dir_all(UTXO_DIR)?;
+        let mut out = BufWriter::new(GzEncoder::new(File::create(CHAINSTATE_TEMP_FILE)?, Compression::fast()));
+        out.write_all(&hex::decode(&self.tip).map_err(|_| UtxoError::Corrupted("tip hash"))?)?;
+        out.write_u32::<LittleEndian>(self.height)?;
+        out.write_u64::<LittleEndian>(self.coins.len() as u64)?;
+        for (key, coin) in &self.coins {
+            write_coin(&mut out, key, coin)?;
+        }
+        out.into_inner().map_err(|e| e.into_error())?.finish()?;
+        fs::rename(CHAINSTATE_TEMP_FILE, CHAINSTATE_FILE)?;
+        Ok(())
+    }
+
+    // Spends the inputs and adds the outputs of a block on top of the tip, returns the undo data
+    pub fn connect(&mut self, block: &Block) -> Result<Vec<(Key, Coin)>, UtxoError> {
+        let rev_hash = reverse_hash(&block.hash);
+        let height = self.height + 1;
+        let mut spent = Vec::new();
+        for tx in &block.txns {
+            let coinbase = tx.is_coinbase();
+            if !coinbase {
+                for input in &tx.inputs {
+                    let key = outpoint_key(&input.prev_output.hash, input.prev_output.idx);
+                    match self.coins.remove(&key) {
+                        Some(coin) => spent.push((key, coin)),
+                        None => {
+                            // Leave the set as it was before the block
+                            self.restore(block, &spent);
+                            return Err(UtxoError::MissingInput(rev_hash, display_outpoint(&key)));
+                        }
+                    }
+                }
+            }
+            for (idx, output) in tx.outputs.iter().enumerate() {
+                let script = hex::decode(&output.pub_key_script).unwrap_or_default();
+                if !is_unspendable(&script) {
+                    // Duplicated coinbases (BIP30, blocks 91842 and 91880) overwrite the earlier outputs, as in Core
+                    self.coins.insert(outpoint_key(&tx.hash, idx as u32), Coin { value: output.value, script, height, coinbase });
+                }
+            }
+        }
+        self.tip = rev_hash;
+        self.height = height;
+        Ok(spent)
+    }
+
+    // Removes the outputs created by a block and puts back the ones it spent, last transaction first
+    // so that outputs both created and spent in the block end up removed
+    fn restore(&mut self, block: &Block, spent: &[(Key, Coin)]) {
+        let mut end = 0;
+        let mut ranges = Vec::with_capacity(block.txns.len());
+        for tx in &block.txns {
+            let start = end;
+            if !tx.is_coinbase() {
+                end = (end + tx.inputs.len()).min(spent.len());
+            }
+            ranges.push(start..end);
+        }
+        for (tx, range) in block.txns.iter().zip(ranges).rev() {
+            for idx in 0..tx.outputs.len() {
+                self.coins.remove(&outpoint_key(&tx.hash, idx as u32));
+            }
+            for (key, coin) in &spent[range] {
+                self.coins.insert(*key, coin.clone());
+            }
+        }
+    }
+
+    pub fn disconnect(&mut self, block: &Block, undo: &[(Key, Coin)]) {
+        self.restore(block, undo);
+        self.tip = reverse_hash(&block.prev_hash);
+        self.height -= 1;
+    }
+
+    pub fn info(&self) -> UtxoSetInfo {
+        let mut keys: Vec<&Key> = self.coins.keys().collect();
+        keys.sort();
+
+        let mut engine = sha256d::Hash::engine();
+        let mut serialized = Vec::new();
+        let mut total = 0;
+        let mut bogosize = 0;
+        for key in &keys {
+            let coin = &self.coins[*key];
+            serialized.clear();
+            write_coin(&mut serialized, key, coin).unwrap();
+            engine.input(&serialized);
+            total += coin.value;
+            bogosize += 50 + coin.script.len();
+        }
+        UtxoSetInfo {
+            height: self.height,
+            bestblock: self.tip.clone(),
+            txouts: keys.len(),
+            transactions: keys.iter().map(|(txid, _)| txid).collect::<HashSet<_>>().len(),
+            bogosize,
+            hash_serialized: reverse_hash(&hex::encode(sha256d::Hash::from_engine(engine))),
+            total_amount: btc_value(total),
+        }
+    }
+}
+
+// Best chain of the stored blocks: the longest one starting at genesis, as display hashes by height.
+// Invalid blocks and their descendants are left out.
+fn best_chain(parents: &HashMap<String, String>, invalid: &HashSet<String>) -> Vec<String> {
+    let mut children: HashMap<&str, Vec<&str>> = HashMap::new();
+    for (hash, prev) in parents {
+        children.entry(prev.as_str()).or_default().push(hash.as_str());
+    }
+    let genesis = genesis_hash();
+    let mut best = (0, genesis.as_str());
+    let mut heights = vec![(genesis.as_str(), 0)];
+    while let Some((hash, height)) = heights.pop() {
+        // Sorted so that equal length branches always resolve the same way
+        let mut next = children.get(hash).cloned().unwrap_or_default();
+        next.retain(|child| !invalid.contains(*child));
+        next.sort();
+        for child in next {
+            if height + 1 > best.0 {
+                best = (height + 1, child);
+            }
+            heights.push((child, height + 1));
+        }
+    }
+
+    let mut chain = vec![best.1.to_string()];
+    while let Some(prev) = parents.get(chain.last().unwrap()) {
+        chain.push(prev.clone());
+    }
+    chain.reverse();
+    chain
+}
+
+// Disconnects blocks until the tip is on the chain, `stored` gives each block with its undo data
+fn rewind<F>(set: &mut UtxoSet, chain: &[String], mut stored: F) -> Result<(), UtxoError>
+where
+    F: FnMut(&str) -> Result<(Block, Vec<(Key, Coin)>), UtxoError>,
+{
+    while chain.get(set.height as usize) != Some(&set.tip) {
+        let (block, undo) = stored(&set.tip)?;
+        eprintln!("Rolling back block {} at height {}", set.tip, set.height);
+        set.disconnect(&block, &undo);
+    }
+    Ok(())
+}
+
+// Brings the set to the tip of the best stored chain, rolling back blocks that left it
+// With validate, the blocks connected without it are rolled back first, then each block is checked
+// against the set before it is connected and the outcome is stored with it and in the index; an
+// invalid block is left unconnected and ends the sync
+pub fn sync(set: &mut UtxoSet, max_blocks: usize, validate: bool) -> Result<(), UtxoError> {
+    eprintln!("Reading stored block headers");
+    let parents: HashMap<String, String> = bcfile::stored_blocks().into_iter()
+        .filter_map(|hash| bcfile::read_prev_hash(&hash).map(|prev| (hash, prev)))
+        .collect();
+    let statuses = bcvalidate::status_tree()?;
+    let chain = best_chain(&parents, &bcvalidate::invalid_blocks(&statuses)?);
+    eprintln!("Best stored chain reaches height {}, UTXO set at {}", chain.len() - 1, set.height);
+
+    let mut kept = chain.len();
+    if validate {
+        kept = 1;
+        while kept <= set.height as usize && kept < chain.len() && bcvalidate::block_status(&statuses, &chain[kept])? == Some(true) {
+            kept += 1;
+        }
+        if kept <= set.height as usize {
+            eprintln!("Blocks from height {} were not validated", kept);
+        }
+    }
+    rewind(set, &chain[..kept], |hash| Ok((load_block(hash)?, load_undo(hash)?)))?;
+
+    let mut connected = 0;
+    for hash in chain.iter().skip(set.height as usize + 1).take(max_blocks) {
+        let mut block = load_block(hash)?;
+        if validate {
+            let result = bcvalidate::validate_block(&block, &set.coins, set.height + 1);
+            block.validation = Some(bcvalidate::validation(set.height + 1, &result));
+            bcvalidate::store_status(&statuses, hash, result.is_ok())?;
+            if let Err(e) = result {
+                bcfile::write_block_file(&block, BlockFormat::Stored)?;
+                set.save()?;
+                return Err(UtxoError::Invalid(hash.clone(), e.to_string()));
+            }
+        }
+        let spent = set.connect(&block)?;
+        store_undo(hash, &spent)?;
+        // Input values are known now, fees and statistics are added to the stored block
+        bcfees::annotate(&mut block, &spent, set.height);
+        bcfile::write_block_file(&block, BlockFormat::Stored)?;
+        connected += 1;
+        if connected % CHECKPOINT_BLOCKS == 0 {
+            eprintln!("  height {}, {} outputs", set.height, set.coins.len());
+            set.save()?;
+        }
+    }
+    set.save()?;
+    eprintln!("{} blocks connected, UTXO set at height {}", connected, set.height);
+    Ok(())
+}
+
+// Usage: utxo [sync [max_blocks] | info | rollback <blocks>]
+pub fn main(args: &[String]) {
+    let result = UtxoSet::load().and_then(|mut set| match args.first().map(String::as_str) {
+        Some("info") => {
+            println!("{}", serde_json::to_string_pretty(&set.info()).unwrap());
+            Ok(())
+        }
+        Some("rollback") => {
+            for _ in 0..args.get(1).and_then(|a| a.parse().ok()).unwrap_or(1) {
+                if set.height == 0 {
+                    break;
+                }
+                let block = load_block(&set.tip)?;
+                let undo = load_undo(&set.tip)?;
+                set.disconnect(&block, &undo);
+            }
+            eprintln!("UTXO set back at height {}", set.height);
+            set.save()
+        }
+        Some("sync") | None => sync(&mut set, args.get(1).and_then(|a| a.parse().ok()).unwrap_or(usize::MAX), false),
+        Some(other) => Err(UtxoError::Missing(format!("command {}, usage: utxo [sync [max_blocks] | info | rollback <blocks>]", other))),
+    });
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bcparse::{OutPoint, Transaction, TxInput, TxOutput};
+
+    // P2PK script of the genesis coinbase, spendable
+    fn output_script() -> String {
+        parse_block(&hex::decode(GENESIS_BLOCK).unwrap()).unwrap().txns.remove(0).outputs.remove(0).pub_key_script
+    }
+
+    fn with_txid(mut tx: Transaction) -> Transaction {
+        tx.hash = hex::encode(sha256d::Hash::hash(&Transaction::serialize(&tx, false)));
+        tx
+    }
+
+    // Coinbase made unique by its height
+    fn coinbase(height: u32) -> Transaction {
+        let mut tx = parse_block(&hex::decode(GENESIS_BLOCK).unwrap()).unwrap().txns.remove(0);
+        tx.inputs[0].signature_script = hex::encode(height.to_le_bytes());
+        with_txid(tx)
+    }
+
+    // Spends the outputs given, one new output per value
+    fn spend(outpoints: &[(&Transaction, u32)], values: &[i64]) -> Transaction {
+        with_txid(Transaction {
+            version: 1,
+            inputs: outpoints.iter()
+                .map(|(tx, idx)| TxInput { prev_output: OutPoint { hash: tx.hash.clone(), idx: *idx }, ..Default::default() })
+                .collect(),
+            outputs: values.iter().map(|&value| TxOutput { value, pub_key_script: output_script(), ..Default::default() }).collect(),
+            ..Default::default()
+        })
+    }
+
+    // The hash only has to tell blocks apart: it commits to the parent and the txids
+    fn block(prev_hash: &str, txns: Vec<Transaction>) -> Block {
+        let mut engine = sha256d::Hash::engine();
+        engine.input(prev_hash.as_bytes());
+        for tx in &txns {
+            engine.input(tx.hash.as_bytes());
+        }
+        let hash = hex::encode(sha256d::Hash::from_engine(engine));
+        Block { hash, prev_hash: prev_hash.to_string(), txns, ..Default::default() }
+    }
+
+    fn genesis() -> Block {
+        parse_block(&hex::decode(GENESIS_BLOCK).unwrap()).unwrap()
+    }
+
+    fn hash_serialized(set: &UtxoSet) -> String {
+        set.info().hash_serialized
+    }
+
+    #[test]
+    fn created_and_spent_in_same_block() {
+        let mut set = UtxoSet::new();
+        let first = block(&genesis().hash, vec![coinbase(1)]);
+        set.connect(&first).unwrap();
+        let before = hash_serialized(&set);
+
+        let parent = spend(&[(&first.txns[0], 0)], &[30, 20]);
+        let child = spend(&[(&parent, 0)], &[25]);
+        let second = block(&first.hash, vec![coinbase(2), parent, child]);
+        let spent = set.connect(&second).unwrap();
+        assert_eq!(spent.iter().map(|(key, coin)| (*key, coin.value)).collect::<Vec<_>>(), vec![
+            (outpoint_key(&first.txns[0].hash, 0), 50 * 100_000_000),
+            (outpoint_key(&second.txns[1].hash, 0), 30),
+        ]);
+        assert!(!set.coins.contains_key(&outpoint_key(&second.txns[1].hash, 0)));
+        assert_eq!(set.coins[&outpoint_key(&second.txns[1].hash, 1)].value, 20);
+        assert_eq!(set.coins[&outpoint_key(&second.txns[2].hash, 0)].height, 2);
+        assert_eq!(set.coins.len(), 3);
+
+        set.disconnect(&second, &spent);
+        assert_eq!(set.coins.len(), 1);
+        assert_eq!(hash_serialized(&set), before);
+        assert_eq!((set.tip.as_str(), set.height), (reverse_hash(&first.hash).as_str(), 1));
+    }
+
+    #[test]
+    fn missing_input_leaves_set_unchanged() {
+        let mut set = UtxoSet::new();
+        let first = block(&genesis().hash, vec![coinbase(1)]);
+        set.connect(&first).unwrap();
+        let before = set.info();
+
+        // The first spend goes through before the second finds nothing to spend
+        let good = spend(&[(&first.txns[0], 0)], &[10]);
+        let unknown = spend(&[(&first.txns[0], 1)], &[10]);
+        let second = block(&first.hash, vec![coinbase(2), good, unknown]);
+        match set.connect(&second) {
+            Err(UtxoError::MissingInput(block, outpoint)) => {
+                assert_eq!(block, reverse_hash(&second.hash));
+                assert_eq!(outpoint, format!("{}:1", reverse_hash(&first.txns[0].hash)));
+            }
+            other => panic!("unexpected {:?}", other.map(|spent| spent.len())),
+        }
+        let after = set.info();
+        assert_eq!((after.height, after.bestblock, after.txouts), (before.height, before.bestblock, before.txouts));
+        assert_eq!(after.hash_serialized, before.hash_serialized);
+    }
+
+    #[test]
+    fn connect_then_disconnect() {
+        let mut set = UtxoSet::new();
+        let first = block(&genesis().hash, vec![coinbase(1)]);
+        set.connect(&first).unwrap();
+        let second = block(&first.hash, vec![coinbase(2), spend(&[(&first.txns[0], 0)], &[1, 2, 3])]);
+        set.connect(&second).unwrap();
+        let before = set.info();
+
+        let third = block(&second.hash, vec![
+            coinbase(3),
+            spend(&[(&second.txns[1], 2), (&second.txns[0], 0)], &[4]),
+            spend(&[(&second.txns[1], 0)], &[1]),
+        ]);
+        let undo = set.connect(&third).unwrap();
+        assert_ne!(hash_serialized(&set), before.hash_serialized);
+        set.disconnect(&third, &undo);
+
+        let after = set.info();
+        assert_eq!((after.height, after.bestblock, after.txouts), (before.height, before.bestblock, before.txouts));
+        assert_eq!(after.hash_serialized, before.hash_serialized);
+        assert!(set.coins[&outpoint_key(&second.txns[0].hash, 0)].coinbase);
+    }
+
+    #[test]
+    fn reorg_to_longer_branch() {
+        let genesis = genesis();
+        let a1 = block(&genesis.hash, vec![coinbase(1)]);
+        let a2 = block(&a1.hash, vec![coinbase(2), spend(&[(&a1.txns[0], 0)], &[7])]);
+        let b1 = block(&genesis.hash, vec![coinbase(11)]);
+        let b2 = block(&b1.hash, vec![coinbase(12)]);
+        let b3 = block(&b2.hash, vec![coinbase(13), spend(&[(&b1.txns[0], 0), (&b2.txns[0], 0)], &[8])]);
+
+        let parents: HashMap<String, String> = [&a1, &a2, &b1, &b2, &b3].iter()
+            .map(|block| (reverse_hash(&block.hash), reverse_hash(&block.prev_hash)))
+            .collect();
+        let chain = best_chain(&parents, &HashSet::new());
+        let expected: Vec<String> = [&genesis, &b1, &b2, &b3].iter().map(|block| reverse_hash(&block.hash)).collect();
+        assert_eq!(chain, expected);
+
+        let mut set = UtxoSet::new();
+        let mut stored = HashMap::new();
+        for block in [a1, a2] {
+            let undo = set.connect(&block).unwrap();
+            stored.insert(reverse_hash(&block.hash), (block, undo));
+        }
+        rewind(&mut set, &chain, |hash| stored.remove(hash).ok_or_else(|| UtxoError::Missing(hash.to_string()))).unwrap();
+        assert!(stored.is_empty());
+        assert_eq!((set.tip.as_str(), set.height), (chain[0].as_str(), 0));
+        assert!(set.coins.is_empty());
+
+        for block in [&b1, &b2, &b3] {
+            set.connect(block).unwrap();
+        }
+        let mut replayed = UtxoSet::new();
+        for block in [&b1, &b2, &b3] {
+            replayed.connect(block).unwrap();
+        }
+        assert_eq!(set.info().bestblock, expected[3]);
+        assert_eq!(hash_serialized(&set), hash_serialized(&replayed));
+    }
+
+    #[test]
+    fn invalid_branch_left_out() {
+        let genesis = genesis();
+        let a1 = block(&genesis.hash, vec![coinbase(1)]);
+        let b1 = block(&genesis.hash, vec![coinbase(11)]);
+        let b2 = block(&b1.hash, vec![coinbase(12)]);
+        let b3 = block(&b2.hash, vec![coinbase(13)]);
+        let parents: HashMap<String, String> = [&a1, &b1, &b2, &b3].iter()
+            .map(|block| (reverse_hash(&block.hash), reverse_hash(&block.prev_hash)))
+            .collect();
+        let display = |blocks: &[&Block]| blocks.iter().map(|block| reverse_hash(&block.hash)).collect::<Vec<_>>();
+
+        assert_eq!(best_chain(&parents, &HashSet::new()), display(&[&genesis, &b1, &b2, &b3]));
+        // The descendants of an invalid block go with it
+        let invalid = display(&[&b2]).into_iter().collect();
+        assert_eq!(best_chain(&parents, &invalid), display(&[&genesis, &a1]));
+        let invalid = display(&[&a1, &b1]).into_iter().collect();
+        assert_eq!(best_chain(&parents, &invalid), display(&[&genesis]));
+    }
+}
@@ -0,0 +1,624 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::fmt;
+use std::io;
+use std::iter;
+use std::thread;
+
+use bitcoin_hashes::{Hash, sha256d};
+use serde::{Deserialize, Serialize};
+
+use crate::bccoinbase::decode_coinbase;
+use crate::bcfees::subsidy;
+use crate::bcindex;
+use crate::bcparse::{Block, Transaction, TxInput, TxOutput};
+use crate::bcscript::flags::{consensus_flags, VerifyFlags};
+use crate::bcscript::interpret::verify;
+use crate::bcscript::script::{push_data, to_script_nb, LOCKTIME_THRESHOLD, SEQUENCE_FINAL};
+use crate::bcscript::sighash::TxContext;
+use crate::bcscript::sigops::{legacy_sigops, MAX_BLOCK_SIGOPS_COST, WITNESS_SCALE_FACTOR};
+use crate::bcutils::reverse_hash;
+use crate::bcutxo::{self, is_bip30_repeat, is_unspendable, outpoint_key, Coin, Key, UtxoSet};
+
+pub const MAX_MONEY: i64 = 21_000_000 * 100_000_000;
+pub const MAX_BLOCK_WEIGHT: usize = 4_000_000;
+const COINBASE_MATURITY: u32 = 100;
+// From BIP34 the coinbase starts with the height, which makes its txid unique. Until the heights of
+// old coinbases come back as a valid prefix, duplicated txids are not looked for (BIP30).
+const BIP34_HEIGHT: u32 = 227931;
+const BIP34_IMPLIES_BIP30_LIMIT: u32 = 1983702;
+pub const STATUS_TREE: &str = "validation";
+
+// Outcome of the validated blocks, in a tree of the transaction index store:
+//   validation  block hash  -> 1 if valid, 0 if not
+// Blocks never validated have no entry. The syncs leave invalid blocks and their descendants out of
+// the best chain.
+
+// Outcome of the checks of a block, stored with it
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Validation {
+    pub height: u32,
+    pub flags: String,
+    pub valid: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// Reasons are the ones Core rejects blocks with
+#[derive(Debug)]
+pub enum ValidationError {
+    Block(&'static str),
+    // Display txid
+    Transaction(&'static str, String),
+    // Display txid, input and the phase and error of the script
+    Script(String, usize, String),
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::Block(reason) => write!(f, "{}", reason),
+            ValidationError::Transaction(reason, txid) => write!(f, "{} in {}", reason, txid),
+            ValidationError::Script(txid, input, error) => write!(f, "mandatory-script-verify-flag-failed ({}) in {}:{}", error, txid, input),
+        }
+    }
+}
+
+// Core's CheckTransaction: checks that need nothing but the transaction
+pub fn check_transaction(tx: &Transaction) -> Result<(), &'static str> {
+    if tx.inputs.is_empty() {
+        return Err("bad-txns-vin-empty");
+    }
+    if tx.outputs.is_empty() {
+        return Err("bad-txns-vout-empty");
+    }
+    if tx.serialize(false).len() * WITNESS_SCALE_FACTOR > MAX_BLOCK_WEIGHT {
+        return Err("bad-txns-oversize");
+    }
+    let mut total: i64 = 0;
+    for output in &tx.outputs {
+        if output.value < 0 {
+            return Err("bad-txns-vout-negative");
+        }
+        if output.value > MAX_MONEY {
+            return Err("bad-txns-vout-toolarge");
+        }
+        total += output.value;
+        if total > MAX_MONEY {
+            return Err("bad-txns-txouttotal-toolarge");
+        }
+    }
+    let mut outpoints = HashSet::new();
+    if !tx.inputs.iter().all(|input| outpoints.insert((&input.prev_output.hash, input.prev_output.idx))) {
+        return Err("bad-txns-inputs-duplicate");
+    }
+    let is_null = |input: &TxInput| input.prev_output.idx == u32::MAX && input.prev_output.hash.bytes().all(|c| c == b'0');
+    if tx.is_coinbase() {
+        if !(2..=100).contains(&(tx.inputs[0].signature_script.len() / 2)) {
+            return Err("bad-cb-length");
+        }
+    } else if tx.inputs.iter().any(is_null) {
+        return Err("bad-txns-prevout-null");
+    }
+    Ok(())
+}
+
+// Core's ComputeMerkleRoot, the last hash of a level of odd size paired with itself. Two equal
+// hashes paired in a level give the root of another list of transactions (CVE-2012-2459), which is
+// told as mutated.
+fn merkle_root(mut hashes: Vec<[u8; 32]>) -> ([u8; 32], bool) {
+    let mut mutated = false;
+    while hashes.len() > 1 {
+        mutated |= hashes.chunks(2).any(|pair| pair.len() == 2 && pair[0] == pair[1]);
+        if hashes.len() % 2 == 1 {
+            hashes.push(hashes[hashes.len() - 1]);
+        }
+        hashes = hashes.chunks(2).map(|pair| sha256d::Hash::hash(&pair.concat()).into_inner()).collect();
+    }
+    (hashes.first().copied().unwrap_or_default(), mutated)
+}
+
+fn hash_bytes(hash: &str) -> [u8; 32] {
+    hex::decode(hash).ok().and_then(|bytes| bytes.try_into().ok()).unwrap_or_default()
+}
+
+fn check_merkle_root(block: &Block) -> Result<(), ValidationError> {
+    let (root, mutated) = merkle_root(block.txns.iter().map(|tx| hash_bytes(&tx.hash)).collect());
+    if root != hash_bytes(&block.merkle_root) {
+        return Err(ValidationError::Block("bad-txnmrklroot"));
+    }
+    if mutated {
+        return Err(ValidationError::Block("bad-txns-duplicate"));
+    }
+    Ok(())
+}
+
+// BIP141: once segwit is active, the coinbase may commit to the wtxids (the coinbase's taken as zero)
+// with the reserved value of its witness. Without a commitment no transaction carries a witness.
+fn check_witness_commitment(block: &Block, flags: VerifyFlags) -> Result<(), ValidationError> {
+    let coinbase = decode_coinbase(block).unwrap_or_default();
+    match (flags.contains(VerifyFlags::WITNESS), coinbase.witness_commitment) {
+        (true, Some(commitment)) => {
+            let reserved_value = coinbase.witness_reserved_value.ok_or(ValidationError::Block("bad-witness-nonce-size"))?;
+            let wtxids = iter::once([0; 32])
+                .chain(block.txns.iter().skip(1).map(|tx| sha256d::Hash::hash(&tx.serialize(true)).into_inner()))
+                .collect();
+            let (root, _) = merkle_root(wtxids);
+            if sha256d::Hash::hash(&[&root[..], &reserved_value].concat())[..] != commitment[..] {
+                return Err(ValidationError::Block("bad-witness-merkle-match"));
+            }
+        }
+        _ => {
+            if block.txns.iter().any(|tx| tx.witnesses.iter().any(|stack| !stack.is_empty())) {
+                return Err(ValidationError::Block("unexpected-witness"));
+            }
+        }
+    }
+    Ok(())
+}
+
+// IsFinalTx: lock times under LOCKTIME_THRESHOLD are heights, the others times, which Core compares
+// to the block time until BIP113 (active with CSV), then to the median time of the previous blocks.
+// A single block does not tell that median, time locks are then not checked (time is None).
+fn is_final(tx: &Transaction, height: u32, time: Option<u32>) -> bool {
+    let lock_time = tx.lock_time as i64;
+    let unlocked = match lock_time < LOCKTIME_THRESHOLD {
+        true => lock_time < height as i64,
+        false => time.is_none_or(|time| lock_time < time as i64),
+    };
+    lock_time == 0 || unlocked || tx.inputs.iter().all(|input| input.sequence == SEQUENCE_FINAL)
+}
+
+// The two blocks whose coinbase duplicates an earlier one were accepted before BIP30
+fn enforces_bip30(block: &Block, height: u32) -> bool {
+    !is_bip30_repeat(block, height) && !(BIP34_HEIGHT..BIP34_IMPLIES_BIP30_LIMIT).contains(&height)
+}
+
+fn check_input(tx: &Transaction, input_idx: usize, spent_outputs: &[TxOutput], flags: VerifyFlags) -> Result<(), ValidationError> {
+    let script_sig = hex::decode(&tx.inputs[input_idx].signature_script).unwrap_or_default();
+    let script_pub_key = hex::decode(&spent_outputs[input_idx].pub_key_script).unwrap_or_default();
+    let witness = tx.witness(input_idx);
+    let ctx = TxContext { tx, input_idx, amount: spent_outputs[input_idx].value, spent_outputs };
+    verify(&script_sig, &script_pub_key, &witness, &ctx, flags)
+        .map_err(|e| ValidationError::Script(reverse_hash(&tx.hash), input_idx, format!("{:?} in {:?}", e.error, e.phase)))
+}
+
+// Inputs are shared between the cores in consecutive chunks, the first failing input of the first
+// failing chunk is the first of the block
+fn check_scripts(block: &Block, spent_outputs: &[Vec<TxOutput>], flags: VerifyFlags) -> Result<(), ValidationError> {
+    let inputs: Vec<(usize, usize)> = block.txns.iter().enumerate().skip(1)
+        .flat_map(|(tx_idx, tx)| (0..tx.inputs.len()).map(move |input_idx| (tx_idx, input_idx)))
+        .collect();
+    if inputs.is_empty() {
+        return Ok(());
+    }
+    let threads = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    let failures: Vec<ValidationError> = thread::scope(|scope| {
+        let workers: Vec<_> = inputs.chunks(inputs.len().div_ceil(threads))
+            .map(|chunk| scope.spawn(move || {
+                chunk.iter().find_map(|&(tx_idx, input_idx)| check_input(&block.txns[tx_idx], input_idx, &spent_outputs[tx_idx], flags).err())
+            }))
+            .collect();
+        workers.into_iter().filter_map(|worker| worker.join().unwrap()).collect()
+    });
+    failures.into_iter().next().map_or(Ok(()), Err)
+}
+
+// CheckBlock, ContextualCheckBlock and ConnectBlock of Core for the block at this height against the
+// outputs left by the previous one. Rules needing the header chain are left out: proof of work,
+// header version and timestamps, time locks under BIP113 and BIP68 relative lock times.
+pub fn validate_block(block: &Block, coins: &HashMap<Key, Coin>, height: u32) -> Result<(), ValidationError> {
+    check_merkle_root(block)?;
+    let stripped_size = block.serialize(false).len();
+    if block.txns.is_empty() || block.txns.len() * WITNESS_SCALE_FACTOR > MAX_BLOCK_WEIGHT || stripped_size * WITNESS_SCALE_FACTOR > MAX_BLOCK_WEIGHT {
+        return Err(ValidationError::Block("bad-blk-length"));
+    }
+    match block.txns.first() {
+        Some(tx) if tx.is_coinbase() => {}
+        _ => return Err(ValidationError::Block("bad-cb-missing")),
+    }
+    if block.txns.iter().skip(1).any(Transaction::is_coinbase) {
+        return Err(ValidationError::Block("bad-cb-multiple"));
+    }
+    for tx in &block.txns {
+        check_transaction(tx).map_err(|reason| ValidationError::Transaction(reason, reverse_hash(&tx.hash)))?;
+    }
+    if block.txns.iter().map(legacy_sigops).sum::<usize>() * WITNESS_SCALE_FACTOR > MAX_BLOCK_SIGOPS_COST {
+        return Err(ValidationError::Block("bad-blk-sigops"));
+    }
+
+    let flags = consensus_flags(height);
+    let lock_time_cutoff = Some(block.timestamp).filter(|_| !flags.contains(VerifyFlags::CHECKSEQUENCEVERIFY));
+    if let Some(tx) = block.txns.iter().find(|tx| !is_final(tx, height, lock_time_cutoff)) {
+        return Err(ValidationError::Transaction("bad-txns-nonfinal", reverse_hash(&tx.hash)));
+    }
+    if height >= BIP34_HEIGHT {
+        let script_sig = hex::decode(&block.txns[0].inputs[0].signature_script).unwrap_or_default();
+        if !script_sig.starts_with(&push_data(&to_script_nb(height as i64))) {
+            return Err(ValidationError::Block("bad-cb-height"));
+        }
+    }
+    check_witness_commitment(block, flags)?;
+    if stripped_size * (WITNESS_SCALE_FACTOR - 1) + block.serialize(true).len() > MAX_BLOCK_WEIGHT {
+        return Err(ValidationError::Block("bad-blk-weight"));
+    }
+
+    if enforces_bip30(block, height) {
+        for tx in &block.txns {
+            if (0..tx.outputs.len()).any(|idx| coins.contains_key(&outpoint_key(&tx.hash, idx as u32))) {
+                return Err(ValidationError::Transaction("bad-txns-BIP30", reverse_hash(&tx.hash)));
+            }
+        }
+    }
+
+    // Outputs created and spent so far in the block, over the set of the previous one
+    let mut created: HashMap<Key, Coin> = HashMap::new();
+    let mut spent = HashSet::new();
+    let mut spent_outputs = Vec::with_capacity(block.txns.len());
+    let mut fees = 0;
+    for tx in &block.txns {
+        let coinbase = tx.is_coinbase();
+        let reject = |reason| ValidationError::Transaction(reason, reverse_hash(&tx.hash));
+        let mut outputs = Vec::new();
+        if !coinbase {
+            let mut input_total = 0;
+            for input in &tx.inputs {
+                let key = outpoint_key(&input.prev_output.hash, input.prev_output.idx);
+                let coin = Some(key).filter(|key| spent.insert(*key))
+                    .and_then(|key| created.get(&key).or_else(|| coins.get(&key)))
+                    .ok_or_else(|| reject("bad-txns-inputs-missingorspent"))?;
+                if coin.coinbase && height - coin.height < COINBASE_MATURITY {
+                    return Err(reject("bad-txns-premature-spend-of-coinbase"));
+                }
+                input_total += coin.value;
+                if !(0..=MAX_MONEY).contains(&coin.value) || input_total > MAX_MONEY {
+                    return Err(reject("bad-txns-inputvalues-outofrange"));
+                }
+                outputs.push(TxOutput { value: coin.value, pub_key_script: hex::encode(&coin.script), ..Default::default() });
+            }
+            let output_total: i64 = tx.outputs.iter().map(|output| output.value).sum();
+            if input_total < output_total {
+                return Err(reject("bad-txns-in-belowout"));
+            }
+            fees += input_total - output_total;
+        }
+        for (idx, output) in tx.outputs.iter().enumerate() {
+            let script = hex::decode(&output.pub_key_script).unwrap_or_default();
+            if !is_unspendable(&script) {
+                created.insert(outpoint_key(&tx.hash, idx as u32), Coin { value: output.value, script, height, coinbase });
+            }
+        }
+        spent_outputs.push(outputs);
+    }
+    if block.sigop_cost(&spent_outputs, flags) > MAX_BLOCK_SIGOPS_COST {
+        return Err(ValidationError::Block("bad-blk-sigops"));
+    }
+    let coinbase_out: i64 = block.txns[0].outputs.iter().map(|output| output.value).sum();
+    if coinbase_out > subsidy(height) + fees {
+        return Err(ValidationError::Block("bad-cb-amount"));
+    }
+    check_scripts(block, &spent_outputs, flags)
+}
+
+pub fn status_tree() -> io::Result<sled::Tree> {
+    bcindex::open_tree(STATUS_TREE)
+}
+
+// Blocks are given by their display hash
+pub fn store_status(statuses: &sled::Tree, rev_hash: &str, valid: bool) -> io::Result<()> {
+    statuses.insert(hash_bytes(&reverse_hash(rev_hash)), &[valid as u8])?;
+    Ok(())
+}
+
+pub fn block_status(statuses: &sled::Tree, rev_hash: &str) -> io::Result<Option<bool>> {
+    Ok(statuses.get(hash_bytes(&reverse_hash(rev_hash)))?.map(|status| status[..] == [1]))
+}
+
+pub fn invalid_blocks(statuses: &sled::Tree) -> io::Result<HashSet<String>> {
+    let mut invalid = HashSet::new();
+    for entry in statuses.iter() {
+        let (hash, status) = entry?;
+        if status[..] != [1] {
+            invalid.insert(reverse_hash(&hex::encode(hash)));
+        }
+    }
+    Ok(invalid)
+}
+
+pub fn validation(height: u32, result: &Result<(), ValidationError>) -> Validation {
+    Validation {
+        height,
+        flags: consensus_flags(height).to_string(),
+        valid: result.is_ok(),
+        error: result.as_ref().err().map(ValidationError::to_string),
+    }
+}
+
+// Usage: validate [max_blocks]
+// Connects the stored blocks to the UTXO set like `utxo sync`, each checked first, after rolling
+// back the ones `utxo sync` connected unchecked. Sync stops at the first invalid block, stored with
+// the reason and marked invalid in the index.
+pub fn main(args: &[String]) {
+    let max_blocks = args.first().and_then(|a| a.parse().ok()).unwrap_or(usize::MAX);
+    let result = UtxoSet::load().and_then(|mut set| bcutxo::sync(&mut set, max_blocks, true));
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bcblocks::GENESIS_BLOCK;
+    use crate::bcparse::{parse_block, parse_transaction, WitnessItem};
+    use crate::bcscript::mainnet::{P2WSH_SCRIPT, P2WSH_TX};
+
+    const SEGWIT_HEIGHT: u32 = 481824;
+
+    fn genesis() -> Block {
+        parse_block(&hex::decode(GENESIS_BLOCK).unwrap()).unwrap()
+    }
+
+    // Txids and merkle root of a block whose transactions were edited
+    fn rehash(block: &mut Block) {
+        for tx in &mut block.txns {
+            tx.hash = hex::encode(sha256d::Hash::hash(&Transaction::serialize(tx, false)));
+        }
+        block.merkle_root = hex::encode(merkle_root(block.txns.iter().map(|tx| hash_bytes(&tx.hash)).collect()).0);
+    }
+
+    // Coinbase with the height of the block, a witness reserved value and a commitment to the
+    // wtxids, followed by a mainnet P2WSH spend
+    fn segwit_block(height: u32) -> Block {
+        let mut block = genesis();
+        block.txns.push(parse_transaction(&hex::decode(P2WSH_TX).unwrap()).unwrap());
+        let coinbase = &mut block.txns[0];
+        coinbase.inputs[0].signature_script = hex::encode([push_data(&to_script_nb(height as i64)), vec![0; 4]].concat());
+        coinbase.is_segwit = true;
+        coinbase.witnesses = vec![vec![WitnessItem { script: hex::encode([0; 32]) }]];
+        coinbase.outputs[0].value = 0;
+        commit(&mut block, [0; 32]);
+        block
+    }
+
+    // Commitment output of the coinbase, for the witness reserved value given
+    fn commit(block: &mut Block, reserved_value: [u8; 32]) {
+        let wtxids = iter::once([0; 32])
+            .chain(block.txns.iter().skip(1).map(|tx| sha256d::Hash::hash(&tx.serialize(true)).into_inner()))
+            .collect();
+        let commitment = sha256d::Hash::hash(&[merkle_root(wtxids).0, reserved_value].concat());
+        let script = [&[0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed][..], &commitment[..]].concat();
+        let outputs = &mut block.txns[0].outputs;
+        outputs.truncate(1);
+        outputs.push(TxOutput { pub_key_script: hex::encode(script), ..Default::default() });
+        rehash(block);
+    }
+
+    fn reason(result: Result<(), ValidationError>) -> String {
+        result.unwrap_err().to_string()
+    }
+
+    // The output spent by the P2WSH transaction of segwit_block, created 200 blocks before
+    fn coins(block: &Block) -> HashMap<Key, Coin> {
+        let outpoint = &block.txns[1].inputs[0].prev_output;
+        let coin = Coin { value: 18393430, script: hex::decode(P2WSH_SCRIPT).unwrap(), height: SEGWIT_HEIGHT - 200, coinbase: false };
+        iter::once((outpoint_key(&outpoint.hash, outpoint.idx), coin)).collect()
+    }
+
+    fn fee(block: &Block) -> i64 {
+        18393430 - block.txns[1].outputs.iter().map(|output| output.value).sum::<i64>()
+    }
+
+    #[test]
+    fn genesis_valid() {
+        validate_block(&genesis(), &HashMap::new(), 0).unwrap();
+    }
+
+    #[test]
+    fn merkle_root_checked() {
+        let mut block = genesis();
+        block.merkle_root = hex::encode([0; 32]);
+        assert_eq!(reason(validate_block(&block, &HashMap::new(), 0)), "bad-txnmrklroot");
+    }
+
+    #[test]
+    fn last_transaction_repeated() {
+        let (a, b, c) = ([1; 32], [2; 32], [3; 32]);
+        let (root, mutated) = merkle_root(vec![a, b, c]);
+        assert!(!mutated);
+        assert_eq!(merkle_root(vec![a, b, c, c]), (root, true));
+    }
+
+    #[test]
+    fn witness_commitment() {
+        // Past the commitment, the spent output is not in the set
+        let block = segwit_block(SEGWIT_HEIGHT);
+        assert_eq!(reason(validate_block(&block, &HashMap::new(), SEGWIT_HEIGHT)), format!("bad-txns-inputs-missingorspent in {}", reverse_hash(&block.txns[1].hash)));
+
+        let mut block = segwit_block(SEGWIT_HEIGHT);
+        block.txns[0].witnesses[0][0].script = hex::encode([1; 32]);
+        assert_eq!(reason(validate_block(&block, &HashMap::new(), SEGWIT_HEIGHT)), "bad-witness-merkle-match");
+
+        let mut block = segwit_block(SEGWIT_HEIGHT);
+        block.txns[0].witnesses[0][0].script = hex::encode([0; 31]);
+        assert_eq!(reason(validate_block(&block, &HashMap::new(), SEGWIT_HEIGHT)), "bad-witness-nonce-size");
+    }
+
+    #[test]
+    fn unexpected_witness() {
+        // Before segwit, commitment or not
+        let block = segwit_block(SEGWIT_HEIGHT - 1);
+        assert_eq!(reason(validate_block(&block, &HashMap::new(), SEGWIT_HEIGHT - 1)), "unexpected-witness");
+
+        let mut block = segwit_block(SEGWIT_HEIGHT);
+        block.txns[0].outputs.truncate(1);
+        rehash(&mut block);
+        assert_eq!(reason(validate_block(&block, &HashMap::new(), SEGWIT_HEIGHT)), "unexpected-witness");
+    }
+
+    #[test]
+    fn block_weight() {
+        let mut block = segwit_block(SEGWIT_HEIGHT);
+        block.txns[1].witnesses[0].push(WitnessItem { script: hex::encode(vec![0; MAX_BLOCK_WEIGHT]) });
+        commit(&mut block, [0; 32]);
+        assert_eq!(reason(validate_block(&block, &HashMap::new(), SEGWIT_HEIGHT)), "bad-blk-weight");
+    }
+
+    #[test]
+    fn lock_time() {
+        let mut block = segwit_block(SEGWIT_HEIGHT);
+        block.txns[1].lock_time = SEGWIT_HEIGHT;
+        block.txns[1].inputs[0].sequence = 0;
+        commit(&mut block, [0; 32]);
+        let txid = reverse_hash(&block.txns[1].hash);
+        assert_eq!(reason(validate_block(&block, &HashMap::new(), SEGWIT_HEIGHT)), format!("bad-txns-nonfinal in {}", txid));
+
+        // Final from the next height, or with final sequences
+        block.txns[1].lock_time = SEGWIT_HEIGHT - 1;
+        commit(&mut block, [0; 32]);
+        assert!(reason(validate_block(&block, &HashMap::new(), SEGWIT_HEIGHT)).starts_with("bad-txns-inputs-missingorspent"));
+        block.txns[1].lock_time = SEGWIT_HEIGHT;
+        block.txns[1].inputs[0].sequence = SEQUENCE_FINAL;
+        commit(&mut block, [0; 32]);
+        assert!(reason(validate_block(&block, &HashMap::new(), SEGWIT_HEIGHT)).starts_with("bad-txns-inputs-missingorspent"));
+
+        // Time locks against the block time before BIP113 only
+        let mut tx = parse_transaction(&hex::decode(P2WSH_TX).unwrap()).unwrap();
+        tx.inputs[0].sequence = 0;
+        tx.lock_time = u32::MAX;
+        assert!(!is_final(&tx, 1, Some(block.timestamp)));
+        assert!(is_final(&tx, 1, None));
+    }
+
+    #[test]
+    fn segwit_block_valid() {
+        let block = segwit_block(SEGWIT_HEIGHT);
+        validate_block(&block, &coins(&block), SEGWIT_HEIGHT).unwrap();
+    }
+
+    #[test]
+    fn missing_and_double_spent_inputs() {
+        let mut block = segwit_block(SEGWIT_HEIGHT);
+        let mut coins = coins(&block);
+        let spent = coins.drain().next().unwrap();
+        let txid = reverse_hash(&block.txns[1].hash);
+        assert_eq!(reason(validate_block(&block, &coins, SEGWIT_HEIGHT)), format!("bad-txns-inputs-missingorspent in {}", txid));
+
+        // The same output spent again by a later transaction of the block
+        coins.insert(spent.0, spent.1);
+        let mut again = parse_transaction(&hex::decode(P2WSH_TX).unwrap()).unwrap();
+        again.version = 2;
+        block.txns.push(again);
+        commit(&mut block, [0; 32]);
+        let txid = reverse_hash(&block.txns[2].hash);
+        assert_eq!(reason(validate_block(&block, &coins, SEGWIT_HEIGHT)), format!("bad-txns-inputs-missingorspent in {}", txid));
+    }
+
+    #[test]
+    fn coinbase_maturity() {
+        let block = segwit_block(SEGWIT_HEIGHT);
+        let txid = reverse_hash(&block.txns[1].hash);
+        let mut coins = coins(&block);
+        for coin in coins.values_mut() {
+            coin.coinbase = true;
+            coin.height = SEGWIT_HEIGHT - COINBASE_MATURITY + 1;
+        }
+        assert_eq!(reason(validate_block(&block, &coins, SEGWIT_HEIGHT)), format!("bad-txns-premature-spend-of-coinbase in {}", txid));
+        for coin in coins.values_mut() {
+            coin.height = SEGWIT_HEIGHT - COINBASE_MATURITY;
+        }
+        validate_block(&block, &coins, SEGWIT_HEIGHT).unwrap();
+    }
+
+    #[test]
+    fn amounts() {
+        let mut block = segwit_block(SEGWIT_HEIGHT);
+        let coins = coins(&block);
+        let txid = reverse_hash(&block.txns[1].hash);
+        let mut short = coins.clone();
+        for coin in short.values_mut() {
+            coin.value -= fee(&block) + 1;
+        }
+        assert_eq!(reason(validate_block(&block, &short, SEGWIT_HEIGHT)), format!("bad-txns-in-belowout in {}", txid));
+
+        // The coinbase may claim the subsidy and the fees, not more
+        block.txns[0].outputs[0].value = subsidy(SEGWIT_HEIGHT) + fee(&block);
+        commit(&mut block, [0; 32]);
+        validate_block(&block, &coins, SEGWIT_HEIGHT).unwrap();
+        block.txns[0].outputs[0].value += 1;
+        commit(&mut block, [0; 32]);
+        assert_eq!(reason(validate_block(&block, &coins, SEGWIT_HEIGHT)), "bad-cb-amount");
+    }
+
+    #[test]
+    fn bip30() {
+        // Before BIP34, a transaction may not repeat the txid of one with unspent outputs
+        let block = genesis();
+        let coin = Coin { value: 1, script: vec![0x51], height: 1, coinbase: true };
+        let unspent: HashMap<Key, Coin> = iter::once((outpoint_key(&block.txns[0].hash, 0), coin)).collect();
+        assert_eq!(reason(validate_block(&block, &unspent, 2)), format!("bad-txns-BIP30 in {}", reverse_hash(&block.txns[0].hash)));
+        validate_block(&block, &HashMap::new(), 2).unwrap();
+
+        // Not checked while BIP34 makes coinbases unique
+        let block = segwit_block(SEGWIT_HEIGHT);
+        let mut coins = coins(&block);
+        let coin = Coin { value: 1, script: vec![0x51], height: 1, coinbase: false };
+        coins.insert(outpoint_key(&block.txns[1].hash, 0), coin);
+        validate_block(&block, &coins, SEGWIT_HEIGHT).unwrap();
+    }
+
+    #[test]
+    fn coinbase_height() {
+        let block = segwit_block(SEGWIT_HEIGHT);
+        assert_eq!(reason(validate_block(&block, &coins(&block), SEGWIT_HEIGHT + 1)), "bad-cb-height");
+        let mut block = segwit_block(SEGWIT_HEIGHT);
+        block.txns[0].inputs[0].signature_script = hex::encode([push_data(&to_script_nb(SEGWIT_HEIGHT as i64 - 1)), vec![0; 4]].concat());
+        commit(&mut block, [0; 32]);
+        assert_eq!(reason(validate_block(&block, &coins(&block), SEGWIT_HEIGHT)), "bad-cb-height");
+    }
+
+    #[test]
+    fn block_sigops() {
+        // Coinbase output of OP_CHECKSIGs, each of legacy cost 4, next to the 3 sigops of the witness script
+        let with_checksigs = |count| {
+            let mut block = segwit_block(SEGWIT_HEIGHT);
+            block.txns[0].outputs[0].pub_key_script = hex::encode(vec![0xac; count]);
+            commit(&mut block, [0; 32]);
+            block
+        };
+        let block = with_checksigs(MAX_BLOCK_SIGOPS_COST / 4 + 1);
+        assert_eq!(reason(validate_block(&block, &coins(&block), SEGWIT_HEIGHT)), "bad-blk-sigops");
+        let block = with_checksigs(MAX_BLOCK_SIGOPS_COST / 4);
+        assert_eq!(reason(validate_block(&block, &coins(&block), SEGWIT_HEIGHT)), "bad-blk-sigops");
+        let block = with_checksigs(MAX_BLOCK_SIGOPS_COST / 4 - 1);
+        validate_block(&block, &coins(&block), SEGWIT_HEIGHT).unwrap();
+    }
+
+    #[test]
+    fn failing_script() {
+        // The amount is signed: another one makes the signatures fail
+        let block = segwit_block(SEGWIT_HEIGHT);
+        let mut coins = coins(&block);
+        for coin in coins.values_mut() {
+            coin.value += 1;
+        }
+        let error = reason(validate_block(&block, &coins, SEGWIT_HEIGHT));
+        assert!(error.starts_with("mandatory-script-verify-flag-failed ("), "{}", error);
+        assert!(error.ends_with(&format!(" in {}:0", reverse_hash(&block.txns[1].hash))), "{}", error);
+    }
+
+    #[test]
+    fn statuses() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let statuses = db.open_tree(STATUS_TREE).unwrap();
+        let (valid, invalid) = (reverse_hash(&genesis().hash), "01".repeat(32));
+        store_status(&statuses, &valid, true).unwrap();
+        store_status(&statuses, &invalid, true).unwrap();
+        store_status(&statuses, &invalid, false).unwrap();
+        assert_eq!(block_status(&statuses, &valid).unwrap(), Some(true));
+        assert_eq!(block_status(&statuses, &invalid).unwrap(), Some(false));
+        assert_eq!(block_status(&statuses, &"00".repeat(32)).unwrap(), None);
+        assert_eq!(invalid_blocks(&statuses).unwrap(), iter::once(invalid).collect());
+    }
+}
@@ -1,3 +1,4 @@
+use std::env;
 use std::process;
 use std::sync::atomic::Ordering;
 use std::sync::mpsc;
@@ -6,13 +7,22 @@ use std::time::{Duration, SystemTime};
 
 use jemalloc_ctl::{epoch, stats};
 
+mod bcaddrindex;
 mod bcblocks;
+mod bccoinbase;
+mod bcfees;
 mod bcfile;
+mod bcfuzz;
+mod bcgetblock;
+mod bcindex;
+mod bcjson;
 mod bcnet;
 mod bcpeers;
 mod bcparse;
 mod bcscript;
 mod bcutils;
+mod bcutxo;
+mod bcvalidate;
 
 use trust_dns_resolver::Resolver;
 use trust_dns_resolver::config::ResolverConfig;
@@ -35,7 +45,33 @@ pub static mut LAST_VOL_HEADERS: usize = 0;
 static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
 fn main() {
-    bcscript::main();
+    let args: Vec<String> = env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("fuzz") => return bcfuzz::main(&args[2..]),
+        Some("schema") => return bcjson::main(&args[2..]),
+        Some("decode") => return bcgetblock::main(&args[2..]),
+        Some("utxo") => return bcutxo::main(&args[2..]),
+        Some("validate") => return bcvalidate::main(&args[2..]),
+        Some("fees") => return bcfees::main(&args[2..]),
+        Some("index") => return bcindex::main(&args[2..]),
+        Some("address") => return bcaddrindex::main(&args[2..]),
+        Some("conformance") => return bcscript::conformance::main(&args[2..]),
+        Some("debug") => return bcscript::debugger::main(&args[2..]),
+        Some("policy") => return bcscript::policy::main(&args[2..]),
+        Some("pubkey") => return bcscript::public_key::main(&args[2..]),
+        Some("signature") => return bcscript::signature::main(&args[2..]),
+        _ => {}
+    }
+
+    // --getblock also writes each block in the `bitcoin-cli getblock <hash> 2` layout
+    let block_format = match args.iter().any(|a| a == "--getblock") {
+        true => bcfile::BlockFormat::GetBlock,
+        false => bcfile::BlockFormat::Stored,
+    };
+    // --txindex keeps the txid and spent-by indexes up to date with the stored blocks,
+    // --addrindex the script index too, which needs the spent-by index for the spends
+    let script_index = args.iter().any(|a| a == "--addrindex");
+    let tx_index = script_index || args.iter().any(|a| a == "--txindex");
 
     bcfile::open_logfile(LOG_FILE);
     bcfile::load_headers_at_startup();
@@ -58,7 +94,7 @@ fn main() {
 
     eprintln!("Début initialisation {} threads", THREADS);
     thread::spawn(move || { check_pool_size(SystemTime::now()); });
-    thread::spawn(move || { bcfile::store_block(block_receiver); });
+    thread::spawn(move || { bcfile::store_block(block_receiver, block_format, tx_index, script_index); });
 
     for i in 0..THREADS {
         let sender = address_channel_sender.clone();
@@ -105,7 +141,7 @@ fn check_pool_size(start_time: SystemTime) {
 
         unsafe {
             eprintln!("\nTotal: {} nodes\t -> TBD: {}, Done: {}, Fail: {}, Connectés/Data: {}/{}", total, other, done, failed, bcnet::NB_NOEUDS_CONNECTES.lock().unwrap().len(), THREADS);
-            eprintln!("{}s Volume / Speed\t\t -> Missing Headers : {}-{}/s,  Downloaded Blocks : {}-{}/s différence {}", elapsed, headers, (headers - LAST_VOL_HEADERS) / elapsed as usize, blocks, (blocks - LAST_VOL_BLOCKS_DIR) / elapsed as usize, blocks - LAST_VOL_BLOCKS_DIR as usize);
+            eprintln!("{}s Volume / Speed\t\t -> Missing Headers : {}-{}/s,  Downloaded Blocks : {}-{}/s différence {}", elapsed, headers, (headers - LAST_VOL_HEADERS) / elapsed as usize, blocks, (blocks - LAST_VOL_BLOCKS_DIR) / elapsed as usize, blocks - LAST_VOL_BLOCKS_DIR);
             LAST_VOL_HEADERS = headers;
             LAST_VOL_BLOCKS_DIR = blocks;
         }